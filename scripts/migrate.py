@@ -0,0 +1,205 @@
+# -*- coding: utf-8 -*-
+
+"""
+Export/import data between Sucredb clusters using SCAN and RESTORE.
+
+Each vnode is scanned from one of its owners, records are written to the
+export file as json lines. The last cursor of every vnode is written to the
+state file so an interrupted export can be resumed.
+SCAN only returns the keys of the selected keyspace, each keyspace is exported
+separately and its records are imported back into the same keyspace.
+"""
+
+# python std lib
+from __future__ import print_function
+import base64
+import json
+import os
+import struct
+
+# 3rd party imports
+from docopt import docopt
+from redis import StrictRedis
+from redis.exceptions import ResponseError
+
+
+def b64(data):
+    return base64.b64encode(data).decode("ascii")
+
+
+def unb64(data):
+    return base64.b64decode(data)
+
+
+def encode_rendered(value):
+    if isinstance(value, list):
+        return [encode_rendered(v) for v in value]
+    if isinstance(value, bytes):
+        return b64(value)
+    return value
+
+
+def decode_rendered(value):
+    if isinstance(value, list):
+        return [decode_rendered(v) for v in value]
+    if isinstance(value, int):
+        return value
+    return unb64(value)
+
+
+def vnode_owners(client):
+    """
+    Returns a list of (host, port) per vnode, in vnode order.
+    """
+    # not CLUSTER SLOTS, the slot ranges aren't in vnode order after a split
+    vnodes = sorted(client.execute_command("CLUSTER", "VNODES"), key=lambda v: v[0])
+    owners = []
+    for vnode in vnodes:
+        host, port = vnode[1][0], vnode[1][1]
+        if isinstance(host, bytes):
+            host = host.decode("ascii")
+        owners.append((host, int(port)))
+    return owners
+
+
+def load_state(state_file):
+    if not state_file or not os.path.exists(state_file):
+        return {}
+    with open(state_file) as f:
+        return json.load(f)
+
+
+def save_state(state_file, state):
+    if not state_file:
+        return
+    tmp = state_file + ".tmp"
+    with open(tmp, "w") as f:
+        json.dump(state, f)
+    os.rename(tmp, state_file)
+
+
+def export(host, port, out_file, state_file, count, keyspace):
+    owners = vnode_owners(StrictRedis(host, port))
+    state = load_state(state_file)
+    clients = {}
+    with open(out_file, "a") as out:
+        for vnode, owner in enumerate(owners):
+            vnode_state = state.get(str(vnode))
+            if vnode_state == "done":
+                continue
+            client = clients.get(owner)
+            if client is None:
+                client = clients[owner] = StrictRedis(*owner, db=keyspace)
+            cursor = unb64(vnode_state) if vnode_state else struct.pack(">H", vnode)
+            while True:
+                next_cursor, entries = client.execute_command("SCAN", cursor, count)
+                for key, ty, dcc, rendered in entries:
+                    out.write(json.dumps({
+                        "keyspace": keyspace,
+                        "key": b64(key),
+                        "type": ty.decode("ascii") if isinstance(ty, bytes) else ty,
+                        "dcc": b64(dcc),
+                        "value": encode_rendered(rendered),
+                    }) + "\n")
+                out.flush()
+                # a cursor pointing to another vnode (or empty) means this one is done
+                if len(next_cursor) < 2 or struct.unpack(">H", next_cursor[:2])[0] != vnode:
+                    state[str(vnode)] = "done"
+                    save_state(state_file, state)
+                    break
+                cursor = next_cursor
+                state[str(vnode)] = b64(cursor)
+                save_state(state_file, state)
+            print("vnode {} exported".format(vnode))
+
+
+class KeyspaceClient(object):
+    """
+    Runs commands in a non default keyspace, following the MOVED redirections
+    as cluster clients can't select a keyspace.
+    """
+
+    def __init__(self, host, port, keyspace):
+        self.keyspace = keyspace
+        self.clients = {}
+        self.node = (host, port)
+
+    def execute_command(self, *args):
+        while True:
+            client = self.clients.get(self.node)
+            if client is None:
+                client = self.clients[self.node] = StrictRedis(*self.node, db=self.keyspace)
+            try:
+                return client.execute_command(*args)
+            except ResponseError as e:
+                if not str(e).startswith("MOVED "):
+                    raise
+                host, port = str(e).split()[2].rsplit(":", 1)
+                self.node = (host, int(port))
+
+
+def import_record(rc, record, regenerate):
+    key = unb64(record["key"])
+    if not regenerate:
+        rc.execute_command("RESTORE", key, unb64(record["dcc"]))
+        return
+    ty, value = record["type"], decode_rendered(record["value"])
+    if ty == "string":
+        # each sibling is written with an empty context, so they stay concurrent
+        for v in value[:-1]:
+            rc.execute_command("SET", key, v, "")
+    elif ty == "hash":
+        for k, v in zip(value[::2], value[1::2]):
+            rc.execute_command("HSET", key, k, v)
+    elif ty == "set":
+        for v in value:
+            rc.execute_command("SADD", key, v)
+    elif ty == "counter":
+        rc.execute_command("CSET", key, value)
+
+
+def import_(rc, host, port, in_file, regenerate, skip):
+    keyspace_clients = {0: rc}
+    with open(in_file) as f:
+        for i, line in enumerate(f):
+            if i < skip:
+                continue
+            record = json.loads(line)
+            keyspace = record.get("keyspace", 0)
+            client = keyspace_clients.get(keyspace)
+            if client is None:
+                client = keyspace_clients[keyspace] = KeyspaceClient(host, port, keyspace)
+            import_record(client, record, regenerate)
+            if i % 1000 == 0:
+                print("imported {} records".format(i))
+
+
+if __name__ == "__main__":
+    __docopt__ = """
+Usage:
+  migrate export [--host IP] [--port PORT] [--state FILE] [--count N] [--keyspace N] <file>
+  migrate import [--host IP] [--port PORT] [--regenerate] [--skip N] <file>
+  migrate -h | --help
+
+Options:
+  --host IP          Cluster node to connect to [default: 127.0.0.1]
+  --port PORT        Port of the node [default: 6379]
+  --state FILE       Export progress file, used to resume an interrupted export (one per keyspace)
+  --count N          Keys per SCAN call [default: 100]
+  --keyspace N       Keyspace to export, see SELECT [default: 0]
+  --regenerate       Discard the exported causal context and replay values as new writes
+  --skip N           Skip the first N records of the file (resume an import) [default: 0]
+  -h --help          show this help and exit
+    """
+
+    args = docopt(__docopt__)
+    host, port = args["--host"], int(args["--port"])
+
+    if args["export"]:
+        export(host, port, args["<file>"], args["--state"], int(args["--count"]),
+               int(args["--keyspace"]))
+    else:
+        from rediscluster import StrictRedisCluster
+        rc = StrictRedisCluster(startup_nodes=[{"host": host, "port": port}],
+                                decode_responses=False, skip_full_coverage_check=True)
+        import_(rc, host, port, args["<file>"], args["--regenerate"], int(args["--skip"]))
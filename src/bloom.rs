@@ -0,0 +1,85 @@
+use std::collections::hash_map::DefaultHasher;
+use std::f64::consts::LN_2;
+use std::hash::Hasher;
+
+/// A plain Bloom filter over byte keys.
+///
+/// Keys can't be removed, so deleted keys keep answering `maybe` until
+/// the filter is rebuilt (i.e. on restart). That only adds false positives.
+#[derive(Debug)]
+pub struct BloomFilter {
+    bits: Vec<u64>,
+    num_bits: u64,
+    num_hashes: u32,
+}
+
+impl BloomFilter {
+    /// Creates a filter sized for `capacity` keys with the given false positive rate
+    pub fn new(capacity: u64, fp_rate: f64) -> Self {
+        assert!(fp_rate > 0.0 && fp_rate < 1.0);
+        let capacity = capacity.max(1) as f64;
+        let num_bits = (-capacity * fp_rate.ln() / (LN_2 * LN_2)).ceil().max(64.0) as u64;
+        let num_hashes = ((num_bits as f64 / capacity) * LN_2).round().max(1.0) as u32;
+        BloomFilter {
+            bits: vec![0; ((num_bits + 63) / 64) as usize],
+            num_bits: num_bits,
+            num_hashes: num_hashes,
+        }
+    }
+
+    // double hashing, see Kirsch and Mitzenmacher
+    fn hashes(&self, key: &[u8]) -> (u64, u64) {
+        let mut hasher = DefaultHasher::new();
+        hasher.write(key);
+        let h1 = hasher.finish();
+        hasher.write_u64(h1);
+        let h2 = hasher.finish() | 1;
+        (h1, h2)
+    }
+
+    pub fn insert(&mut self, key: &[u8]) {
+        let (h1, h2) = self.hashes(key);
+        for i in 0..self.num_hashes as u64 {
+            let bit = h1.wrapping_add(i.wrapping_mul(h2)) % self.num_bits;
+            self.bits[(bit / 64) as usize] |= 1 << (bit % 64);
+        }
+    }
+
+    /// Returns false if the key was definitely never inserted
+    pub fn contains(&self, key: &[u8]) -> bool {
+        let (h1, h2) = self.hashes(key);
+        (0..self.num_hashes as u64).all(|i| {
+            let bit = h1.wrapping_add(i.wrapping_mul(h2)) % self.num_bits;
+            self.bits[(bit / 64) as usize] & (1 << (bit % 64)) != 0
+        })
+    }
+
+    pub fn clear(&mut self) {
+        for b in &mut self.bits {
+            *b = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bloom() {
+        let mut bloom = BloomFilter::new(1000, 0.01);
+        for i in 0..1000 {
+            bloom.insert(format!("key{}", i).as_bytes());
+        }
+        for i in 0..1000 {
+            assert!(bloom.contains(format!("key{}", i).as_bytes()));
+        }
+        let false_positives = (1000..11000)
+            .filter(|i| bloom.contains(format!("key{}", i).as_bytes()))
+            .count();
+        assert!(false_positives < 300, "{} false positives", false_positives);
+
+        bloom.clear();
+        assert!(!bloom.contains(b"key1"));
+    }
+}
@@ -1,12 +1,14 @@
 use bincode;
-use bytes::Bytes;
-use config;
-use cubes::{self, Cube};
+use bytes::{BufMut, Bytes, BytesMut};
+use config::{self, OversizeValuePolicy};
+use cubes::{self, Cube, ResponseFn};
 use database::{Context, Database};
 use metrics::{self, Meter};
 use resp::RespValue;
+use snap;
 use std::convert::TryInto;
-use std::net;
+use std::io::{Read, Write};
+use std::{net, time};
 use types::*;
 use utils::{assume_str, replace_default};
 use version_vector::*;
@@ -23,7 +25,10 @@ pub enum CommandError {
     InvalidArgCount,
     InvalidKey,
     InvalidValue,
+    ValueTooLarge(usize),
     InvalidConsistencyValue,
+    InvalidKeyspace,
+    InvalidCursor,
     InvalidIntValue,
     InvalidExec,
     InvalidCommand,
@@ -31,11 +36,55 @@ pub enum CommandError {
     MultiplePartitions,
     MultipleKeyMutations,
     Unavailable,
+    NotEnoughReplicas,
+    // carries the current value, rendered like GET
+    CasMismatch(RespValue),
+    // the NX/XX condition of a SET didn't hold, carries the current value like CasMismatch
+    ConditionFailed(RespValue),
+    Overloaded,
+    // the stored value of the key failed its checksum or couldn't be decoded
+    Corrupted,
+    // a request with the same client request id (REQID) is still running
+    RequestInProgress,
+}
+
+impl CommandError {
+    /// Whether the request may have been applied, even partially, when this error is
+    /// returned. Like a timeout after the local write, as opposed to a validation error.
+    pub fn is_outcome_unknown(&self) -> bool {
+        match *self {
+            CommandError::Timeout | CommandError::StorageError => true,
+            _ => false,
+        }
+    }
 }
 
 impl Into<RespValue> for CommandError {
     fn into(self) -> RespValue {
-        RespValue::Error(format!("{:?}", self).into())
+        match self {
+            CommandError::CasMismatch(current) => {
+                RespValue::Array(vec![RespValue::Error("CasMismatch".into()), current])
+            }
+            CommandError::ConditionFailed(current) => {
+                RespValue::Array(vec![RespValue::Error("ConditionFailed".into()), current])
+            }
+            _ => RespValue::Error(format!("{:?}", self).into()),
+        }
+    }
+}
+
+// SET NX sets only if the key has no live value, SET XX only if it does
+#[derive(Copy, Clone, PartialEq)]
+enum SetCondition {
+    IfAbsent,
+    IfPresent,
+}
+
+fn parse_set_condition(arg: &[u8]) -> Option<SetCondition> {
+    match arg {
+        b"NX" | b"nx" => Some(SetCondition::IfAbsent),
+        b"XX" | b"xx" => Some(SetCondition::IfPresent),
+        _ => None,
     }
 }
 
@@ -69,15 +118,118 @@ fn check_key_len(key_len: usize) -> Result<(), CommandError> {
     }
 }
 
-fn check_value_len(value_len: usize) -> Result<(), CommandError> {
-    if value_len > config::MAX_VALUE_LEN {
-        Err(CommandError::InvalidKey)
+// Values exchanged with clients that enabled compression (HELLO COMPRESS) start with a
+// format byte, like the stored values do (see VALUE_FORMAT_VERSION), telling whether
+// the rest is the value as is or compressed with the snappy framing format.
+const CLIENT_VALUE_PLAIN: u8 = 0;
+const CLIENT_VALUE_SNAPPY: u8 = 1;
+
+fn compress_value(value: Bytes, threshold: usize) -> Bytes {
+    if value.len() <= threshold {
+        let mut plain = BytesMut::with_capacity(1 + value.len());
+        plain.put_u8(CLIENT_VALUE_PLAIN);
+        plain.put_slice(&value);
+        return plain.freeze();
+    }
+    let mut compressed = Vec::with_capacity(value.len() / 2);
+    compressed.push(CLIENT_VALUE_SNAPPY);
+    let mut writer = snap::Writer::new(compressed);
+    writer.write_all(&value).expect("Can't compress value");
+    writer.into_inner().expect("Can't compress value").into()
+}
+
+// compresses the values of a rendered value, the context (last item) is left as is
+fn compress_rendered_value(rendered: RespValue, threshold: usize) -> RespValue {
+    match rendered {
+        RespValue::Array(mut items) => {
+            let context = items.pop();
+            let mut items: Vec<_> = items
+                .into_iter()
+                .map(|v| match v {
+                    RespValue::Data(d) => RespValue::Data(compress_value(d, threshold)),
+                    v => v,
+                })
+                .collect();
+            items.extend(context);
+            RespValue::Array(items)
+        }
+        rendered => rendered,
+    }
+}
+
+// Keys in non default keyspaces are stored (and hashed) as
+// KEYSPACE_MARKER + u16(be) keyspace + key
+// Keys in the default keyspace are stored as they are, so the keys written before
+// keyspaces existed stay reachable, except the ones starting with the marker. Those are
+// escaped with the prefix of keyspace 0, otherwise they'd be keys of other keyspaces.
+const KEYSPACE_MARKER: u8 = 0xFF;
+/// Bytes added to the keys of non default keyspaces, they count towards MAX_KEY_LEN.
+/// Keys written before that was checked may be longer.
+pub const KEYSPACE_PREFIX_LEN: usize = 3;
+
+// the key as stored, which must fit in MAX_KEY_LEN
+fn keyspace_key(context: &Context, key: &Bytes) -> Result<Bytes, CommandError> {
+    if context.keyspace == 0 && key.first() != Some(&KEYSPACE_MARKER) {
+        Ok(key.clone())
     } else {
-        Ok(())
+        check_key_len(KEYSPACE_PREFIX_LEN + key.len())?;
+        let mut ks_key = BytesMut::with_capacity(KEYSPACE_PREFIX_LEN + key.len());
+        ks_key.put_u8(KEYSPACE_MARKER);
+        ks_key.put_u16_be(context.keyspace);
+        ks_key.put_slice(key);
+        Ok(ks_key.freeze())
+    }
+}
+
+/// The keyspace of a stored key and the key within it, see keyspace_key
+pub fn split_keyspace_key(key: &[u8]) -> (u16, &[u8]) {
+    if key.len() >= KEYSPACE_PREFIX_LEN && key[0] == KEYSPACE_MARKER {
+        let keyspace = (key[1] as u16) << 8 | key[2] as u16;
+        (keyspace, &key[KEYSPACE_PREFIX_LEN..])
+    } else {
+        (0, key)
     }
 }
 
 impl Database {
+    fn check_value_len(&self, value_len: usize) -> Result<(), CommandError> {
+        if value_len <= config::MAX_VALUE_LEN {
+            return Ok(());
+        }
+        match self.config.oversize_value_policy {
+            OversizeValuePolicy::Reject => Err(CommandError::ValueTooLarge(config::MAX_VALUE_LEN)),
+            OversizeValuePolicy::Warn => {
+                warn!(
+                    "Accepting value of {} bytes, larger than the {} bytes limit",
+                    value_len,
+                    config::MAX_VALUE_LEN
+                );
+                Ok(())
+            }
+        }
+    }
+
+    // the value sent by a client that enabled compression, without its format byte
+    fn decompress_value(&self, value: &Bytes) -> Result<Bytes, CommandError> {
+        match value.first() {
+            Some(&CLIENT_VALUE_PLAIN) => return Ok(value.slice_from(1)),
+            Some(&CLIENT_VALUE_SNAPPY) => (),
+            _ => return Err(CommandError::InvalidValue),
+        }
+        // don't inflate more than needed to know the value is too large
+        let limit = match self.config.oversize_value_policy {
+            OversizeValuePolicy::Reject => config::MAX_VALUE_LEN as u64 + 1,
+            OversizeValuePolicy::Warn => u64::max_value(),
+        };
+        let mut decompressed = Vec::new();
+        snap::Reader::new(&value[1..])
+            .take(limit)
+            .read_to_end(&mut decompressed)
+            .map_err(|_| CommandError::InvalidValue)?;
+        self.check_value_len(decompressed.len())?;
+        Ok(decompressed.into())
+    }
+
     pub fn handler_cmd(&self, mut context: Context) {
         let cmd = context.commands.pop().unwrap();
         if let Err(e) = self.handle_cmd(&mut context, cmd) {
@@ -88,6 +240,8 @@ impl Database {
 
     fn handle_cmd(&self, context: &mut Context, cmd: RespValue) -> Result<(), CommandError> {
         debug!("Processing ({:?}) {:?}", context.token, cmd);
+        // only set by the consistency of this command, if any
+        context.durable = false;
         let mut args = Vec::new();
         match cmd {
             RespValue::Array(ref a) => {
@@ -114,8 +268,15 @@ impl Database {
         if context.is_exec {
             match arg0.as_ref() {
                 b"CSET" | b"cset" => self.cmd_cset(context, args),
-                b"INCRBY" | b"incrby" => self.cmd_incrby(context, args),
+                b"INCRBY" | b"incrby" => self.cmd_incrby(context, args, false),
+                b"DECRBY" | b"decrby" => self.cmd_incrby(context, args, true),
+                b"INCR" | b"incr" => self.cmd_incr(context, args, 1),
+                b"DECR" | b"decr" => self.cmd_incr(context, args, -1),
                 b"SET" | b"set" => self.cmd_set(context, args, false),
+                b"SETEX" | b"setex" => self.cmd_setex(context, args),
+                b"EXPIRE" | b"expire" => self.cmd_expire(context, args, false),
+                b"EXPIREAT" | b"expireat" => self.cmd_expire(context, args, true),
+                b"PERSIST" | b"persist" => self.cmd_persist(context, args),
                 b"HSET" | b"hset" => self.cmd_hset(context, args),
                 b"HDEL" | b"hdel" => self.cmd_hdel(context, args),
                 b"SADD" | b"sadd" => self.cmd_sadd(context, args),
@@ -140,9 +301,20 @@ impl Database {
                 b"GET" | b"get" => self.cmd_get(context, args),
                 b"MGET" | b"mget" => self.cmd_mget(context, args),
                 b"SET" | b"set" => self.cmd_set(context, args, false),
+                b"SETEX" | b"setex" => self.cmd_setex(context, args),
+                b"EXPIRE" | b"expire" => self.cmd_expire(context, args, false),
+                b"EXPIREAT" | b"expireat" => self.cmd_expire(context, args, true),
+                b"PERSIST" | b"persist" => self.cmd_persist(context, args),
+                b"TTL" | b"ttl" => self.cmd_ttl(context, args, cubes::render_ttl),
+                b"PTTL" | b"pttl" => self.cmd_ttl(context, args, cubes::render_pttl),
+                b"CAS" | b"cas" => self.cmd_cas(context, args),
+                b"MSET" | b"mset" => self.cmd_mset(context, args),
                 b"CGET" | b"cget" => self.cmd_cget(context, args),
                 b"CSET" | b"cset" => self.cmd_cset(context, args),
-                b"INCRBY" | b"incrby" => self.cmd_incrby(context, args),
+                b"INCRBY" | b"incrby" => self.cmd_incrby(context, args, false),
+                b"DECRBY" | b"decrby" => self.cmd_incrby(context, args, true),
+                b"INCR" | b"incr" => self.cmd_incr(context, args, 1),
+                b"DECR" | b"decr" => self.cmd_incr(context, args, -1),
                 b"HGETALL" | b"hgetall" => self.cmd_hgetall(context, args),
                 b"HSET" | b"hset" => self.cmd_hset(context, args),
                 b"HDEL" | b"hdel" => self.cmd_hdel(context, args),
@@ -161,6 +333,16 @@ impl Database {
                     check_arg_count(args.len(), 0, 0).and_then(|_| Ok(self.respond_ok(context)))
                 }
                 b"CONFIG" | b"config" => self.cmd_config(context, args),
+                b"INFO" | b"info" => self.cmd_info(context, args),
+                b"SELECT" | b"select" => self.cmd_select(context, args),
+                b"HELLO" | b"hello" => self.cmd_hello(context, args),
+                b"AFFINITY" | b"affinity" => self.cmd_affinity(context, args),
+                b"SCAN" | b"scan" => self.cmd_scan(context, args),
+                b"DELRANGE" | b"delrange" => self.cmd_delrange(context, args),
+                b"WAIT" | b"wait" => self.cmd_wait(context, args),
+                b"RESTORE" | b"restore" => self.cmd_restore(context, args),
+                b"DEBUG" | b"debug" => self.cmd_debug(context, args),
+                b"REQID" | b"reqid" => self.cmd_reqid(context, args),
                 _ => {
                     debug!("Unknown command {:?}", cmd);
                     Err(CommandError::UnknownCommand)
@@ -182,22 +364,74 @@ impl Database {
         }
     }
 
+    // reads have nothing to fsync, a durable consistency (e.g. `q!`) is an error
     fn parse_consistency(
         &self,
+        context: &mut Context,
+        try: bool,
+        args: &[&Bytes],
+        i: usize,
+    ) -> Result<ConsistencyLevel, CommandError> {
+        if try && args[i].len() > 1 && args[i].last() == Some(&b'!') {
+            return Err(CommandError::InvalidConsistencyValue);
+        }
+        let default = self.config.consistency_read;
+        self.parse_consistency_or(context, try, args, i, default)
+    }
+
+    fn parse_write_consistency(
+        &self,
+        context: &mut Context,
+        try: bool,
+        args: &[&Bytes],
+        i: usize,
+    ) -> Result<ConsistencyLevel, CommandError> {
+        let default = self.config.consistency_write;
+        self.parse_consistency_or(context, try, args, i, default)
+    }
+
+    // a trailing `!` (e.g. `q!`) also requires the coordinator to fsync writes,
+    // that's recorded in the context as the durability of the next flush.
+    // It's reset by each command, see handle_cmd
+    fn parse_consistency_or(
+        &self,
+        context: &mut Context,
         try: bool,
         args: &[&Bytes],
         i: usize,
+        default: ConsistencyLevel,
     ) -> Result<ConsistencyLevel, CommandError> {
         Ok(if try {
-            args[i]
-                .as_ref()
-                .try_into()
+            let mut arg = &args[i][..];
+            if arg.len() > 1 && arg.last() == Some(&b'!') {
+                context.durable = true;
+                arg = &arg[..arg.len() - 1];
+            }
+            arg.try_into()
                 .map_err(|_| CommandError::InvalidConsistencyValue)?
         } else {
-            self.config.consistency_read
+            default
         })
     }
 
+    fn value_render_fn(&self, context: &Context) -> ResponseFn {
+        self.value_render_fn_with(context.compression, context.key_history)
+    }
+
+    fn value_render_fn_with(&self, compression: bool, key_history: bool) -> ResponseFn {
+        let render: fn(Cube) -> RespValue = if key_history {
+            cubes::render_value_history
+        } else {
+            cubes::render_value
+        };
+        if compression {
+            let threshold = self.config.client_compression_threshold as usize;
+            Box::new(move |cube: Cube| compress_rendered_value(render(cube), threshold))
+        } else {
+            Box::new(render)
+        }
+    }
+
     fn cmd_multi(&self, context: &mut Context, args: &[&Bytes]) -> Result<(), CommandError> {
         assert!(!context.is_multi);
         context.is_multi = true;
@@ -210,7 +444,8 @@ impl Database {
             return Err(CommandError::InvalidExec);
         }
         check_arg_count(args.len(), 0, 1)?;
-        let consistency = self.parse_consistency(args.len() > 0, args, 0)?;
+        let consistency = self.parse_write_consistency(context, args.len() > 0, args, 0)?;
+        let durable = context.durable;
         assert!(!context.is_exec);
         context.is_exec = true;
         let mut cmds = replace_default(&mut context.commands);
@@ -219,19 +454,192 @@ impl Database {
             self.handle_cmd(context, cmd)?;
         }
         context.commands = cmds;
+        context.durable = durable;
         self.set_flush(context, consistency)
     }
 
+    // runs the command at most once per client request id, while the response is remembered
+    // retries get it back instead, so a retried write doesn't create another version
+    fn cmd_reqid(&self, context: &mut Context, args: &[&Bytes]) -> Result<(), CommandError> {
+        if args.len() < 3 {
+            return Err(CommandError::InvalidArgCount);
+        }
+        // scoped to the keyspace, connections using the same one share the ids
+        let id = (
+            context.keyspace,
+            parse_int(true, args, 0)?,
+            parse_int(true, args, 1)?,
+        );
+        match args[2].as_ref() {
+            b"REQID" | b"reqid" | b"MULTI" | b"multi" | b"EXEC" | b"exec" => {
+                return Err(CommandError::InvalidCommand)
+            }
+            _ => (),
+        }
+        if let Some(response) = self.request_id_begin(id)? {
+            return Ok(self.respond_resp(context, response));
+        }
+        // Database::respond remembers the response, whatever path the command takes
+        context.request_id = Some(id);
+        let cmd = args[2..]
+            .iter()
+            .map(|&arg| RespValue::Data(arg.clone()))
+            .collect();
+        self.handle_cmd(context, RespValue::Array(cmd))
+    }
+
     fn cmd_config(&self, context: &mut Context, _args: &[&Bytes]) -> Result<(), CommandError> {
         Ok(self.respond_resp(context, RespValue::Array(Default::default())))
     }
 
+    // redis style "field:value" lines, the section argument is ignored
+    fn cmd_info(&self, context: &mut Context, args: &[&Bytes]) -> Result<(), CommandError> {
+        check_arg_count(args.len(), 0, 1)?;
+        let info = format!(
+            concat!(
+                "# Sucredb\r\nread_replica_policy:{}\r\ninflight_requests:{}\r\n",
+                "client_connections:{}\r\nhealth:{}\r\n"
+            ),
+            self.config.read_replica_policy.as_str(),
+            self.inflight_requests(),
+            self.client_connections(),
+            self.health().as_str()
+        );
+        Ok(self.respond_resp(context, RespValue::Data(info.into())))
+    }
+
+    // enables the listed capabilities for the connection, others are disabled
+    // and unknown ones are ignored. Replies with the enabled capabilities.
+    fn cmd_hello(&self, context: &mut Context, args: &[&Bytes]) -> Result<(), CommandError> {
+        context.compression = false;
+        context.stale_reads = false;
+        context.key_history = false;
+        context.sync_repair = false;
+        for arg in args {
+            match arg.as_ref() {
+                b"COMPRESS" | b"compress" => context.compression = true,
+                b"STALE" | b"stale" => context.stale_reads = self.config.bootstrap_stale_reads,
+                b"HISTORY" | b"history" => context.key_history = true,
+                b"REPAIR" | b"repair" => context.sync_repair = true,
+                _ => (),
+            }
+        }
+        let mut enabled = Vec::new();
+        if context.compression {
+            enabled.push(RespValue::Data("COMPRESS".into()));
+        }
+        if context.stale_reads {
+            enabled.push(RespValue::Data("STALE".into()));
+        }
+        if context.key_history {
+            enabled.push(RespValue::Data("HISTORY".into()));
+        }
+        if context.sync_repair {
+            enabled.push(RespValue::Data("REPAIR".into()));
+        }
+        Ok(self.respond_resp(context, RespValue::Array(enabled)))
+    }
+
+    fn cmd_select(&self, context: &mut Context, args: &[&Bytes]) -> Result<(), CommandError> {
+        check_arg_count(args.len(), 1, 1)?;
+        let keyspace: u16 = parse_int(true, args, 0)?;
+        if keyspace >= self.config.keyspace_max {
+            return Err(CommandError::InvalidKeyspace);
+        }
+        context.keyspace = keyspace;
+        Ok(self.respond_ok(context))
+    }
+
+    fn cmd_wait(&self, context: &mut Context, args: &[&Bytes]) -> Result<(), CommandError> {
+        check_arg_count(args.len(), 4, 4)?;
+        check_key_len(args[0].len())?;
+        let vv = self.parse_vv(true, args, 1)?;
+        let replicas: usize = parse_int(true, args, 2)?;
+        let timeout: u64 = parse_int(true, args, 3)?;
+        // bounded like the writes it waits for, this also keeps the deadline from overflowing
+        if replicas == 0 || time::Duration::from_millis(timeout) > self.config.write_timeout() {
+            return Err(CommandError::InvalidIntValue);
+        }
+        let key = keyspace_key(context, args[0])?;
+        self.wait(
+            context,
+            &key,
+            vv,
+            replicas,
+            time::Duration::from_millis(timeout),
+        )
+    }
+
+    fn cmd_scan(&self, context: &mut Context, args: &[&Bytes]) -> Result<(), CommandError> {
+        check_arg_count(args.len(), 1, 3)?;
+        let count = if args.len() > 1 {
+            parse_int(true, args, 1)?
+        } else {
+            config::DEFAULT_SCAN_COUNT
+        };
+        if count == 0 || count > config::MAX_SCAN_COUNT {
+            return Err(CommandError::InvalidIntValue);
+        }
+        let prefix = args.get(2).map_or_else(Bytes::new, |&p| p.clone());
+        check_key_len(prefix.len())?;
+        let prefix = keyspace_key(context, &prefix)?;
+        self.scan(context, args[0], &prefix, count)
+    }
+
+    fn cmd_delrange(&self, context: &mut Context, args: &[&Bytes]) -> Result<(), CommandError> {
+        metrics::REQUEST_DEL.mark(1);
+        check_arg_count(args.len(), 1, 2)?;
+        // an empty prefix would delete everything
+        if args[0].is_empty() {
+            return Err(CommandError::InvalidKey);
+        }
+        check_key_len(args[0].len())?;
+        let default = self.config.consistency_delete;
+        let consistency = self.parse_consistency_or(context, args.len() > 1, args, 1, default)?;
+        let prefix = keyspace_key(context, args[0])?;
+        self.delrange(context, &prefix, consistency)
+    }
+
+    // merges a cube previously exported by SCAN, keeping its causal context
+    fn cmd_restore(&self, context: &mut Context, args: &[&Bytes]) -> Result<(), CommandError> {
+        metrics::REQUEST_SET.mark(1);
+        check_arg_count(args.len(), 2, 3)?;
+        check_key_len(args[0].len())?;
+        self.check_value_len(args[1].len())?;
+        let restored: Cube =
+            bincode::deserialize(args[1]).map_err(|_| CommandError::InvalidValue)?;
+        let consistency = self.parse_write_consistency(context, args.len() > 2, args, 2)?;
+        let key = keyspace_key(context, args[0])?;
+        self.set(
+            context,
+            &key,
+            Box::new(move |_i, _v, c: Cube| {
+                Ok((c.merge(restored), Some(RespValue::Status("OK".into()))))
+            }),
+            consistency,
+            false,
+            None,
+        )
+    }
+
+    // invalid or unknown nodes are accepted and simply ignored by reads
+    fn cmd_affinity(&self, context: &mut Context, args: &[&Bytes]) -> Result<(), CommandError> {
+        check_arg_count(args.len(), 0, 1)?;
+        context.read_affinity = if args.is_empty() {
+            None
+        } else {
+            Some(parse_int(true, args, 0)?)
+        };
+        Ok(self.respond_ok(context))
+    }
+
     fn cmd_hgetall(&self, context: &mut Context, args: &[&Bytes]) -> Result<(), CommandError> {
         metrics::REQUEST_GET.mark(1);
         check_arg_count(args.len(), 1, 2)?;
         check_key_len(args[0].len())?;
-        let consistency = self.parse_consistency(args.len() > 1, args, 1)?;
-        self.get(context, args[0], consistency, Box::new(cubes::render_map))
+        let consistency = self.parse_consistency(context, args.len() > 1, args, 1)?;
+        let key = keyspace_key(context, args[0])?;
+        self.get(context, &key, consistency, Box::new(cubes::render_map))
     }
 
     fn cmd_hset(&self, context: &mut Context, args: &[&Bytes]) -> Result<(), CommandError> {
@@ -239,13 +647,14 @@ impl Database {
         check_arg_count(args.len(), 3, 4)?;
         check_key_len(args[0].len())?;
         check_key_len(args[1].len())?;
-        check_value_len(args[2].len())?;
+        self.check_value_len(args[2].len())?;
         let hash_key = args[1].clone();
         let hash_value = args[2].clone();
-        let consistency = self.parse_consistency(args.len() > 3, args, 3)?;
+        let consistency = self.parse_write_consistency(context, args.len() > 3, args, 3)?;
+        let key = keyspace_key(context, args[0])?;
         self.set(
             context,
-            args[0],
+            &key,
             Box::new(move |i, v, c: Cube| {
                 let mut map = c.into_map().ok_or(CommandError::TypeError)?;
                 let result = map.insert(i, v, hash_key, hash_value) as i64;
@@ -263,10 +672,11 @@ impl Database {
         check_key_len(args[0].len())?;
         check_key_len(args[1].len())?;
         let hash_key = args[1].clone();
-        let consistency = self.parse_consistency(args.len() > 2, args, 2)?;
+        let consistency = self.parse_write_consistency(context, args.len() > 2, args, 2)?;
+        let key = keyspace_key(context, args[0])?;
         self.set(
             context,
-            args[0],
+            &key,
             Box::new(move |i, v, c: Cube| {
                 let mut map = c.into_map().ok_or(CommandError::TypeError)?;
                 let result = map.remove(i, v, &hash_key) as i64;
@@ -282,20 +692,22 @@ impl Database {
         metrics::REQUEST_GET.mark(1);
         check_arg_count(args.len(), 1, 2)?;
         check_key_len(args[0].len())?;
-        let consistency = self.parse_consistency(args.len() > 1, args, 1)?;
-        self.get(context, args[0], consistency, Box::new(cubes::render_set))
+        let consistency = self.parse_consistency(context, args.len() > 1, args, 1)?;
+        let key = keyspace_key(context, args[0])?;
+        self.get(context, &key, consistency, Box::new(cubes::render_set))
     }
 
     fn cmd_sadd(&self, context: &mut Context, args: &[&Bytes]) -> Result<(), CommandError> {
         metrics::REQUEST_SET.mark(1);
         check_arg_count(args.len(), 2, 3)?;
         check_key_len(args[0].len())?;
-        check_value_len(args[1].len())?;
+        self.check_value_len(args[1].len())?;
         let set_value = args[1].clone();
-        let consistency = self.parse_consistency(args.len() > 2, args, 2)?;
+        let consistency = self.parse_write_consistency(context, args.len() > 2, args, 2)?;
+        let key = keyspace_key(context, args[0])?;
         self.set(
             context,
-            args[0],
+            &key,
             Box::new(move |i, v, c: Cube| {
                 let mut set = c.into_set().ok_or(CommandError::TypeError)?;
                 let result = set.insert(i, v, set_value) as i64;
@@ -311,12 +723,13 @@ impl Database {
         metrics::REQUEST_DEL.mark(1);
         check_arg_count(args.len(), 2, 3)?;
         check_key_len(args[0].len())?;
-        check_value_len(args[1].len())?;
+        self.check_value_len(args[1].len())?;
         let set_value = args[1].clone();
-        let consistency = self.parse_consistency(args.len() > 2, args, 2)?;
+        let consistency = self.parse_write_consistency(context, args.len() > 2, args, 2)?;
+        let key = keyspace_key(context, args[0])?;
         self.set(
             context,
-            args[0],
+            &key,
             Box::new(move |i, v, c: Cube| {
                 let mut set = c.into_set().ok_or(CommandError::TypeError)?;
                 let result = set.remove(i, v, &set_value) as i64;
@@ -332,8 +745,10 @@ impl Database {
         metrics::REQUEST_GET.mark(1);
         check_arg_count(args.len(), 1, 2)?;
         check_key_len(args[0].len())?;
-        let consistency = self.parse_consistency(args.len() > 1, args, 1)?;
-        self.get(context, args[0], consistency, Box::new(cubes::render_value))
+        let consistency = self.parse_consistency(context, args.len() > 1, args, 1)?;
+        let key = keyspace_key(context, args[0])?;
+        let render_fn = self.value_render_fn(context);
+        self.get(context, &key, consistency, render_fn)
     }
 
     fn cmd_mget(&self, context: &mut Context, args: &[&Bytes]) -> Result<(), CommandError> {
@@ -346,12 +761,18 @@ impl Database {
         if key_count >= args.len() {
             return Err(CommandError::InvalidCommand);
         }
-        let keys = &args[1..1 + key_count];
-        let consistency = self.parse_consistency(args.len() > 1 + key_count, args, 1 + key_count)?;
-        for key in keys {
+        let consistency =
+            self.parse_consistency(context, args.len() > 1 + key_count, args, 1 + key_count)?;
+        let mut keys = Vec::with_capacity(key_count);
+        for key in &args[1..1 + key_count] {
             check_key_len(key.len())?;
+            keys.push(keyspace_key(context, key)?);
         }
-        self.mget(context, keys, consistency, Box::new(cubes::render_value))
+        let keys: Vec<&Bytes> = keys.iter().collect();
+        let (compression, key_history) = (context.compression, context.key_history);
+        self.mget(context, &keys, consistency, &|| {
+            self.value_render_fn_with(compression, key_history)
+        })
     }
 
     fn cmd_set(
@@ -361,16 +782,45 @@ impl Database {
         reply_result: bool,
     ) -> Result<(), CommandError> {
         metrics::REQUEST_SET.mark(1);
+        // the condition goes last, after the optional context and consistency
+        let condition = if args.len() > 2 {
+            parse_set_condition(args[args.len() - 1])
+        } else {
+            None
+        };
+        let args = if condition.is_some() {
+            &args[..args.len() - 1]
+        } else {
+            args
+        };
         check_arg_count(args.len(), 2, 4)?;
         check_key_len(args[0].len())?;
-        check_value_len(args[1].len())?;
-        let value = args[1].clone();
+        self.check_value_len(args[1].len())?;
+        let value = if context.compression {
+            self.decompress_value(args[1])?
+        } else {
+            args[1].clone()
+        };
         let vv = self.parse_vv(args.len() > 2, args, 2)?;
-        let consistency = self.parse_consistency(args.len() > 3, args, 3)?;
+        let consistency = self.parse_write_consistency(context, args.len() > 3, args, 3)?;
+        let key = keyspace_key(context, args[0])?;
+        let render_fn = if reply_result {
+            Some(self.value_render_fn(context))
+        } else {
+            None
+        };
+        let mut failed_fn = condition.map(|_| self.value_render_fn(context));
         self.set(
             context,
-            args[0],
+            &key,
             Box::new(move |i, v, c: Cube| {
+                // checked against the coordinator value, tombstones don't count as values
+                if let Some(condition) = condition {
+                    if c.has_values() != (condition == SetCondition::IfPresent) {
+                        let current = failed_fn.as_mut().unwrap()(c);
+                        return Err(CommandError::ConditionFailed(current));
+                    }
+                }
                 let mut cube_value = c.into_value().ok_or(CommandError::TypeError)?;
                 cube_value.set(i, v, Some(value), &vv);
                 let resp = if reply_result {
@@ -382,23 +832,196 @@ impl Database {
             }),
             consistency,
             reply_result,
-            if reply_result {
-                Some(Box::new(cubes::render_value))
-            } else {
-                None
-            },
+            render_fn,
+        )
+    }
+
+    // like SET but the new version expires after the given seconds, in all replicas
+    fn cmd_setex(&self, context: &mut Context, args: &[&Bytes]) -> Result<(), CommandError> {
+        metrics::REQUEST_SET.mark(1);
+        check_arg_count(args.len(), 3, 5)?;
+        check_key_len(args[0].len())?;
+        let ttl: u64 = parse_int(true, args, 1)?;
+        if ttl == 0 {
+            return Err(CommandError::InvalidIntValue);
+        }
+        self.check_value_len(args[2].len())?;
+        let value = if context.compression {
+            self.decompress_value(args[2])?
+        } else {
+            args[2].clone()
+        };
+        let vv = self.parse_vv(args.len() > 3, args, 3)?;
+        let consistency = self.parse_write_consistency(context, args.len() > 4, args, 4)?;
+        let key = keyspace_key(context, args[0])?;
+        let expire_at = cubes::now_millis().saturating_add(ttl.saturating_mul(1000));
+        self.set(
+            context,
+            &key,
+            Box::new(move |i, v, c: Cube| {
+                let mut cube_value = c.into_value().ok_or(CommandError::TypeError)?;
+                cube_value.set_expiring(i, v, value, &vv, expire_at);
+                Ok((Cube::Value(cube_value), Some(RespValue::Status("OK".into()))))
+            }),
+            consistency,
+            false,
+            None,
         )
     }
 
+    // EXPIRE key seconds / EXPIREAT key timestamp (seconds since epoch)
+    fn cmd_expire(
+        &self,
+        context: &mut Context,
+        args: &[&Bytes],
+        absolute: bool,
+    ) -> Result<(), CommandError> {
+        metrics::REQUEST_SET.mark(1);
+        check_arg_count(args.len(), 2, 3)?;
+        check_key_len(args[0].len())?;
+        let secs: u64 = parse_int(true, args, 1)?;
+        let expire_at = if absolute {
+            secs.saturating_mul(1000)
+        } else if secs == 0 {
+            return Err(CommandError::InvalidIntValue);
+        } else {
+            cubes::now_millis().saturating_add(secs.saturating_mul(1000))
+        };
+        let consistency = self.parse_write_consistency(context, args.len() > 2, args, 2)?;
+        let key = keyspace_key(context, args[0])?;
+        self.set_expiration(context, &key, Some(expire_at), consistency)
+    }
+
+    fn cmd_persist(&self, context: &mut Context, args: &[&Bytes]) -> Result<(), CommandError> {
+        metrics::REQUEST_SET.mark(1);
+        check_arg_count(args.len(), 1, 2)?;
+        check_key_len(args[0].len())?;
+        let consistency = self.parse_write_consistency(context, args.len() > 1, args, 1)?;
+        let key = keyspace_key(context, args[0])?;
+        self.set_expiration(context, &key, None, consistency)
+    }
+
+    // expirations belong to the versions, so changing it writes the value again
+    // as a new version that supersedes the current one, through the coordinator.
+    // there's no merged value of concurrent versions to write, those keys fail with
+    // ConditionFailed and the current values so the client can resolve them first.
+    fn set_expiration(
+        &self,
+        context: &mut Context,
+        key: &Bytes,
+        expire_at: Option<u64>,
+        consistency: ConsistencyLevel,
+    ) -> Result<(), CommandError> {
+        let mut failed_fn = self.value_render_fn(context);
+        self.set(
+            context,
+            key,
+            Box::new(move |i, v, c: Cube| {
+                let vv = c.context().clone();
+                let mut cube_value = c.into_value().ok_or(CommandError::TypeError)?;
+                let mut live = cube_value.live();
+                if live.len() > 1 {
+                    let current = failed_fn(Cube::Value(cube_value));
+                    return Err(CommandError::ConditionFailed(current));
+                }
+                let result = match (live.pop(), expire_at) {
+                    // nothing to expire, stored like a DEL of a missing key
+                    (None, _) => {
+                        cube_value.set(i, v, None, &vv);
+                        0
+                    }
+                    (Some((value, _)), Some(expire_at)) => {
+                        cube_value.set_expiring(i, v, value, &vv, expire_at);
+                        1
+                    }
+                    (Some((value, expiration)), None) => {
+                        cube_value.set(i, v, Some(value), &vv);
+                        expiration.is_some() as i64
+                    }
+                };
+                Ok((Cube::Value(cube_value), Some(RespValue::Int(result))))
+            }),
+            consistency,
+            false,
+            None,
+        )
+    }
+
+    // served by the local replica, if the node is one
+    fn cmd_ttl(
+        &self,
+        context: &mut Context,
+        args: &[&Bytes],
+        render: fn(Cube) -> RespValue,
+    ) -> Result<(), CommandError> {
+        metrics::REQUEST_GET.mark(1);
+        check_arg_count(args.len(), 1, 1)?;
+        check_key_len(args[0].len())?;
+        let key = keyspace_key(context, args[0])?;
+        self.get(context, &key, ConsistencyLevel::One, Box::new(render))
+    }
+
+    // sets the value only if the current causal context is exactly `context`,
+    // otherwise replies with CasMismatch and the current value.
+    // the check runs on the coordinator, before the write is sent to the replicas.
+    fn cmd_cas(&self, context: &mut Context, args: &[&Bytes]) -> Result<(), CommandError> {
+        metrics::REQUEST_SET.mark(1);
+        check_arg_count(args.len(), 3, 4)?;
+        check_key_len(args[0].len())?;
+        self.check_value_len(args[2].len())?;
+        let vv = self.parse_vv(true, args, 1)?;
+        let value = if context.compression {
+            self.decompress_value(args[2])?
+        } else {
+            args[2].clone()
+        };
+        let consistency = self.parse_write_consistency(context, args.len() > 3, args, 3)?;
+        let key = keyspace_key(context, args[0])?;
+        let mut mismatch_fn = self.value_render_fn(context);
+        self.set(
+            context,
+            &key,
+            Box::new(move |i, v, c: Cube| {
+                if !c.context_eq(&vv) {
+                    return Err(CommandError::CasMismatch(mismatch_fn(c)));
+                }
+                let mut cube_value = c.into_value().ok_or(CommandError::TypeError)?;
+                cube_value.set(i, v, Some(value), &vv);
+                Ok((Cube::Value(cube_value), Some(RespValue::Status("OK".into()))))
+            }),
+            consistency,
+            false,
+            None,
+        )
+    }
+
+    // sets all key value pairs in a single batch, like a MULTI/EXEC of SETs.
+    // all keys must belong to the same vnode.
+    fn cmd_mset(&self, context: &mut Context, args: &[&Bytes]) -> Result<(), CommandError> {
+        assert!(!context.is_multi && !context.is_exec);
+        context.is_multi = true;
+        check_arg_count(args.len(), 2, 201)?;
+        let pairs_len = args.len() / 2 * 2;
+        for pair in args[..pairs_len].chunks(2) {
+            self.cmd_set(context, pair, false)?;
+        }
+        let consistency =
+            self.parse_write_consistency(context, args.len() > pairs_len, args, pairs_len)?;
+        context.is_exec = true;
+        self.mset_flush(context, consistency)
+    }
+
     fn cmd_del(&self, context: &mut Context, args: &[&Bytes]) -> Result<(), CommandError> {
         metrics::REQUEST_DEL.mark(1);
         check_arg_count(args.len(), 1, 3)?;
         check_key_len(args[0].len())?;
         let vv = self.parse_vv(args.len() > 1, args, 1)?;
-        let consistency = self.parse_consistency(args.len() > 2, args, 2)?;
+        let default = self.config.consistency_delete;
+        let consistency = self.parse_consistency_or(context, args.len() > 2, args, 2, default)?;
+        let key = keyspace_key(context, args[0])?;
         self.set(
             context,
-            args[0],
+            &key,
             Box::new(move |i, v, mut c: Cube| {
                 let result = c.del(i, v, &vv) as i64;
                 Ok((c, Some(RespValue::Int(result))))
@@ -414,10 +1037,11 @@ impl Database {
         check_arg_count(args.len(), 2, 3)?;
         check_key_len(args[0].len())?;
         let value: i64 = parse_int(args.len() > 1, args, 1)?;
-        let consistency = self.parse_consistency(args.len() > 2, args, 2)?;
+        let consistency = self.parse_write_consistency(context, args.len() > 2, args, 2)?;
+        let key = keyspace_key(context, args[0])?;
         self.set(
             context,
-            args[0],
+            &key,
             Box::new(move |i, v, c: Cube| {
                 let mut counter = c.into_counter().ok_or(CommandError::TypeError)?;
                 counter.clear(i, v);
@@ -434,24 +1058,55 @@ impl Database {
         metrics::REQUEST_GET.mark(1);
         check_arg_count(args.len(), 1, 2)?;
         check_key_len(args[0].len())?;
-        let consistency = self.parse_consistency(args.len() > 1, args, 1)?;
+        let consistency = self.parse_consistency(context, args.len() > 1, args, 1)?;
+        let key = keyspace_key(context, args[0])?;
         self.get(
             context,
-            args[0],
+            &key,
             consistency,
             Box::new(cubes::render_counter),
         )
     }
 
-    fn cmd_incrby(&self, context: &mut Context, args: &[&Bytes]) -> Result<(), CommandError> {
-        metrics::REQUEST_SET.mark(1);
+    fn cmd_incrby(
+        &self,
+        context: &mut Context,
+        args: &[&Bytes],
+        negate: bool,
+    ) -> Result<(), CommandError> {
         check_arg_count(args.len(), 2, 3)?;
+        let mut inc: i64 = parse_int(args.len() > 1, args, 1)?;
+        if negate {
+            inc = inc.checked_neg().ok_or(CommandError::InvalidIntValue)?;
+        }
+        self.counter_inc(context, args, inc, 2)
+    }
+
+    fn cmd_incr(&self, context: &mut Context, args: &[&Bytes], inc: i64) -> Result<(), CommandError> {
+        check_arg_count(args.len(), 1, 2)?;
+        self.counter_inc(context, args, inc, 1)
+    }
+
+    // increments are tracked per node, so concurrent ones add up on merge
+    fn counter_inc(
+        &self,
+        context: &mut Context,
+        args: &[&Bytes],
+        inc: i64,
+        consistency_arg: usize,
+    ) -> Result<(), CommandError> {
+        metrics::REQUEST_SET.mark(1);
         check_key_len(args[0].len())?;
-        let inc: i64 = parse_int(args.len() > 1, args, 1)?;
-        let consistency = self.parse_consistency(args.len() > 2, args, 2)?;
+        let consistency = self.parse_write_consistency(
+            context,
+            args.len() > consistency_arg,
+            args,
+            consistency_arg,
+        )?;
+        let key = keyspace_key(context, args[0])?;
         self.set(
             context,
-            args[0],
+            &key,
             Box::new(move |i, v, c: Cube| {
                 let mut counter = c.into_counter().ok_or(CommandError::TypeError)?;
                 counter.inc(i, v, inc);
@@ -465,13 +1120,50 @@ impl Database {
 
     fn cmd_type(&self, context: &mut Context, args: &[&Bytes]) -> Result<(), CommandError> {
         check_arg_count(args.len(), 1, 2)?;
-        let consistency = self.parse_consistency(args.len() > 1, args, 1)?;
-        self.get(context, args[0], consistency, Box::new(cubes::render_type))
+        let consistency = self.parse_consistency(context, args.len() > 1, args, 1)?;
+        let key = keyspace_key(context, args[0])?;
+        self.get(context, &key, consistency, Box::new(cubes::render_type))
     }
 
     fn cmd_cluster(&self, context: &mut Context, args: &[&Bytes]) -> Result<(), CommandError> {
-        check_arg_count(args.len(), 1, 1)?;
+        check_arg_count(args.len(), 1, 3)?;
         match args[0].as_ref() {
+            b"AAE" | b"aae" => {
+                check_arg_count(args.len(), 2, 3)?;
+                match args[1].as_ref() {
+                    b"START" | b"start" => {
+                        check_arg_count(args.len(), 2, 2)?;
+                        let (job, _) = self.aae_start();
+                        Ok(self.respond_int(context, job as _))
+                    }
+                    b"STATUS" | b"status" => {
+                        check_arg_count(args.len(), 3, 3)?;
+                        let job: u64 = parse_int(true, args, 2)?;
+                        let resp_vnodes = self
+                            .aae_progress(job)
+                            .into_iter()
+                            .map(|(vnode, p)| {
+                                let state = if p.running {
+                                    "running"
+                                } else if p.done {
+                                    "done"
+                                } else {
+                                    "pending"
+                                };
+                                RespValue::Array(vec![
+                                    RespValue::Int(vnode as _),
+                                    RespValue::Data(state.as_bytes().into()),
+                                    RespValue::Int(p.synced as _),
+                                    RespValue::Int(p.failed as _),
+                                    RespValue::Int(p.received as _),
+                                ])
+                            })
+                            .collect();
+                        Ok(self.respond_resp(context, RespValue::Array(resp_vnodes)))
+                    }
+                    _ => Err(CommandError::UnknownCommand),
+                }
+            }
             b"CONNECTIONS" | b"connections" => {
                 let conns = self.fabric.connections();
                 let resp_conns = conns.into_iter().map(|x| RespValue::Int(x as _)).collect();
@@ -481,6 +1173,39 @@ impl Database {
                 self.dht.rebalance().unwrap();
                 Ok(self.respond_ok(context))
             }
+            b"SECRET" | b"secret" => {
+                check_arg_count(args.len(), 2, 3)?;
+                let accept = args.get(2).map(|a| &a[..]);
+                match self.fabric.set_secret(&args[1][..], accept) {
+                    Ok(()) => Ok(self.respond_ok(context)),
+                    Err(e) => {
+                        let error = RespValue::Error(e.to_string().into());
+                        Ok(self.respond_resp(context, error))
+                    }
+                }
+            }
+            b"SPLIT" | b"split" => match self.dht.split() {
+                Ok(()) => Ok(self.respond_ok(context)),
+                Err(e) => {
+                    let error = RespValue::Error(e.to_string().into());
+                    Ok(self.respond_resp(context, error))
+                }
+            },
+            b"SYNC" | b"sync" => {
+                check_arg_count(args.len(), 3, 3)?;
+                let vnode: VNodeNo = parse_int(true, args, 1)?;
+                let peer: NodeId = parse_int(true, args, 2)?;
+                match self.trigger_sync(vnode, peer) {
+                    Ok(cookie) => {
+                        let cookie = format!("{:?}", cookie);
+                        Ok(self.respond_resp(context, RespValue::Data(cookie.as_bytes().into())))
+                    }
+                    Err(e) => {
+                        let error = RespValue::Error(e.to_string().into());
+                        Ok(self.respond_resp(context, error))
+                    }
+                }
+            }
             b"SLOTS" | b"slots" => {
                 let mut slots = Vec::new();
                 for (&(start, end), members) in &self.dht.slots() {
@@ -496,11 +1221,87 @@ impl Database {
                 }
                 Ok(self.respond_resp(context, RespValue::Array(slots)))
             }
+            b"VNODES" | b"vnodes" => {
+                let topology = self.topology();
+                let resp_vnodes = topology
+                    .vnodes
+                    .iter()
+                    .enumerate()
+                    .map(|(vnode, vn)| {
+                        let mut entry = vec![RespValue::Int(vnode as _)];
+                        // the replicas that serve reads, the complete ones first
+                        for node in vn.owners.iter().chain(&vn.retiring) {
+                            let addr = topology.nodes.iter().find(|&&(n, _)| n == *node);
+                            if let Some(&(_, addr)) = addr {
+                                entry.push(RespValue::Array(vec![
+                                    RespValue::Data(addr.ip().to_string().as_bytes().into()),
+                                    RespValue::Int(addr.port() as _),
+                                    RespValue::Data(node.to_string().as_bytes().into()),
+                                ]));
+                            }
+                        }
+                        RespValue::Array(entry)
+                    })
+                    .collect();
+                Ok(self.respond_resp(context, RespValue::Array(resp_vnodes)))
+            }
+            _ => Err(CommandError::UnknownCommand),
+        }
+    }
+
+    fn cmd_debug(&self, context: &mut Context, args: &[&Bytes]) -> Result<(), CommandError> {
+        check_arg_count(args.len(), 1, 1)?;
+        match args[0].as_ref() {
+            b"VNODES" | b"vnodes" => {
+                let resp_vnodes = self
+                    .vnodes_summary()
+                    .into_iter()
+                    .map(|(vnode, s)| {
+                        let status_age_ms = s.status_age.as_secs() * 1_000
+                            + (s.status_age.subsec_nanos() / 1_000_000) as u64;
+                        let clocks = s
+                            .clocks
+                            .into_iter()
+                            .map(|(node, version)| {
+                                RespValue::Array(vec![
+                                    RespValue::Data(node.to_string().as_bytes().into()),
+                                    RespValue::Int(version as _),
+                                ])
+                            })
+                            .collect();
+                        RespValue::Array(vec![
+                            RespValue::Int(vnode as _),
+                            RespValue::Data(format!("{:?}", s.status).as_bytes().into()),
+                            RespValue::Int(status_age_ms as _),
+                            RespValue::Int(s.keys as _),
+                            RespValue::Array(clocks),
+                            RespValue::Int(s.syncs as _),
+                            RespValue::Int(s.pending_bootstrap as _),
+                            RespValue::Int(s.bootstrap_failures as _),
+                            RespValue::Int(s.pending_recovery as _),
+                        ])
+                    })
+                    .collect();
+                Ok(self.respond_resp(context, RespValue::Array(resp_vnodes)))
+            }
             _ => Err(CommandError::UnknownCommand),
         }
     }
 
     pub fn respond(&self, context: &mut Context) {
+        if let Some(request_id) = context.request_id.take() {
+            self.request_id_end(request_id, context.response.last());
+        }
+        if context.batch.is_some() {
+            let mut context = replace_default(context);
+            let (batch, positions) = context.batch.take().unwrap();
+            let response = context.take_response();
+            let done = batch.lock().unwrap().complete(&positions, response);
+            if let Some(mut parent) = done {
+                self.respond(&mut parent);
+            }
+            return;
+        }
         debug!("Respond request ({}) {:?}", context.token, context.response);
         (&self.response_fn)(replace_default(context));
     }
@@ -519,10 +1320,21 @@ impl Database {
     }
 
     pub fn respond_error(&self, context: &mut Context, error: CommandError) {
+        // the request didn't run, so a retry with the same request id can run it
+        if !error.is_outcome_unknown() {
+            self.forget_request_id(context);
+        }
         self.respond_resp(context, error.into());
     }
 
+    fn forget_request_id(&self, context: &mut Context) {
+        if let Some(request_id) = context.request_id.take() {
+            self.request_id_end(request_id, None);
+        }
+    }
+
     pub fn respond_moved(&self, context: &mut Context, vnode: VNodeNo, addr: net::SocketAddr) {
+        self.forget_request_id(context);
         self.respond_resp(
             context,
             RespValue::Error(format!("MOVED {} {}", vnode, addr).into()),
@@ -530,6 +1342,7 @@ impl Database {
     }
 
     pub fn respond_ask(&self, context: &mut Context, vnode: VNodeNo, addr: net::SocketAddr) {
+        self.forget_request_id(context);
         self.respond_resp(
             context,
             RespValue::Error(format!("ASK {} {}", vnode, addr).into()),
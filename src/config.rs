@@ -1,17 +1,22 @@
 use std::cmp::max;
 use std::convert::TryInto;
+use std::env;
 use std::fs::File;
 use std::io::Read;
-use std::net::SocketAddr;
+use std::net::{SocketAddr, ToSocketAddrs};
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::time::Duration;
 
 use log;
 use log4rs;
 use num_cpus;
 use serde_yaml as yaml;
 
+use hash::HashAlgorithm;
+use storage::{EvictionPolicy, StorageBackend, StorageFormat};
 use types::ConsistencyLevel;
+use workers::PanicPolicy;
 use utils::GenericError;
 
 // Remember to update defaults in sucredb.yaml!
@@ -23,6 +28,9 @@ pub const DEFAULT_REPLICATION_FACTOR: &str = "3";
 pub const DEFAULT_PARTITIONS: &str = "64";
 pub const MAX_KEY_LEN: usize = 500;
 pub const MAX_VALUE_LEN: usize = 10 * 1024 * 1024;
+pub const DEFAULT_SCAN_COUNT: usize = 100;
+pub const MAX_SCAN_COUNT: usize = 1000;
+pub const ENV_PREFIX: &str = "SUCREDB_";
 
 #[derive(Debug, Clone)]
 pub struct Config {
@@ -30,25 +38,73 @@ pub struct Config {
     pub cluster_name: String,
     pub listen_addr: SocketAddr,
     pub fabric_addr: SocketAddr,
+    pub zone: String,
     pub cmd_init: Option<InitCommand>,
     pub worker_timer: u32,
     pub worker_count: u16,
+    pub worker_panic_policy: PanicPolicy,
+    pub storage_worker_count: u16,
     pub sync_incomming_max: u16,
     pub sync_outgoing_max: u16,
     pub sync_auto: bool,
     pub sync_timeout: u32,
     pub sync_msg_timeout: u32,
     pub sync_msg_inflight: u32,
+    pub sync_msg_batch_max: u32,
+    pub log_retention: u32,
+    pub zombie_timeout: u32,
+    pub sync_backpressure_latency: u32,
+    pub sync_bandwidth_limit: u64,
+    pub bootstrap_retry_backoff: u32,
+    pub bootstrap_retry_backoff_max: u32,
+    pub bootstrap_failures_max: u32,
+    pub bootstrap_stale_reads: bool,
+    pub read_repair: bool,
+    pub read_repair_sync: bool,
     pub dht_sync_on_connect: bool,
     pub dht_sync_aae: bool,
     pub fabric_timeout: u32,
+    pub fabric_capture: bool,
+    pub fabric_format: StorageFormat,
+    pub fabric_compression: FabricCompression,
+    pub fabric_compression_threshold: u32,
+    pub fabric_send_queue_max: u32,
+    pub fabric_batch_max: u32,
+    pub fabric_connections_per_peer: u32,
+    pub fabric_incoming_max: u32,
+    pub fabric_secret: String,
+    pub fabric_secret_accept: String,
     pub request_timeout: u32,
+    pub request_timeout_read: u32,
+    pub request_timeout_write: u32,
+    pub request_id_ttl: u32,
+    pub inflight_requests_max: u32,
+    pub coordinator_offload_inflight: u32,
     pub client_connection_max: u32,
+    pub client_compression_threshold: u32,
     pub value_version_max: u16,
-    pub seed_nodes: Vec<SocketAddr>,
+    pub expire_sweep_keys: u32,
+    pub tombstone_gc_interval: u32,
+    pub checkpoint_interval: u32,
+    pub checkpoint_writes: u32,
+    pub oversize_value_policy: OversizeValuePolicy,
+    pub keyspace_max: u16,
+    pub max_vnodes: u16,
+    pub storage_format: StorageFormat,
+    pub storage_backend: StorageBackend,
+    pub max_memory: u64,
+    pub eviction_policy: EvictionPolicy,
+    pub hash_algorithm: HashAlgorithm,
+    pub bloom_filter_keys: u64,
+    pub bloom_filter_fp_rate: f64,
+    pub hot_keys_sample_rate: u32,
+    pub hot_keys_max: u32,
+    pub seed_nodes: Vec<HostAddr>,
     // TODO: these should be in the cluster config instead
     pub consistency_read: ConsistencyLevel,
+    pub read_replica_policy: ReplicaPolicy,
     pub consistency_write: ConsistencyLevel,
+    pub consistency_delete: ConsistencyLevel,
 }
 
 impl Default for Config {
@@ -59,28 +115,311 @@ impl Default for Config {
             cluster_name: DEFAULT_CLUSTER_NAME.into(),
             listen_addr: DEFAULT_LISTEN_ADDR.parse().unwrap(),
             fabric_addr: DEFAULT_FABRIC_ADDR.parse().unwrap(),
+            zone: String::new(),
             cmd_init: None,
             worker_timer: 500,
             worker_count: max(4, num_cpus::get() as u16 * 2),
+            worker_panic_policy: PanicPolicy::Restart,
+            storage_worker_count: 0,
             sync_incomming_max: 10,
             sync_outgoing_max: 10,
             sync_timeout: 10_000,
             sync_msg_timeout: 1000,
             sync_msg_inflight: 10,
+            sync_msg_batch_max: 0,
+            log_retention: 72 * 3600 * 1000,
+            zombie_timeout: 60_000,
+            sync_backpressure_latency: 0,
+            sync_bandwidth_limit: 0,
+            bootstrap_retry_backoff: 1000,
+            bootstrap_retry_backoff_max: 60_000,
+            bootstrap_failures_max: 10,
+            bootstrap_stale_reads: false,
+            read_repair: false,
+            read_repair_sync: false,
             sync_auto: true,
             dht_sync_on_connect: true,
             dht_sync_aae: true,
             fabric_timeout: 1000,
+            fabric_capture: false,
+            fabric_format: StorageFormat::Bincode,
+            fabric_compression: FabricCompression::None,
+            fabric_compression_threshold: 4 * 1024,
+            fabric_send_queue_max: 10_000,
+            fabric_batch_max: 0,
+            fabric_connections_per_peer: 1,
+            fabric_incoming_max: 1024,
+            fabric_secret: String::new(),
+            fabric_secret_accept: String::new(),
             request_timeout: 1000,
+            request_timeout_read: 0,
+            request_timeout_write: 0,
+            request_id_ttl: 60_000,
+            inflight_requests_max: 10_000,
+            coordinator_offload_inflight: 0,
             client_connection_max: 100,
+            client_compression_threshold: 1024,
             value_version_max: 100,
+            expire_sweep_keys: 1000,
+            tombstone_gc_interval: 60_000,
+            checkpoint_interval: 60_000,
+            checkpoint_writes: 0,
+            oversize_value_policy: OversizeValuePolicy::Reject,
+            keyspace_max: 16,
+            max_vnodes: 0,
+            storage_format: StorageFormat::Bincode,
+            storage_backend: StorageBackend::RocksDb,
+            max_memory: 0,
+            eviction_policy: EvictionPolicy::Lru,
+            hash_algorithm: HashAlgorithm::Crc16,
+            bloom_filter_keys: 0,
+            bloom_filter_fp_rate: 0.01,
+            hot_keys_sample_rate: 0,
+            hot_keys_max: 10,
             seed_nodes: Vec::new(),
             consistency_read: ConsistencyLevel::One,
             consistency_write: ConsistencyLevel::One,
+            consistency_delete: ConsistencyLevel::Quorum,
+            read_replica_policy: ReplicaPolicy::RoundRobin,
         }
     }
 }
 
+impl Config {
+    /// Time without progress before a sync is aborted.
+    /// Never shorter than 3.1 sync_msg_timeout so a sync survives a few resends.
+    pub fn effective_sync_timeout(&self) -> u32 {
+        max(self.sync_timeout, self.sync_msg_timeout.saturating_mul(31) / 10)
+    }
+
+    /// Timeout of reads, request_timeout unless request_timeout_read is set
+    pub fn read_timeout(&self) -> Duration {
+        let timeout = match self.request_timeout_read {
+            0 => self.request_timeout,
+            t => t,
+        };
+        Duration::from_millis(timeout as _)
+    }
+
+    /// Timeout of writes, request_timeout unless request_timeout_write is set
+    pub fn write_timeout(&self) -> Duration {
+        let timeout = match self.request_timeout_write {
+            0 => self.request_timeout,
+            t => t,
+        };
+        Duration::from_millis(timeout as _)
+    }
+
+    /// Checks the ranges of the settings and how they relate to each other,
+    /// so a bad config fails at startup instead of at the first request.
+    pub fn validate(&self) -> Result<(), GenericError> {
+        macro_rules! check {
+            ($cond:expr, $($arg:tt)+) => {
+                if !$cond {
+                    return Err(format!($($arg)+).into());
+                }
+            };
+        }
+        check!(self.worker_count > 0, "worker_count must be at least 1");
+        check!(self.worker_timer > 0, "worker_timer must be over 0ms");
+        check!(
+            self.sync_incomming_max > 0,
+            "sync_incomming_max must be at least 1, otherwise vnodes can't bootstrap"
+        );
+        check!(
+            self.sync_outgoing_max > 0,
+            "sync_outgoing_max must be at least 1, otherwise other nodes can't bootstrap"
+        );
+        check!(
+            self.fabric_connections_per_peer > 0,
+            "fabric_connections_per_peer must be at least 1"
+        );
+        check!(self.sync_msg_inflight > 0, "sync_msg_inflight must be at least 1");
+        check!(self.sync_msg_timeout > 0, "sync_msg_timeout must be over 0ms");
+        check!(self.request_timeout > 0, "request_timeout must be over 0ms");
+        check!(self.fabric_timeout > 0, "fabric_timeout must be over 0ms");
+        check!(self.log_retention >= 1000, "log_retention must be at least 1s");
+        check!(
+            self.bootstrap_retry_backoff <= self.bootstrap_retry_backoff_max,
+            "bootstrap_retry_backoff={}ms can't be over bootstrap_retry_backoff_max={}ms",
+            self.bootstrap_retry_backoff,
+            self.bootstrap_retry_backoff_max
+        );
+        check!(self.value_version_max > 0, "value_version_max must be at least 1");
+        check!(self.keyspace_max > 0, "keyspace_max must be at least 1");
+        check!(
+            self.bloom_filter_fp_rate > 0.0 && self.bloom_filter_fp_rate < 1.0,
+            "bloom_filter_fp_rate={} must be between 0 and 1",
+            self.bloom_filter_fp_rate
+        );
+        check!(
+            self.fabric_secret_accept.is_empty() || !self.fabric_secret.is_empty(),
+            "fabric_secret_accept requires fabric_secret"
+        );
+        check!(
+            self.max_memory == 0 || self.storage_backend == StorageBackend::Memory,
+            "max_memory requires the memory storage_backend"
+        );
+        check!(
+            self.hot_keys_sample_rate == 0 || self.hot_keys_max > 0,
+            "hot_keys_max must be at least 1 when sampling hot keys"
+        );
+
+        if let Some(ref init) = self.cmd_init {
+            // same limits DHT::init asserts
+            check!(
+                init.partitions >= 32
+                    && init.partitions <= 1024
+                    && init.partitions.is_power_of_two(),
+                "partitions={} must be a power of 2 between 32 and 1024",
+                init.partitions
+            );
+            check!(
+                init.replication_factor <= 6,
+                "replication_factor={} can't be over 6",
+                init.replication_factor
+            );
+            check!(
+                init.replication_factor as u16 <= init.partitions,
+                "replication_factor={} can't be over partitions={}",
+                init.replication_factor,
+                init.partitions
+            );
+            for &(name, level) in &[
+                ("consistency_read", self.consistency_read),
+                ("consistency_write", self.consistency_write),
+                ("consistency_delete", self.consistency_delete),
+            ] {
+                check!(
+                    level.required(init.replication_factor) >= 1,
+                    "{}={:?} requires replication_factor>=1",
+                    name,
+                    level
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// What to do with values larger than MAX_VALUE_LEN
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum OversizeValuePolicy {
+    Reject,
+    // accept with a warning, meant for controlled bulk loads
+    Warn,
+}
+
+impl FromStr for OversizeValuePolicy {
+    type Err = GenericError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(OversizeValuePolicy::Reject),
+            "warn" => Ok(OversizeValuePolicy::Warn),
+            _ => Err(format!("Unknown oversize value policy `{}`", s).into()),
+        }
+    }
+}
+
+/// How reads choose the replicas they query first
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ReplicaPolicy {
+    // all replicas, the first replies win
+    All,
+    Random,
+    RoundRobin,
+    // lowest observed latency first
+    LatencyAware,
+}
+
+impl ReplicaPolicy {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ReplicaPolicy::All => "all",
+            ReplicaPolicy::Random => "random",
+            ReplicaPolicy::RoundRobin => "round_robin",
+            ReplicaPolicy::LatencyAware => "latency_aware",
+        }
+    }
+}
+
+impl FromStr for ReplicaPolicy {
+    type Err = GenericError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "all" => Ok(ReplicaPolicy::All),
+            "random" => Ok(ReplicaPolicy::Random),
+            "round_robin" => Ok(ReplicaPolicy::RoundRobin),
+            "latency_aware" => Ok(ReplicaPolicy::LatencyAware),
+            _ => Err(format!("Unknown replica policy `{}`", s).into()),
+        }
+    }
+}
+
+/// Compression of the fabric messages sent to other nodes
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FabricCompression {
+    None,
+    Lz4,
+    Snappy,
+}
+
+impl FromStr for FabricCompression {
+    type Err = GenericError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(FabricCompression::None),
+            "lz4" => Ok(FabricCompression::Lz4),
+            "snappy" => Ok(FabricCompression::Snappy),
+            _ => Err(format!("Unknown fabric compression `{}`", s).into()),
+        }
+    }
+}
+
+/// A `host:port` address, the host being an IPv4 literal, an IPv6 literal in brackets
+/// or a name. Names are resolved every time the address is used, as they may move.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostAddr(String);
+
+impl HostAddr {
+    /// The addresses the host resolves to, in the order given by the resolver
+    pub fn resolve(&self) -> Result<Vec<SocketAddr>, GenericError> {
+        let addrs: Vec<SocketAddr> = self.0.to_socket_addrs()?.collect();
+        if addrs.is_empty() {
+            return Err(format!("`{}` didn't resolve to any address", self.0).into());
+        }
+        Ok(addrs)
+    }
+}
+
+impl FromStr for HostAddr {
+    type Err = GenericError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.parse::<SocketAddr>().is_ok() {
+            return Ok(HostAddr(s.into()));
+        }
+        // a name, unbracketed IPv6 literals are rejected as the port would be ambiguous
+        match s.rfind(':') {
+            Some(i) if i > 0 && !s[..i].contains(':') && s[i + 1..].parse::<u16>().is_ok() => {
+                Ok(HostAddr(s.into()))
+            }
+            _ => Err(format!("`{}` isn't a host:port address", s).into()),
+        }
+    }
+}
+
+impl From<SocketAddr> for HostAddr {
+    fn from(addr: SocketAddr) -> Self {
+        HostAddr(addr.to_string())
+    }
+}
+
+/// Parses a `host:port` address to bind to, resolving the host once if it's a name
+pub fn resolve_addr(s: &str) -> Result<SocketAddr, GenericError> {
+    Ok(s.parse::<HostAddr>()?.resolve()?[0])
+}
+
 #[derive(Debug, Clone)]
 pub struct InitCommand {
     pub replication_factor: u8,
@@ -121,20 +460,43 @@ pub fn parse_size(size_text: &str) -> Result<i64, GenericError> {
     number.checked_mul(scale).ok_or("Overflow error".into())
 }
 
+// environment overrides are always strings, so numbers and booleans are parsed from them
+fn as_str(v: &yaml::Value) -> Option<&str> {
+    v.as_str()
+}
+
+fn as_u64(v: &yaml::Value) -> Option<u64> {
+    v.as_u64().or_else(|| v.as_str().and_then(|s| s.parse().ok()))
+}
+
+fn as_f64(v: &yaml::Value) -> Option<f64> {
+    v.as_f64().or_else(|| v.as_str().and_then(|s| s.parse().ok()))
+}
+
+fn as_bool(v: &yaml::Value) -> Option<bool> {
+    v.as_bool().or_else(|| v.as_str().and_then(|s| s.parse().ok()))
+}
+
 macro_rules! cfg {
     ($yaml:ident, $target:ident, $string:ident, $method:ident) => {
         if let Some(v) = $yaml.get(stringify!($string)) {
-            let v = v
-                .$method()
-                .expect(concat!("Can't access field with", stringify!($method)));
+            let v = $method(v).expect(concat!(
+                "Can't access key ",
+                stringify!($string),
+                " with ",
+                stringify!($method)
+            ));
             $target.$string = v.into();
         }
     };
     ($yaml:ident, $target:ident, $string:ident, $method:ident,try_into) => {
         if let Some(v) = $yaml.get(stringify!($string)) {
-            let v = v
-                .$method()
-                .expect(concat!("Can't access field with", stringify!($method)));
+            let v = $method(v).expect(concat!(
+                "Can't access key ",
+                stringify!($string),
+                " with ",
+                stringify!($method)
+            ));
             $target.$string = v
                 .try_into()
                 .expect(concat!("Can't convert ", stringify!($string)));
@@ -142,10 +504,10 @@ macro_rules! cfg {
     };
     ($yaml:ident, $target:ident, $string:ident, $method:ident, $convert:expr) => {
         if let Some(v) = $yaml.get(stringify!($string)) {
-            let v = v.$method().expect(concat!(
+            let v = $method(v).expect(concat!(
                 "Can't access key ",
                 stringify!($string),
-                " with",
+                " with ",
                 stringify!($method)
             ));
             $target.$string = $convert(v)
@@ -172,23 +534,105 @@ pub fn read_config_file(path: &Path, config: &mut Config) {
     };
     debug!("Done reading config file: {:?}", config);
 
+    apply_config(&yaml, config);
+
+    if let Some(config_value) = yaml.get("logging") {
+        setup_logging(config_value);
+    }
+}
+
+/// Overrides the config with the environment variables named like the config file keys
+/// with the `SUCREDB_` prefix, e.g. `SUCREDB_FABRIC_ADDR` for `fabric_addr`.
+/// Values are parsed like the ones in the config file and `SUCREDB_SEED_NODES`
+/// takes a comma separated list. Panics if a value can't be parsed.
+pub fn read_config_env(config: &mut Config) {
+    let mut overrides = yaml::Mapping::new();
+    for (key, value) in env::vars() {
+        if key.starts_with(ENV_PREFIX) {
+            debug!("Config override from the environment: {}", key);
+            let key = key[ENV_PREFIX.len()..].to_lowercase();
+            overrides.insert(yaml::Value::String(key), yaml::Value::String(value));
+        }
+    }
+    apply_config(&yaml::Value::Mapping(overrides), config);
+}
+
+fn apply_config(yaml: &yaml::Value, config: &mut Config) {
     cfg!(yaml, config, data_dir, as_str);
     cfg!(yaml, config, cluster_name, as_str);
-    cfg!(yaml, config, listen_addr, as_str, SocketAddr::from_str);
-    cfg!(yaml, config, fabric_addr, as_str, SocketAddr::from_str);
+    cfg!(yaml, config, listen_addr, as_str, resolve_addr);
+    cfg!(yaml, config, fabric_addr, as_str, resolve_addr);
+    cfg!(yaml, config, zone, as_str);
     // pub cmd_init: Option<InitCommand>,
     cfg!(yaml, config, worker_timer, as_str, parse_duration);
     cfg!(yaml, config, worker_count, as_u64, try_into);
+    cfg!(yaml, config, worker_panic_policy, as_str, PanicPolicy::from_str);
+    cfg!(yaml, config, storage_worker_count, as_u64, try_into);
     cfg!(yaml, config, sync_incomming_max, as_u64, try_into);
     cfg!(yaml, config, sync_outgoing_max, as_u64, try_into);
     cfg!(yaml, config, sync_auto, as_bool);
     cfg!(yaml, config, sync_timeout, as_str, parse_duration);
     cfg!(yaml, config, sync_msg_timeout, as_str, parse_duration);
     cfg!(yaml, config, sync_msg_inflight, as_u64, try_into);
+    cfg!(yaml, config, sync_msg_batch_max, as_u64, try_into);
+    cfg!(yaml, config, log_retention, as_str, parse_duration);
+    cfg!(yaml, config, zombie_timeout, as_str, parse_duration);
+    cfg!(yaml, config, sync_backpressure_latency, as_str, parse_duration);
+    cfg!(yaml, config, sync_bandwidth_limit, as_str, parse_size);
+    cfg!(yaml, config, bootstrap_retry_backoff, as_str, parse_duration);
+    cfg!(yaml, config, bootstrap_retry_backoff_max, as_str, parse_duration);
+    cfg!(yaml, config, bootstrap_failures_max, as_u64, try_into);
+    cfg!(yaml, config, bootstrap_stale_reads, as_bool);
+    cfg!(yaml, config, read_repair, as_bool);
+    cfg!(yaml, config, read_repair_sync, as_bool);
     cfg!(yaml, config, fabric_timeout, as_str, parse_duration);
+    cfg!(yaml, config, fabric_capture, as_bool);
+    cfg!(yaml, config, fabric_format, as_str, StorageFormat::from_str);
+    cfg!(
+        yaml,
+        config,
+        fabric_compression,
+        as_str,
+        FabricCompression::from_str
+    );
+    cfg!(yaml, config, fabric_compression_threshold, as_u64, try_into);
+    cfg!(yaml, config, fabric_send_queue_max, as_u64, try_into);
+    cfg!(yaml, config, fabric_batch_max, as_u64, try_into);
+    cfg!(yaml, config, fabric_connections_per_peer, as_u64, try_into);
+    cfg!(yaml, config, fabric_incoming_max, as_u64, try_into);
+    cfg!(yaml, config, fabric_secret, as_str);
+    cfg!(yaml, config, fabric_secret_accept, as_str);
     cfg!(yaml, config, request_timeout, as_str, parse_duration);
+    cfg!(yaml, config, request_timeout_read, as_str, parse_duration);
+    cfg!(yaml, config, request_timeout_write, as_str, parse_duration);
+    cfg!(yaml, config, request_id_ttl, as_str, parse_duration);
+    cfg!(yaml, config, inflight_requests_max, as_u64, try_into);
+    cfg!(yaml, config, coordinator_offload_inflight, as_u64, try_into);
     cfg!(yaml, config, client_connection_max, as_u64, try_into);
+    cfg!(yaml, config, client_compression_threshold, as_u64, try_into);
     cfg!(yaml, config, value_version_max, as_u64, try_into);
+    cfg!(yaml, config, expire_sweep_keys, as_u64, try_into);
+    cfg!(yaml, config, tombstone_gc_interval, as_str, parse_duration);
+    cfg!(yaml, config, checkpoint_interval, as_str, parse_duration);
+    cfg!(yaml, config, checkpoint_writes, as_u64, try_into);
+    cfg!(
+        yaml,
+        config,
+        oversize_value_policy,
+        as_str,
+        OversizeValuePolicy::from_str
+    );
+    cfg!(yaml, config, keyspace_max, as_u64, try_into);
+    cfg!(yaml, config, max_vnodes, as_u64, try_into);
+    cfg!(yaml, config, storage_format, as_str, StorageFormat::from_str);
+    cfg!(yaml, config, storage_backend, as_str, StorageBackend::from_str);
+    cfg!(yaml, config, max_memory, as_u64);
+    cfg!(yaml, config, eviction_policy, as_str, EvictionPolicy::from_str);
+    cfg!(yaml, config, hash_algorithm, as_str, HashAlgorithm::from_str);
+    cfg!(yaml, config, bloom_filter_keys, as_u64);
+    cfg!(yaml, config, bloom_filter_fp_rate, as_f64);
+    cfg!(yaml, config, hot_keys_sample_rate, as_u64, try_into);
+    cfg!(yaml, config, hot_keys_max, as_u64, try_into);
     cfg!(
         yaml,
         config,
@@ -203,23 +647,39 @@ pub fn read_config_file(path: &Path, config: &mut Config) {
         as_str,
         ConsistencyLevel::from_str
     );
+    cfg!(
+        yaml,
+        config,
+        consistency_delete,
+        as_str,
+        ConsistencyLevel::from_str
+    );
+    cfg!(
+        yaml,
+        config,
+        read_replica_policy,
+        as_str,
+        ReplicaPolicy::from_str
+    );
 
     if let Some(v) = yaml.get("seed_nodes") {
-        config.seed_nodes = v
-            .as_sequence()
-            .expect("seed_nodes is not a sequence")
-            .iter()
-            .map(|v| {
-                v.as_str()
-                    .expect("seed_nodes element is not a string")
-                    .parse()
-                    .expect("seed_nodes element can't be parsed")
-            })
-            .collect();
-    }
-
-    if let Some(config_value) = yaml.get("logging") {
-        setup_logging(config_value);
+        fn parse_seed(v: &str) -> HostAddr {
+            v.trim().parse().expect("seed_nodes element can't be parsed")
+        }
+        config.seed_nodes = match v.as_str() {
+            // comma separated, from the environment
+            Some(v) => v
+                .split(',')
+                .filter(|v| !v.trim().is_empty())
+                .map(parse_seed)
+                .collect(),
+            None => v
+                .as_sequence()
+                .expect("seed_nodes is not a sequence")
+                .iter()
+                .map(|v| parse_seed(v.as_str().expect("seed_nodes element is not a string")))
+                .collect(),
+        };
     }
 }
 
@@ -244,17 +704,15 @@ pub fn setup_logging(config_value: &yaml::Value) {
     log4rs::init_config(config).expect("failed to init logging");
 }
 
-pub fn setup_default_logging() {
+pub fn setup_default_logging(json: bool) {
+    let mut console = log4rs::append::console::ConsoleAppender::builder()
+        .target(log4rs::append::console::Target::Stderr);
+    if json {
+        console = console.encoder(Box::new(log4rs::encode::json::JsonEncoder::new()));
+    }
     let config = log4rs::config::Config::builder()
         .appender(
-            log4rs::config::Appender::builder().build(
-                "console",
-                Box::new(
-                    log4rs::append::console::ConsoleAppender::builder()
-                        .target(log4rs::append::console::Target::Stderr)
-                        .build(),
-                ),
-            ),
+            log4rs::config::Appender::builder().build("console", Box::new(console.build())),
         )
         .logger(
             log4rs::config::Logger::builder()
@@ -3,6 +3,7 @@ use bytes::Bytes;
 use command::CommandError;
 use linear_map::{Entry as LMEntry, LinearMap};
 use resp::RespValue;
+use serde::{Deserialize, Deserializer};
 use std::boxed::FnBox;
 use std::time;
 use version_vector::*;
@@ -11,6 +12,12 @@ pub type MutatorFn =
     Box<FnBox(Id, Version, Cube) -> Result<(Cube, Option<RespValue>), CommandError> + Send>;
 pub type ResponseFn = Box<FnMut(Cube) -> RespValue + Send>;
 
+// millis since epoch
+pub fn now_millis() -> u64 {
+    let timestamp = time::UNIX_EPOCH.elapsed().unwrap();
+    timestamp.as_secs() * 1_000 + (timestamp.subsec_nanos() / 1_000_000) as u64
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum Cube {
     // the order is used to merge different types in a deterministic way
@@ -51,6 +58,64 @@ impl Cube {
         }
     }
 
+    // whether this cube causal context includes all of `vv`
+    pub fn descends(&self, vv: &VersionVector) -> bool {
+        use self::Cube::*;
+        match *self {
+            Counter(ref a) => a.vv.descends(vv),
+            Value(ref a) => a.vv.descends(vv),
+            Map(ref a) => a.vv.descends(vv),
+            Set(ref a) => a.vv.descends(vv),
+            Void(ref a) => a.descends(vv),
+        }
+    }
+
+    // the causal context of this cube, deleting with it removes every version
+    pub fn context(&self) -> &VersionVector {
+        use self::Cube::*;
+        match *self {
+            Counter(ref a) => &a.vv,
+            Value(ref a) => &a.vv,
+            Map(ref a) => &a.vv,
+            Set(ref a) => &a.vv,
+            Void(ref a) => a,
+        }
+    }
+
+    // whether this cube causal context is exactly `vv`
+    pub fn context_eq(&self, vv: &VersionVector) -> bool {
+        let cube_vv = self.context();
+        cube_vv.descends(vv) && vv.descends(cube_vv)
+    }
+
+    // whether there's anything besides tombstones
+    pub fn has_values(&self) -> bool {
+        use self::Cube::*;
+        match *self {
+            Counter(ref a) => !a.values.is_empty(),
+            Value(ref a) => a.values.values().any(|v| v.is_some()),
+            Map(ref a) => !a.values.is_empty(),
+            Set(ref a) => !a.values.is_empty(),
+            Void(_) => false,
+        }
+    }
+
+    // length of the largest value (or set member) in the cube
+    pub fn max_value_len(&self) -> usize {
+        use self::Cube::*;
+        match *self {
+            Counter(_) | Void(_) => 0,
+            Value(ref a) => a
+                .values
+                .values()
+                .map(|v| v.as_ref().map_or(0, |v| v.len()))
+                .max()
+                .unwrap_or(0),
+            Map(ref a) => a.values.values().map(|v| v.value.len()).max().unwrap_or(0),
+            Set(ref a) => a.values.keys().map(|v| v.len()).max().unwrap_or(0),
+        }
+    }
+
     impl_into!(into_value, Value);
     impl_into!(into_counter, Counter);
     impl_into!(into_map, Map);
@@ -89,6 +154,39 @@ impl Cube {
         true
     }
 
+    /// Caps the number of concurrent versions (siblings) of a value to `max`,
+    /// discarding the oldest ones (see `DotMap::discard_oldest`).
+    /// The causal context is kept intact so the discarded versions stay superseded.
+    pub fn discard_oldest_versions(&mut self, max: usize) {
+        if let Cube::Value(ref mut a) = *self {
+            a.values.discard_oldest(max);
+            a.discard_expires();
+        }
+    }
+
+    // removes the versions expired at `now` (millis since epoch),
+    // returns whether any was removed
+    pub fn expire(&mut self, now: u64) -> bool {
+        if let Cube::Value(ref mut a) = *self {
+            a.expire(now)
+        } else {
+            false
+        }
+    }
+
+    // removes the versions left by deletes once every replica has them (dots in `safe`),
+    // returns whether any was removed
+    pub fn discard_tombstones(&mut self, safe: &BitmappedVersionVector) -> bool {
+        if let Cube::Value(ref mut a) = *self {
+            let len = a.values.len();
+            a.values
+                .retain(|&(id, version), value| value.is_some() || !safe.contains(id, version));
+            a.values.len() != len
+        } else {
+            false
+        }
+    }
+
     pub fn merge(self, other: Self) -> Self {
         use self::Cube::*;
         match (self, other) {
@@ -173,6 +271,15 @@ impl Counter {
 pub struct Value {
     values: DotMap<Option<Bytes>>,
     vv: VersionVector,
+    // expiration of the versions with a ttl, in millis since epoch.
+    // dots are immutable, so every replica expires the same versions.
+    #[serde(default, deserialize_with = "deserialize_expires")]
+    expires: DotMap<u64>,
+}
+
+// values stored before expirations existed end without the field
+fn deserialize_expires<'de, D: Deserializer<'de>>(deserializer: D) -> Result<DotMap<u64>, D::Error> {
+    Ok(DotMap::deserialize(deserializer).unwrap_or_default())
 }
 
 impl Value {
@@ -180,6 +287,7 @@ impl Value {
         Value {
             values: Default::default(),
             vv,
+            expires: Default::default(),
         }
     }
 
@@ -191,11 +299,63 @@ impl Value {
         self.values.discard(vv);
         self.values.insert(node, version, value);
         self.vv.add(node, version);
+        self.discard_expires();
+    }
+
+    // like set, but the new version expires at `expire_at` (millis since epoch)
+    pub fn set_expiring(
+        &mut self,
+        node: Id,
+        version: Version,
+        value: Bytes,
+        vv: &VersionVector,
+        expire_at: u64,
+    ) {
+        self.set(node, version, Some(value), vv);
+        self.expires.insert(node, version, expire_at);
+    }
+
+    // the live (not deleted) versions and their expiration, if any
+    pub fn live(&self) -> Vec<(Bytes, Option<u64>)> {
+        self.values
+            .iter()
+            .filter_map(|(dot, value)| {
+                value
+                    .as_ref()
+                    .map(|value| (value.clone(), self.expires.get(dot).cloned()))
+            })
+            .collect()
+    }
+
+    fn expire(&mut self, now: u64) -> bool {
+        let expired: Vec<_> = self
+            .expires
+            .iter()
+            .filter(|&(_, &at)| at <= now)
+            .map(|(&dot, _)| dot)
+            .collect();
+        for &(id, version) in &expired {
+            // the dot stays in the causal context, like an overwrite
+            self.values.remove(id, version);
+            self.expires.remove(id, version);
+        }
+        !expired.is_empty()
+    }
+
+    // expirations of versions no longer present are useless
+    fn discard_expires(&mut self) {
+        let values = &self.values;
+        self.expires
+            .retain(|&(id, version), _| values.contains_key(&(id, version)));
     }
 
     fn merge(mut self, mut other: Self) -> Self {
         self.values.merge(&mut other.values, &self.vv, &other.vv);
         self.vv.merge(&other.vv);
+        for ((id, version), at) in other.expires.into_iter() {
+            self.expires.insert(id, version, at);
+        }
+        self.discard_expires();
         self
     }
 }
@@ -307,11 +467,10 @@ struct MapValue {
 
 impl MapValue {
     fn new(dot: (Id, Version), value: Bytes) -> Self {
-        let timestamp = time::UNIX_EPOCH.elapsed().unwrap();
         MapValue {
             dots: DotSet::from_dot(dot),
             value,
-            timestamp: timestamp.as_secs() * 1_000 + (timestamp.subsec_nanos() / 1_000_000) as u64,
+            timestamp: now_millis(),
         }
     }
 }
@@ -354,6 +513,46 @@ pub fn render_value(cube: Cube) -> RespValue {
     }
 }
 
+/// Like render_value but values without live versions are marked (see HELLO HISTORY),
+/// [NOTFOUND, rendered] when the key has no causal history and [DELETED, rendered]
+/// when it only has deletes.
+pub fn render_value_history(cube: Cube) -> RespValue {
+    let status = match cube {
+        Cube::Void(_) => "NOTFOUND",
+        Cube::Value(ref v) if v.values.values().all(|v| v.is_none()) => "DELETED",
+        _ => return render_value(cube),
+    };
+    RespValue::Array(vec![RespValue::Status(status.into()), render_value(cube)])
+}
+
+/// Millis until the value expires, -1 if it doesn't expire and -2 if there's no value.
+/// With concurrent versions the one that lasts the longest counts.
+pub fn render_pttl(cube: Cube) -> RespValue {
+    match cube {
+        Cube::Value(v) => {
+            let now = now_millis();
+            let mut ttl = -2;
+            for (_, expiration) in v.live() {
+                match expiration {
+                    Some(at) => ttl = ttl.max(at.saturating_sub(now) as i64),
+                    None => return RespValue::Int(-1),
+                }
+            }
+            RespValue::Int(ttl)
+        }
+        Cube::Void(_) => RespValue::Int(-2),
+        _ => CommandError::TypeError.into(),
+    }
+}
+
+/// Like render_pttl but in seconds, rounded like redis does.
+pub fn render_ttl(cube: Cube) -> RespValue {
+    match render_pttl(cube) {
+        RespValue::Int(ttl) if ttl > 0 => RespValue::Int((ttl + 500) / 1000),
+        resp => resp,
+    }
+}
+
 pub fn render_counter(cube: Cube) -> RespValue {
     match cube {
         Cube::Counter(c) => RespValue::Int(c.get()),
@@ -404,6 +603,23 @@ pub fn render_set(cube: Cube) -> RespValue {
     }
 }
 
+// [key, type, serialized cube, rendered value]
+pub fn render_dump(key: Bytes, cube: Cube) -> RespValue {
+    let serialized = bincode::serialize(&cube).expect("Can't serialize Cube");
+    let render_fn: fn(Cube) -> RespValue = match cube {
+        Cube::Counter(_) => render_counter,
+        Cube::Map(_) => render_map,
+        Cube::Set(_) => render_set,
+        Cube::Value(_) | Cube::Void(_) => render_value,
+    };
+    RespValue::Array(vec![
+        RespValue::Data(key),
+        render_type(cube.clone()),
+        RespValue::Data(serialized.into()),
+        render_fn(cube),
+    ])
+}
+
 /*
 Using the vv from cubes to track key dots (the latest version from each node) doesn't work, example:
 
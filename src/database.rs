@@ -1,19 +1,25 @@
-use bytes::Bytes;
-use command::CommandError;
-use config::Config;
+use byteorder::{BigEndian, ByteOrder};
+use bytes::{BufMut, Bytes, BytesMut};
+use command::{split_keyspace_key, CommandError, KEYSPACE_PREFIX_LEN};
+use config::{self, Config, ReplicaPolicy};
 use cubes::*;
 use dht::{RingDescription, DHT};
 use fabric::*;
+use hash::HashAlgorithm;
+use inflightmap::InFlightMap;
 use metrics::{self, Gauge};
 use rand::{thread_rng, Rng};
 use resp::RespValue;
+use std::collections::hash_map::Entry as HMEntry;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex, RwLock};
-use std::{net, time};
-use storage::{Storage, StorageManager};
+use std::{mem, net, time};
+use storage::{Storage, StorageFormat, StorageManager, META_STORAGE_NUM};
 pub use types::*;
-use utils::LoggerExt;
+use utils::{sleep_ms, GenericError, IdHashMap, LoggerExt};
 use utils::{assume_str, is_dir_empty_or_absent, join_u64, replace_default, split_u64};
-use version_vector::Version;
+use version_vector::{Version, VersionVector};
 use vnode::*;
 use vnode_sync::SyncDirection;
 use workers::*;
@@ -21,15 +27,41 @@ use workers::*;
 // require sync as it can be called from any worker thread
 pub type DatabaseResponseFn = Box<Fn(Context) + Send + Sync>;
 
+// client request ids remembered at once, the oldest are forgotten first
+const REQUEST_IDS_MAX: usize = 100_000;
+// keys deleted by each write request of a DELRANGE
+const DELRANGE_BATCH: usize = 1000;
+
 pub enum WorkerMsg {
     Fabric(NodeId, FabricMsg),
     Command(Context),
-    Tick(time::Instant),
+    // each worker gets one, with its index, see handler_tick
+    Tick(time::Instant, usize),
     DHTFabric(NodeId, FabricMsg),
     DHTChange,
     Exit,
 }
 
+// work for the storage worker pool, see `storage_worker_count`
+pub enum StorageMsg {
+    // reads the keys and replies to the node (which can be this one) like handler_get_remote
+    Get(NodeId, Storage, Cube, MsgRemoteGet),
+    Exit,
+}
+
+impl ExitMsg for StorageMsg {
+    fn exit_msg() -> Self {
+        StorageMsg::Exit
+    }
+    fn is_exit(&self) -> bool {
+        if let StorageMsg::Exit = self {
+            true
+        } else {
+            false
+        }
+    }
+}
+
 impl ExitMsg for WorkerMsg {
     fn exit_msg() -> Self {
         WorkerMsg::Exit
@@ -47,6 +79,16 @@ impl ExitMsg for WorkerMsg {
 struct Stats {
     incomming_syncs: u16,
     outgoing_syncs: u16,
+    // sync starts waiting for an outgoing slot, with their deadline
+    queued_sync_starts: VecDeque<(NodeId, MsgSyncStart, time::Instant)>,
+    aae_jobs: u64,
+    // position for the round robin read replica policy
+    read_replica_rr: usize,
+    // moving average of the replicas read latency, in micros
+    replica_latency: IdHashMap<NodeId, u64>,
+    // bytes the outgoing syncs can still send (negative when in debt) and when it was refilled
+    sync_budget: i64,
+    sync_budget_refill: Option<time::Instant>,
 }
 
 pub struct ContextRead {
@@ -68,6 +110,24 @@ pub struct ContextWrite {
 #[derive(Default)]
 pub struct Context {
     pub token: Token,
+    // selected keyspace (SELECT), persists for the lifetime of the connection
+    pub keyspace: u16,
+    // preferred replica for reads (AFFINITY), persists for the lifetime of the connection
+    pub read_affinity: Option<NodeId>,
+    // client accepts/sends compressed values (HELLO COMPRESS), persists for the lifetime of the connection
+    pub compression: bool,
+    // client accepts stale reads from bootstrapping vnodes (HELLO STALE), persists for the lifetime of the connection
+    pub stale_reads: bool,
+    // client wants empty values marked as not found or deleted (HELLO HISTORY), persists for the lifetime of the connection
+    pub key_history: bool,
+    // quorum reads wait for their read repairs (HELLO REPAIR), persists for the lifetime of the connection
+    pub sync_repair: bool,
+    // (replica, cookie) of the read coordinated for another replica, which gets the results
+    pub proxied: Option<(NodeId, Cookie)>,
+    // whether the coordinator must fsync the writes of the current request
+    pub durable: bool,
+    // (client id, request id) of the current request (REQID), its response is remembered
+    pub request_id: Option<(u16, u64, u64)>,
     pub is_multi: bool,
     pub is_exec: bool,
     // response queue
@@ -80,21 +140,118 @@ pub struct Context {
     pub commands: Vec<RespValue>,
     pub reads: Vec<ContextRead>,
     pub writes: Vec<ContextWrite>,
+    // set for the per vnode parts of a MGET, with the positions of their keys
+    pub batch: Option<(Arc<Mutex<ContextBatch>>, Vec<usize>)>,
+}
+
+// a MGET (or DELRANGE) spanning multiple vnodes, each vnode replies to its own context
+// and the client response is sent once all of them are done
+pub struct ContextBatch {
+    context: Option<Context>,
+    results: Vec<RespValue>,
+    pending: usize,
+    // turns the results into a single response, otherwise they're sent as an array
+    reduce: Option<fn(Vec<RespValue>) -> RespValue>,
+}
+
+impl ContextBatch {
+    // returns the original context, with the full response, once all parts are done
+    pub fn complete(&mut self, positions: &[usize], response: RespValue) -> Option<Context> {
+        let values = match response {
+            RespValue::Array(values) => values,
+            other => vec![other],
+        };
+        if values.len() == positions.len() {
+            for (&i, value) in positions.iter().zip(values) {
+                self.results[i] = value;
+            }
+        } else {
+            // the vnode failed as a whole (eg. timeout), its keys get the error
+            let error = values.into_iter().next().unwrap_or(RespValue::Nil);
+            for &i in positions {
+                self.results[i] = error.clone();
+            }
+        }
+        self.pending -= 1;
+        if self.pending != 0 {
+            return None;
+        }
+        let results = replace_default(&mut self.results);
+        let reduce = self.reduce;
+        self.context.take().map(|mut context| {
+            context.response = match reduce {
+                Some(reduce) => vec![reduce(results)],
+                None => results,
+            };
+            context
+        })
+    }
+}
+
+// the number of deleted keys or, if some failed, the first error and
+// the number of keys deleted regardless
+fn reduce_delrange(results: Vec<RespValue>) -> RespValue {
+    let deleted = results.iter().filter(|r| **r == RespValue::Int(1)).count() as i64;
+    let error = results.into_iter().find(|r| match *r {
+        RespValue::Error(_) => true,
+        _ => false,
+    });
+    match error {
+        Some(error) => RespValue::Array(vec![error, RespValue::Int(deleted)]),
+        None => RespValue::Int(deleted),
+    }
 }
 
 impl Context {
     pub fn new(token: Token) -> Self {
         Context {
             token,
+            keyspace: 0,
+            read_affinity: None,
+            compression: false,
+            stale_reads: false,
+            key_history: false,
+            sync_repair: false,
+            proxied: None,
+            durable: false,
+            request_id: None,
             is_multi: false,
             is_exec: false,
             response: Default::default(),
             commands: Default::default(),
             writes: Default::default(),
             reads: Default::default(),
+            batch: None,
         }
     }
 
+    /// A context whose response is dropped, for the requests a node makes on its own
+    pub fn detached() -> Self {
+        let batch = ContextBatch {
+            context: None,
+            results: vec![RespValue::Nil],
+            pending: 1,
+            reduce: None,
+        };
+        let mut context = Context::default();
+        context.batch = Some((Arc::new(Mutex::new(batch)), vec![0]));
+        context
+    }
+
+    fn batch_part(&self, batch: &Arc<Mutex<ContextBatch>>, positions: Vec<usize>) -> Self {
+        let mut context = Context::new(self.token);
+        context.keyspace = self.keyspace;
+        context.read_affinity = self.read_affinity;
+        context.compression = self.compression;
+        context.stale_reads = self.stale_reads;
+        context.key_history = self.key_history;
+        context.sync_repair = self.sync_repair;
+        context.is_multi = true;
+        context.is_exec = true;
+        context.batch = Some((batch.clone(), positions));
+        context
+    }
+
     pub fn take_response(&mut self) -> RespValue {
         if self.is_exec {
             self.is_multi = false;
@@ -126,6 +283,70 @@ impl Context {
 // zombie vnodes need to eagerly sync with other nodes
 // track dead peers with the fabric or gossip and use that info
 
+/// Point in time metrics of the node, see Database::metrics
+#[derive(Debug, Serialize)]
+pub struct DatabaseMetrics {
+    pub node: NodeId,
+    pub incoming_syncs: u16,
+    pub outgoing_syncs: u16,
+    // sync starts waiting for an outgoing slot
+    pub queued_syncs: usize,
+    pub inflight_requests: usize,
+    pub client_connections: usize,
+    // msgs waiting in each worker queue, see vnode_worker
+    pub worker_queues: Vec<usize>,
+    // sum of the vnodes approximate sizes, in bytes
+    pub approximate_size: u64,
+    // sum of the vnodes stored keys and their values size, in bytes
+    pub keys: u64,
+    pub value_bytes: u64,
+    // hottest hot_keys_max keys out of the vnodes ones
+    pub hot_keys: Vec<(Bytes, u64)>,
+    pub vnodes: Vec<VNodeMetrics>,
+}
+
+/// Layout of the cluster, see Database::topology
+#[derive(Debug, Serialize)]
+pub struct Topology {
+    pub partitions: usize,
+    pub replication_factor: usize,
+    pub hash_algorithm: HashAlgorithm,
+    // members and the address clients connect to
+    pub nodes: Vec<(NodeId, net::SocketAddr)>,
+    // indexed by vnode number
+    pub vnodes: Vec<VNodeTopology>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct VNodeTopology {
+    pub owners: Vec<NodeId>,
+    // still bootstrapping, they take writes but not reads
+    pub pending: Vec<NodeId>,
+    // on their way out, they take reads and writes until the pending ones finish
+    pub retiring: Vec<NodeId>,
+}
+
+/// Readiness of the node for load balancers and orchestrators, see Database::health
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+pub enum Health {
+    /// Its vnodes are ready with enough replicas reachable for the default consistency levels
+    Healthy,
+    /// Serves requests, but some vnodes are bootstrapping or lack reachable replicas
+    Degraded,
+    /// The storage doesn't respond or none of its vnodes can serve requests
+    Unavailable,
+}
+
+impl Health {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Health::Healthy => "healthy",
+            Health::Degraded => "degraded",
+            Health::Unavailable => "unavailable",
+        }
+    }
+}
+
 pub struct Database {
     pub dht: DHT<net::SocketAddr>,
     pub fabric: Arc<Fabric>,
@@ -135,7 +356,15 @@ pub struct Database {
     pub config: Config,
     stats: Mutex<Stats>,
     vnodes: RwLock<Vec<Mutex<VNode>>>,
+    // vnodes created so far, behind the ring partitions until a split is applied locally.
+    // Only changes while holding the vnodes write lock.
+    partitions: AtomicUsize,
+    // open client connections, tracked by the server
+    client_connections: AtomicUsize,
     workers: Mutex<WorkerManager<WorkerMsg>>,
+    storage_workers: Mutex<Option<WorkerManager<StorageMsg>>>,
+    // responses of the recent requests with a client request id, None while running
+    request_ids: Mutex<InFlightMap<(u16, u64, u64), Option<RespValue>, time::Instant>>,
 }
 
 macro_rules! fabric_send_error {
@@ -163,7 +392,13 @@ macro_rules! fabric_send_error {
 
 macro_rules! vnode {
     ($s:expr, $k:expr, $ok:expr) => {{
+        let poisoned = $s.vnodes.read().unwrap()[$k as usize].is_poisoned();
+        if poisoned {
+            $s.recover_vnode($k);
+        }
         let vnodes = $s.vnodes.read().unwrap();
+        // exposed as a structured field by the json log encoder
+        let _mdc = ::log_mdc::insert_scoped("vnode", $k.to_string());
         let mut locked_vnode = vnodes[$k as usize].lock().unwrap();
         Some(&mut *locked_vnode).map($ok).unwrap()
     }};
@@ -178,10 +413,22 @@ impl Database {
             panic!("Can't init cluster when data directory isn't clean");
         }
 
-        let storage_manager =
-            StorageManager::new(&config.data_dir).expect("Failed to create storage manager");
-        let meta_storage = storage_manager
-            .open(u16::max_value())
+        let mut storage_manager = if config.max_memory != 0 {
+            StorageManager::new_bounded(
+                config.log_retention as u64 / 1000,
+                config.max_memory,
+                config.eviction_policy,
+            )
+        } else {
+            StorageManager::new_with(
+                &config.data_dir,
+                config.storage_backend,
+                config.log_retention as u64 / 1000,
+            )
+                .expect("Failed to create storage manager")
+        };
+        let mut meta_storage = storage_manager
+            .open(META_STORAGE_NUM)
             .expect("Can't open storage");
         let meta_node = meta_storage
             .get_vec(b"node")
@@ -195,7 +442,11 @@ impl Database {
         let meta_ring = meta_storage
             .get_vec(b"ring")
             .expect("Can't read previous ring from storage");
+        let meta_format = meta_storage
+            .get_vec(b"storage_format")
+            .expect("Can't read storage format from storage");
 
+        let fresh_data_dir = meta_node.is_none();
         let (old_node, node) = if let Some(s_node) = meta_node {
             let prev_node: NodeId = String::from_utf8(s_node).unwrap().parse().unwrap();
             if meta_clean_shutdown.is_some() {
@@ -216,6 +467,27 @@ impl Database {
                 );
             };
         }
+        // the format is only picked from the config for new data dirs
+        let storage_format = if let Some(format_in_storage) = meta_format {
+            let format: StorageFormat = assume_str(&format_in_storage)
+                .parse()
+                .expect("Can't parse storage format from storage");
+            if format != config.storage_format {
+                warn!(
+                    "Ignoring configured storage format `{}`, data is stored as `{}`",
+                    config.storage_format.as_str(),
+                    format.as_str()
+                );
+            }
+            format
+        } else if fresh_data_dir {
+            config.storage_format
+        } else {
+            // data dirs created before the format was recorded
+            StorageFormat::Bincode
+        };
+        storage_manager.set_format(storage_format);
+        meta_storage.set_format(storage_format);
         // save init (1 of 2)
         meta_storage
             .del(b"clean_shutdown")
@@ -226,6 +498,9 @@ impl Database {
         meta_storage
             .set(b"node", node.to_string().as_bytes())
             .expect("Can't save node id");
+        meta_storage
+            .set(b"storage_format", storage_format.as_str().as_bytes())
+            .expect("Can't save storage format");
         meta_storage.sync().expect("Can't sync storage");
 
         info!("Metadata loaded! node_id:{} previous:{:?}", node, old_node);
@@ -258,13 +533,25 @@ impl Database {
             ).expect("Can't join cluster")
         };
 
+        if dht.hash_algorithm() != config.hash_algorithm {
+            panic!(
+                "Hash algorithm differs! Expected `{}` got `{}` from the cluster",
+                config.hash_algorithm.as_str(),
+                dht.hash_algorithm().as_str()
+            );
+        }
+
         // save init (2 of 2)
         meta_storage
             .set(b"ring", &dht.save_ring())
             .expect("Can't save ring");
         meta_storage.sync().expect("Can't sync storage");
 
-        let workers = WorkerManager::new(node.to_string(), config.worker_count as _);
+        let workers = WorkerManager::new(
+            node.to_string(),
+            config.worker_count as _,
+            config.worker_panic_policy,
+        );
 
         let db = Arc::new(Database {
             fabric: fabric,
@@ -273,9 +560,13 @@ impl Database {
             meta_storage: meta_storage,
             response_fn: response_fn,
             vnodes: Default::default(),
+            partitions: Default::default(),
+            client_connections: Default::default(),
             workers: workers.into(),
+            storage_workers: Default::default(),
             config: config.clone(),
             stats: Default::default(),
+            request_ids: Mutex::new(InFlightMap::new()),
         });
 
         db.workers.lock().unwrap().start(|| {
@@ -285,7 +576,7 @@ impl Database {
                     match wm {
                         WorkerMsg::Fabric(from, m) => db.handler_fabric_msg(from, m),
                         WorkerMsg::Command(context) => db.handler_cmd(context),
-                        WorkerMsg::Tick(time) => db.handler_tick(time),
+                        WorkerMsg::Tick(time, worker) => db.handler_tick(time, worker),
                         WorkerMsg::DHTFabric(from, m) => db.dht.handler_fabric_msg(from, m),
                         WorkerMsg::DHTChange => db.handler_dht_change(),
                         WorkerMsg::Exit => (),
@@ -294,11 +585,46 @@ impl Database {
             })
         });
 
+        if config.storage_worker_count != 0 {
+            let mut storage_workers = WorkerManager::new(
+                format!("{}:storage", node),
+                config.storage_worker_count as _,
+                config.worker_panic_policy,
+            );
+            storage_workers.start(|| {
+                let node = db.dht.node();
+                let fabric = db.fabric.clone();
+                let sender = db.sender();
+                Box::new(move |sm| match sm {
+                    StorageMsg::Get(to, storage, void, msg) => {
+                        let ack = MsgRemoteGetAck {
+                            cookie: msg.cookie,
+                            vnode: msg.vnode,
+                            result: storage_get_keys(&storage, &void, &msg.keys),
+                        };
+                        if to == node {
+                            let _ = sender.try_send_to(
+                                ack.vnode as usize,
+                                WorkerMsg::Fabric(node, FabricMsg::RemoteGetAck(ack)),
+                            );
+                        } else {
+                            let _ = fabric.send_msg(to, &ack);
+                        }
+                    }
+                    StorageMsg::Exit => (),
+                })
+            });
+            *db.storage_workers.lock().unwrap() = Some(storage_workers);
+        }
+
         let sender = db.sender();
         timer_fn(
             node.to_string(),
             time::Duration::from_millis(config.worker_timer as _),
-            move |now| sender.try_send(WorkerMsg::Tick(now)).is_ok(),
+            move |now| {
+                (0..sender.workers())
+                    .all(|w| sender.try_send_to(w, WorkerMsg::Tick(now, w)).is_ok())
+            },
         );
 
         // register dht nodes into fabric
@@ -321,35 +647,162 @@ impl Database {
         // other types of fabric msgs
         for &msg_type in &[FabricMsgType::Crud, FabricMsgType::Synch] {
             let sender = db.sender();
-            let callback = move |f, m| {
-                sender.send(WorkerMsg::Fabric(f, m));
+            let callback = move |f, m: FabricMsg| {
+                // msgs for the same vnode go to the same worker, see vnode_worker
+                let _ = match m.vnode() {
+                    Some(vnode) => sender.try_send_to(vnode as usize, WorkerMsg::Fabric(f, m)),
+                    None => sender.try_send(WorkerMsg::Fabric(f, m)),
+                };
             };
             db.fabric.register_msg_handler(msg_type, Box::new(callback));
         }
 
+        // a split interrupted by a restart continues from the vnodes it started with
+        let split_from = db
+            .meta_storage
+            .get_vec(b"split_from")
+            .expect("Can't read split progress from storage")
+            .map(|count| assume_str(&count).parse::<usize>().unwrap());
+
         // create vnodes
-        {
+        let refused_vnodes = {
             // acquire exclusive lock to vnodes to initialize them
             let mut vnodes = db.vnodes.write().unwrap();
             let (ready_vnodes, pending_vnodes) = db.dht.vnodes_for_node(db.dht.node());
+            let refused_vnodes = db.refused_vnodes_at_startup(&ready_vnodes, &pending_vnodes);
             // TODO: this can be done in parallel
-            *vnodes = (0..db.dht.partitions() as VNodeNo)
+            *vnodes = (0..split_from.unwrap_or_else(|| db.dht.partitions()) as VNodeNo)
                 .map(|i| {
-                    let vn = if ready_vnodes.contains(&i) {
-                        VNode::new(&db, i, VNodeStatus::Ready)
-                    } else if pending_vnodes.contains(&i) {
-                        VNode::new(&db, i, VNodeStatus::Bootstrap)
-                    } else {
-                        VNode::new(&db, i, VNodeStatus::Absent)
-                    };
-                    Mutex::new(vn)
+                    Mutex::new(db.load_vnode(i, &ready_vnodes, &pending_vnodes, &refused_vnodes))
                 })
                 .collect();
+            db.partitions.store(vnodes.len(), Ordering::Release);
+            refused_vnodes
+        };
+        db.decline_vnodes(&refused_vnodes);
+        if split_from.is_some() {
+            db.split_vnodes();
         }
 
         db
     }
 
+    fn load_vnode(
+        &self,
+        i: VNodeNo,
+        ready_vnodes: &[VNodeNo],
+        pending_vnodes: &[VNodeNo],
+        refused_vnodes: &[VNodeNo],
+    ) -> VNode {
+        if refused_vnodes.contains(&i) {
+            VNode::new(self, i, VNodeStatus::Absent)
+        } else if ready_vnodes.contains(&i) {
+            VNode::new(self, i, VNodeStatus::Ready)
+        } else if pending_vnodes.contains(&i) {
+            VNode::new(self, i, VNodeStatus::Bootstrap)
+        } else {
+            VNode::new(self, i, VNodeStatus::Absent)
+        }
+    }
+
+    // A vnode mutex is poisoned if a worker panics while holding it.
+    // As the vnode state can't be trusted anymore it's reloaded from storage,
+    // just like after an unclean shutdown. Inflight requests and syncs are lost.
+    pub fn recover_vnode(&self, i: VNodeNo) {
+        let mut vnodes = self.vnodes.write().unwrap();
+        if !vnodes[i as usize].is_poisoned() {
+            return;
+        }
+        warn!("Reloading vnode {} after a worker panic", i);
+        let (ready_vnodes, pending_vnodes) = self.dht.vnodes_for_node(self.dht.node());
+        // it stays refused if it was, otherwise it already counted towards max_vnodes
+        let refused_vnodes = self.saved_refused_vnodes();
+        vnodes[i as usize] = Mutex::new(self.load_vnode(
+            i,
+            &ready_vnodes,
+            &pending_vnodes,
+            &refused_vnodes,
+        ));
+    }
+
+    // the vnodes refused by the last dht change, see handler_dht_change
+    fn saved_refused_vnodes(&self) -> Vec<VNodeNo> {
+        self.meta_storage
+            .get_vec(b"refused_vnodes")
+            .expect("Can't read refused vnodes from storage")
+            .map_or_else(Vec::new, |refused| {
+                assume_str(&refused)
+                    .split(',')
+                    .filter(|v| !v.is_empty())
+                    .map(|v| v.parse::<VNodeNo>().unwrap())
+                    .collect()
+            })
+    }
+
+    fn save_refused_vnodes(&self, refused: &[VNodeNo]) {
+        let refused: Vec<_> = refused.iter().map(|v| v.to_string()).collect();
+        self.meta_storage
+            .set(b"refused_vnodes", refused.join(",").as_bytes())
+            .log_error("Can't save refused vnodes");
+        metrics::VNODES_REFUSED.set(refused.len() as _);
+    }
+
+    // gives up the ownership of the refused vnodes, so the ring doesn't count on this node
+    // for them. The callback runs handler_dht_change with the new ring
+    fn decline_vnodes(&self, refused: &[VNodeNo]) {
+        for &i in refused {
+            if self.dht.nodes_for_vnode(i, true, false).contains(&self.dht.node()) {
+                self.dht
+                    .decline_vnode(self.dht.node(), i)
+                    .log_error("Can't decline vnode");
+            }
+        }
+    }
+
+    // The vnodes owned by this node hold data, they're kept even if max_vnodes was lowered.
+    // Pending vnodes refused before the restart stay refused and the ones over the cap
+    // are refused too, in order, like in handler_dht_change
+    fn refused_vnodes_at_startup(
+        &self,
+        ready_vnodes: &[VNodeNo],
+        pending_vnodes: &[VNodeNo],
+    ) -> Vec<VNodeNo> {
+        if self.config.max_vnodes == 0 {
+            self.save_refused_vnodes(&[]);
+            return Vec::new();
+        }
+        let saved = self.saved_refused_vnodes();
+        let node = self.dht.node();
+        let (owned, mut pending): (Vec<VNodeNo>, Vec<VNodeNo>) = ready_vnodes
+            .iter()
+            .chain(pending_vnodes)
+            .cloned()
+            .partition(|&v| self.dht.nodes_for_vnode(v, false, false).contains(&node));
+        pending.sort();
+        let (mut refused, hosted): (Vec<_>, Vec<_>) =
+            pending.into_iter().partition(|v| saved.contains(v));
+        let room = (self.config.max_vnodes as usize).saturating_sub(owned.len());
+        refused.extend(hosted.into_iter().skip(room));
+        refused.sort();
+        if owned.len() > self.config.max_vnodes as usize {
+            warn!(
+                "Keeping the {} vnodes owned by this node, over its max_vnodes cap ({}). \
+                 Add capacity to the cluster and rebalance to move them",
+                owned.len(),
+                self.config.max_vnodes
+            );
+        }
+        if !refused.is_empty() {
+            warn!(
+                "Refusing ownership of vnodes {:?}, node is at its max_vnodes cap ({}). \
+                 Add capacity to the cluster",
+                refused, self.config.max_vnodes
+            );
+        }
+        self.save_refused_vnodes(&refused);
+        refused
+    }
+
     pub fn save(&self, shutdown: bool) {
         for vn in self.vnodes.read().unwrap().iter() {
             vn.lock().unwrap().save(self, shutdown);
@@ -362,12 +815,56 @@ impl Database {
         self.meta_storage.sync().expect("Can't sync storage");
     }
 
+    /// Shuts the node down cleanly, so the next start keeps the node and vnode ids
+    /// and skips recovering the vnodes from the storage.
+    /// Waits up to the request timeouts for the inflight requests to finish, then stops
+    /// handling fabric messages and drains the worker queues, so nothing changes the
+    /// vnodes while every one of them is saved and the storage synced.
+    /// Inflight syncs are abandoned, peers retry them.
+    pub fn shutdown(&self) {
+        info!("Shutting down");
+        let timeout = self.config.read_timeout().max(self.config.write_timeout());
+        let deadline = time::Instant::now() + timeout;
+        while self.inflight_requests() != 0 && time::Instant::now() < deadline {
+            sleep_ms(10);
+        }
+        let inflight = self.inflight_requests();
+        if inflight != 0 {
+            warn!("Shutting down with {} inflight requests", inflight);
+        }
+        self.fabric.discard_msgs();
+        // the storage workers queue their replies to the workers, so they stop first.
+        // Neither is joined with the lock held, the workers take it to get the senders.
+        let storage_workers = self.storage_workers.lock().unwrap().take();
+        drop(storage_workers);
+        let threads = self.workers.lock().unwrap().stop();
+        for t in threads {
+            let _ = t.join();
+        }
+        self.save(true);
+        info!("Shutdown complete");
+    }
+
     // Gets a Sender handle that allows sending work to the database worker pool
     pub fn sender(&self) -> WorkerSender<WorkerMsg> {
         self.workers.lock().unwrap().sender()
     }
 
+    // Like sender() but for the storage worker pool, None if it's disabled
+    pub fn storage_sender(&self) -> Option<WorkerSender<StorageMsg>> {
+        self.storage_workers
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|w| w.sender())
+    }
+
     fn handler_dht_change(&self) {
+        // vnodes not created yet are created with the partitions of the ring
+        if self.partitions() != 0 && self.dht.partitions() > self.partitions() {
+            self.split_vnodes();
+        }
+
         // save dht
         self.meta_storage
             .set(b"ring", &self.dht.save_ring())
@@ -376,7 +873,10 @@ impl Database {
         // register nodes
         self.fabric.set_nodes(self.dht.members().into_iter());
 
-        for (i, vn) in self.vnodes.read().unwrap().iter().enumerate() {
+        let vnodes = self.vnodes.read().unwrap();
+        // with a cap, new vnodes are only taken after releasing the ones moving away
+        let mut acquired = Vec::new();
+        for (i, vn) in vnodes.iter().enumerate() {
             let final_status = if self
                 .dht
                 .nodes_for_vnode(i as VNodeNo, true, true)
@@ -386,22 +886,137 @@ impl Database {
             } else {
                 VNodeStatus::Absent
             };
-            vn.lock().unwrap().handler_dht_change(self, final_status);
+            let mut vn = vn.lock().unwrap();
+            if self.config.max_vnodes != 0
+                && final_status == VNodeStatus::Ready
+                && vn.status() == VNodeStatus::Absent
+            {
+                acquired.push(i);
+            } else {
+                vn.handler_dht_change(self, final_status);
+            }
+        }
+
+        let mut hosted = vnodes
+            .iter()
+            .filter(|vn| match vn.lock().unwrap().status() {
+                VNodeStatus::Ready | VNodeStatus::Bootstrap => true,
+                _ => false,
+            })
+            .count();
+        let mut refused = Vec::new();
+        for i in acquired {
+            if hosted >= self.config.max_vnodes as usize {
+                refused.push(i);
+                continue;
+            }
+            hosted += 1;
+            vnodes[i]
+                .lock()
+                .unwrap()
+                .handler_dht_change(self, VNodeStatus::Ready);
+        }
+        if !refused.is_empty() {
+            warn!(
+                "Refusing ownership of vnodes {:?}, node is at its max_vnodes cap ({}). \
+                 Add capacity to the cluster",
+                refused, self.config.max_vnodes
+            );
+        }
+        // so they stay refused across restarts
+        self.save_refused_vnodes(&refused);
+        drop(vnodes);
+        self.decline_vnodes(&refused);
+    }
+
+    // The ring partitions were doubled (maybe more than once), each vnode moves the keys
+    // of the second half of its slots to the vnode it splits into, see DHT::split.
+    // Every replica of a vnode is a replica of both halves, so the keys move locally
+    // instead of being streamed. Writes to the moved keys rejected while the nodes
+    // disagree on the ring reach the new vnodes through the regular syncs.
+    // Vnodes that were bootstrapping start over, once for each half.
+    // The vnodes are split one at a time, only the vnode and the one it splits into
+    // are locked while the keys move. The new vnode takes its keys (see key_vnode)
+    // once its split is done, requests racing with it fail as Unavailable.
+    fn split_vnodes(&self) {
+        // the split progress is recorded along with the ring it comes from,
+        // so a restart midway loads the vnodes it started with and resumes
+        self.meta_storage
+            .set(b"split_from", self.partitions().to_string().as_bytes())
+            .expect("Can't save split progress");
+        self.meta_storage
+            .set(b"ring", &self.dht.save_ring())
+            .expect("Can't save ring");
+        self.meta_storage.sync().expect("Can't sync storage");
+
+        let partitions = self.dht.partitions();
+        while self.partitions() < partitions {
+            let count = self.partitions();
+            info!("Splitting {} vnodes into {}", count, count * 2);
+            let placeholders: Vec<_> = (count..count * 2)
+                .map(|i| Mutex::new(VNode::split_placeholder(self, i as VNodeNo)))
+                .collect();
+            self.vnodes.write().unwrap().extend(placeholders);
+            for i in 0..count {
+                let vnodes = self.vnodes.read().unwrap();
+                let mut vnode = vnodes[i].lock().unwrap();
+                let mut child = vnodes[count + i].lock().unwrap();
+                *child = vnode.split(self, (count + i) as VNodeNo);
+                self.partitions.store(count + i + 1, Ordering::Release);
+            }
         }
+        self.meta_storage
+            .del(b"split_from")
+            .expect("Can't delete split progress");
+        self.meta_storage.sync().expect("Can't sync storage");
+    }
+
+    /// The worker that handles the fabric msgs and ticks of the vnode, so a vnode
+    /// isn't contended between workers and they get the same number of vnodes.
+    /// It follows the partition count (splits) and the worker count (restarts).
+    pub fn vnode_worker(&self, vnode: VNodeNo) -> usize {
+        // like WorkerSender::try_send_to
+        vnode as usize % self.config.worker_count as usize
     }
 
-    fn handler_tick(&self, time: time::Instant) {
-        self.dht.handler_tick(time);
+    // every worker ticks its vnodes, the first one does the node wide work as well
+    fn handler_tick(&self, time: time::Instant, worker: usize) {
+        if worker == 0 {
+            self.dht.handler_tick(time);
+
+            // reload vnodes left behind by panicking workers
+            let poisoned: Vec<_> = self
+                .vnodes
+                .read()
+                .unwrap()
+                .iter()
+                .enumerate()
+                .filter(|&(_, vn)| vn.is_poisoned())
+                .map(|(i, _)| i as VNodeNo)
+                .collect();
+            for i in poisoned {
+                self.recover_vnode(i);
+            }
+
+            self.start_queued_syncs(time);
+        }
 
-        let mut incomming_syncs = 0usize;
         let vnodes = self.vnodes.read().unwrap();
-        for vn in vnodes.iter() {
-            let mut vn = vn.lock().unwrap();
-            vn.handler_tick(self, time);
-            incomming_syncs += vn.syncs_inflight().0;
+        // vnodes being split into aren't ticked yet
+        for (i, vn) in vnodes.iter().enumerate().take(self.partitions()) {
+            if self.vnode_worker(i as VNodeNo) == worker {
+                vn.lock().unwrap().handler_tick(self, time);
+            }
+        }
+        if worker != 0 || !self.config.sync_auto {
+            return;
         }
+        let mut incomming_syncs: usize = vnodes
+            .iter()
+            .map(|vn| vn.lock().unwrap().syncs_inflight().0)
+            .sum();
         // auto start sync in random vnodes
-        if self.config.sync_auto && incomming_syncs < self.config.sync_incomming_max as usize {
+        if incomming_syncs < self.config.sync_incomming_max as usize {
             for vn in vnodes
                 .iter()
                 .cycle()
@@ -417,6 +1032,9 @@ impl Database {
     }
 
     fn handler_fabric_msg(&self, from: NodeId, msg: FabricMsg) {
+        if self.unknown_vnode(from, &msg) {
+            return;
+        }
         match msg {
             FabricMsg::RemoteGet(m) => {
                 vnode!(self, m.vnode, |vn| vn.handler_get_remote(self, from, m));
@@ -434,6 +1052,9 @@ impl Database {
                 vnode!(self, m.vnode, |vn| vn.handler_sync_start(self, from, m));
             }
             FabricMsg::SyncSend(m) => {
+                vnode!(self, m.vnode, |vn| vn.handler_sync_send(self, from, m.into()));
+            }
+            FabricMsg::SyncSendBatch(m) => {
                 vnode!(self, m.vnode, |vn| vn.handler_sync_send(self, from, m));
             }
             FabricMsg::SyncAck(m) => {
@@ -442,10 +1063,60 @@ impl Database {
             FabricMsg::SyncFin(m) => {
                 vnode!(self, m.vnode, |vn| vn.handler_sync_fin(self, from, m));
             }
+            FabricMsg::ClockProbe(m) => {
+                vnode!(self, m.vnode, |vn| vn.handler_clock_probe(self, from, m));
+            }
+            FabricMsg::ClockProbeAck(m) => {
+                vnode!(self, m.vnode, |vn| vn.handler_clock_probe_ack(self, from, m));
+            }
+            FabricMsg::AAETreeRequest(m) => {
+                vnode!(self, m.vnode, |vn| vn.handler_aae_tree_request(self, from, m));
+            }
+            FabricMsg::AAETree(m) => {
+                vnode!(self, m.vnode, |vn| vn.handler_aae_tree(self, from, m));
+            }
             msg => unreachable!("Can't handle {:?}", msg),
         }
     }
 
+    // Msgs for vnodes of a ring split this node didn't apply yet, requests get an error
+    // and the rest are dropped. Their senders retry once the split propagates.
+    fn unknown_vnode(&self, from: NodeId, msg: &FabricMsg) -> bool {
+        let vnode = match msg.vnode() {
+            Some(vnode) => vnode,
+            None => return false,
+        };
+        if (vnode as usize) < self.partitions() {
+            return false;
+        }
+        debug!("Msg from {} for unknown vnode {}", from, vnode);
+        let _ = match *msg {
+            FabricMsg::RemoteGet(ref m) => {
+                fabric_send_error!(self, from, m, MsgRemoteGetAck, FabricError::WrongVNode)
+            }
+            FabricMsg::RemoteSet(ref m) if m.reply => {
+                fabric_send_error!(self, from, m, MsgRemoteSetAck, FabricError::WrongVNode)
+            }
+            FabricMsg::SyncStart(ref m) => {
+                fabric_send_error!(self, from, m, MsgSyncFin, FabricError::WrongVNode)
+            }
+            _ => Ok(()),
+        };
+        true
+    }
+
+    /// Handles messages as if they were received from the fabric, in order.
+    /// Meant to replay a `fabric_capture` (see `fabric::read_capture`) into a fresh
+    /// node to reproduce an issue. Ticks and client requests aren't replayed.
+    pub fn replay_fabric_msgs<I: IntoIterator<Item = (NodeId, FabricMsg)>>(&self, msgs: I) {
+        for (from, msg) in msgs {
+            match msg.get_type() {
+                FabricMsgType::DHT => self.dht.handler_fabric_msg(from, msg),
+                _ => self.handler_fabric_msg(from, msg),
+            }
+        }
+    }
+
     fn syncs_inflight(&self) -> usize {
         self.vnodes
             .read()
@@ -465,6 +1136,98 @@ impl Database {
         vnode._start_sync(self)
     }
 
+    /// Starts a request with a client request id (REQID). Returns the response of the
+    /// previous request with the same id if it ran recently, None if the request
+    /// should run or an error if the previous one is still running.
+    pub fn request_id_begin(
+        &self,
+        id: (u16, u64, u64),
+    ) -> Result<Option<RespValue>, CommandError> {
+        let now = time::Instant::now();
+        let expire = now + time::Duration::from_millis(self.config.request_id_ttl as _);
+        let mut request_ids = self.request_ids.lock().unwrap();
+        while request_ids.pop_expired(now).is_some() {}
+        match request_ids.get(&id) {
+            Some(&Some(ref response)) => return Ok(Some(response.clone())),
+            Some(&None) => return Err(CommandError::RequestInProgress),
+            None => (),
+        }
+        request_ids.insert_bounded(id, None, expire, REQUEST_IDS_MAX);
+        Ok(None)
+    }
+
+    /// Remembers the response of a request started with request_id_begin, errors included
+    /// as the request may have been applied regardless (eg. timeouts). Without a response
+    /// the request is forgotten instead, so it can be retried.
+    pub fn request_id_end(&self, id: (u16, u64, u64), response: Option<&RespValue>) {
+        let mut request_ids = self.request_ids.lock().unwrap();
+        match response {
+            None => {
+                request_ids.remove(&id);
+            }
+            Some(response) => {
+                if let HMEntry::Occupied(mut o) = request_ids.entry(id) {
+                    *o.get_mut() = Some(response.clone());
+                }
+            }
+        }
+    }
+
+    /// Holds a sync start refused for the lack of outgoing slots,
+    /// the tick starts it once a slot frees up or fails it after the sync timeout.
+    pub fn queue_sync_start(&self, from: NodeId, msg: MsgSyncStart) {
+        let mut stats = self.stats.lock().unwrap();
+        // receivers resend the start until the sync begins, keep the first deadline
+        if let Some(queued) = stats
+            .queued_sync_starts
+            .iter_mut()
+            .find(|q| q.1.cookie == msg.cookie)
+        {
+            queued.1 = msg;
+            return;
+        }
+        let timeout = time::Duration::from_millis(self.config.effective_sync_timeout() as _);
+        stats
+            .queued_sync_starts
+            .push_back((from, msg, time::Instant::now() + timeout));
+    }
+
+    fn start_queued_syncs(&self, now: time::Instant) {
+        let (expired, ready) = {
+            let mut stats = self.stats.lock().unwrap();
+            let queued = mem::replace(&mut stats.queued_sync_starts, Default::default());
+            let (expired, mut pending): (Vec<_>, VecDeque<_>) =
+                queued.into_iter().partition(|q| q.2 <= now);
+            let free = self
+                .config
+                .sync_outgoing_max
+                .saturating_sub(stats.outgoing_syncs) as usize;
+            let ready: Vec<_> = pending.drain(..free.min(pending.len())).collect();
+            stats.queued_sync_starts = pending;
+            (expired, ready)
+        };
+        for (from, msg, _) in expired {
+            debug!("Queued sync start {:?} timed out", msg.cookie);
+            let _ = fabric_send_error!(self, from, msg, MsgSyncFin, FabricError::QueueFull);
+        }
+        // may be queued again if another vnode took the slot in the meantime
+        for (from, msg, _) in ready {
+            vnode!(self, msg.vnode, |vn| vn.handler_sync_start(self, from, msg));
+        }
+    }
+
+    pub fn client_connections(&self) -> usize {
+        self.client_connections.load(Ordering::Relaxed)
+    }
+
+    pub fn signal_client_connected(&self) {
+        self.client_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn signal_client_disconnected(&self) {
+        self.client_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+
     pub fn signal_sync_start(&self, direction: SyncDirection) -> bool {
         let mut stats = self.stats.lock().unwrap();
         match direction {
@@ -485,52 +1248,388 @@ impl Database {
         }
     }
 
-    pub fn signal_sync_end(&self, direction: SyncDirection) {
+    /// Whether the outgoing syncs can send more data, see `sync_bandwidth_limit`.
+    /// The budget refills continuously, up to a second worth of traffic.
+    pub fn sync_bandwidth_available(&self) -> bool {
+        let limit = self.config.sync_bandwidth_limit as i64;
+        if limit == 0 {
+            return true;
+        }
         let mut stats = self.stats.lock().unwrap();
-        match direction {
-            SyncDirection::Incomming => {
-                stats.incomming_syncs = stats.incomming_syncs.checked_sub(1).unwrap();
-                metrics::SYNC_INCOMING.dec();
+        let now = time::Instant::now();
+        match stats.sync_budget_refill {
+            Some(refill) => {
+                let elapsed = now - refill;
+                let micros = elapsed.as_secs() as i64 * 1_000_000 + elapsed.subsec_micros() as i64;
+                let refilled = limit.saturating_mul(micros) / 1_000_000;
+                // keep the fractions for the next call
+                if refilled != 0 {
+                    stats.sync_budget = (stats.sync_budget + refilled).min(limit);
+                    stats.sync_budget_refill = Some(now);
+                }
             }
-            SyncDirection::Outgoing => {
-                stats.outgoing_syncs = stats.outgoing_syncs.checked_sub(1).unwrap();
-                metrics::SYNC_OUTGOING.dec();
+            None => {
+                stats.sync_budget = limit;
+                stats.sync_budget_refill = Some(now);
             }
         }
+        stats.sync_budget > 0
     }
 
-    // CLIENT CRUD
-    pub fn set_flush(
-        &self,
-        context: &mut Context,
-        consistency: ConsistencyLevel,
-    ) -> Result<(), CommandError> {
-        debug_assert!(context.is_multi && context.is_exec);
-        let mut multi_vnode = None;
-        for (wi, w) in context.writes.iter().enumerate() {
-            // vnode can't changes mid way a batch we need to error
-            let write_vnode = self.dht.key_vnode(&w.key);
-            if multi_vnode.is_none() {
-                multi_vnode = Some(write_vnode);
-            } else if multi_vnode != Some(write_vnode) {
-                return Err(CommandError::MultiplePartitions);
-            }
+    /// Accounts bytes sent by an outgoing sync, the budget can go into debt
+    /// so messages larger than it still get through.
+    pub fn sync_bandwidth_consume(&self, bytes: usize) {
+        if self.config.sync_bandwidth_limit != 0 {
+            self.stats.lock().unwrap().sync_budget -= bytes as i64;
+        }
+    }
 
-            // make sure key doesn't appear in the rest of the commands
-            for w2 in &context.writes[wi + 1..] {
-                if w.key == w2.key {
-                    return Err(CommandError::MultipleKeyMutations);
-                }
-            }
+    /// Starts an on demand anti-entropy job over all Ready vnodes,
+    /// returns the job id and the number of vnodes involved.
+    /// Syncs are started by the tick, subject to `sync_incomming_max`.
+    pub fn aae_start(&self) -> (u64, usize) {
+        let job = {
+            let mut stats = self.stats.lock().unwrap();
+            stats.aae_jobs += 1;
+            stats.aae_jobs
+        };
+        let vnodes = self.vnodes.read().unwrap();
+        let count = vnodes
+            .iter()
+            .filter(|vn| vn.lock().unwrap().aae_start(self, job))
+            .count();
+        info!("Started aae job {} over {} vnodes", job, count);
+        (job, count)
+    }
+
+    /// Forces a sync of `vnode` from `peer`, subject to `sync_incomming_max`,
+    /// returns the cookie to follow it in the metrics.
+    pub fn trigger_sync(&self, vnode: VNodeNo, peer: NodeId) -> Result<Cookie, GenericError> {
+        let vnodes = self.vnodes.read().unwrap();
+        match vnodes.get(vnode as usize) {
+            Some(vn) => vn.lock().unwrap().trigger_sync(self, peer),
+            None => Err(format!("vnode {} doesn't exist", vnode).into()),
         }
+    }
 
-        if let Some(vnode) = multi_vnode {
-            vnode!(self, vnode, |vn| vn.do_flush(self, context, consistency))
-        } else {
-            Ok(self.respond_resp(context, RespValue::Array(Default::default())))
+    pub fn aae_progress(&self, job: u64) -> Vec<(VNodeNo, AaeProgress)> {
+        self.vnodes
+            .read()
+            .unwrap()
+            .iter()
+            .enumerate()
+            .filter_map(|(i, vn)| {
+                vn.lock()
+                    .unwrap()
+                    .aae_progress(job)
+                    .map(|p| (i as VNodeNo, p))
+            })
+            .collect()
+    }
+
+    /// Requests waiting for replicas, across all vnodes
+    pub fn inflight_requests(&self) -> usize {
+        self.vnodes
+            .read()
+            .unwrap()
+            .iter()
+            .map(|vn| vn.lock().unwrap().inflight_requests())
+            .sum()
+    }
+
+    /// Snapshot of the node and vnodes state, serializable for monitoring.
+    /// Each vnode is locked in turn, so they're not consistent with each other.
+    pub fn metrics(&self) -> DatabaseMetrics {
+        let vnodes: Vec<VNodeMetrics> = self
+            .vnodes
+            .read()
+            .unwrap()
+            .iter()
+            .map(|vn| vn.lock().unwrap().metrics())
+            .collect();
+        let (incoming_syncs, outgoing_syncs, queued_syncs) = {
+            let stats = self.stats.lock().unwrap();
+            (
+                stats.incomming_syncs,
+                stats.outgoing_syncs,
+                stats.queued_sync_starts.len(),
+            )
+        };
+        let mut hot_keys: Vec<(Bytes, u64)> = vnodes
+            .iter()
+            .flat_map(|m| m.hot_keys.iter().cloned())
+            .collect();
+        hot_keys.sort_by(|a, b| b.1.cmp(&a.1));
+        hot_keys.truncate(self.config.hot_keys_max as usize);
+        DatabaseMetrics {
+            node: self.dht.node(),
+            incoming_syncs: incoming_syncs,
+            outgoing_syncs: outgoing_syncs,
+            queued_syncs: queued_syncs,
+            inflight_requests: vnodes.iter().map(|m| m.inflight_requests).sum(),
+            client_connections: self.client_connections(),
+            worker_queues: self.sender().queue_lens(),
+            approximate_size: vnodes.iter().map(|m| m.approximate_size).sum(),
+            keys: vnodes.iter().map(|m| m.keys).sum(),
+            value_bytes: vnodes.iter().map(|m| m.value_bytes).sum(),
+            hot_keys: hot_keys,
+            vnodes: vnodes,
         }
     }
 
+    /// Cheap aggregate of the vnodes, fabric and storage state, meant for external probes.
+    /// Bootstrapping vnodes make the node Degraded, as they take writes and stale reads.
+    pub fn health(&self) -> Health {
+        if self.meta_storage.get(b"node", |_| ()).is_err() {
+            return Health::Unavailable;
+        }
+        let node = self.dht.node();
+        let (mut serving, mut degraded) = (false, false);
+        for vnode in 0..self.partitions() as VNodeNo {
+            let nodes = self.dht.nodes_for_vnode(vnode, true, true);
+            if !nodes.contains(&node) {
+                continue;
+            }
+            match vnode!(self, vnode, |vn| vn.status()) {
+                VNodeStatus::Ready => {
+                    serving = true;
+                    let required = [
+                        self.config.consistency_read,
+                        self.config.consistency_write,
+                        self.config.consistency_delete,
+                    ].iter()
+                        .map(|c| c.required(nodes.len() as u8) as usize)
+                        .max()
+                        .unwrap();
+                    degraded |= self.live_replicas(&nodes) < required;
+                }
+                VNodeStatus::Bootstrap => {
+                    serving = true;
+                    degraded = true;
+                }
+                // the vnode didn't catch up with the ring yet
+                VNodeStatus::Zombie | VNodeStatus::Absent => degraded = true,
+            }
+        }
+        if !serving {
+            Health::Unavailable
+        } else if degraded {
+            Health::Degraded
+        } else {
+            Health::Healthy
+        }
+    }
+
+    /// Read-only view of the ring, so tooling and smart clients can route requests
+    /// directly to the replicas. The keys map to vnodes with vnode_for_key.
+    pub fn topology(&self) -> Topology {
+        let mut nodes: Vec<_> = self.dht.members_meta().into_iter().collect();
+        nodes.sort();
+        let vnodes = self
+            .dht
+            .vnodes_layout()
+            .into_iter()
+            .map(|(owners, pending, retiring)| VNodeTopology {
+                owners: owners,
+                pending: pending,
+                retiring: retiring,
+            })
+            .collect();
+        Topology {
+            partitions: self.dht.partitions(),
+            replication_factor: self.dht.replication_factor(),
+            hash_algorithm: self.dht.hash_algorithm(),
+            nodes: nodes,
+            vnodes: vnodes,
+        }
+    }
+
+    /// The vnode that stores the key, as stored (keys of non default keyspaces are prefixed)
+    pub fn vnode_for_key(&self, key: &[u8]) -> VNodeNo {
+        self.dht.key_vnode(key)
+    }
+
+    /// Vnodes in this node, see `key_vnode`
+    pub fn partitions(&self) -> usize {
+        self.partitions.load(Ordering::Acquire)
+    }
+
+    /// Like `vnode_for_key` but among the vnodes in this node. Until a ring split is
+    /// applied locally the keys of the new vnodes are still in the ones they split from.
+    pub fn key_vnode(&self, key: &[u8]) -> VNodeNo {
+        let partitions = self.partitions();
+        let mut level = self.dht.partitions();
+        let mut vnode = self.dht.key_vnode(key) as usize;
+        // each split level doubles the vnodes, the new ones are installed in order
+        while vnode >= partitions {
+            level /= 2;
+            vnode %= level;
+        }
+        vnode as VNodeNo
+    }
+
+    pub fn vnodes_summary(&self) -> Vec<(VNodeNo, VNodeSummary)> {
+        self.vnodes
+            .read()
+            .unwrap()
+            .iter()
+            .enumerate()
+            .map(|(i, vn)| {
+                let summary = match vn.lock() {
+                    Ok(vn) => vn.summary(),
+                    // still readable, the panic may have left it halfway through a change
+                    Err(poisoned) => VNodeSummary {
+                        pending_recovery: true,
+                        ..poisoned.into_inner().summary()
+                    },
+                };
+                (i as VNodeNo, summary)
+            })
+            .collect()
+    }
+
+    // number of the given replicas that can take part in a request right now,
+    // this node plus the ones with an established fabric connection
+    pub fn live_replicas(&self, nodes: &[NodeId]) -> usize {
+        let node = self.dht.node();
+        nodes
+            .iter()
+            .filter(|&&n| n == node || self.fabric.is_connected(n))
+            .count()
+    }
+
+    // orders the replicas by preference for reads, according to read_replica_policy
+    pub fn order_read_replicas(&self, nodes: &mut [NodeId]) {
+        if nodes.len() < 2 {
+            return;
+        }
+        match self.config.read_replica_policy {
+            ReplicaPolicy::All => (),
+            ReplicaPolicy::Random => thread_rng().shuffle(nodes),
+            ReplicaPolicy::RoundRobin => {
+                let mut stats = self.stats.lock().unwrap();
+                stats.read_replica_rr = stats.read_replica_rr.wrapping_add(1);
+                let len = nodes.len();
+                nodes.rotate_left(stats.read_replica_rr % len);
+            }
+            ReplicaPolicy::LatencyAware => {
+                // replicas without samples go first, so they get some
+                let stats = self.stats.lock().unwrap();
+                nodes.sort_by_key(|n| stats.replica_latency.get(n).cloned().unwrap_or(0));
+            }
+        }
+    }
+
+    pub fn observe_replica_latency(&self, node: NodeId, latency: time::Duration) {
+        let micros = latency.as_secs() * 1_000_000 + (latency.subsec_nanos() / 1_000) as u64;
+        let mut stats = self.stats.lock().unwrap();
+        let avg = stats.replica_latency.entry(node).or_insert(micros);
+        *avg = (*avg * 7 + micros) / 8;
+    }
+
+    pub fn signal_sync_end(&self, direction: SyncDirection) {
+        let mut stats = self.stats.lock().unwrap();
+        match direction {
+            SyncDirection::Incomming => {
+                stats.incomming_syncs = stats.incomming_syncs.checked_sub(1).unwrap();
+                metrics::SYNC_INCOMING.dec();
+            }
+            SyncDirection::Outgoing => {
+                stats.outgoing_syncs = stats.outgoing_syncs.checked_sub(1).unwrap();
+                metrics::SYNC_OUTGOING.dec();
+            }
+        }
+    }
+
+    // CLIENT CRUD
+    pub fn set_flush(
+        &self,
+        context: &mut Context,
+        consistency: ConsistencyLevel,
+    ) -> Result<(), CommandError> {
+        debug_assert!(context.is_multi && context.is_exec);
+        let mut multi_vnode = None;
+        for (wi, w) in context.writes.iter().enumerate() {
+            // vnode can't changes mid way a batch we need to error
+            let write_vnode = self.key_vnode(&w.key);
+            if multi_vnode.is_none() {
+                multi_vnode = Some(write_vnode);
+            } else if multi_vnode != Some(write_vnode) {
+                return Err(CommandError::MultiplePartitions);
+            }
+
+            // make sure key doesn't appear in the rest of the commands
+            for w2 in &context.writes[wi + 1..] {
+                if w.key == w2.key {
+                    return Err(CommandError::MultipleKeyMutations);
+                }
+            }
+        }
+
+        if let Some(vnode) = multi_vnode {
+            vnode!(self, vnode, |vn| vn.do_flush(self, context, consistency))
+        } else {
+            Ok(self.respond_resp(context, RespValue::Array(Default::default())))
+        }
+    }
+
+    // like set_flush but the writes are grouped by vnode, each vnode flushes its writes
+    // as a batch. Vnodes succeed or fail independently, so it isn't atomic across vnodes,
+    // the keys of the vnodes that failed get the error.
+    pub fn mset_flush(
+        &self,
+        context: &mut Context,
+        consistency: ConsistencyLevel,
+    ) -> Result<(), CommandError> {
+        debug_assert!(context.is_multi && context.is_exec);
+        let mut groups: Vec<(VNodeNo, Vec<usize>)> = Vec::new();
+        for (wi, w) in context.writes.iter().enumerate() {
+            for w2 in &context.writes[wi + 1..] {
+                if w.key == w2.key {
+                    return Err(CommandError::MultipleKeyMutations);
+                }
+            }
+            let vnode = self.key_vnode(&w.key);
+            match groups.iter().position(|&(v, _)| v == vnode) {
+                Some(g) => groups[g].1.push(wi),
+                None => groups.push((vnode, vec![wi])),
+            }
+        }
+        if groups.len() <= 1 {
+            return self.set_flush(context, consistency);
+        }
+
+        let batch = Arc::new(Mutex::new(ContextBatch {
+            context: None,
+            results: vec![RespValue::Nil; context.writes.len()],
+            pending: groups.len(),
+            reduce: None,
+        }));
+        let mut writes: Vec<_> = context.writes.drain(..).map(Some).collect();
+        let parts: Vec<_> = groups
+            .into_iter()
+            .map(|(vnode, positions)| {
+                let part_writes = positions
+                    .iter()
+                    .map(|&i| writes[i].take().expect("Write in multiple parts"))
+                    .collect();
+                let mut part = context.batch_part(&batch, positions);
+                part.durable = context.durable;
+                part.writes = part_writes;
+                (vnode, part)
+            })
+            .collect();
+        // parts may complete synchronously, so the context must be in place
+        batch.lock().unwrap().context = Some(replace_default(context));
+        for (vnode, mut part) in parts {
+            let result = vnode!(self, vnode, |vn| vn.do_flush(self, &mut part, consistency));
+            if let Err(e) = result {
+                self.respond_error(&mut part, e);
+            }
+        }
+        Ok(())
+    }
+
     pub fn set(
         &self,
         context: &mut Context,
@@ -555,7 +1654,7 @@ impl Database {
             Ok(())
         } else {
             debug_assert_eq!(context.writes.len(), 1);
-            let vnode = self.dht.key_vnode(key);
+            let vnode = self.key_vnode(key);
             vnode!(self, vnode, |vn| vn.do_flush(self, context, consistency))
         }
     }
@@ -568,7 +1667,7 @@ impl Database {
         response_fn: ResponseFn,
     ) -> Result<(), CommandError> {
         debug_assert!(!context.is_multi && !context.is_exec);
-        let vnode = self.dht.key_vnode(key);
+        let vnode = self.key_vnode(key);
         vnode!(self, vnode, |vn| vn.do_get(
             self,
             context,
@@ -578,34 +1677,204 @@ impl Database {
         ))
     }
 
+    // cursor is the vnode number (u16 big endian) followed by the last returned key
+    // (as stored), the returned cursor moves to the next vnode once the current one is
+    // exhausted. Only the keys of the selected keyspace are returned, without its prefix
+    pub fn scan(
+        &self,
+        context: &mut Context,
+        cursor: &[u8],
+        prefix: &[u8],
+        count: usize,
+    ) -> Result<(), CommandError> {
+        let (vnode, start) = if cursor.len() >= 2 {
+            (BigEndian::read_u16(&cursor[..2]), &cursor[2..])
+        } else if cursor.is_empty() || cursor == b"0" {
+            (0, &cursor[..0])
+        } else {
+            return Err(CommandError::InvalidCursor);
+        };
+        // the tail is a key, built into fixed size buffers by the storage
+        if start.len() > config::MAX_KEY_LEN + KEYSPACE_PREFIX_LEN
+            || (!start.is_empty() && split_keyspace_key(start).0 != context.keyspace)
+        {
+            return Err(CommandError::InvalidCursor);
+        }
+        if vnode as usize >= self.partitions() {
+            return Err(CommandError::InvalidCursor);
+        }
+        let keyspace = context.keyspace;
+        let (entries, exhausted) = vnode!(self, vnode, |vn| vn.do_scan(
+            self, start, prefix, keyspace, count
+        ))?;
+
+        let mut next_cursor = BytesMut::new();
+        if !exhausted {
+            let last_key = &entries.last().expect("Non empty scan results").0;
+            next_cursor.reserve(2 + last_key.len());
+            next_cursor.put_u16_be(vnode);
+            next_cursor.put_slice(last_key);
+        } else if (vnode as usize + 1) < self.partitions() {
+            next_cursor.reserve(2);
+            next_cursor.put_u16_be(vnode + 1);
+        }
+        let entries = entries
+            .into_iter()
+            .map(|(k, cube)| {
+                let key_len = split_keyspace_key(&k).1.len();
+                render_dump(k.slice_from(k.len() - key_len), cube)
+            })
+            .collect();
+        self.respond_resp(
+            context,
+            RespValue::Array(vec![
+                RespValue::Data(next_cursor.freeze()),
+                RespValue::Array(entries),
+            ]),
+        );
+        Ok(())
+    }
+
+    pub fn wait(
+        &self,
+        context: &mut Context,
+        key: &Bytes,
+        vv: VersionVector,
+        replicas: usize,
+        timeout: time::Duration,
+    ) -> Result<(), CommandError> {
+        let vnode = self.key_vnode(key);
+        vnode!(self, vnode, |vn| vn.do_wait(
+            self, context, key, vv, replicas, timeout
+        ))
+    }
+
+    // keys are grouped by vnode, each vnode is queried once with all its keys.
+    // if some vnodes fail their keys get the error while the others succeed.
     pub fn mget(
         &self,
         context: &mut Context,
         keys: &[&Bytes],
         consistency: ConsistencyLevel,
-        response_fn: ResponseFn,
+        response_fn: &Fn() -> ResponseFn,
     ) -> Result<(), CommandError> {
         debug_assert!(context.is_multi && context.is_exec);
-        let mut multi_vnode = None;
-        for key in keys {
-            let write_vnode = self.dht.key_vnode(key);
-            if multi_vnode.is_none() {
-                multi_vnode = Some(write_vnode);
-            } else if multi_vnode != Some(write_vnode) {
-                return Err(CommandError::MultiplePartitions);
+        let mut groups: Vec<(VNodeNo, Vec<usize>)> = Vec::new();
+        for (i, key) in keys.iter().enumerate() {
+            let vnode = self.key_vnode(key);
+            match groups.iter().position(|&(v, _)| v == vnode) {
+                Some(g) => groups[g].1.push(i),
+                None => groups.push((vnode, vec![i])),
             }
         }
-        if let Some(vnode) = multi_vnode {
-            vnode!(self, vnode, |vn| vn.do_get(
+        match groups.len() {
+            0 => Ok(self.respond_resp(context, RespValue::Array(Default::default()))),
+            1 => vnode!(self, groups[0].0, |vn| vn.do_get(
                 self,
                 context,
                 keys,
                 consistency,
-                response_fn
-            ))
-        } else {
-            Ok(self.respond_resp(context, RespValue::Array(Default::default())))
+                response_fn()
+            )),
+            _ => {
+                let batch = Arc::new(Mutex::new(ContextBatch {
+                    context: None,
+                    results: vec![RespValue::Nil; keys.len()],
+                    pending: groups.len(),
+                    reduce: None,
+                }));
+                let parts: Vec<_> = groups
+                    .into_iter()
+                    .map(|(vnode, positions)| {
+                        let part_keys: Vec<&Bytes> = positions.iter().map(|&i| keys[i]).collect();
+                        (vnode, part_keys, context.batch_part(&batch, positions))
+                    })
+                    .collect();
+                // parts may complete synchronously, so the context must be in place
+                batch.lock().unwrap().context = Some(replace_default(context));
+                for (vnode, part_keys, mut part) in parts {
+                    let result = vnode!(self, vnode, |vn| vn.do_get(
+                        self,
+                        &mut part,
+                        &part_keys,
+                        consistency,
+                        response_fn()
+                    ));
+                    if let Err(e) = result {
+                        self.respond_error(&mut part, e);
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+
+    // deletes the keys starting with the prefix in the vnodes ready in this node,
+    // in batches of DELRANGE_BATCH keys written like a DEL of all their versions.
+    // Batches succeed or fail independently, so it isn't atomic.
+    pub fn delrange(
+        &self,
+        context: &mut Context,
+        prefix: &[u8],
+        consistency: ConsistencyLevel,
+    ) -> Result<(), CommandError> {
+        debug_assert!(!context.is_multi && !context.is_exec);
+        let mut parts = Vec::new();
+        for vnode in 0..self.partitions() as VNodeNo {
+            let keys = vnode!(self, vnode, |vn| vn.do_delrange_keys(self, prefix))?;
+            for chunk in keys.chunks(DELRANGE_BATCH) {
+                parts.push((vnode, chunk.to_vec()));
+            }
+        }
+        if parts.is_empty() {
+            return Ok(self.respond_int(context, 0));
+        }
+
+        let total = parts.iter().map(|p| p.1.len()).sum();
+        let batch = Arc::new(Mutex::new(ContextBatch {
+            context: None,
+            results: vec![RespValue::Nil; total],
+            pending: parts.len(),
+            reduce: Some(reduce_delrange),
+        }));
+        let mut position = 0;
+        let parts: Vec<_> = parts
+            .into_iter()
+            .map(|(vnode, keys)| {
+                let positions = (position..position + keys.len()).collect();
+                position += keys.len();
+                let mut part = context.batch_part(&batch, positions);
+                part.durable = context.durable;
+                part.is_exec = false;
+                for key in &keys {
+                    // the part is multi, so this only queues the write
+                    self.set(
+                        &mut part,
+                        key,
+                        Box::new(|i, v, mut c: Cube| {
+                            let deleted = c.has_values() as i64;
+                            let vv = c.context().clone();
+                            c.del(i, v, &vv);
+                            Ok((c, Some(RespValue::Int(deleted))))
+                        }),
+                        consistency,
+                        false,
+                        None,
+                    )?;
+                }
+                part.is_exec = true;
+                Ok((vnode, part))
+            })
+            .collect::<Result<_, CommandError>>()?;
+        // parts may complete synchronously, so the context must be in place
+        batch.lock().unwrap().context = Some(replace_default(context));
+        for (vnode, mut part) in parts {
+            let result = vnode!(self, vnode, |vn| vn.do_flush(self, &mut part, consistency));
+            if let Err(e) = result {
+                self.respond_error(&mut part, e);
+            }
         }
+        Ok(())
     }
 }
 
@@ -614,6 +1883,8 @@ impl Drop for Database {
         debug!("Droping database");
         // force dropping vnodes before other components
         let _ = self.vnodes.write().map(|mut vns| vns.clear());
+        // and the storage workers, which may hold a Storage of a queued read
+        let _ = self.storage_workers.lock().map(|mut w| w.take());
     }
 }
 
@@ -624,11 +1895,14 @@ mod tests {
     use config;
     use env_logger;
     use resp::RespValue;
+    use serde_yaml;
     use std::collections::HashMap;
     use std::sync::{Arc, Mutex};
-    use std::{fs, net, ops};
+    use std::{fs, net, ops, time};
+    use storage::StorageBackend;
     use utils::sleep_ms;
-    use version_vector::VersionVector;
+    use version_vector::{BitmappedVersionVector, VersionVector};
+    use vnode_sync::SyncResult;
 
     #[allow(non_upper_case_globals)]
     const One: &[u8] = b"One";
@@ -646,9 +1920,18 @@ mod tests {
 
     impl TestDatabase {
         fn new(fabric_addr: net::SocketAddr, data_dir: &str, create: bool) -> Self {
+            Self::new_with(fabric_addr, data_dir, create, |_| ())
+        }
+
+        fn new_with<F: FnOnce(&mut config::Config)>(
+            fabric_addr: net::SocketAddr,
+            data_dir: &str,
+            create: bool,
+            config_fn: F,
+        ) -> Self {
             let responses1 = Arc::new(Mutex::new(HashMap::new()));
             let responses2 = responses1.clone();
-            let config = config::Config {
+            let mut config = config::Config {
                 data_dir: data_dir.into(),
                 fabric_addr: fabric_addr,
                 cluster_name: "test".into(),
@@ -666,6 +1949,7 @@ mod tests {
                 seed_nodes: vec!["127.0.0.1:9000".parse().unwrap()],
                 ..Default::default()
             };
+            config_fn(&mut config);
             let db = Database::new(
                 &config,
                 Box::new(move |mut ctx| {
@@ -725,7 +2009,16 @@ mod tests {
         }
 
         fn do_cmd(&self, token: Token, args: &[&[u8]]) {
+            self.do_cmd_ks(token, 0, args)
+        }
+
+        fn do_cmd_ks(&self, token: Token, keyspace: u16, args: &[&[u8]]) {
             let mut context = Context::new(token);
+            context.keyspace = keyspace;
+            self.do_cmd_context(context, args)
+        }
+
+        fn do_cmd_context(&self, mut context: Context, args: &[&[u8]]) {
             context.commands.push(RespValue::Array(
                 args.iter().map(|&x| RespValue::Data(x.into())).collect(),
             ));
@@ -793,6 +2086,8 @@ mod tests {
         assert_eq!(db.response_values(1).0, [b"value1"]);
 
         let prev_logs = db.dump_logs();
+        let prev_key_stats = (db.metrics().keys, db.metrics().value_bytes);
+        assert_eq!(prev_key_stats.0, 1);
 
         db.save(shutdown);
         drop(db);
@@ -808,6 +2103,7 @@ mod tests {
         }
 
         assert_eq!(prev_logs, db.dump_logs(),);
+        assert_eq!(prev_key_stats, (db.metrics().keys, db.metrics().value_bytes));
     }
 
     #[test]
@@ -820,6 +2116,107 @@ mod tests {
         test_reload_stub(false);
     }
 
+    #[test]
+    fn test_shutdown_skips_recovery() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let mut db = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db", true);
+        db.do_cmd(1, &[b"SET", b"test", b"value1", b"", One]);
+        assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+        let prev_node = db.dht.node();
+        let vnode_ids = |db: &TestDatabase| -> Vec<NodeId> {
+            db.vnodes
+                .read()
+                .unwrap()
+                .iter()
+                .map(|vn| vn.lock().unwrap()._id())
+                .collect()
+        };
+        let prev_vnode_ids = vnode_ids(&db);
+
+        db.shutdown();
+        drop(db);
+        db = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db", false);
+
+        // unclean restarts get new ids, as the vnodes are recovered from the storage
+        assert_eq!(db.dht.node(), prev_node);
+        assert_eq!(vnode_ids(&db), prev_vnode_ids);
+        db.do_cmd(1, &[b"GET", b"test", One]);
+        assert_eq!(db.response_values(1).0, [b"value1"]);
+    }
+
+    #[test]
+    fn test_reload_corrupted() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let mut db = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db", true);
+        for i in 0..TEST_JOIN_SIZE {
+            db.do_cmd(i, &[b"SET", i.to_string().as_bytes(), b"value", b"", One]);
+            assert_eq!(db.response_resp(i), RespValue::Status("OK".into()));
+        }
+        let vnode = db.key_vnode(b"0");
+        db.vnodes.read().unwrap()[vnode as usize]
+            .lock()
+            .unwrap()
+            ._corrupt(b"0");
+        db.save(false);
+        for i in 0..PARTITIONS {
+            db.meta_storage
+                .set(i.to_string().as_bytes(), b"garbage")
+                .unwrap();
+        }
+        drop(db);
+        db = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db", false);
+
+        // the vnodes are recovered from their keys, skipping the corrupted one
+        for i in 1..TEST_JOIN_SIZE {
+            db.do_cmd(i, &[b"GET", i.to_string().as_bytes(), One]);
+            assert_eq!(db.response_values(i).0, [b"value"]);
+        }
+        assert_eq!(db.metrics().keys, TEST_JOIN_SIZE as u64);
+    }
+
+    #[test]
+    fn test_scan_corrupted() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        let db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        db1.dht.rebalance().unwrap();
+        db1.wait_syncs();
+        db2.wait_syncs();
+        for key in &[&b"{k}1"[..], b"{k}2"] {
+            db1.do_cmd(1, &[b"SET", *key, b"value", b"", All]);
+            assert_eq!(db1.response_resp(1), RespValue::Status("OK".into()));
+        }
+        let vnode = db1.key_vnode(b"{k}") as usize;
+        let scan = || -> Vec<Bytes> {
+            let vnodes = db1.vnodes.read().unwrap();
+            let mut vn = vnodes[vnode].lock().unwrap();
+            let (entries, _) = vn.do_scan(&db1, b"", b"{k}", 0, 10).unwrap();
+            entries.into_iter().map(|(k, _)| k).collect()
+        };
+
+        // the corrupted key is skipped and healed from the other replica by a tick
+        db1.vnodes.read().unwrap()[vnode]
+            .lock()
+            .unwrap()
+            ._corrupt(b"{k}1");
+        assert_eq!(scan(), [&b"{k}2"[..]]);
+        while scan().len() != 2 {
+            sleep_ms(10);
+        }
+
+        db1.vnodes.read().unwrap()[vnode]
+            .lock()
+            .unwrap()
+            ._corrupt(b"{k}1");
+        db1.do_cmd(1, &[b"DELRANGE", b"{k}"]);
+        assert_eq!(db1.response_resp(1), RespValue::Int(1));
+        db1.do_cmd(1, &[b"GET", b"{k}1", All]);
+        assert_eq!(db1.response_values(1).0, [b"value"]);
+    }
+
     #[test]
     fn test_one() {
         let _ = fs::remove_dir_all("t/");
@@ -857,274 +2254,2505 @@ mod tests {
     }
 
     #[test]
-    fn test_two() {
+    fn test_keyspaces() {
         let _ = fs::remove_dir_all("t/");
         let _ = env_logger::try_init();
-        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
-        let db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
-        db2.dht.rebalance().unwrap();
+        let db = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db", true);
 
-        db1.wait_syncs();
-        db2.wait_syncs();
+        db.do_cmd_ks(1, 1, &[b"SET", b"test", b"value1"]);
+        assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+        db.do_cmd_ks(1, 2, &[b"SET", b"test", b"value2"]);
+        assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
 
-        db1.do_cmd(1, &[b"GET", b"test", One]);
-        assert_eq!(db1.response_values(1).0.len(), 0);
+        db.do_cmd(1, &[b"GET", b"test", One]);
+        assert_eq!(db.response_values(1).0.len(), 0);
+        db.do_cmd_ks(1, 1, &[b"GET", b"test", One]);
+        assert_eq!(db.response_values(1).0, [b"value1"]);
+        db.do_cmd_ks(1, 2, &[b"GET", b"test", One]);
+        assert_eq!(db.response_values(1).0, [b"value2"]);
+
+        db.do_cmd(1, &[b"SELECT", b"1"]);
+        assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+        db.do_cmd(1, &[b"SELECT", b"10000"]);
+        assert_eq!(
+            db.response_resp(1),
+            RespValue::Error("InvalidKeyspace".into())
+        );
+        // default keyspace keys starting with the marker can't reach other keyspaces
+        db.do_cmd(1, &[b"SET", b"\xFF\x00\x01test", b"value0"]);
+        assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+        db.do_cmd(1, &[b"GET", b"\xFF\x00\x01test", One]);
+        assert_eq!(db.response_values(1).0, [b"value0"]);
+        db.do_cmd_ks(1, 1, &[b"GET", b"test", One]);
+        assert_eq!(db.response_values(1).0, [b"value1"]);
+        db.do_cmd(1, &[b"DELRANGE", b"\xFF"]);
+        assert_eq!(db.response_resp(1), RespValue::Int(1));
+        db.do_cmd_ks(1, 2, &[b"GET", b"test", One]);
+        assert_eq!(db.response_values(1).0, [b"value2"]);
+
+        // SCAN only sees the selected keyspace
+        let scan_keys = |keyspace: u16| {
+            let mut keys = Vec::new();
+            let mut cursor: Vec<u8> = Vec::new();
+            loop {
+                db.do_cmd_ks(1, keyspace, &[b"SCAN", &cursor]);
+                let mut response = match db.response_resp(1) {
+                    RespValue::Array(a) => a,
+                    r => panic!("unexpected response {:?}", r),
+                };
+                if let RespValue::Array(entries) = response.pop().unwrap() {
+                    for entry in entries {
+                        match entry {
+                            RespValue::Array(ref entry) => match entry[0] {
+                                RespValue::Data(ref k) => keys.push(k.clone()),
+                                _ => panic!("unexpected entry {:?}", entry),
+                            },
+                            _ => panic!("unexpected entry {:?}", entry),
+                        }
+                    }
+                }
+                cursor = match response.pop().unwrap() {
+                    RespValue::Data(c) => c.to_vec(),
+                    r => panic!("unexpected cursor {:?}", r),
+                };
+                if cursor.is_empty() {
+                    return keys;
+                }
+            }
+        };
+        assert_eq!(scan_keys(1), [Bytes::from("test")]);
+        assert_eq!(scan_keys(2), [Bytes::from("test")]);
+
+        // the prefix counts towards the key length
+        let key = vec![b'k'; config::MAX_KEY_LEN];
+        db.do_cmd(1, &[b"SET", &key, b"value"]);
+        assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+        db.do_cmd_ks(1, 1, &[b"SET", &key, b"value"]);
+        assert_eq!(db.response_resp(1), RespValue::Error("InvalidKey".into()));
+        db.do_cmd_ks(1, 1, &[b"SET", &key[KEYSPACE_PREFIX_LEN..], b"value"]);
+        assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+    }
 
-        db1.do_cmd(1, &[b"GETSET", b"test", b"value1", b"", All]);
-        assert_eq!(db1.response_values(1).0, &[b"value1"]);
+    #[test]
+    fn test_durable_write() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db", true);
 
-        for &db in &[&db1, &db2] {
-            db.do_cmd(1, &[b"GET", b"test", One]);
-            assert_eq!(db.response_values(1).0, [b"value1"]);
-        }
+        db.do_cmd(1, &[b"SET", b"test", b"value1", b"", b"Q!"]);
+        assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+        db.do_cmd(1, &[b"GET", b"test", b"Q"]);
+        assert_eq!(db.response_values(1).0, [b"value1"]);
+        db.do_cmd(1, &[b"SET", b"test", b"value1", b"", b"!"]);
+        assert_eq!(
+            db.response_resp(1),
+            RespValue::Error("InvalidConsistencyValue".into())
+        );
+        // reads can't be durable
+        db.do_cmd(1, &[b"GET", b"test", b"Q!"]);
+        assert_eq!(
+            db.response_resp(1),
+            RespValue::Error("InvalidConsistencyValue".into())
+        );
     }
 
-    const TEST_JOIN_SIZE: u64 = 100;
+    #[test]
+    fn test_client_compression() {
+        use std::io::{Read, Write};
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db", true);
+        let compressed_context = || {
+            let mut context = Context::new(1);
+            context.compression = true;
+            context
+        };
+
+        db.do_cmd(1, &[b"HELLO", b"COMPRESS", b"UNKNOWN"]);
+        assert_eq!(
+            db.response_resp(1),
+            RespValue::Array(vec![RespValue::Data("COMPRESS".into())])
+        );
+
+        let big_value = vec![b'a'; 10_000];
+        let mut writer = ::snap::Writer::new(vec![1u8]);
+        writer.write_all(&big_value).unwrap();
+        let compressed = writer.into_inner().unwrap();
+        db.do_cmd_context(compressed_context(), &[b"SET", b"big", &compressed]);
+        assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+        db.do_cmd_context(compressed_context(), &[b"SET", b"small", b"\x00value"]);
+        assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+        // plain values are taken as they are, whatever they start with
+        let snappy_like = &compressed[1..30];
+        let mut plain = vec![0u8];
+        plain.extend_from_slice(snappy_like);
+        db.do_cmd_context(compressed_context(), &[b"SET", b"plain", &plain]);
+        assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+        db.do_cmd(1, &[b"GET", b"plain"]);
+        assert_eq!(db.response_values(1).0, [snappy_like]);
+        // the format byte is required
+        db.do_cmd_context(compressed_context(), &[b"SET", b"empty", b""]);
+        assert_eq!(db.response_resp(1), RespValue::Error("InvalidValue".into()));
+
+        // connections without compression see plain values
+        db.do_cmd(1, &[b"GET", b"big"]);
+        assert_eq!(db.response_values(1).0, [big_value.clone()]);
+
+        db.do_cmd_context(compressed_context(), &[b"GET", b"big"]);
+        let values = db.response_values(1).0;
+        assert!(values[0].len() < big_value.len());
+        assert_eq!(values[0][0], 1);
+        let mut decompressed = Vec::new();
+        ::snap::Reader::new(&values[0][1..])
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, big_value);
+
+        // small values are never compressed
+        db.do_cmd_context(compressed_context(), &[b"GET", b"small"]);
+        assert_eq!(db.response_values(1).0, [b"\x00value"]);
+    }
 
     #[test]
-    fn test_bootstrap() {
+    fn test_key_history() {
         let _ = fs::remove_dir_all("t/");
         let _ = env_logger::try_init();
-        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
-        for i in 0..TEST_JOIN_SIZE {
-            db1.do_cmd(
-                i,
-                &[
-                    b"GETSET",
-                    i.to_string().as_bytes(),
-                    i.to_string().as_bytes(),
-                    b"",
-                    One,
-                ],
+        let db = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db", true);
+        let history_context = || {
+            let mut context = Context::new(1);
+            context.key_history = true;
+            context
+        };
+        let status = |resp: RespValue| match resp {
+            RespValue::Array(mut a) => match a.remove(0) {
+                RespValue::Status(s) => s,
+                r => panic!("Unexpected {:?}", r),
+            },
+            r => panic!("Unexpected {:?}", r),
+        };
+
+        db.do_cmd(1, &[b"HELLO", b"HISTORY"]);
+        assert_eq!(
+            db.response_resp(1),
+            RespValue::Array(vec![RespValue::Data("HISTORY".into())])
+        );
+
+        db.do_cmd_context(history_context(), &[b"GET", b"key"]);
+        assert_eq!(status(db.response_resp(1)), "NOTFOUND");
+
+        db.do_cmd(1, &[b"SET", b"key", b"value"]);
+        assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+        db.do_cmd_context(history_context(), &[b"GET", b"key"]);
+        assert_eq!(db.response_values(1).0, [b"value"]);
+
+        db.do_cmd(1, &[b"GET", b"key"]);
+        let vv = db.response_values(1).1;
+        db.do_cmd(1, &[b"DEL", b"key", &encode_vv(&vv)]);
+        assert_eq!(db.response_resp(1), RespValue::Int(1));
+        db.do_cmd_context(history_context(), &[b"GET", b"key"]);
+        assert_eq!(status(db.response_resp(1)), "DELETED");
+
+        // without the capability both look the same
+        db.do_cmd(1, &[b"GET", b"key"]);
+        assert!(db.response_values(1).0.is_empty());
+    }
+
+    #[test]
+    fn test_oversize_values() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let at_limit = vec![b'a'; config::MAX_VALUE_LEN];
+        let over_limit = vec![b'a'; config::MAX_VALUE_LEN + 1];
+        {
+            let db = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db", true);
+            db.do_cmd(1, &[b"SET", b"a", &at_limit]);
+            assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+            db.do_cmd(1, &[b"SET", b"b", &over_limit]);
+            assert_eq!(
+                db.response_resp(1),
+                RespValue::Error(format!("ValueTooLarge({})", config::MAX_VALUE_LEN).into())
             );
-            db1.response_values(i);
+            db.do_cmd(1, &[b"GET", b"b"]);
+            assert!(db.response_values(1).0.is_empty());
         }
 
-        // boostrap node 2 from 1
+        let _ = fs::remove_dir_all("t/");
+        let db = TestDatabase::new_with(
+            "127.0.0.1:9000".parse().unwrap(),
+            "t/db",
+            true,
+            |config| config.oversize_value_policy = config::OversizeValuePolicy::Warn,
+        );
+        db.do_cmd(1, &[b"SET", b"b", &over_limit]);
+        assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+        db.do_cmd(1, &[b"GET", b"b"]);
+        assert_eq!(db.response_values(1).0, [over_limit]);
+
+        // a node rejecting them skips the oversize key, the rest of the vnode still syncs
+        db.do_cmd(1, &[b"SET", b"{b}a", &at_limit]);
+        assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
         let db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
-        warn!("will check data before balancing");
-        for i in 0..TEST_JOIN_SIZE {
-            for &db in &[&db1, &db2] {
-                db.do_cmd(i, &[b"GET", i.to_string().as_bytes(), One]);
-                assert_eq!(db.response_values(i).0, [i.to_string().as_bytes()]);
+        db.dht.rebalance().unwrap();
+        db.wait_syncs();
+        db2.wait_syncs();
+        let vnode = db2.key_vnode(b"b") as usize;
+        let vnodes = db2.vnodes.read().unwrap();
+        let vnode = vnodes[vnode].lock().unwrap();
+        assert_eq!(vnode.status(), VNodeStatus::Ready);
+        assert!(vnode._is_stored(b"{b}a"));
+        assert!(!vnode._is_stored(b"b"));
+    }
+
+    #[test]
+    fn test_value_version_max() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db = TestDatabase::new_with(
+            "127.0.0.1:9000".parse().unwrap(),
+            "t/db",
+            true,
+            |config| config.value_version_max = 2,
+        );
+
+        for value in &[b"value1", b"value2", b"value3"] {
+            db.do_cmd(1, &[b"GETSET", b"test", *value, b"", One]);
+            db.response_values(1);
+        }
+        // only the newest siblings are kept
+        db.do_cmd(1, &[b"GET", b"test", One]);
+        let (mut values, vv) = db.response_values(1);
+        values.sort();
+        assert_eq!(values, [b"value2", b"value3"]);
+
+        // the context still covers the discarded version
+        db.do_cmd(1, &[b"SET", b"test", b"value4", &encode_vv(&vv), One]);
+        db.response_resp(1);
+        db.do_cmd(1, &[b"GET", b"test", One]);
+        assert_eq!(db.response_values(1).0, [b"value4"]);
+    }
+
+    #[test]
+    fn test_stale_reads() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db = TestDatabase::new_with(
+            "127.0.0.1:9000".parse().unwrap(),
+            "t/db",
+            true,
+            |config| config.bootstrap_stale_reads = true,
+        );
+        let stale_context = || {
+            let mut context = Context::new(1);
+            context.stale_reads = true;
+            context
+        };
+
+        db.do_cmd(1, &[b"HELLO", b"STALE"]);
+        assert_eq!(
+            db.response_resp(1),
+            RespValue::Array(vec![RespValue::Data("STALE".into())])
+        );
+
+        let vnode = db.dht.key_vnode(b"test");
+        db.vnodes.read().unwrap()[vnode as usize]
+            .lock()
+            .unwrap()
+            ._set_status(&db, VNodeStatus::Bootstrap);
+        db.do_cmd_context(stale_context(), &[b"GET", b"test", One]);
+        match db.response_resp(1) {
+            RespValue::Array(ref a) if a.len() == 2 => {
+                assert_eq!(a[0], RespValue::Status("STALE".into()));
+                assert_eq!(decode_values(a[1].clone()).0.len(), 0);
             }
+            r => panic!("unexpected response {:?}", r),
         }
+    }
 
-        db2.dht.rebalance().unwrap();
+    #[test]
+    fn test_wait() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db", true);
 
-        warn!("will check data during balancing");
-        for i in 0..TEST_JOIN_SIZE {
-            for &db in &[&db1, &db2] {
-                db.do_cmd(i, &[b"GET", i.to_string().as_bytes(), One]);
-                assert_eq!(db.response_values(i).0, [i.to_string().as_bytes()]);
+        db.do_cmd(1, &[b"GETSET", b"test", b"value1", b""]);
+        let (_, vv) = db.response_values(1);
+
+        db.do_cmd(1, &[b"WAIT", b"test", &encode_vv(&vv), b"1", b"1000"]);
+        assert_eq!(db.response_resp(1), RespValue::Int(1));
+
+        // a version that doesn't exist yet is never confirmed
+        let mut future_vv = VersionVector::new();
+        for (id, version) in vv.iter() {
+            future_vv.add(id, version + 1);
+        }
+        db.do_cmd(1, &[b"WAIT", b"test", &encode_vv(&future_vv), b"1", b"0"]);
+        assert_eq!(db.response_resp(1), RespValue::Int(0));
+
+        db.do_cmd(1, &[b"WAIT", b"test", &encode_vv(&vv), b"1", b"18446744073709551615"]);
+        assert_eq!(db.response_resp(1), RespValue::Error("InvalidIntValue".into()));
+    }
+
+    #[test]
+    fn test_scan_restore() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        for i in 0..50 {
+            let key = format!("key{}", i);
+            db1.do_cmd(1, &[b"SET", key.as_bytes(), b"value"]);
+            assert_eq!(db1.response_resp(1), RespValue::Status("OK".into()));
+        }
+
+        let mut dump = Vec::new();
+        let mut cursor: Vec<u8> = Vec::new();
+        loop {
+            db1.do_cmd(1, &[b"SCAN", &cursor, b"7"]);
+            let mut response = match db1.response_resp(1) {
+                RespValue::Array(a) => a,
+                r => panic!("unexpected response {:?}", r),
+            };
+            let entries = response.pop().unwrap();
+            if let RespValue::Array(entries) = entries {
+                for entry in entries {
+                    if let RespValue::Array(entry) = entry {
+                        match (&entry[0], &entry[2]) {
+                            (&RespValue::Data(ref k), &RespValue::Data(ref d)) => {
+                                dump.push((k.clone(), d.clone()))
+                            }
+                            _ => panic!("unexpected entry {:?}", entry),
+                        }
+                    }
+                }
+            }
+            cursor = match response.pop().unwrap() {
+                RespValue::Data(c) => c.to_vec(),
+                r => panic!("unexpected cursor {:?}", r),
+            };
+            if cursor.is_empty() {
+                break;
+            }
+        }
+        assert_eq!(dump.len(), 50);
+        drop(db1);
+
+        let db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", true);
+        for (key, dcc) in dump {
+            db2.do_cmd(1, &[b"RESTORE", &key, &dcc]);
+            assert_eq!(db2.response_resp(1), RespValue::Status("OK".into()));
+            db2.do_cmd(1, &[b"GET", &key, One]);
+            assert_eq!(db2.response_values(1).0, [b"value"]);
+        }
+    }
+
+    #[test]
+    fn test_scan_prefix() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        for i in 0..20 {
+            for prefix in &["a", "ab", "b"] {
+                let key = format!("{}{}", prefix, i);
+                db1.do_cmd(1, &[b"SET", key.as_bytes(), b"value"]);
+                assert_eq!(db1.response_resp(1), RespValue::Status("OK".into()));
+            }
+        }
+
+        let mut keys = Vec::new();
+        let mut cursor: Vec<u8> = Vec::new();
+        loop {
+            db1.do_cmd(1, &[b"SCAN", &cursor, b"3", b"ab"]);
+            let mut response = match db1.response_resp(1) {
+                RespValue::Array(a) => a,
+                r => panic!("unexpected response {:?}", r),
+            };
+            if let RespValue::Array(entries) = response.pop().unwrap() {
+                for entry in entries {
+                    match entry {
+                        RespValue::Array(ref entry) => match entry[0] {
+                            RespValue::Data(ref k) => keys.push(k.clone()),
+                            _ => panic!("unexpected entry {:?}", entry),
+                        },
+                        _ => panic!("unexpected entry {:?}", entry),
+                    }
+                }
+            }
+            cursor = match response.pop().unwrap() {
+                RespValue::Data(c) => c.to_vec(),
+                r => panic!("unexpected cursor {:?}", r),
+            };
+            if cursor.is_empty() {
+                break;
             }
         }
+        assert_eq!(keys.len(), 20);
+        assert!(keys.iter().all(|k| k.starts_with(b"ab")));
+
+        let long_prefix = vec![b'a'; config::MAX_KEY_LEN + 1];
+        db1.do_cmd(1, &[b"SCAN", b"", b"3", &long_prefix]);
+        assert_eq!(db1.response_resp(1), RespValue::Error("InvalidKey".into()));
+    }
+
+    #[test]
+    fn test_scan_invalid_cursor() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        let mut cursor = vec![0u8; 2 + config::MAX_KEY_LEN + KEYSPACE_PREFIX_LEN];
+        db1.do_cmd(1, &[b"SCAN", &cursor]);
+        assert!(match db1.response_resp(1) {
+            RespValue::Array(_) => true,
+            _ => false,
+        });
+        cursor.push(0);
+        db1.do_cmd(1, &[b"SCAN", &cursor]);
+        assert_eq!(db1.response_resp(1), RespValue::Error("InvalidCursor".into()));
+        db1.do_cmd(1, &[b"SCAN", &[0xFF, 0xFF]]);
+        assert_eq!(db1.response_resp(1), RespValue::Error("InvalidCursor".into()));
+    }
+
+    #[test]
+    fn test_two() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        let db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        db2.dht.rebalance().unwrap();
 
+        db1.wait_syncs();
         db2.wait_syncs();
 
-        warn!("will check data after balancing");
+        db1.do_cmd(1, &[b"GET", b"test", One]);
+        assert_eq!(db1.response_values(1).0.len(), 0);
+
+        db1.do_cmd(1, &[b"GETSET", b"test", b"value1", b"", All]);
+        assert_eq!(db1.response_values(1).0, &[b"value1"]);
+
+        for &db in &[&db1, &db2] {
+            db.do_cmd(1, &[b"GET", b"test", One]);
+            assert_eq!(db.response_values(1).0, [b"value1"]);
+        }
+    }
+
+    #[test]
+    fn test_split() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        let db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        db2.dht.rebalance().unwrap();
+        db1.wait_syncs();
+        db2.wait_syncs();
+
+        for i in 0..TEST_JOIN_SIZE {
+            let key = i.to_string();
+            db1.do_cmd(i, &[b"SET", key.as_bytes(), key.as_bytes(), All]);
+            assert_eq!(db1.response_resp(i), RespValue::Status("OK".into()));
+        }
+        let keys = db1.metrics().keys;
+        assert_eq!(keys, TEST_JOIN_SIZE);
+
+        // refused until db1 sees the bootstrap of db2 finished
+        loop {
+            db1.do_cmd(0, &[b"CLUSTER", b"SPLIT"]);
+            if db1.response_resp(0) == RespValue::Status("OK".into()) {
+                break;
+            }
+            sleep_ms(100);
+        }
+        while db1.partitions() != PARTITIONS * 2 || db2.partitions() != PARTITIONS * 2 {
+            sleep_ms(10);
+        }
+
+        for &db in &[&db1, &db2] {
+            let metrics = db.metrics();
+            assert_eq!(metrics.vnodes.len(), PARTITIONS * 2);
+            assert_eq!(metrics.keys, keys);
+            // about half of the keys moved to the new vnodes
+            let moved: u64 = metrics.vnodes[PARTITIONS..].iter().map(|m| m.keys).sum();
+            assert!(moved > 0 && moved < keys);
+        }
         for i in 0..TEST_JOIN_SIZE {
+            let key = i.to_string();
+            let vnode = db1.dht.key_vnode(key.as_bytes());
+            assert_eq!(db1.key_vnode(key.as_bytes()), vnode);
             for &db in &[&db1, &db2] {
-                db.do_cmd(i, &[b"GET", i.to_string().as_bytes(), One]);
-                assert_eq!(db.response_values(i).0, [i.to_string().as_bytes()]);
+                let vnodes = db.vnodes.read().unwrap();
+                assert!(vnodes[vnode as usize].lock().unwrap()._is_stored(key.as_bytes()));
             }
+            db2.do_cmd(i, &[b"GET", key.as_bytes(), All]);
+            assert_eq!(db2.response_values(i).0, [key.as_bytes()]);
         }
 
-        // remove first node
-        db2.dht.remove_node(db1.dht.node()).unwrap();
-        sleep_ms(100);
+        for i in 0..TEST_JOIN_SIZE {
+            let key = format!("new{}", i);
+            db2.do_cmd(i, &[b"SET", key.as_bytes(), b"value", All]);
+            assert_eq!(db2.response_resp(i), RespValue::Status("OK".into()));
+            db1.do_cmd(i, &[b"GET", key.as_bytes(), All]);
+            assert_eq!(db1.response_values(i).0, [b"value"]);
+        }
+    }
 
-        let db3 = TestDatabase::new("127.0.0.1:9002".parse().unwrap(), "t/db3", false);
+    #[test]
+    fn test_max_vnodes() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        let db2 = TestDatabase::new_with(
+            "127.0.0.1:9001".parse().unwrap(),
+            "t/db2",
+            false,
+            |config| config.max_vnodes = 10,
+        );
+        db2.dht.rebalance().unwrap();
+        db1.wait_syncs();
+        db2.wait_syncs();
 
-        db3.dht.rebalance().unwrap();
-        db3.wait_syncs();
+        let hosted_vnodes = |db: &TestDatabase| -> Vec<usize> {
+            db.vnodes
+                .read()
+                .unwrap()
+                .iter()
+                .enumerate()
+                .filter(|&(_, vn)| vn.lock().unwrap().status() != VNodeStatus::Absent)
+                .map(|(i, _)| i)
+                .collect()
+        };
+        // db2 hosts 10 vnodes and declined the others, the ring doesn't count on it for them
+        let hosted = hosted_vnodes(&db2);
+        assert_eq!(hosted.len(), 10);
+        assert_eq!(db2.dht.vnodes_for_node(db2.dht.node()).0.len(), 10);
 
         for i in 0..TEST_JOIN_SIZE {
-            for &db in &[&db2, &db3] {
-                db.do_cmd(i, &[b"GET", i.to_string().as_bytes(), One]);
-                assert_eq!(db.response_values(i).0, [i.to_string().as_bytes()]);
+            db1.do_cmd(i, &[b"SET", i.to_string().as_bytes(), b"value", b"", All]);
+            assert_eq!(db1.response_resp(i), RespValue::Status("OK".into()));
+        }
+        let clocks: Vec<_> = hosted
+            .iter()
+            .map(|&i| db2.vnodes.read().unwrap()[i].lock().unwrap()._clocks())
+            .collect();
+
+        // the refused vnodes stay refused after a restart
+        drop(db2);
+        let db2 = TestDatabase::new_with(
+            "127.0.0.1:9001".parse().unwrap(),
+            "t/db2",
+            false,
+            |config| config.max_vnodes = 10,
+        );
+        assert_eq!(hosted_vnodes(&db2), hosted);
+
+        // lowering the cap keeps the vnodes it already owns, with their data
+        drop(db2);
+        let db2 = TestDatabase::new_with(
+            "127.0.0.1:9001".parse().unwrap(),
+            "t/db2",
+            false,
+            |config| config.max_vnodes = 5,
+        );
+        assert_eq!(hosted_vnodes(&db2), hosted);
+        let vnodes = db2.vnodes.read().unwrap();
+        for (&i, clocks) in hosted.iter().zip(clocks) {
+            let vnode = vnodes[i].lock().unwrap();
+            assert_eq!(vnode.status(), VNodeStatus::Ready);
+            assert_eq!(vnode._clocks(), clocks);
+        }
+    }
+
+    #[test]
+    fn test_clock_probe() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        let db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        db2.dht.rebalance().unwrap();
+        db1.wait_syncs();
+        db2.wait_syncs();
+
+        db1.do_cmd(1, &[b"SET", b"test", b"value1", b"", All]);
+        assert_eq!(db1.response_resp(1), RespValue::Status("OK".into()));
+        let vnode = db1.dht.key_vnode(b"test");
+        let node2 = db2.dht.node();
+        let db2_clocks = db2.vnodes.read().unwrap()[vnode as usize]
+            .lock()
+            .unwrap()
+            ._clocks();
+        assert!(db2_clocks.iter().next().is_some());
+
+        db1.vnodes.read().unwrap()[vnode as usize]
+            .lock()
+            .unwrap()
+            .probe_clock(&db1, node2);
+        let probed = (0..1000)
+            .filter_map(|_| {
+                sleep_ms(1);
+                db1.vnodes.read().unwrap()[vnode as usize]
+                    .lock()
+                    .unwrap()
+                    .peer_clock(node2)
+                    .map(|&(ref clocks, _)| clocks.clone())
+            })
+            .next()
+            .unwrap();
+        assert_eq!(probed, db2_clocks);
+    }
+
+    #[test]
+    fn test_fabric_capture_replay() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        let db2 = TestDatabase::new_with(
+            "127.0.0.1:9001".parse().unwrap(),
+            "t/db2",
+            false,
+            |config| config.fabric_capture = true,
+        );
+        db2.dht.rebalance().unwrap();
+        db1.wait_syncs();
+        db2.wait_syncs();
+
+        db1.do_cmd(1, &[b"SET", b"test", b"value1", b"", All]);
+        assert_eq!(db1.response_resp(1), RespValue::Status("OK".into()));
+
+        let captured = read_capture("t/db2/fabric_capture").unwrap();
+        assert!(captured.iter().any(|&(from, ref msg)| {
+            from == db1.dht.node() && match *msg {
+                FabricMsg::RemoteSet(_) => true,
+                _ => false,
             }
+        }));
+
+        // replaying the writes into a fresh node reproduces them,
+        // the ring changes are left out as it's a different cluster
+        let db3 = TestDatabase::new("127.0.0.1:9002".parse().unwrap(), "t/db3", true);
+        db3.replay_fabric_msgs(captured.into_iter().filter(|&(_, ref msg)| match *msg {
+            FabricMsg::RemoteSet(_) => true,
+            _ => false,
+        }));
+        db3.do_cmd(1, &[b"GET", b"test"]);
+        assert_eq!(db3.response_values(1).0, [b"value1"]);
+    }
+
+    #[test]
+    fn test_stale_sync_start() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        let db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        db2.dht.rebalance().unwrap();
+        db1.wait_syncs();
+        db2.wait_syncs();
+
+        let start_msg = || MsgSyncStart {
+            vnode: 0,
+            cookie: Cookie::new(1, 2),
+            clocks_in_peer: Default::default(),
+            target: Some(db1.dht.node()),
+            aae: false,
+            slice: (0, 1),
+        };
+        let node2 = db2.dht.node();
+        db1.vnodes.read().unwrap()[0]
+            .lock()
+            .unwrap()
+            .handler_sync_start(&db1, node2, start_msg());
+        // db2 doesn't know the cookie, so the sync is terminated
+        db1.wait_syncs();
+
+        // a retransmitted start is ignored
+        db1.vnodes.read().unwrap()[0]
+            .lock()
+            .unwrap()
+            .handler_sync_start(&db1, node2, start_msg());
+        assert_eq!(db1.syncs_inflight(), 0);
+    }
+
+    #[test]
+    fn test_sync_stalled_sender() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new_with(
+            "127.0.0.1:9000".parse().unwrap(),
+            "t/db1",
+            true,
+            |config| {
+                config.worker_timer = 50;
+                config.sync_msg_timeout = 100;
+            },
+        );
+        let db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        db2.dht.rebalance().unwrap();
+        db1.wait_syncs();
+        db2.wait_syncs();
+
+        // db2 has no outgoing slots left, so it queues the starts and never sends anything
+        let mut slots = 0;
+        while db2.signal_sync_start(SyncDirection::Outgoing) {
+            slots += 1;
+        }
+        let node2 = db2.dht.node();
+        let started = time::Instant::now();
+        db1.vnodes.read().unwrap()[0]
+            .lock()
+            .unwrap()
+            ._start_sync_receiver(&db1, node2);
+        db1.wait_syncs();
+        // gave up on the peer after a few starts, well before the sync timeout
+        assert!(started.elapsed() < time::Duration::from_secs(5));
+        assert_eq!(db2.metrics().queued_syncs, 1);
+        assert_eq!(
+            db1.vnodes.read().unwrap()[0].lock().unwrap()._stalled_peer(),
+            Some(node2)
+        );
+
+        for _ in 0..slots {
+            db2.signal_sync_end(SyncDirection::Outgoing);
+        }
+    }
+
+    const TEST_JOIN_SIZE: u64 = 100;
+
+    #[test]
+    fn test_sync_missing_seqs() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db", true);
+        // a fake peer, the messages sent to it are dropped
+        let peer = 42;
+        let mut fin_clocks = BitmappedVersionVector::new();
+        for version in 1..5 {
+            fin_clocks.add(peer, version);
+        }
+        // a sync with the given seqs, each with a key at version seq + 1
+        let sync_msgs = |cookie: Cookie, seqs: &[u64]| {
+            let mut msgs: Vec<_> = seqs
+                .iter()
+                .map(|&seq| {
+                    let mut value = Cube::new(&BitmappedVersionVector::new())
+                        .into_value()
+                        .unwrap();
+                    value.set(peer, seq + 1, Some("value".into()), &VersionVector::new());
+                    let msg = MsgSyncSend {
+                        vnode: 0,
+                        cookie: cookie,
+                        seq: seq,
+                        key: format!("key{}", seq).into(),
+                        value: Cube::Value(value),
+                        estimated_keys: None,
+                    };
+                    (peer, FabricMsg::SyncSend(msg))
+                })
+                .collect();
+            let fin = MsgSyncFin {
+                vnode: 0,
+                cookie: cookie,
+                result: Ok(fin_clocks.clone()),
+            };
+            msgs.push((peer, FabricMsg::SyncFin(fin)));
+            msgs
+        };
+        let start_sync = || {
+            db.vnodes.read().unwrap()[0]
+                .lock()
+                .unwrap()
+                ._start_sync_receiver(&db, peer)
+        };
+        let clocks = || db.vnodes.read().unwrap()[0].lock().unwrap()._clocks();
+
+        // seq 1 was lost, so the sender clocks aren't merged
+        db.replay_fabric_msgs(sync_msgs(start_sync(), &[0, 2]));
+        assert_eq!(db.syncs_inflight(), 0);
+        assert!(clocks().contains(peer, 1) && clocks().contains(peer, 3));
+        assert!(!clocks().contains(peer, 2) && !clocks().contains(peer, 4));
+
+        db.replay_fabric_msgs(sync_msgs(start_sync(), &[0, 1, 2]));
+        assert_eq!(db.syncs_inflight(), 0);
+        assert!(clocks().contains_all(peer, 4));
+    }
+
+    #[test]
+    fn test_sync_cancel() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db", true);
+        // fake peers, the messages sent to them are dropped
+        let (peer, aae_peer, sender_peer) = (42, 43, 44);
+        let send = |cookie: Cookie, seq: u64| {
+            let mut value = Cube::new(&BitmappedVersionVector::new())
+                .into_value()
+                .unwrap();
+            value.set(peer, seq + 1, Some("value".into()), &VersionVector::new());
+            let msg = MsgSyncSend {
+                vnode: 0,
+                cookie: cookie,
+                seq: seq,
+                key: format!("key{}", seq).into(),
+                value: Cube::Value(value),
+                estimated_keys: None,
+            };
+            (peer, FabricMsg::SyncSend(msg))
+        };
+        let fin = |from: NodeId, cookie: Cookie| {
+            let mut clocks = BitmappedVersionVector::new();
+            for version in 1..5 {
+                clocks.add(peer, version);
+            }
+            let msg = MsgSyncFin {
+                vnode: 0,
+                cookie: cookie,
+                result: Ok(clocks),
+            };
+            (from, FabricMsg::SyncFin(msg))
+        };
+        let ack = |from: NodeId, cookie: Cookie, seq: u64| {
+            let msg = MsgSyncAck {
+                vnode: 0,
+                cookie: cookie,
+                seq: seq,
+            };
+            (from, FabricMsg::SyncAck(msg))
+        };
+        let syncs = || {
+            let metrics = db.metrics();
+            (metrics.incoming_syncs, metrics.outgoing_syncs)
+        };
+        let sync_nodes = || db.vnodes.read().unwrap()[0].lock().unwrap()._sync_nodes();
+
+        let (cookie, aae_cookie) = {
+            let vnodes = db.vnodes.read().unwrap();
+            let mut vnode = vnodes[0].lock().unwrap();
+            (
+                vnode._start_sync_receiver(&db, peer),
+                vnode._start_aae_receiver(&db, aae_peer),
+            )
+        };
+        let sender_cookie = Cookie::new(1, 2);
+        db.vnodes.read().unwrap()[0].lock().unwrap().handler_sync_start(
+            &db,
+            sender_peer,
+            MsgSyncStart {
+                vnode: 0,
+                cookie: sender_cookie,
+                clocks_in_peer: Default::default(),
+                target: Some(db.dht.node()),
+                aae: false,
+                slice: (0, 1),
+            },
+        );
+        db.replay_fabric_msgs(vec![send(cookie, 0)]);
+        assert_eq!(syncs(), (2, 1));
+        assert_eq!(sync_nodes(), 2);
+
+        // the vnode moves away while the msgs of all syncs are in flight
+        db.vnodes.read().unwrap()[0]
+            .lock()
+            .unwrap()
+            .handler_dht_change(&db, VNodeStatus::Absent);
+        assert_eq!(syncs(), (0, 1));
+        assert_eq!(sync_nodes(), 0);
+        assert_eq!(
+            db.vnodes.read().unwrap()[0].lock().unwrap().status(),
+            VNodeStatus::Zombie
+        );
+
+        // late msgs of the canceled receivers don't resurrect them
+        db.replay_fabric_msgs(vec![
+            send(cookie, 1),
+            fin(peer, cookie),
+            ack(aae_peer, aae_cookie, 0),
+            fin(aae_peer, aae_cookie),
+        ]);
+        assert_eq!(syncs(), (0, 1));
+        assert_eq!(sync_nodes(), 0);
+        let clocks = db.vnodes.read().unwrap()[0].lock().unwrap()._clocks();
+        assert!(clocks.contains(peer, 1));
+        assert!(!clocks.contains(peer, 2) && !clocks.contains(peer, 4));
+
+        // the sender keeps going while zombie and finishes normally
+        db.replay_fabric_msgs(vec![ack(sender_peer, sender_cookie, 0)]);
+        assert_eq!(syncs(), (0, 1));
+        db.replay_fabric_msgs(vec![fin(sender_peer, sender_cookie)]);
+        assert_eq!(syncs(), (0, 0));
+        assert_eq!(db.syncs_inflight(), 0);
+    }
+
+    #[test]
+    fn test_sync_progress() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db", true);
+        for i in 0..TEST_JOIN_SIZE {
+            db.do_cmd(i, &[b"SET", i.to_string().as_bytes(), i.to_string().as_bytes(), b"", One]);
+            assert_eq!(db.response_resp(i), RespValue::Status("OK".into()));
+        }
+        // fake peers, the messages sent to them are dropped
+        let (peer, bootstrap_peer) = (42, 43);
+        let cookie = db.vnodes.read().unwrap()[0]
+            .lock()
+            .unwrap()
+            ._start_sync_receiver(&db, peer);
+        let mut value = Cube::new(&BitmappedVersionVector::new())
+            .into_value()
+            .unwrap();
+        value.set(peer, 1, Some("value".into()), &VersionVector::new());
+        let msg = MsgSyncSend {
+            vnode: 0,
+            cookie: cookie,
+            seq: 0,
+            key: "key".into(),
+            value: Cube::Value(value),
+            estimated_keys: None,
+        };
+        db.replay_fabric_msgs(vec![(peer, FabricMsg::SyncSend(msg))]);
+        let bootstrap_cookie = Cookie::new(1, 2);
+        db.vnodes.read().unwrap()[0].lock().unwrap().handler_sync_start(
+            &db,
+            bootstrap_peer,
+            MsgSyncStart {
+                vnode: 0,
+                cookie: bootstrap_cookie,
+                clocks_in_peer: Default::default(),
+                target: None,
+                aae: false,
+                slice: (0, 1),
+            },
+        );
+
+        let metrics = db.metrics().vnodes.remove(0);
+        assert_eq!(metrics.sync_progress.len(), 2);
+        for progress in &metrics.sync_progress {
+            if progress.cookie == cookie {
+                assert_eq!((progress.kind, progress.peer), ("sync", peer));
+                assert_eq!(progress.direction, SyncDirection::Incomming);
+                assert_eq!(progress.keys, 1);
+                assert!(progress.bytes > 0);
+                assert_eq!((progress.estimated_keys, progress.eta_ms), (None, None));
+            } else {
+                assert_eq!(progress.cookie, bootstrap_cookie);
+                assert_eq!((progress.kind, progress.peer), ("bootstrap", bootstrap_peer));
+                assert_eq!(progress.direction, SyncDirection::Outgoing);
+                assert_eq!(progress.estimated_keys, Some(metrics.keys));
+                // nothing could be sent to the fake peer
+                assert_eq!((progress.keys, progress.eta_ms), (0, None));
+            }
+        }
+    }
+
+    #[test]
+    fn test_bootstrap() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        for i in 0..TEST_JOIN_SIZE {
+            db1.do_cmd(
+                i,
+                &[
+                    b"GETSET",
+                    i.to_string().as_bytes(),
+                    i.to_string().as_bytes(),
+                    b"",
+                    One,
+                ],
+            );
+            db1.response_values(i);
+        }
+
+        // boostrap node 2 from 1
+        let db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        warn!("will check data before balancing");
+        for i in 0..TEST_JOIN_SIZE {
+            for &db in &[&db1, &db2] {
+                db.do_cmd(i, &[b"GET", i.to_string().as_bytes(), One]);
+                assert_eq!(db.response_values(i).0, [i.to_string().as_bytes()]);
+            }
+        }
+
+        db2.dht.rebalance().unwrap();
+
+        warn!("will check data during balancing");
+        for i in 0..TEST_JOIN_SIZE {
+            for &db in &[&db1, &db2] {
+                db.do_cmd(i, &[b"GET", i.to_string().as_bytes(), One]);
+                assert_eq!(db.response_values(i).0, [i.to_string().as_bytes()]);
+            }
+        }
+
+        db2.wait_syncs();
+
+        warn!("will check data after balancing");
+        for i in 0..TEST_JOIN_SIZE {
+            for &db in &[&db1, &db2] {
+                db.do_cmd(i, &[b"GET", i.to_string().as_bytes(), One]);
+                assert_eq!(db.response_values(i).0, [i.to_string().as_bytes()]);
+            }
+        }
+
+        // remove first node
+        db2.dht.remove_node(db1.dht.node()).unwrap();
+        sleep_ms(100);
+
+        let db3 = TestDatabase::new("127.0.0.1:9002".parse().unwrap(), "t/db3", false);
+
+        db3.dht.rebalance().unwrap();
+        db3.wait_syncs();
+
+        for i in 0..TEST_JOIN_SIZE {
+            for &db in &[&db2, &db3] {
+                db.do_cmd(i, &[b"GET", i.to_string().as_bytes(), One]);
+                assert_eq!(db.response_values(i).0, [i.to_string().as_bytes()]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_metrics() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db", true);
+        for i in 0..TEST_JOIN_SIZE {
+            db.do_cmd(i, &[b"SET", i.to_string().as_bytes(), b"value", b"", One]);
+            assert_eq!(db.response_resp(i), RespValue::Status("OK".into()));
+        }
+
+        let metrics = db.metrics();
+        assert_eq!(metrics.node, db.dht.node());
+        assert_eq!(metrics.inflight_requests, 0);
+        assert_eq!(metrics.vnodes.len(), PARTITIONS);
+        assert_eq!(metrics.keys, TEST_JOIN_SIZE);
+        assert!(metrics.value_bytes > 0);
+        let mut versions = 0;
+        for (i, vn) in metrics.vnodes.iter().enumerate() {
+            assert_eq!(vn.vnode as usize, i);
+            assert_eq!(vn.status, VNodeStatus::Ready);
+            assert_eq!((vn.syncs_incoming, vn.syncs_outgoing), (0, 0));
+            assert_eq!(vn.next_request_timeout, None);
+            assert_eq!(vn.clocks.len(), vn.log_sizes.len());
+            versions += vn.clocks.iter().map(|&(_, v)| v).sum::<u64>();
+            assert_eq!(
+                vn.clocks.iter().map(|&(_, v)| v).sum::<u64>(),
+                vn.log_sizes.iter().map(|&(_, v)| v).sum::<u64>()
+            );
+        }
+        assert_eq!(versions, TEST_JOIN_SIZE);
+
+        let dump = serde_yaml::to_string(&metrics).unwrap();
+        assert!(dump.contains("Ready"));
+
+        // replacing a value doesn't add a key
+        db.do_cmd(0, &[b"GET", b"0", One]);
+        let vv = db.response_values(0).1;
+        db.do_cmd(0, &[b"SET", b"0", b"value", &encode_vv(&vv), One]);
+        assert_eq!(db.response_resp(0), RespValue::Status("OK".into()));
+        assert_eq!(db.metrics().keys, TEST_JOIN_SIZE);
+    }
+
+    #[test]
+    fn test_topology() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        let db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        db1.dht.rebalance().unwrap();
+        db1.wait_syncs();
+        db2.wait_syncs();
+
+        let topology = db1.topology();
+        assert_eq!(topology.partitions, PARTITIONS);
+        assert_eq!(topology.replication_factor, 3);
+        assert_eq!(topology.hash_algorithm, db1.dht.hash_algorithm());
+        let nodes: Vec<_> = topology.nodes.iter().map(|&(n, _)| n).collect();
+        let mut expected = vec![db1.dht.node(), db2.dht.node()];
+        expected.sort();
+        assert_eq!(nodes, expected);
+        assert!(topology.nodes.iter().all(|&(_, addr)| addr == db1.config.listen_addr));
+        assert_eq!(topology.vnodes.len(), PARTITIONS);
+        for (i, vn) in topology.vnodes.iter().enumerate() {
+            let mut owners = vn.owners.clone();
+            owners.extend(&vn.pending);
+            owners.extend(&vn.retiring);
+            owners.sort();
+            let mut nodes = db1.dht.nodes_for_vnode(i as _, true, true);
+            nodes.sort();
+            assert_eq!(owners, nodes);
+        }
+        assert_eq!(db1.vnode_for_key(b"key"), db1.dht.key_vnode(b"key"));
+
+        let dump = serde_yaml::to_string(&topology).unwrap();
+        assert!(dump.contains("replication_factor"));
+    }
+
+    #[test]
+    fn test_bootstrap_memory() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        fn memory(config: &mut config::Config) {
+            config.storage_backend = StorageBackend::Memory;
+        }
+        let db1 = TestDatabase::new_with("127.0.0.1:9000".parse().unwrap(), "t/db1", true, memory);
+        for i in 0..TEST_JOIN_SIZE {
+            db1.do_cmd(i, &[b"SET", i.to_string().as_bytes(), i.to_string().as_bytes(), b"", One]);
+            assert_eq!(db1.response_resp(i), RespValue::Status("OK".into()));
+        }
+
+        let db2 = TestDatabase::new_with("127.0.0.1:9001".parse().unwrap(), "t/db2", false, memory);
+        db2.dht.rebalance().unwrap();
+        db2.wait_syncs();
+
+        for i in 0..TEST_JOIN_SIZE {
+            for &db in &[&db1, &db2] {
+                db.do_cmd(i, &[b"GET", i.to_string().as_bytes(), One]);
+                assert_eq!(db.response_values(i).0, [i.to_string().as_bytes()]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_bootstrap_batches() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        // a few keys per msg, the last one of each goes over
+        fn batches(config: &mut config::Config) {
+            config.sync_msg_batch_max = 200;
+        }
+        let db1 = TestDatabase::new_with("127.0.0.1:9000".parse().unwrap(), "t/db1", true, batches);
+        for i in 0..TEST_JOIN_SIZE {
+            db1.do_cmd(i, &[b"SET", i.to_string().as_bytes(), i.to_string().as_bytes(), b"", One]);
+            assert_eq!(db1.response_resp(i), RespValue::Status("OK".into()));
+        }
+
+        let db2 =
+            TestDatabase::new_with("127.0.0.1:9001".parse().unwrap(), "t/db2", false, batches);
+        db2.dht.rebalance().unwrap();
+        db2.wait_syncs();
+        assert_eq!(db2.metrics().keys, TEST_JOIN_SIZE);
+
+        for i in 0..TEST_JOIN_SIZE {
+            for &db in &[&db1, &db2] {
+                db.do_cmd(i, &[b"GET", i.to_string().as_bytes(), One]);
+                assert_eq!(db.response_values(i).0, [i.to_string().as_bytes()]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_bootstrap_failures() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db", true);
+        let vnodes = db.vnodes.read().unwrap();
+        let mut vn = vnodes[0].lock().unwrap();
+        vn._set_status(&db, VNodeStatus::Bootstrap);
+
+        let mut last_retry_at = None;
+        for i in 1..db.config.bootstrap_failures_max + 2 {
+            vn._handle_bootstrap_result(&db, SyncResult::Error);
+            let (failures, failed, retry_at) = vn._bootstrap_failures();
+            assert_eq!(failures, i);
+            assert_eq!(failed, i >= db.config.bootstrap_failures_max);
+            assert!(retry_at >= last_retry_at && retry_at.is_some());
+            last_retry_at = retry_at;
+            // pending retries are started by the tick
+            assert_eq!(vn.syncs_inflight(), (1, 0));
+        }
+        assert!(vn.bootstrap_failed());
+
+        // leaving bootstrap resets the failures
+        vn._set_status(&db, VNodeStatus::Absent);
+        assert_eq!(vn._bootstrap_failures(), (0, false, None));
+    }
+
+    #[test]
+    fn test_bootstrap_resume() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        for i in 0..TEST_JOIN_SIZE {
+            db1.do_cmd(i, &[b"SET", i.to_string().as_bytes(), i.to_string().as_bytes(), b"", One]);
+            assert_eq!(db1.response_resp(i), RespValue::Status("OK".into()));
+        }
+
+        // restart node 2 while it's bootstrapping from 1
+        let mut db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        db2.dht.rebalance().unwrap();
+        sleep_ms(100);
+        let db2_node = db2.dht.node();
+        drop(db2);
+        while db1.fabric.connections().contains(&db2_node) {
+            sleep_ms(10);
+        }
+        db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        db2.wait_fabric();
+        db2.wait_syncs();
+
+        for i in 0..TEST_JOIN_SIZE {
+            db2.do_cmd(i, &[b"GET", i.to_string().as_bytes(), One]);
+            assert_eq!(db2.response_values(i).0, [i.to_string().as_bytes()]);
+        }
+    }
+
+    #[test]
+    fn test_bootstrap_queued_syncs() {
+        // vnodes bootstrap in turns as node 1 can only serve a few at a time
+        fn few_outgoing(config: &mut config::Config) {
+            config.sync_outgoing_max = 4;
+            config.worker_timer = 100;
+        }
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 =
+            TestDatabase::new_with("127.0.0.1:9000".parse().unwrap(), "t/db1", true, few_outgoing);
+        for i in 0..TEST_JOIN_SIZE {
+            db1.do_cmd(i, &[b"SET", i.to_string().as_bytes(), i.to_string().as_bytes(), b"", One]);
+            assert_eq!(db1.response_resp(i), RespValue::Status("OK".into()));
+        }
+
+        let db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        db2.dht.rebalance().unwrap();
+        db2.wait_syncs();
+
+        for i in 0..TEST_JOIN_SIZE {
+            db2.do_cmd(i, &[b"GET", i.to_string().as_bytes(), One]);
+            assert_eq!(db2.response_values(i).0, [i.to_string().as_bytes()]);
+        }
+        let metrics = db1.metrics();
+        assert_eq!(metrics.queued_syncs, 0);
+        assert_eq!(metrics.outgoing_syncs, 0);
+        for vn in db2.metrics().vnodes {
+            assert_eq!(vn.status, VNodeStatus::Ready);
+        }
+    }
+
+    #[test]
+    fn test_bootstrap_multi_source() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        let db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        db2.dht.rebalance().unwrap();
+        db2.wait_syncs();
+        for i in 0..TEST_JOIN_SIZE {
+            db1.do_cmd(i, &[b"SET", i.to_string().as_bytes(), i.to_string().as_bytes(), b"", One]);
+            assert_eq!(db1.response_resp(i), RespValue::Status("OK".into()));
+        }
+
+        // node 3 streams a slice of each vnode from each of the other two
+        let db3 = TestDatabase::new("127.0.0.1:9002".parse().unwrap(), "t/db3", false);
+        db3.dht.rebalance().unwrap();
+        db3.wait_syncs();
+
+        for i in 0..TEST_JOIN_SIZE {
+            db3.do_cmd(i, &[b"GET", i.to_string().as_bytes(), One]);
+            assert_eq!(db3.response_values(i).0, [i.to_string().as_bytes()]);
+        }
+        for vn in db3.metrics().vnodes {
+            assert_eq!(vn.status, VNodeStatus::Ready);
+        }
+    }
+
+    #[test]
+    fn test_bootstrap_2() {
+        // similar to the previous, but values in n1 are rewritten + sibling
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        for i in 0..TEST_JOIN_SIZE {
+            db1.do_cmd(i, &[b"GETSET", i.to_string().as_bytes(), b"", b"", One]);
+            let (_, vv) = db1.response_values(i);
+
+            // rewrite key
+            db1.do_cmd(
+                i,
+                &[
+                    b"GETSET",
+                    i.to_string().as_bytes(),
+                    i.to_string().as_bytes(),
+                    &encode_vv(&vv),
+                    One,
+                ],
+            );
+            db1.response_values(i);
+
+            // add sibling
+            db1.do_cmd(
+                i,
+                &[
+                    b"GETSET",
+                    i.to_string().as_bytes(),
+                    i.to_string().as_bytes(),
+                    &encode_vv(&vv),
+                    One,
+                ],
+            );
+            db1.response_values(i);
+        }
+
+        // boostrap node 2 from 1
+        let db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+
+        db2.dht.rebalance().unwrap();
+        db2.wait_syncs();
+
+        for i in 0..TEST_JOIN_SIZE {
+            for &db in &[&db1, &db2] {
+                db.do_cmd(i, &[b"GET", i.to_string().as_bytes(), One]);
+                assert_eq!(
+                    db.response_values(i).0,
+                    [i.to_string().as_bytes(), i.to_string().as_bytes()]
+                );
+            }
+        }
+
+        // remove first node so node 3 boostrap from 1
+        db2.dht.remove_node(db1.dht.node()).unwrap();
+        sleep_ms(100);
+
+        let db3 = TestDatabase::new("127.0.0.1:9002".parse().unwrap(), "t/db3", false);
+
+        db3.dht.rebalance().unwrap();
+        db3.wait_syncs();
+
+        for i in 0..TEST_JOIN_SIZE {
+            for &db in &[&db2, &db3] {
+                db.do_cmd(i, &[b"GET", i.to_string().as_bytes(), One]);
+                assert_eq!(
+                    db.response_values(i).0,
+                    [i.to_string().as_bytes(), i.to_string().as_bytes()]
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_sync() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        let mut db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        let mut db3 = TestDatabase::new("127.0.0.1:9002".parse().unwrap(), "t/db3", false);
+
+        db1.dht.rebalance().unwrap();
+        db1.wait_syncs();
+        db2.wait_syncs();
+        db3.wait_syncs();
+
+        // sim partition
+        warn!("droping db2");
+        drop(db2);
+        warn!("droping db3");
+        drop(db3);
+
+        for i in 0..TEST_JOIN_SIZE {
+            db1.do_cmd(i, &[b"GETSET", i.to_string().as_bytes(), b"", b"", One]);
+            let (_, vv) = db1.response_values(i);
+
+            // rewrite key
+            db1.do_cmd(
+                i,
+                &[
+                    b"GETSET",
+                    i.to_string().as_bytes(),
+                    i.to_string().as_bytes(),
+                    &encode_vv(&vv),
+                    One,
+                ],
+            );
+            db1.response_values(i);
+
+            // add sibling
+            db1.do_cmd(
+                i,
+                &[
+                    b"GETSET",
+                    i.to_string().as_bytes(),
+                    i.to_string().as_bytes(),
+                    &encode_vv(&vv),
+                    One,
+                ],
+            );
+            db1.response_values(i);
+        }
+
+        // sim partition heal
+        warn!("bringing back db2");
+        db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        db2.wait_fabric();
+
+        warn!("will check before sync");
+        for i in 0..TEST_JOIN_SIZE {
+            for &db in &[&db1, &db2] {
+                db.do_cmd(i, &[b"GET", i.to_string().as_bytes(), Quorum]);
+                assert_eq!(
+                    db.response_values(i).0,
+                    [i.to_string().as_bytes(), i.to_string().as_bytes()]
+                );
+            }
+        }
+
+        // force some syncs
+        warn!("starting syncs");
+        db2.force_syncs();
+        db2.wait_syncs();
+
+        warn!("will check after sync");
+        for i in 0..TEST_JOIN_SIZE {
+            for &db in &[&db2] {
+                db.do_cmd(i, &[b"GET", i.to_string().as_bytes(), One]);
+                assert_eq!(
+                    db.response_values(i).0,
+                    [i.to_string().as_bytes(), i.to_string().as_bytes()]
+                );
+            }
+        }
+
+        // nuke db1 so db3 is forced to sync with db2
+        warn!("droping db1");
+        drop(db1);
+        // sim partition heal
+        warn!("bringing back db3");
+        db3 = TestDatabase::new("127.0.0.1:9002".parse().unwrap(), "t/db3", false);
+        db3.wait_fabric();
+
+        // force some syncs
+        warn!("starting syncs");
+        db3.force_syncs();
+        db3.wait_syncs();
+
+        warn!("will check after sync");
+        for i in 0..TEST_JOIN_SIZE {
+            for &db in &[&db3] {
+                db.do_cmd(i, &[b"GET", i.to_string().as_bytes(), One]);
+                assert_eq!(
+                    db.response_values(i).0,
+                    [i.to_string().as_bytes(), i.to_string().as_bytes()]
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_read_replica_policy() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        {
+            let db = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db", true);
+            db.do_cmd(1, &[b"INFO"]);
+            assert_eq!(
+                db.response_resp(1),
+                RespValue::Data(
+                    concat!(
+                        "# Sucredb\r\nread_replica_policy:round_robin\r\n",
+                        "inflight_requests:0\r\nclient_connections:0\r\nhealth:healthy\r\n"
+                    ).into()
+                )
+            );
+            let mut first = Vec::new();
+            for _ in 0..3 {
+                let mut nodes = vec![1, 2, 3];
+                db.order_read_replicas(&mut nodes);
+                first.push(nodes[0]);
+            }
+            first.sort();
+            assert_eq!(first, [1, 2, 3]);
+        }
+
+        let _ = fs::remove_dir_all("t/");
+        let db = TestDatabase::new_with(
+            "127.0.0.1:9000".parse().unwrap(),
+            "t/db",
+            true,
+            |config| config.read_replica_policy = config::ReplicaPolicy::LatencyAware,
+        );
+        db.observe_replica_latency(1, time::Duration::from_millis(50));
+        db.observe_replica_latency(2, time::Duration::from_millis(5));
+        db.observe_replica_latency(3, time::Duration::from_millis(20));
+        let mut nodes = vec![1, 2, 3, 4];
+        db.order_read_replicas(&mut nodes);
+        // unknown replicas go first so they get sampled
+        assert_eq!(nodes, [4, 2, 3, 1]);
+        // replica 2 becomes slow
+        for _ in 0..10 {
+            db.observe_replica_latency(2, time::Duration::from_millis(100));
+        }
+        db.order_read_replicas(&mut nodes);
+        assert_eq!(nodes, [4, 3, 1, 2]);
+    }
+
+    #[test]
+    fn test_mset() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db", true);
+        db.do_cmd(1, &[b"MSET", b"{t}a", b"1", b"{t}b", b"2", Quorum]);
+        assert_eq!(
+            db.response_resp(1),
+            RespValue::Array(vec![
+                RespValue::Status("OK".into()),
+                RespValue::Status("OK".into()),
+            ])
+        );
+        db.do_cmd(1, &[b"GET", b"{t}a"]);
+        assert_eq!(db.response_values(1).0, [b"1"]);
+        db.do_cmd(1, &[b"GET", b"{t}b"]);
+        assert_eq!(db.response_values(1).0, [b"2"]);
+
+        db.do_cmd(1, &[b"MSET", b"{t}a", b"1", b"{t}a", b"2"]);
+        assert_eq!(
+            db.response_resp(1),
+            RespValue::Error("MultipleKeyMutations".into())
+        );
+
+        let other_key = (0..)
+            .map(|i| format!("other{}", i))
+            .find(|k| db.dht.key_vnode(k.as_bytes()) != db.dht.key_vnode(b"{t}a"))
+            .unwrap();
+        // the keys of each vnode are written as a batch
+        db.do_cmd(1, &[b"MSET", b"{t}c", b"3", other_key.as_bytes(), b"4", b"{t}d", b"5"]);
+        assert_eq!(
+            db.response_resp(1),
+            RespValue::Array(vec![
+                RespValue::Status("OK".into()),
+                RespValue::Status("OK".into()),
+                RespValue::Status("OK".into()),
+            ])
+        );
+        db.do_cmd(1, &[b"GET", b"{t}c"]);
+        assert_eq!(db.response_values(1).0, [b"3"]);
+        db.do_cmd(1, &[b"GET", other_key.as_bytes()]);
+        assert_eq!(db.response_values(1).0, [b"4"]);
+
+        let keys: Vec<_> = (0..101).map(|i| format!("key{}", i)).collect();
+        let mut cmd: Vec<&[u8]> = vec![b"MSET"];
+        for key in &keys {
+            cmd.push(key.as_bytes());
+            cmd.push(b"value");
+        }
+        db.do_cmd(1, &cmd);
+        assert_eq!(db.response_resp(1), RespValue::Error("InvalidArgCount".into()));
+    }
+
+    #[test]
+    fn test_request_id() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db", true);
+        db.do_cmd(1, &[b"REQID", b"1", b"9", b"SET", b"key", b"a"]);
+        assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+        // a retry gets the same response without writing another version
+        db.do_cmd(1, &[b"REQID", b"1", b"9", b"SET", b"key", b"b"]);
+        assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+        db.do_cmd(1, &[b"GET", b"key"]);
+        assert_eq!(db.response_values(1).0, [b"a"]);
+
+        db.do_cmd(1, &[b"REQID", b"2", b"9", b"SET", b"key", b"b"]);
+        assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+        db.do_cmd(1, &[b"GET", b"key"]);
+        assert_eq!(db.response_values(1).0.len(), 2);
+
+        db.do_cmd(1, &[b"REQID", b"x", b"9", b"GET", b"key"]);
+        assert_eq!(db.response_resp(1), RespValue::Error("InvalidIntValue".into()));
+        db.do_cmd(1, &[b"REQID", b"3", b"9", b"MULTI"]);
+        assert_eq!(db.response_resp(1), RespValue::Error("InvalidCommand".into()));
+        db.do_cmd(1, &[b"REQID", b"3", b"9"]);
+        assert_eq!(db.response_resp(1), RespValue::Error("InvalidArgCount".into()));
+
+        // validation errors are forgotten, the request didn't run
+        db.do_cmd(1, &[b"REQID", b"4", b"9", b"SET", b"key", b"c", b"", b"x"]);
+        assert_eq!(
+            db.response_resp(1),
+            RespValue::Error("InvalidConsistencyValue".into())
+        );
+        db.do_cmd(1, &[b"REQID", b"4", b"9", b"SET", b"key", b"c"]);
+        assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+
+        // ids are scoped to the keyspace
+        db.do_cmd_ks(1, 1, &[b"REQID", b"1", b"9", b"SET", b"key", b"a"]);
+        assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+        db.do_cmd_ks(1, 1, &[b"REQID", b"1", b"9", b"GET", b"key"]);
+        assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+        db.do_cmd_ks(1, 2, &[b"REQID", b"1", b"9", b"GET", b"key"]);
+        assert!(db.response_values(1).0.is_empty());
+    }
+
+    #[test]
+    fn test_request_id_timeout() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        // reads query every replica and take the first reply, the local one
+        let db1 = TestDatabase::new_with(
+            "127.0.0.1:9000".parse().unwrap(),
+            "t/db1",
+            true,
+            |config| config.read_replica_policy = config::ReplicaPolicy::All,
+        );
+        let db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        db2.dht.rebalance().unwrap();
+        db1.wait_syncs();
+        db2.wait_syncs();
+
+        // the replica gets the writes but never answers
+        db2.fabric
+            .register_msg_handler(FabricMsgType::Crud, Box::new(|_, _| ()));
+        db1.do_cmd(1, &[b"REQID", b"1", b"9", b"SET", b"key", b"a", b"", All]);
+        assert_eq!(db1.response_resp(1), RespValue::Error("Timeout".into()));
+        // the write was applied locally, so retries get the timeout instead of writing again
+        db1.do_cmd(1, &[b"REQID", b"1", b"9", b"SET", b"key", b"b", b"", All]);
+        assert_eq!(db1.response_resp(1), RespValue::Error("Timeout".into()));
+        db1.do_cmd(1, &[b"GET", b"key", One]);
+        assert_eq!(db1.response_values(1).0, [b"a"]);
+    }
+
+    #[test]
+    fn test_delrange() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db", true);
+        for key in &[&b"user:1:a"[..], b"user:1:b", b"user:10:a", b"user:2:a"] {
+            db.do_cmd(1, &[b"SET", *key, b"value"]);
+            assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+        }
+        db.do_cmd(1, &[b"DELRANGE", b"user:1:"]);
+        assert_eq!(db.response_resp(1), RespValue::Int(2));
+        db.do_cmd(1, &[b"GET", b"user:1:a"]);
+        assert!(db.response_values(1).0.is_empty());
+        db.do_cmd(1, &[b"GET", b"user:10:a"]);
+        assert_eq!(db.response_values(1).0, [b"value"]);
+        // tombstones aren't counted again
+        db.do_cmd(1, &[b"DELRANGE", b"user:1:"]);
+        assert_eq!(db.response_resp(1), RespValue::Int(0));
+        db.do_cmd(1, &[b"DELRANGE", b"user:", One]);
+        assert_eq!(db.response_resp(1), RespValue::Int(2));
+        db.do_cmd(1, &[b"DELRANGE", b""]);
+        assert_eq!(db.response_resp(1), RespValue::Error("InvalidKey".into()));
+    }
+
+    #[test]
+    fn test_vnode_workers() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db = TestDatabase::new_with(
+            "127.0.0.1:9000".parse().unwrap(),
+            "t/db",
+            true,
+            |config| config.worker_count = 3,
+        );
+        assert_eq!(db.metrics().worker_queues.len(), 3);
+        let mut per_worker = vec![0; 3];
+        for vnode in 0..db.partitions() {
+            per_worker[db.vnode_worker(vnode as VNodeNo)] += 1;
+        }
+        let (min, max) = (per_worker.iter().min(), per_worker.iter().max());
+        assert!(max.unwrap() - min.unwrap() <= 1, "{:?}", per_worker);
+        // the same vnode always goes to the same worker
+        assert_eq!(db.vnode_worker(7), db.vnode_worker(7 + 3));
+
+        // and still works through the sharded dispatch
+        db.do_cmd(1, &[b"SET", b"test", b"value"]);
+        assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+        db.do_cmd(1, &[b"GET", b"test"]);
+        assert_eq!(db.response_values(1).0, [b"value"]);
+    }
+
+    #[test]
+    fn test_hot_keys() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db = TestDatabase::new_with(
+            "127.0.0.1:9000".parse().unwrap(),
+            "t/db",
+            true,
+            |config| config.hot_keys_sample_rate = 1,
+        );
+        assert!(db.metrics().hot_keys.is_empty());
+        for i in 0..10 {
+            db.do_cmd(1, &[b"GET", b"hot"]);
+            assert!(db.response_values(1).0.is_empty());
+            db.do_cmd(1, &[b"SET", format!("cold{}", i).as_bytes(), b"value"]);
+            assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+        }
+        let hot_keys = db.metrics().hot_keys;
+        assert_eq!(hot_keys[0].0, Bytes::from("hot"));
+        assert!(hot_keys[0].1 >= 10);
+        assert!(hot_keys.len() <= 10);
+    }
+
+    #[test]
+    fn test_vnode_snapshot() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db", true);
+        db.do_cmd(1, &[b"SET", b"test", b"value"]);
+        assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+        let vnode = db.dht.key_vnode(b"test");
+        let mut dump = Vec::new();
+        let (keys, clocks) = {
+            let vnodes = db.vnodes.read().unwrap();
+            let vnode = vnodes[vnode as usize].lock().unwrap();
+            (vnode.snapshot(&mut dump).unwrap(), vnode._clocks())
+        };
+        assert!(keys >= 1);
+
+        db.do_cmd(1, &[b"SET", b"test", b"value2"]);
+        assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+        {
+            let vnodes = db.vnodes.read().unwrap();
+            let _locked = vnodes[vnode as usize].lock().unwrap();
+            assert!(VNode::restore(&db, vnode + 1, &mut &dump[..]).is_err());
+            assert!(VNode::restore(&db, vnode, &mut &dump[..dump.len() - 1]).is_err());
+            // a huge frame length, past the magic
+            let mut corrupted = dump.clone();
+            corrupted[8..12].copy_from_slice(&[0xFF; 4]);
+            assert!(VNode::restore(&db, vnode, &mut &corrupted[..]).is_err());
+        }
+        // the failed restores left the vnode as it was
+        db.do_cmd(1, &[b"GET", b"test"]);
+        assert_eq!(db.response_values(1).0, [b"value2"]);
+        {
+            let vnodes = db.vnodes.read().unwrap();
+            let mut locked = vnodes[vnode as usize].lock().unwrap();
+            *locked = VNode::restore(&db, vnode, &mut &dump[..]).unwrap();
+            assert_eq!(locked.status(), VNodeStatus::Ready);
+            assert_eq!(locked._clocks(), clocks);
+        }
+        db.do_cmd(1, &[b"GET", b"test"]);
+        assert_eq!(db.response_values(1).0, [b"value"]);
+    }
+
+    #[test]
+    fn test_setex() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        let db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        db1.dht.rebalance().unwrap();
+        db1.wait_syncs();
+        db2.wait_syncs();
+
+        db1.do_cmd(0, &[b"SETEX", b"key", b"0", b"value"]);
+        assert_eq!(db1.response_resp(0), RespValue::Error("InvalidIntValue".into()));
+
+        db1.do_cmd(0, &[b"SETEX", b"key", b"1", b"value", b"", All]);
+        assert_eq!(db1.response_resp(0), RespValue::Status("OK".into()));
+        db1.do_cmd(0, &[b"SETEX", b"other", b"1", b"value", b"", All]);
+        assert_eq!(db1.response_resp(0), RespValue::Status("OK".into()));
+        db2.do_cmd(0, &[b"GET", b"other", One]);
+        let (values, vv) = db2.response_values(0);
+        assert_eq!(values, [b"value"]);
+        // overwritten by a version without ttl
+        db2.do_cmd(0, &[b"SET", b"other", b"value2", &encode_vv(&vv), All]);
+        assert_eq!(db2.response_resp(0), RespValue::Status("OK".into()));
+
+        sleep_ms(1100);
+        for db in &[&db1, &db2] {
+            db.do_cmd(0, &[b"GET", b"key", All]);
+            assert!(db.response_values(0).0.is_empty());
+            db.do_cmd(0, &[b"GET", b"other", All]);
+            assert_eq!(db.response_values(0).0, [b"value2"]);
+        }
+    }
+
+    #[test]
+    fn test_expire() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        let db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        db1.dht.rebalance().unwrap();
+        db1.wait_syncs();
+        db2.wait_syncs();
+
+        db1.do_cmd(0, &[b"TTL", b"key"]);
+        assert_eq!(db1.response_resp(0), RespValue::Int(-2));
+        db1.do_cmd(0, &[b"EXPIRE", b"key", b"1", All]);
+        assert_eq!(db1.response_resp(0), RespValue::Int(0));
+        db1.do_cmd(0, &[b"EXPIRE", b"key", b"0", All]);
+        assert_eq!(db1.response_resp(0), RespValue::Error("InvalidIntValue".into()));
+
+        db1.do_cmd(0, &[b"SET", b"key", b"value", b"", All]);
+        assert_eq!(db1.response_resp(0), RespValue::Status("OK".into()));
+        db1.do_cmd(0, &[b"TTL", b"key"]);
+        assert_eq!(db1.response_resp(0), RespValue::Int(-1));
+        db1.do_cmd(0, &[b"PERSIST", b"key", All]);
+        assert_eq!(db1.response_resp(0), RespValue::Int(0));
+        db1.do_cmd(0, &[b"EXPIRE", b"key", b"100", All]);
+        assert_eq!(db1.response_resp(0), RespValue::Int(1));
+        // replicated with the new version
+        db2.do_cmd(0, &[b"TTL", b"key"]);
+        assert_eq!(db2.response_resp(0), RespValue::Int(100));
+        db2.do_cmd(0, &[b"PTTL", b"key"]);
+        match db2.response_resp(0) {
+            RespValue::Int(ttl) => assert!(ttl > 99_000 && ttl <= 100_000),
+            r => panic!("unexpected {:?}", r),
+        }
+        db2.do_cmd(0, &[b"PERSIST", b"key", All]);
+        assert_eq!(db2.response_resp(0), RespValue::Int(1));
+        db1.do_cmd(0, &[b"TTL", b"key"]);
+        assert_eq!(db1.response_resp(0), RespValue::Int(-1));
+        db1.do_cmd(0, &[b"GET", b"key", All]);
+        assert_eq!(db1.response_values(0).0, [b"value"]);
+
+        let expire_at = format!("{}", now_millis() / 1000 + 1);
+        db1.do_cmd(0, &[b"EXPIREAT", b"key", expire_at.as_bytes(), All]);
+        assert_eq!(db1.response_resp(0), RespValue::Int(1));
+        sleep_ms(2100);
+        for db in &[&db1, &db2] {
+            db.do_cmd(0, &[b"GET", b"key", All]);
+            assert!(db.response_values(0).0.is_empty());
+            db.do_cmd(0, &[b"TTL", b"key"]);
+            assert_eq!(db.response_resp(0), RespValue::Int(-2));
+        }
+
+        // concurrent versions must be resolved first
+        db1.do_cmd(0, &[b"SET", b"key", b"value1", b"", All]);
+        db1.response_resp(0);
+        db1.do_cmd(0, &[b"SET", b"key", b"value2", b"", All]);
+        db1.response_resp(0);
+        db1.do_cmd(0, &[b"EXPIRE", b"key", b"100", All]);
+        match db1.response_resp(0) {
+            RespValue::Array(ref a) => {
+                assert_eq!(a[0], RespValue::Error("ConditionFailed".into()));
+            }
+            r => panic!("expected ConditionFailed, got {:?}", r),
+        }
+        db1.do_cmd(0, &[b"TTL", b"key"]);
+        assert_eq!(db1.response_resp(0), RespValue::Int(-1));
+    }
+
+    #[test]
+    fn test_tombstone_gc() {
+        fn fast_gc(config: &mut config::Config) {
+            config.tombstone_gc_interval = 100;
+            config.worker_timer = 100;
+        }
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new_with("127.0.0.1:9000".parse().unwrap(), "t/db1", true, fast_gc);
+        let db2 =
+            TestDatabase::new_with("127.0.0.1:9001".parse().unwrap(), "t/db2", false, fast_gc);
+        db1.dht.rebalance().unwrap();
+        db1.wait_syncs();
+        db2.wait_syncs();
+
+        db1.do_cmd(0, &[b"SET", b"key", b"value", b"", All]);
+        assert_eq!(db1.response_resp(0), RespValue::Status("OK".into()));
+        db1.do_cmd(0, &[b"GET", b"key", All]);
+        let (_, vv) = db1.response_values(0);
+        db1.do_cmd(0, &[b"DEL", b"key", &encode_vv(&vv), All]);
+        db1.response_resp(0);
+
+        // the tombstone goes away once both replicas have seen the delete
+        let vnode = db1.dht.key_vnode(b"key") as usize;
+        let stored = |db: &TestDatabase| {
+            db.vnodes.read().unwrap()[vnode]
+                .lock()
+                .unwrap()
+                ._is_stored(b"key")
+        };
+        for _ in 0..50 {
+            if !stored(&db1) && !stored(&db2) {
+                break;
+            }
+            sleep_ms(100);
+        }
+        for db in &[&db1, &db2] {
+            assert!(!stored(db));
+            db.do_cmd(0, &[b"GET", b"key", All]);
+            assert!(db.response_values(0).0.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_checkpoint() {
+        fn fast_checkpoint(config: &mut config::Config) {
+            config.checkpoint_interval = 100;
+            config.worker_timer = 100;
+        }
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let mut db = TestDatabase::new_with(
+            "127.0.0.1:9000".parse().unwrap(),
+            "t/db",
+            true,
+            fast_checkpoint,
+        );
+        db.do_cmd(1, &[b"SET", b"test", b"value1", b"", One]);
+        assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+
+        let vnode = db.dht.key_vnode(b"test") as usize;
+        let clocks = |db: &TestDatabase| db.vnodes.read().unwrap()[vnode].lock().unwrap()._clocks();
+        let saved_clocks = |db: &TestDatabase| {
+            db.vnodes.read().unwrap()[vnode]
+                .lock()
+                .unwrap()
+                ._saved_clocks(db)
+        };
+        for _ in 0..50 {
+            if clocks(&db) == saved_clocks(&db) {
+                break;
+            }
+            sleep_ms(100);
+        }
+        assert_eq!(clocks(&db), saved_clocks(&db));
+
+        // an unclean restart recovers the writes after the checkpoint
+        db.do_cmd(1, &[b"SET", b"test2", b"value2", b"", One]);
+        assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+        drop(db);
+        db = TestDatabase::new_with(
+            "127.0.0.1:9000".parse().unwrap(),
+            "t/db",
+            false,
+            fast_checkpoint,
+        );
+        db.do_cmd(1, &[b"GET", b"test", One]);
+        assert_eq!(db.response_values(1).0, [b"value1"]);
+        db.do_cmd(1, &[b"GET", b"test2", One]);
+        assert_eq!(db.response_values(1).0, [b"value2"]);
+    }
+
+    #[test]
+    fn test_checkpoint_writes() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db = TestDatabase::new_with(
+            "127.0.0.1:9000".parse().unwrap(),
+            "t/db",
+            true,
+            |config| {
+                config.checkpoint_interval = 0;
+                config.checkpoint_writes = 2;
+            },
+        );
+        let vnode = db.dht.key_vnode(b"test") as usize;
+        let checkpointed = |db: &TestDatabase| {
+            let vnodes = db.vnodes.read().unwrap();
+            let vn = vnodes[vnode].lock().unwrap();
+            vn._clocks() == vn._saved_clocks(db)
+        };
+
+        db.do_cmd(1, &[b"SET", b"test", b"value1", b"", One]);
+        assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+        assert!(!checkpointed(&db));
+        db.do_cmd(1, &[b"SET", b"test", b"value2", b"", One]);
+        assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+        assert!(checkpointed(&db));
+    }
+
+    #[test]
+    fn test_counter() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        let db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        db1.dht.rebalance().unwrap();
+        db1.wait_syncs();
+        db2.wait_syncs();
+
+        // both nodes coordinate increments, which must add up
+        db1.do_cmd(0, &[b"INCRBY", b"key", b"5", One]);
+        assert_eq!(db1.response_resp(0), RespValue::Status("OK".into()));
+        db2.do_cmd(0, &[b"DECR", b"key", One]);
+        assert_eq!(db2.response_resp(0), RespValue::Status("OK".into()));
+        db2.do_cmd(0, &[b"INCR", b"key", One]);
+        assert_eq!(db2.response_resp(0), RespValue::Status("OK".into()));
+        db1.do_cmd(0, &[b"DECRBY", b"key", b"2", One]);
+        assert_eq!(db1.response_resp(0), RespValue::Status("OK".into()));
+        sleep_ms(100);
+        for db in &[&db1, &db2] {
+            db.do_cmd(0, &[b"CGET", b"key", All]);
+            assert!(db.response_resp(0) == RespValue::Int(3));
+        }
+
+        db1.do_cmd(0, &[b"DECRBY", b"key", b"-9223372036854775808"]);
+        assert!(db1.response_resp(0) == RespValue::Error("InvalidIntValue".into()));
+    }
+
+    #[test]
+    fn test_mget() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        let db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        db1.dht.rebalance().unwrap();
+        db1.wait_syncs();
+        db2.wait_syncs();
+
+        let keys: Vec<String> = (0..10).map(|i| format!("key{}", i)).collect();
+        assert!(
+            keys.iter()
+                .any(|k| db1.dht.key_vnode(k.as_bytes()) != db1.dht.key_vnode(b"key0"))
+        );
+        for k in &keys[..5] {
+            db1.do_cmd(0, &[b"SET", k.as_bytes(), k.as_bytes()]);
+            assert_eq!(db1.response_resp(0), RespValue::Status("OK".into()));
+        }
+
+        let mut cmd: Vec<&[u8]> = vec![b"MGET", b"10"];
+        cmd.extend(keys.iter().map(|k| k.as_bytes()));
+        db1.do_cmd(0, &cmd);
+        match db1.response_resp(0) {
+            RespValue::Array(results) => {
+                assert_eq!(results.len(), keys.len());
+                for (i, result) in results.into_iter().enumerate() {
+                    let values = decode_values(result).0;
+                    if i < 5 {
+                        assert_eq!(values, [keys[i].as_bytes()]);
+                    } else {
+                        assert!(values.is_empty());
+                    }
+                }
+            }
+            _ => panic!("expected an array"),
+        }
+
+        // no vnode can reach a quorum, every key gets the error
+        let db2_node = db2.dht.node();
+        drop(db2);
+        while db1.fabric.connections().contains(&db2_node) {
+            sleep_ms(10);
+        }
+        cmd.push(Quorum);
+        db1.do_cmd(0, &cmd);
+        assert!(
+            db1.response_resp(0)
+                == RespValue::Array(vec![
+                    RespValue::Error("NotEnoughReplicas".into());
+                    keys.len()
+                ])
+        );
+    }
+
+    #[test]
+    fn test_debug_vnodes() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db", true);
+        db.do_cmd(1, &[b"SET", b"a", b"1"]);
+        assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+
+        db.do_cmd(1, &[b"DEBUG", b"VNODES"]);
+        let vnodes = match db.response_resp(1) {
+            RespValue::Array(vnodes) => vnodes,
+            r => panic!("unexpected response {:?}", r),
+        };
+        assert_eq!(vnodes.len(), PARTITIONS);
+        let key_vnode = db.dht.key_vnode(b"a") as usize;
+        let node = RespValue::Data(db.dht.node().to_string().as_bytes().into());
+        for (i, vnode) in vnodes.into_iter().enumerate() {
+            let fields = match vnode {
+                RespValue::Array(fields) => fields,
+                r => panic!("unexpected response {:?}", r),
+            };
+            assert_eq!(fields[0], RespValue::Int(i as _));
+            assert_eq!(fields[1], RespValue::Data("Ready".into()));
+            let (expected_keys, expected_clocks) = if i == key_vnode {
+                (1, vec![RespValue::Array(vec![node.clone(), RespValue::Int(1)])])
+            } else {
+                (0, vec![])
+            };
+            assert_eq!(fields[3], RespValue::Int(expected_keys));
+            assert_eq!(fields[4], RespValue::Array(expected_clocks));
+            assert_eq!(&fields[5..], &vec![RespValue::Int(0); 4][..]);
+        }
+
+        db.do_cmd(1, &[b"DEBUG", b"UNKNOWN"]);
+        assert_eq!(db.response_resp(1), RespValue::Error("UnknownCommand".into()));
+    }
+
+    #[test]
+    fn test_aae_on_demand() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        let mut db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        db1.dht.rebalance().unwrap();
+        db1.wait_syncs();
+        db2.wait_syncs();
+
+        // sim partition
+        drop(db2);
+        for i in 0..TEST_JOIN_SIZE {
+            db1.do_cmd(i, &[b"SET", i.to_string().as_bytes(), b"value", b"", One]);
+            assert_eq!(db1.response_resp(i), RespValue::Status("OK".into()));
+        }
+        db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        db2.wait_fabric();
+
+        db2.do_cmd(0, &[b"CLUSTER", b"AAE", b"START"]);
+        let job = match db2.response_resp(0) {
+            RespValue::Int(job) => job.to_string(),
+            r => panic!("unexpected response {:?}", r),
+        };
+        let received = loop {
+            db2.do_cmd(0, &[b"CLUSTER", b"AAE", b"STATUS", job.as_bytes()]);
+            let vnodes = match db2.response_resp(0) {
+                RespValue::Array(vnodes) => vnodes,
+                r => panic!("unexpected response {:?}", r),
+            };
+            assert_eq!(vnodes.len(), PARTITIONS);
+            let mut received = 0;
+            let mut done = true;
+            for vnode in vnodes {
+                if let RespValue::Array(ref p) = vnode {
+                    done &= p[1] == RespValue::Data("done".as_bytes().into());
+                    if let RespValue::Int(r) = p[4] {
+                        received += r;
+                    }
+                }
+            }
+            if done {
+                break received;
+            }
+            sleep_ms(10);
+        };
+        assert!(received >= TEST_JOIN_SIZE as i64);
+
+        for i in 0..TEST_JOIN_SIZE {
+            db2.do_cmd(i, &[b"GET", i.to_string().as_bytes(), One]);
+            assert_eq!(db2.response_values(i).0, [b"value"]);
+        }
+    }
+
+    // runs an on demand anti-entropy job in db and waits for it to finish
+    fn run_aae(db: &TestDatabase) {
+        db.do_cmd(0, &[b"CLUSTER", b"AAE", b"START"]);
+        let job = match db.response_resp(0) {
+            RespValue::Int(job) => job.to_string(),
+            r => panic!("unexpected response {:?}", r),
+        };
+        loop {
+            db.do_cmd(0, &[b"CLUSTER", b"AAE", b"STATUS", job.as_bytes()]);
+            let done = match db.response_resp(0) {
+                RespValue::Array(vnodes) => vnodes.iter().all(|vnode| match *vnode {
+                    RespValue::Array(ref p) => p[1] == RespValue::Data("done".as_bytes().into()),
+                    _ => false,
+                }),
+                r => panic!("unexpected response {:?}", r),
+            };
+            if done {
+                break;
+            }
+            sleep_ms(10);
+        }
+    }
+
+    #[test]
+    fn test_trigger_sync() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        let mut db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        db1.dht.rebalance().unwrap();
+        db1.wait_syncs();
+        db2.wait_syncs();
+
+        // sim partition
+        drop(db2);
+        for i in 0..TEST_JOIN_SIZE {
+            db1.do_cmd(i, &[b"SET", i.to_string().as_bytes(), b"value", b"", One]);
+            assert_eq!(db1.response_resp(i), RespValue::Status("OK".into()));
+        }
+        db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        db2.wait_fabric();
+
+        let peer = db1.dht.node();
+        assert!(db2.trigger_sync(PARTITIONS as VNodeNo, peer).is_err());
+        assert!(db2.trigger_sync(0, db2.dht.node()).is_err());
+        assert!(db2.trigger_sync(0, 42).is_err());
+        {
+            // holding the vnode so the sync can't finish in between
+            let vnodes = db2.vnodes.read().unwrap();
+            let mut vnode = vnodes[0].lock().unwrap();
+            let cookie = vnode.trigger_sync(&db2, peer).unwrap();
+            assert!(vnode.trigger_sync(&db2, peer).is_err());
+            let metrics = vnode.metrics();
+            assert!(metrics.sync_progress.iter().any(|p| p.cookie == cookie && p.peer == peer));
+        }
+        db2.wait_syncs();
+
+        for i in 0..TEST_JOIN_SIZE {
+            let key = i.to_string();
+            if db2.key_vnode(key.as_bytes()) == 0 {
+                db2.do_cmd(i, &[b"GET", key.as_bytes(), One]);
+                assert_eq!(db2.response_values(i).0, [b"value"]);
+            }
+        }
+
+        db2.do_cmd(0, &[b"CLUSTER", b"SYNC", b"0", b"42"]);
+        match db2.response_resp(0) {
+            RespValue::Error(_) => (),
+            r => panic!("unexpected response {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_aae_merkle() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        let mut db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        db1.dht.rebalance().unwrap();
+        db1.wait_syncs();
+        db2.wait_syncs();
+
+        // builds the trees, from here on db1 keeps them updated with each write
+        run_aae(&db2);
+
+        // sim partition
+        drop(db2);
+        for i in 0..TEST_JOIN_SIZE {
+            db1.do_cmd(i, &[b"GETSET", i.to_string().as_bytes(), b"value", b"", One]);
+            let vv = db1.response_values(i).1;
+            if i % 2 == 0 {
+                db1.do_cmd(i, &[b"DEL", i.to_string().as_bytes(), &encode_vv(&vv), One]);
+                db1.response_resp(i);
+            }
+        }
+        db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        db2.wait_fabric();
+        run_aae(&db2);
+
+        for i in 0..TEST_JOIN_SIZE {
+            db2.do_cmd(i, &[b"GET", i.to_string().as_bytes(), One]);
+            let values = db2.response_values(i).0;
+            if i % 2 == 0 {
+                assert_eq!(values.len(), 0);
+            } else {
+                assert_eq!(values, [b"value"]);
+            }
+        }
+        // the incrementally updated trees match
+        for i in 0..PARTITIONS {
+            assert_eq!(
+                db1.vnodes.read().unwrap()[i].lock().unwrap()._merkle_root(),
+                db2.vnodes.read().unwrap()[i].lock().unwrap()._merkle_root()
+            );
+        }
+    }
+
+    #[test]
+    fn test_aae_lost_keys() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        let db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        db1.dht.rebalance().unwrap();
+        db1.wait_syncs();
+        db2.wait_syncs();
+
+        for i in 0..TEST_JOIN_SIZE {
+            db1.do_cmd(i, &[b"SET", i.to_string().as_bytes(), b"value", b"", All]);
+            assert_eq!(db1.response_resp(i), RespValue::Status("OK".into()));
+        }
+        // db2 clocks still cover the keys it lost, only the trees tell them apart
+        for i in 0..TEST_JOIN_SIZE {
+            let key = i.to_string();
+            let vnode = db2.key_vnode(key.as_bytes()) as usize;
+            db2.vnodes.read().unwrap()[vnode]
+                .lock()
+                .unwrap()
+                ._lose(key.as_bytes());
+        }
+        run_aae(&db2);
+
+        for i in 0..TEST_JOIN_SIZE {
+            db2.do_cmd(i, &[b"GET", i.to_string().as_bytes(), One]);
+            assert_eq!(db2.response_values(i).0, [b"value"]);
         }
     }
 
     #[test]
-    fn test_bootstrap_2() {
-        // similar to the previous, but values in n1 are rewritten + sibling
+    fn test_sync_expired_log() {
         let _ = fs::remove_dir_all("t/");
         let _ = env_logger::try_init();
         let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
-        for i in 0..TEST_JOIN_SIZE {
-            db1.do_cmd(i, &[b"GETSET", i.to_string().as_bytes(), b"", b"", One]);
-            let (_, vv) = db1.response_values(i);
-
-            // rewrite key
-            db1.do_cmd(
-                i,
-                &[
-                    b"GETSET",
-                    i.to_string().as_bytes(),
-                    i.to_string().as_bytes(),
-                    &encode_vv(&vv),
-                    One,
-                ],
-            );
-            db1.response_values(i);
+        let mut db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        db1.dht.rebalance().unwrap();
+        db1.wait_syncs();
+        db2.wait_syncs();
 
-            // add sibling
-            db1.do_cmd(
-                i,
-                &[
-                    b"GETSET",
-                    i.to_string().as_bytes(),
-                    i.to_string().as_bytes(),
-                    &encode_vv(&vv),
-                    One,
-                ],
-            );
-            db1.response_values(i);
+        // sim partition
+        drop(db2);
+        for i in 0..TEST_JOIN_SIZE {
+            db1.do_cmd(i, &[b"SET", i.to_string().as_bytes(), b"value", b"", One]);
+            assert_eq!(db1.response_resp(i), RespValue::Status("OK".into()));
         }
+        // the writes db2 missed are past the log retention
+        for vnode in db1.vnodes.read().unwrap().iter() {
+            vnode.lock().unwrap()._expire_log();
+        }
+        db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        db2.wait_fabric();
+        db2.force_syncs();
 
-        // boostrap node 2 from 1
-        let db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        for i in 0..TEST_JOIN_SIZE {
+            db2.do_cmd(i, &[b"GET", i.to_string().as_bytes(), One]);
+            assert_eq!(db2.response_values(i).0, [b"value"]);
+        }
+    }
 
-        db2.dht.rebalance().unwrap();
+    #[test]
+    fn test_read_repair() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        fn read_repair(config: &mut config::Config) {
+            config.read_repair = true;
+        }
+        let db1 =
+            TestDatabase::new_with("127.0.0.1:9000".parse().unwrap(), "t/db1", true, read_repair);
+        let mut db2 =
+            TestDatabase::new_with("127.0.0.1:9001".parse().unwrap(), "t/db2", false, read_repair);
+        db1.dht.rebalance().unwrap();
+        db1.wait_syncs();
         db2.wait_syncs();
 
+        // sim partition
+        drop(db2);
         for i in 0..TEST_JOIN_SIZE {
-            for &db in &[&db1, &db2] {
-                db.do_cmd(i, &[b"GET", i.to_string().as_bytes(), One]);
-                assert_eq!(
-                    db.response_values(i).0,
-                    [i.to_string().as_bytes(), i.to_string().as_bytes()]
-                );
-            }
+            db1.do_cmd(i, &[b"SET", i.to_string().as_bytes(), b"value", b"", One]);
+            assert_eq!(db1.response_resp(i), RespValue::Status("OK".into()));
         }
+        db2 =
+            TestDatabase::new_with("127.0.0.1:9001".parse().unwrap(), "t/db2", false, read_repair);
+        db2.wait_fabric();
 
-        // remove first node so node 3 boostrap from 1
-        db2.dht.remove_node(db1.dht.node()).unwrap();
+        for i in 0..TEST_JOIN_SIZE {
+            db2.do_cmd(i, &[b"GET", i.to_string().as_bytes(), One]);
+            assert!(db2.response_values(i).0.is_empty());
+        }
+        // the reads see the divergence and repair db2
+        for i in 0..TEST_JOIN_SIZE {
+            db1.do_cmd(i, &[b"GET", i.to_string().as_bytes(), All]);
+            assert_eq!(db1.response_values(i).0, [b"value"]);
+        }
         sleep_ms(100);
-
-        let db3 = TestDatabase::new("127.0.0.1:9002".parse().unwrap(), "t/db3", false);
-
-        db3.dht.rebalance().unwrap();
-        db3.wait_syncs();
-
         for i in 0..TEST_JOIN_SIZE {
-            for &db in &[&db2, &db3] {
-                db.do_cmd(i, &[b"GET", i.to_string().as_bytes(), One]);
-                assert_eq!(
-                    db.response_values(i).0,
-                    [i.to_string().as_bytes(), i.to_string().as_bytes()]
-                );
-            }
+            db2.do_cmd(i, &[b"GET", i.to_string().as_bytes(), One]);
+            assert_eq!(db2.response_values(i).0, [b"value"]);
         }
     }
 
     #[test]
-    fn test_sync() {
+    fn test_read_repair_sync() {
         let _ = fs::remove_dir_all("t/");
         let _ = env_logger::try_init();
-        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
-        let mut db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
-        let mut db3 = TestDatabase::new("127.0.0.1:9002".parse().unwrap(), "t/db3", false);
-
+        fn read_repair(config: &mut config::Config) {
+            config.read_repair = true;
+        }
+        let db1 =
+            TestDatabase::new_with("127.0.0.1:9000".parse().unwrap(), "t/db1", true, read_repair);
+        let mut db2 =
+            TestDatabase::new_with("127.0.0.1:9001".parse().unwrap(), "t/db2", false, read_repair);
         db1.dht.rebalance().unwrap();
         db1.wait_syncs();
         db2.wait_syncs();
-        db3.wait_syncs();
 
         // sim partition
-        warn!("droping db2");
         drop(db2);
-        warn!("droping db3");
-        drop(db3);
-
         for i in 0..TEST_JOIN_SIZE {
-            db1.do_cmd(i, &[b"GETSET", i.to_string().as_bytes(), b"", b"", One]);
-            let (_, vv) = db1.response_values(i);
-
-            // rewrite key
-            db1.do_cmd(
-                i,
-                &[
-                    b"GETSET",
-                    i.to_string().as_bytes(),
-                    i.to_string().as_bytes(),
-                    &encode_vv(&vv),
-                    One,
-                ],
-            );
-            db1.response_values(i);
-
-            // add sibling
-            db1.do_cmd(
-                i,
-                &[
-                    b"GETSET",
-                    i.to_string().as_bytes(),
-                    i.to_string().as_bytes(),
-                    &encode_vv(&vv),
-                    One,
-                ],
-            );
-            db1.response_values(i);
+            db1.do_cmd(i, &[b"SET", i.to_string().as_bytes(), b"value", b"", One]);
+            assert_eq!(db1.response_resp(i), RespValue::Status("OK".into()));
         }
-
-        // sim partition heal
-        warn!("bringing back db2");
-        db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        db2 =
+            TestDatabase::new_with("127.0.0.1:9001".parse().unwrap(), "t/db2", false, read_repair);
         db2.wait_fabric();
 
-        warn!("will check before sync");
+        db1.do_cmd(0, &[b"HELLO", b"REPAIR"]);
+        assert_eq!(
+            db1.response_resp(0),
+            RespValue::Array(vec![RespValue::Data("REPAIR".into())])
+        );
+        let repair_context = |token| {
+            let mut context = Context::new(token);
+            context.sync_repair = true;
+            context
+        };
         for i in 0..TEST_JOIN_SIZE {
-            for &db in &[&db1, &db2] {
-                db.do_cmd(i, &[b"GET", i.to_string().as_bytes(), Quorum]);
-                assert_eq!(
-                    db.response_values(i).0,
-                    [i.to_string().as_bytes(), i.to_string().as_bytes()]
-                );
-            }
+            db1.do_cmd_context(repair_context(i), &[b"GET", i.to_string().as_bytes(), All]);
+            assert_eq!(db1.response_values(i).0, [b"value"]);
+            // the repair is already in db2 when the read responds
+            db2.do_cmd(i, &[b"GET", i.to_string().as_bytes(), One]);
+            assert_eq!(db2.response_values(i).0, [b"value"]);
         }
+    }
 
-        // force some syncs
-        warn!("starting syncs");
-        db2.force_syncs();
+    #[test]
+    fn test_coordinator_offload() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new_with("127.0.0.1:9000".parse().unwrap(), "t/db1", true, |c| {
+            c.coordinator_offload_inflight = 1;
+        });
+        let db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        db1.dht.rebalance().unwrap();
+        db1.wait_syncs();
         db2.wait_syncs();
 
-        warn!("will check after sync");
-        for i in 0..TEST_JOIN_SIZE {
-            for &db in &[&db2] {
-                db.do_cmd(i, &[b"GET", i.to_string().as_bytes(), One]);
-                assert_eq!(
-                    db.response_values(i).0,
-                    [i.to_string().as_bytes(), i.to_string().as_bytes()]
-                );
-            }
+        let vnode = db1.key_vnode(b"0");
+        let key = (1..TEST_JOIN_SIZE)
+            .map(|i| i.to_string())
+            .find(|k| db1.key_vnode(k.as_bytes()) == vnode)
+            .unwrap();
+        for (i, k) in ["0", &key[..]].iter().enumerate() {
+            db1.do_cmd(i as _, &[b"SET", k.as_bytes(), k.as_bytes(), b"", All]);
+            assert_eq!(db1.response_resp(i as _), RespValue::Status("OK".into()));
+        }
+        {
+            // the first read stays inflight while db2 can't reply,
+            // so db2 gets to coordinate the second one
+            let vnodes = db2.vnodes.read().unwrap();
+            let _locked = vnodes[vnode as usize].lock().unwrap();
+            db1.do_cmd(0, &[b"GET", b"0", Quorum]);
+            db1.do_cmd(1, &[b"GET", key.as_bytes(), Quorum]);
+            assert_eq!(db1.inflight_requests(), 2);
         }
+        assert_eq!(db1.response_values(0).0, [b"0"]);
+        assert_eq!(db1.response_values(1).0, [key.as_bytes()]);
+    }
 
-        // nuke db1 so db3 is forced to sync with db2
-        warn!("droping db1");
-        drop(db1);
-        // sim partition heal
-        warn!("bringing back db3");
-        db3 = TestDatabase::new("127.0.0.1:9002".parse().unwrap(), "t/db3", false);
-        db3.wait_fabric();
+    #[test]
+    fn test_sync_bandwidth() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        fn limit(config: &mut config::Config) {
+            config.sync_bandwidth_limit = 10_000;
+        }
+        let db = TestDatabase::new_with("127.0.0.1:9000".parse().unwrap(), "t/db", true, limit);
+        assert!(db.sync_bandwidth_available());
+        db.sync_bandwidth_consume(15_000);
+        assert!(!db.sync_bandwidth_available());
+        // refills 10 bytes per ms
+        sleep_ms(600);
+        assert!(db.sync_bandwidth_available());
+    }
 
-        // force some syncs
-        warn!("starting syncs");
-        db3.force_syncs();
-        db3.wait_syncs();
+    #[test]
+    fn test_storage_workers() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        fn workers(config: &mut config::Config) {
+            config.storage_worker_count = 2;
+        }
+        let db1 =
+            TestDatabase::new_with("127.0.0.1:9000".parse().unwrap(), "t/db1", true, workers);
+        let db2 =
+            TestDatabase::new_with("127.0.0.1:9001".parse().unwrap(), "t/db2", false, workers);
+        db1.dht.rebalance().unwrap();
+        db1.wait_syncs();
+        db2.wait_syncs();
 
-        warn!("will check after sync");
         for i in 0..TEST_JOIN_SIZE {
-            for &db in &[&db3] {
-                db.do_cmd(i, &[b"GET", i.to_string().as_bytes(), One]);
-                assert_eq!(
-                    db.response_values(i).0,
-                    [i.to_string().as_bytes(), i.to_string().as_bytes()]
-                );
+            db1.do_cmd(i, &[b"SET", i.to_string().as_bytes(), b"value", b"", All]);
+            assert_eq!(db1.response_resp(i), RespValue::Status("OK".into()));
+        }
+        // both the local and the remote reads go through the storage workers
+        for &cl in &[One, All] {
+            for i in 0..TEST_JOIN_SIZE {
+                db2.do_cmd(i, &[b"GET", i.to_string().as_bytes(), cl]);
+                assert_eq!(db2.response_values(i).0, [b"value"]);
             }
         }
+        db1.do_cmd(0, &[b"GET", b"missing", All]);
+        assert!(db1.response_values(0).0.is_empty());
     }
 
     #[test]
@@ -1151,7 +4779,11 @@ mod tests {
             db1.response_values(0);
         }
 
+        let db3_node = db3.dht.node();
         drop(db3);
+        while db1.fabric.connections().contains(&db3_node) {
+            sleep_ms(10);
+        }
         for &cl in &[One, Quorum] {
             db1.do_cmd(0, &[b"GET", b"key", cl]);
             assert_eq!(db1.response_values(0).0, [b"value"]);
@@ -1160,12 +4792,16 @@ mod tests {
         }
         for &cl in &[All] {
             db1.do_cmd(0, &[b"GET", b"key", cl]);
-            assert_eq!(db1.response_resp(0), RespValue::Error("Unavailable".into()));
+            assert_eq!(db1.response_resp(0), RespValue::Error("NotEnoughReplicas".into()));
             db1.do_cmd(0, &[b"GETSET", b"other", b"", b"", cl]);
-            assert_eq!(db1.response_resp(0), RespValue::Error("Unavailable".into()));
+            assert_eq!(db1.response_resp(0), RespValue::Error("NotEnoughReplicas".into()));
         }
 
+        let db2_node = db2.dht.node();
         drop(db2);
+        while db1.fabric.connections().contains(&db2_node) {
+            sleep_ms(10);
+        }
         for &cl in &[One] {
             db1.do_cmd(0, &[b"GET", b"key", cl]);
             assert_eq!(db1.response_values(0).0, [b"value"]);
@@ -1174,10 +4810,185 @@ mod tests {
         }
         for &cl in &[Quorum, All] {
             db1.do_cmd(0, &[b"GET", b"key", cl]);
-            assert_eq!(db1.response_resp(0), RespValue::Error("Unavailable".into()));
+            assert_eq!(db1.response_resp(0), RespValue::Error("NotEnoughReplicas".into()));
             db1.do_cmd(0, &[b"GETSET", b"other", b"", b"", cl]);
-            assert_eq!(db1.response_resp(0), RespValue::Error("Unavailable".into()));
+            assert_eq!(db1.response_resp(0), RespValue::Error("NotEnoughReplicas".into()));
+        }
+    }
+
+    #[test]
+    fn test_consistency_write_default() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new_with(
+            "127.0.0.1:9000".parse().unwrap(),
+            "t/db1",
+            true,
+            |config| config.consistency_write = ConsistencyLevel::All,
+        );
+        let db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        db1.dht.rebalance().unwrap();
+        db1.wait_syncs();
+        db2.wait_syncs();
+
+        db1.do_cmd(0, &[b"SET", b"key", b"value"]);
+        assert_eq!(db1.response_resp(0), RespValue::Status("OK".into()));
+
+        let db2_node = db2.dht.node();
+        drop(db2);
+        while db1.fabric.connections().contains(&db2_node) {
+            sleep_ms(10);
+        }
+        // writes use the configured default, reads still default to One
+        db1.do_cmd(0, &[b"SET", b"key", b"value"]);
+        assert_eq!(db1.response_resp(0), RespValue::Error("NotEnoughReplicas".into()));
+        db1.do_cmd(0, &[b"SET", b"key", b"value", b"", One]);
+        assert_eq!(db1.response_resp(0), RespValue::Status("OK".into()));
+        db1.do_cmd(0, &[b"GET", b"key"]);
+        assert_eq!(db1.response_values(0).0, [b"value"]);
+
+        // nothing was written, so the request id is forgotten and the retry runs
+        db1.do_cmd(0, &[b"REQID", b"1", b"9", b"SET", b"key", b"value"]);
+        assert_eq!(db1.response_resp(0), RespValue::Error("NotEnoughReplicas".into()));
+        db1.do_cmd(0, &[b"REQID", b"1", b"9", b"SET", b"key", b"value", b"", One]);
+        assert_eq!(db1.response_resp(0), RespValue::Status("OK".into()));
+    }
+
+    #[test]
+    fn test_consistency_delete_default() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        let db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        db1.dht.rebalance().unwrap();
+        db1.wait_syncs();
+        db2.wait_syncs();
+
+        db1.do_cmd(0, &[b"SET", b"key", b"value", b"", All]);
+        assert_eq!(db1.response_resp(0), RespValue::Status("OK".into()));
+        db1.do_cmd(0, &[b"GET", b"key"]);
+        let vv = db1.response_values(0).1;
+        // the response waits for the other replica to store the tombstone
+        db1.do_cmd(0, &[b"DEL", b"key", &encode_vv(&vv)]);
+        assert_eq!(db1.response_resp(0), RespValue::Int(1));
+        db2.do_cmd(0, &[b"GET", b"key", One]);
+        assert!(db2.response_values(0).0.is_empty());
+
+        let db2_node = db2.dht.node();
+        drop(db2);
+        while db1.fabric.connections().contains(&db2_node) {
+            sleep_ms(10);
+        }
+        // writes still default to One
+        db1.do_cmd(0, &[b"SET", b"key", b"value"]);
+        assert_eq!(db1.response_resp(0), RespValue::Status("OK".into()));
+        db1.do_cmd(0, &[b"GET", b"key"]);
+        let vv = db1.response_values(0).1;
+        db1.do_cmd(0, &[b"DEL", b"key", &encode_vv(&vv)]);
+        assert_eq!(db1.response_resp(0), RespValue::Error("NotEnoughReplicas".into()));
+        db1.do_cmd(0, &[b"DEL", b"key", &encode_vv(&vv), One]);
+        assert_eq!(db1.response_resp(0), RespValue::Int(1));
+    }
+
+    #[test]
+    fn test_health() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        let db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        db1.dht.rebalance().unwrap();
+        db1.wait_syncs();
+        db2.wait_syncs();
+        assert_eq!(db1.health(), Health::Healthy);
+        assert_eq!(db2.health(), Health::Healthy);
+
+        // not in the ring yet, so it has nothing to serve
+        let db3 = TestDatabase::new("127.0.0.1:9002".parse().unwrap(), "t/db3", false);
+        assert_eq!(db3.health(), Health::Unavailable);
+
+        let db2_node = db2.dht.node();
+        drop(db2);
+        while db1.fabric.connections().contains(&db2_node) {
+            sleep_ms(10);
+        }
+        // deletes default to quorum, which needs both replicas
+        assert_eq!(db1.health(), Health::Degraded);
+    }
+
+    #[test]
+    fn test_cas() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db", true);
+        db.dht.rebalance().unwrap();
+
+        db.do_cmd(0, &[b"CAS", b"key", b"", b"value1"]);
+        assert_eq!(db.response_resp(0), RespValue::Status("OK".into()));
+        db.do_cmd(0, &[b"CAS", b"key", b"", b"value2"]);
+        let current = match db.response_resp(0) {
+            RespValue::Array(mut a) => {
+                assert_eq!(a[0], RespValue::Error("CasMismatch".into()));
+                a.pop().unwrap()
+            }
+            _ => panic!("expected CasMismatch"),
+        };
+        let (values, vv) = decode_values(current);
+        assert_eq!(values, [b"value1"]);
+
+        db.do_cmd(0, &[b"CAS", b"key", &encode_vv(&vv), b"value2"]);
+        assert_eq!(db.response_resp(0), RespValue::Status("OK".into()));
+        db.do_cmd(0, &[b"CAS", b"key", &encode_vv(&vv), b"value3"]);
+        match db.response_resp(0) {
+            RespValue::Array(ref a) if a[0] == RespValue::Error("CasMismatch".into()) => (),
+            _ => panic!("expected CasMismatch"),
         }
+        db.do_cmd(0, &[b"GET", b"key"]);
+        assert_eq!(db.response_values(0).0, [b"value2"]);
+    }
+
+    #[test]
+    fn test_set_condition() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db", true);
+        db.dht.rebalance().unwrap();
+        let condition_failed = |response: RespValue| match response {
+            RespValue::Array(mut a) => {
+                assert_eq!(a[0], RespValue::Error("ConditionFailed".into()));
+                decode_values(a.pop().unwrap())
+            }
+            r => panic!("expected ConditionFailed, got {:?}", r),
+        };
+
+        db.do_cmd(0, &[b"SET", b"key", b"value1", b"XX"]);
+        assert_eq!(condition_failed(db.response_resp(0)).0.len(), 0);
+        db.do_cmd(0, &[b"SET", b"key", b"value1", b"NX"]);
+        assert_eq!(db.response_resp(0), RespValue::Status("OK".into()));
+        db.do_cmd(0, &[b"SET", b"key", b"value2", b"", One, b"nx"]);
+        let (values, vv) = condition_failed(db.response_resp(0));
+        assert_eq!(values, [b"value1"]);
+
+        db.do_cmd(0, &[b"SET", b"key", b"value2", &encode_vv(&vv), b"XX"]);
+        assert_eq!(db.response_resp(0), RespValue::Status("OK".into()));
+        db.do_cmd(0, &[b"GET", b"key"]);
+        let (values, vv) = db.response_values(0);
+        assert_eq!(values, [b"value2"]);
+
+        // tombstones aren't values
+        db.do_cmd(0, &[b"DEL", b"key", &encode_vv(&vv)]);
+        assert_eq!(db.response_resp(0), RespValue::Int(1));
+        db.do_cmd(0, &[b"SET", b"key", b"value3", b"XX"]);
+        condition_failed(db.response_resp(0));
+        db.do_cmd(0, &[b"SET", b"key", b"value3", b"NX"]);
+        assert_eq!(db.response_resp(0), RespValue::Status("OK".into()));
+        db.do_cmd(0, &[b"GET", b"key"]);
+        assert_eq!(db.response_values(0).0, [b"value3"]);
+
+        // a value that happens to be NX is still a value
+        db.do_cmd(0, &[b"SET", b"other", b"NX"]);
+        assert_eq!(db.response_resp(0), RespValue::Status("OK".into()));
+        db.do_cmd(0, &[b"GET", b"other"]);
+        assert_eq!(db.response_values(0).0, [b"NX"]);
     }
 
     fn stub_aae_converge(drop: usize) {
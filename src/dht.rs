@@ -13,10 +13,10 @@ use rand::{thread_rng, Rng};
 use serde::de::DeserializeOwned;
 use serde::Serialize;
 
-use config::Config;
+use config::{Config, HostAddr};
 use database::{NodeId, VNodeNo};
 use fabric::{Fabric, FabricMsg, FabricMsgRef, FabricMsgType};
-use hash::{hash_slot, HASH_SLOTS};
+use hash::{HashAlgorithm, HASH_SLOTS};
 use types::PhysicalNodeId;
 use utils::{split_u64, GenericError, IdHashMap, IdHashSet};
 use version_vector::VersionVector;
@@ -38,6 +38,8 @@ impl<T: Clone + PartialEq + Serialize + DeserializeOwned + Send + Sync + fmt::De
 const DHT_AAE_INTERVAL_MS: u64 = 1_000;
 // interval for active anti entropy checks
 const DHT_AAE_TRIGGER_INTERVAL_MS: u64 = 1_000;
+// interval to resolve and contact the seeds again, while no other node is reachable
+const DHT_SEED_RETRY_INTERVAL_MS: u64 = 5_000;
 
 /// The Cluster controller, it knows how to map keys to their vnodes and
 /// whose nodes hold data for each vnodes.
@@ -58,6 +60,9 @@ struct Inner<T: Metadata> {
     next_req_broadcast: Instant,
     sync_on_connect: bool,
     sync_aae: bool,
+    // seeds the node joined with, their names may resolve to new addresses over time
+    seeds: Vec<HostAddr>,
+    next_seed_retry: Instant,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
@@ -112,6 +117,48 @@ struct Ring<T: Metadata> {
     // Note: For the version vector to be "valid" all proposedchanges by a node must be serialized
     version: VersionVector,
     cluster: String,
+    hash_algorithm: HashAlgorithm,
+    // failure domain of the nodes, kept apart from Node so older versions can still
+    // read the ring, they ignore the trailing bytes
+    zones: IdHashMap<NodeId, String>,
+    // times the partitions were doubled since the cluster was initialized, see split
+    splits: u8,
+}
+
+// rings saved or sent before the partitions could be split
+#[derive(Deserialize)]
+#[serde(bound = "T: DeserializeOwned")]
+struct RingV2<T: Metadata> {
+    vnodes: Vec<VNode>,
+    nodes: IdHashMap<NodeId, Node<T>>,
+    replication_factor: usize,
+    version: VersionVector,
+    cluster: String,
+    hash_algorithm: HashAlgorithm,
+    zones: IdHashMap<NodeId, String>,
+}
+
+// rings saved or sent before the zones were recorded
+#[derive(Deserialize)]
+#[serde(bound = "T: DeserializeOwned")]
+struct RingV1<T: Metadata> {
+    vnodes: Vec<VNode>,
+    nodes: IdHashMap<NodeId, Node<T>>,
+    replication_factor: usize,
+    version: VersionVector,
+    cluster: String,
+    hash_algorithm: HashAlgorithm,
+}
+
+// rings saved or sent before the hash algorithm was recorded, all of them used crc16
+#[derive(Deserialize)]
+#[serde(bound = "T: DeserializeOwned")]
+struct RingV0<T: Metadata> {
+    vnodes: Vec<VNode>,
+    nodes: IdHashMap<NodeId, Node<T>>,
+    replication_factor: usize,
+    version: VersionVector,
+    cluster: String,
 }
 
 pub struct RingDescription {
@@ -134,17 +181,127 @@ impl<T: Metadata> Ring<T> {
     }
 
     fn deserialize(bytes: &[u8]) -> Result<Ring<T>, GenericError> {
-        bincode::deserialize(bytes).map_err(|e| format!("Can't deserialize Ring: {:?}", e).into())
+        bincode::deserialize(bytes)
+            .or_else(|e| {
+                // older formats are missing the trailing splits, zones and hash algorithm
+                bincode::deserialize::<RingV2<T>>(bytes)
+                    .map(|v2| Ring {
+                        vnodes: v2.vnodes,
+                        nodes: v2.nodes,
+                        replication_factor: v2.replication_factor,
+                        version: v2.version,
+                        cluster: v2.cluster,
+                        hash_algorithm: v2.hash_algorithm,
+                        zones: v2.zones,
+                        splits: 0,
+                    })
+                    .or_else(|_| {
+                        bincode::deserialize::<RingV1<T>>(bytes).map(|v1| Ring {
+                            vnodes: v1.vnodes,
+                            nodes: v1.nodes,
+                            replication_factor: v1.replication_factor,
+                            version: v1.version,
+                            cluster: v1.cluster,
+                            hash_algorithm: v1.hash_algorithm,
+                            zones: Default::default(),
+                            splits: 0,
+                        })
+                    })
+                    .or_else(|_| {
+                        bincode::deserialize::<RingV0<T>>(bytes).map(|v0| Ring {
+                            vnodes: v0.vnodes,
+                            nodes: v0.nodes,
+                            replication_factor: v0.replication_factor,
+                            version: v0.version,
+                            cluster: v0.cluster,
+                            hash_algorithm: HashAlgorithm::Crc16,
+                            zones: Default::default(),
+                            splits: 0,
+                        })
+                    })
+                    .map_err(|_| e)
+            })
+            .map_err(|e| format!("Can't deserialize Ring: {:?}", e).into())
     }
 
-    fn new(cluster: &str, partitions: u16, replication_factor: u8) -> Self {
+    fn new(
+        cluster: &str,
+        partitions: u16,
+        replication_factor: u8,
+        hash_algorithm: HashAlgorithm,
+    ) -> Self {
         Ring {
             version: Default::default(),
             replication_factor: replication_factor as usize,
             vnodes: vec![Default::default(); partitions as usize],
             nodes: Default::default(),
             cluster: cluster.into(),
+            hash_algorithm: hash_algorithm,
+            zones: Default::default(),
+            splits: 0,
+        }
+    }
+
+    // Splitting keeps the first half of the slots of each vnode in place and moves the
+    // second half to a new vnode numbered after all the existing ones, so vnode v of P
+    // splits into v and v + P. Keys that stay keep their vnode and don't move on disk.
+    fn vnode_for_slot(&self, slot: u16) -> VNodeNo {
+        let partitions = self.vnodes.len();
+        let base = partitions >> self.splits;
+        // use / instead of % to get continuous hash slots for each vnode
+        let index = slot as usize / (HASH_SLOTS as usize / partitions);
+        let mut vnode = index >> self.splits;
+        for level in 0..self.splits as usize {
+            // the most significant bit of the remainder comes from the first split
+            if index & (1 << (self.splits as usize - 1 - level)) != 0 {
+                vnode += base << level;
+            }
+        }
+        vnode as VNodeNo
+    }
+
+    // the inclusive range of slots of the vnode, inverse of vnode_for_slot
+    fn vnode_slots(&self, vnode: VNodeNo) -> (u16, u16) {
+        let partitions = self.vnodes.len();
+        let base = partitions >> self.splits;
+        let vnode = vnode as usize;
+        let mut index = (vnode % base) << self.splits;
+        for level in 0..self.splits as usize {
+            if (vnode / (base << level)) & 1 != 0 {
+                index |= 1 << (self.splits as usize - 1 - level);
+            }
+        }
+        let slots_per_partition = HASH_SLOTS as usize / partitions;
+        (
+            (index * slots_per_partition) as u16,
+            ((index + 1) * slots_per_partition - 1) as u16,
+        )
+    }
+
+    // doubles the vnodes, each new one starts with the same owners as the one it splits from
+    fn split_vnodes(&mut self) {
+        let children = self.vnodes.clone();
+        self.vnodes.extend(children);
+        self.splits += 1;
+    }
+
+    fn split(&mut self, this: NodeId) -> Result<(), GenericError> {
+        if self.vnodes.len() * 2 > HASH_SLOTS as usize {
+            return Err(format!("Can't split {} partitions any further", self.vnodes.len()).into());
+        }
+        if self
+            .vnodes
+            .iter()
+            .any(|vn| vn.owners.values().any(|&s| s != Owner))
+        {
+            return Err("Can't split partitions while the ring is rebalancing".into());
+        }
+        self.split_vnodes();
+        for vn in &mut self.vnodes {
+            vn.version.event(this);
         }
+        self.version.event(this);
+        Ok(())
     }
 
     fn valid_nodes_count(&self) -> usize {
@@ -158,6 +315,41 @@ impl<T: Metadata> Ring<T> {
             .count()
     }
 
+    fn set_zone(&mut self, this: NodeId, node: NodeId, zone: &str) {
+        if self.zones.get(&node).map_or("", |z| z.as_str()) == zone {
+            return;
+        }
+        if zone.is_empty() {
+            self.zones.remove(&node);
+        } else {
+            self.zones.insert(node, zone.into());
+        }
+        if let Some(node) = self.nodes.get_mut(&node) {
+            node.version.event(this);
+        }
+        self.version.event(this);
+    }
+
+    // how many of the other (non retiring) replicas of the vnode share the zone of node,
+    // nodes without a zone never conflict
+    fn zone_conflicts(
+        zones: &IdHashMap<NodeId, String>,
+        vn: &VNode,
+        node: NodeId,
+        skip: NodeId,
+    ) -> usize {
+        let zone = match zones.get(&node) {
+            Some(zone) => zone,
+            None => return 0,
+        };
+        vn.owners
+            .iter()
+            .filter(|&(&n, &s)| {
+                n != node && n != skip && s != Retiring && zones.get(&n) == Some(zone)
+            })
+            .count()
+    }
+
     fn leave_node(&mut self, this: NodeId, leaving: NodeId) -> Result<(), GenericError> {
         if let Some(node) = self.nodes.get_mut(&leaving) {
             if node.status != Leaving {
@@ -262,6 +454,37 @@ impl<T: Metadata> Ring<T> {
         Ok(())
     }
 
+    // node gives up the vnode, a retiring node (if any) takes it back so the vnode
+    // keeps its replicas, otherwise it's left for the next rebalance
+    fn decline_vnode(
+        &mut self,
+        this: NodeId,
+        declined: NodeId,
+        vn_no: VNodeNo,
+    ) -> Result<(), GenericError> {
+        self.version.event(this);
+        let vn = &mut self.vnodes[vn_no as usize];
+        match vn.owners.get(&declined) {
+            Some(&Owner) | Some(&Pending) => (),
+            _ => return Err(format!("{} is not part of vnodes[{}]", declined, vn_no).into()),
+        }
+        vn.owners.remove(&declined);
+        let retiring = vn
+            .owners
+            .iter()
+            .filter(|&(_, &s)| s == Retiring)
+            .map(|(&n, _)| n)
+            .min();
+        if let Some(retiring) = retiring {
+            vn.owners.insert(retiring, Owner);
+        }
+        if !vn.owners.values().any(|&s| s == Pending) {
+            vn.owners.retain(|_, s| *s != Retiring);
+        }
+        vn.version.event(this);
+        Ok(())
+    }
+
     fn replace_node(
         &mut self,
         this: NodeId,
@@ -313,15 +536,33 @@ impl<T: Metadata> Ring<T> {
                 self.cluster, other.cluster
             ).into());
         }
-        if self.vnodes.len() != other.vnodes.len() && !self.vnodes.is_empty() {
+        if self.hash_algorithm != other.hash_algorithm && !self.vnodes.is_empty() {
+            return Err(format!(
+                "Incompatible hash algorithm {:?} != {:?}",
+                self.hash_algorithm, other.hash_algorithm
+            ).into());
+        }
+        if other.vnodes.is_empty() {
+            return Err("Other ring isn't valid".into());
+        }
+        if self.vnodes.len() >> self.splits != other.vnodes.len() >> other.splits
+            && !self.vnodes.is_empty()
+        {
             return Err(format!(
                 "Incompatible partition count {:?} != {:?}",
                 self.vnodes.len(),
                 other.vnodes.len()
             ).into());
         }
-        if other.vnodes.is_empty() {
-            return Err("Other ring isn't valid".into());
+        if !self.vnodes.is_empty() {
+            // a ring that missed splits is split first, its vnodes then compare
+            // with the ones they split into
+            while self.splits < other.splits {
+                self.split_vnodes();
+            }
+            while other.splits < self.splits {
+                other.split_vnodes();
+            }
         }
 
         if self.version.descends(&other.version) {
@@ -343,11 +584,14 @@ impl<T: Metadata> Ring<T> {
         );
         self.version.merge(&other.version);
 
-        // merge nodes
+        // merge nodes, the zone follows the node version
         for (n, other_node) in other.nodes.drain() {
             match self.nodes.entry(n) {
                 HMEntry::Vacant(v) => {
                     v.insert(other_node);
+                    if let Some(zone) = other.zones.remove(&n) {
+                        self.zones.insert(n, zone);
+                    }
                 }
                 HMEntry::Occupied(mut o) => {
                     let node = o.get_mut();
@@ -356,6 +600,10 @@ impl<T: Metadata> Ring<T> {
                     }
                     if other_node.version.descends(&other_node.version) {
                         *node = other_node;
+                        match other.zones.remove(&n) {
+                            Some(zone) => self.zones.insert(n, zone),
+                            None => self.zones.remove(&n),
+                        };
                         continue;
                     }
 
@@ -481,17 +729,21 @@ impl<T: Metadata> Ring<T> {
         // partitions per node
         let vnpn = ((self.vnodes.len() * self.replication_factor) as f64 / node_map.len() as f64)
             .ceil() as usize;
+        let zones = &self.zones;
 
         // 1. complete replicas
         for (vn_no, vn) in self.vnodes.iter_mut().enumerate() {
             let replicas = vn.owners.values().filter(|&&s| s != Retiring).count();
             for _ in replicas..self.replication_factor {
-                // try to find a candidate that is doing less work
+                // try to find a candidate that is doing less work, preferring the ones
+                // in a zone not yet used by the vnode as long as they have room for it
                 if let Some((&node, vns)) = node_map
                     .iter_mut()
                     .filter(|&(n, _)| !vn.owners.contains_key(n))
-                    .min_by_key(|&(_, ref p)| p.len())
-                {
+                    .min_by_key(|&(&n, ref p)| {
+                        let conflicts = Self::zone_conflicts(zones, vn, n, n);
+                        (p.len() >= vnpn, conflicts, p.len(), n)
+                    }) {
                     assert!(vns.insert(vn_no));
                     assert!(vn.owners.insert(node, Pending).is_none());
                     continue;
@@ -521,20 +773,38 @@ impl<T: Metadata> Ring<T> {
             }
         }
 
-        // 2. robin-hood
+        // 2. robin-hood, also moving replicas that share a zone to unused zones
         for (vn_no, vn) in self.vnodes.iter_mut().enumerate() {
-            let doing_much: IdHashSet<_> = vn
+            let mut moving: Vec<_> = vn
                 .owners
                 .iter()
-                .filter(|&(n, &s)| s != Retiring && node_map.get(n).unwrap().len() > vnpn)
-                .map(|(n, _)| *n)
-                .collect();
-            let candidates: IdHashSet<_> = node_map
-                .iter()
-                .filter(|&(n, vns)| vns.len() < vnpn && !vn.owners.contains_key(n))
-                .map(|(n, _)| *n)
+                .filter(|&(&n, &s)| {
+                    s != Retiring
+                        && (node_map.get(&n).unwrap().len() > vnpn
+                            || Self::zone_conflicts(zones, vn, n, n) != 0)
+                })
+                .map(|(&n, _)| n)
                 .collect();
-            for (from, to) in doing_much.into_iter().zip(candidates) {
+            moving.sort();
+            for from in moving {
+                let from_conflicts = Self::zone_conflicts(zones, vn, from, from);
+                let to = node_map
+                    .iter()
+                    .filter(|&(n, vns)| vns.len() < vnpn && !vn.owners.contains_key(n))
+                    .map(|(&n, vns)| (Self::zone_conflicts(zones, vn, n, from), vns.len(), n))
+                    .min();
+                let to = match to {
+                    // overloaded nodes can hand over to a zone as good as theirs,
+                    // zone conflicts only move to a strictly better one
+                    Some((conflicts, _, to))
+                        if conflicts < from_conflicts
+                            || (conflicts == from_conflicts
+                                && node_map.get(&from).unwrap().len() > vnpn) =>
+                    {
+                        to
+                    }
+                    _ => continue,
+                };
                 assert!(vn.owners.insert(to, Pending).is_none());
                 assert!(vn.owners.insert(from, Retiring).is_some());
                 assert!(node_map.get_mut(&from).unwrap().remove(&vn_no));
@@ -640,13 +910,19 @@ impl<T: Metadata> DHT<T> {
         assert!(replication_factor <= 6, "Replication factor must be <= 6");
 
         let dht = Self::new(fabric, config);
-        let ring = Ring::new(&config.cluster_name, partitions, replication_factor);
+        let ring = Ring::new(
+            &config.cluster_name,
+            partitions,
+            replication_factor,
+            config.hash_algorithm,
+        );
         dht.inner.write().unwrap().ring = ring;
 
         if let Some(old_node) = old_node {
-            dht.replace_node(old_node, dht.node, addr, meta).unwrap();
+            dht.replace_node(old_node, dht.node, addr, &config.zone, meta)
+                .unwrap();
         } else {
-            dht.join_node(dht.node, addr, meta).unwrap();
+            dht.join_node(dht.node, addr, &config.zone, meta).unwrap();
         }
         dht.rebalance().unwrap();
 
@@ -666,9 +942,10 @@ impl<T: Metadata> DHT<T> {
         dht.inner.write().unwrap().ring = ring;
 
         if let Some(old_node) = old_node {
-            dht.replace_node(old_node, dht.node, addr, meta).unwrap();
+            dht.replace_node(old_node, dht.node, addr, &config.zone, meta)
+                .unwrap();
         } else {
-            dht.join_node(dht.node, addr, meta).unwrap();
+            dht.join_node(dht.node, addr, &config.zone, meta).unwrap();
         }
 
         Ok(dht)
@@ -678,26 +955,26 @@ impl<T: Metadata> DHT<T> {
         fabric: Arc<Fabric>,
         config: &Config,
         meta: T,
-        seeds: &[SocketAddr],
+        seeds: &[HostAddr],
         old_node: Option<NodeId>,
     ) -> Result<DHT<T>, GenericError> {
         let addr = fabric.addr();
         let dht = Self::new(fabric.clone(), config);
+        dht.inner.write().unwrap().seeds = seeds.to_vec();
 
         info!("Registering seeds {:?}", seeds);
-        for &seed in seeds {
-            if seed != addr {
-                fabric.register_seed(seed);
-            }
-        }
+        Self::register_seeds(&fabric, seeds);
 
         info!("Connecting to seeds");
         let mut connections = fabric.connections();
-        for _ in 0..10 {
+        for i in 0..10 {
             if !connections.is_empty() {
                 break;
             }
             thread::sleep(Duration::from_millis(500));
+            if i % 2 == 1 {
+                Self::register_seeds(&fabric, seeds);
+            }
             connections = fabric.connections();
         }
         if connections.is_empty() {
@@ -714,22 +991,41 @@ impl<T: Metadata> DHT<T> {
         }
 
         if let Some(old_node) = old_node {
-            dht.replace_node(old_node, dht.node, addr, meta).unwrap();
+            dht.replace_node(old_node, dht.node, addr, &config.zone, meta)
+                .unwrap();
         } else {
-            dht.join_node(dht.node, addr, meta).unwrap();
+            dht.join_node(dht.node, addr, &config.zone, meta).unwrap();
         }
         Ok(dht)
     }
 
+    // resolves the seeds (again) and connects to the addresses that aren't this node
+    fn register_seeds(fabric: &Fabric, seeds: &[HostAddr]) {
+        let addr = fabric.addr();
+        for seed in seeds {
+            match seed.resolve() {
+                Ok(addrs) => {
+                    let addrs: Vec<_> = addrs.into_iter().filter(|&a| a != addr).collect();
+                    if !addrs.is_empty() {
+                        fabric.register_seed(addrs);
+                    }
+                }
+                Err(e) => warn!("Can't resolve seed {:?}: {}", seed, e),
+            }
+        }
+    }
+
     fn new(fabric: Arc<Fabric>, config: &Config) -> DHT<T> {
         let inner = Arc::new(RwLock::new(Inner {
             node: fabric.node(),
-            ring: Ring::new(&config.cluster_name, 0, 0),
+            ring: Ring::new(&config.cluster_name, 0, 0, config.hash_algorithm),
             callback: Default::default(),
             fabric: fabric.clone(),
             next_req_broadcast: Instant::now(),
             sync_aae: config.dht_sync_aae,
             sync_on_connect: config.dht_sync_on_connect,
+            seeds: Vec::new(),
+            next_seed_retry: Instant::now(),
         }));
 
         // TODO: move this to Database
@@ -838,12 +1134,31 @@ impl<T: Metadata> DHT<T> {
 
     pub fn handler_tick(&self, time: Instant) {
         let r_inner = self.inner.read().unwrap();
-        if r_inner.sync_aae && time >= r_inner.next_req_broadcast {
+        let aae = r_inner.sync_aae && time >= r_inner.next_req_broadcast;
+        if aae {
             trace!("Triggered AAE");
             Self::broadcast_req(&*r_inner);
-            drop(r_inner);
-            self.inner.write().unwrap().next_req_broadcast +=
-                Duration::from_millis(DHT_AAE_TRIGGER_INTERVAL_MS);
+        }
+        // an isolated node may be holding outdated seed addresses
+        let seeds_retry = !r_inner.seeds.is_empty() && time >= r_inner.next_seed_retry;
+        let seeds = if seeds_retry && r_inner.fabric.connections().is_empty() {
+            Some((r_inner.fabric.clone(), r_inner.seeds.clone()))
+        } else {
+            None
+        };
+        drop(r_inner);
+        if let Some((fabric, seeds)) = seeds {
+            info!("No connections to other nodes, contacting the seeds again");
+            Self::register_seeds(&fabric, &seeds);
+        }
+        if aae || seeds_retry {
+            let mut w_inner = self.inner.write().unwrap();
+            if aae {
+                w_inner.next_req_broadcast += Duration::from_millis(DHT_AAE_TRIGGER_INTERVAL_MS);
+            }
+            if seeds_retry {
+                w_inner.next_seed_retry = time + Duration::from_millis(DHT_SEED_RETRY_INTERVAL_MS);
+            }
         }
     }
 
@@ -864,9 +1179,20 @@ impl<T: Metadata> DHT<T> {
         self.inner.read().unwrap().ring.replication_factor
     }
 
+    /// The algorithm the cluster was initialized with
+    pub fn hash_algorithm(&self) -> HashAlgorithm {
+        self.inner.read().unwrap().ring.hash_algorithm
+    }
+
+    pub fn key_slot(&self, key: &[u8]) -> u16 {
+        self.hash_algorithm().hash_slot(key)
+    }
+
     pub fn key_vnode(&self, key: &[u8]) -> VNodeNo {
-        // use / instead of % to get continuous hash slots for each vnode
-        (hash_slot(key) / (HASH_SLOTS / self.partitions() as VNodeNo)) as VNodeNo
+        let inner = self.inner.read().unwrap();
+        inner
+            .ring
+            .vnode_for_slot(inner.ring.hash_algorithm.hash_slot(key))
     }
 
     pub fn vnodes_for_node(&self, node: NodeId) -> (Vec<VNodeNo>, Vec<VNodeNo>) {
@@ -950,8 +1276,40 @@ impl<T: Metadata> DHT<T> {
             .collect()
     }
 
+    /// Metadata of the members, like members() but with the meta instead of the fabric addr
+    pub fn members_meta(&self) -> IdHashMap<NodeId, T> {
+        let inner = self.inner.read().unwrap();
+        inner
+            .ring
+            .nodes
+            .iter()
+            .filter(|&(_, v)| v.status != Invalid)
+            .map(|(k, v)| (k.clone(), v.meta.clone()))
+            .collect()
+    }
+
+    /// Owner, pending and retiring nodes of each vnode, from a single read of the ring
+    pub fn vnodes_layout(&self) -> Vec<(Vec<NodeId>, Vec<NodeId>, Vec<NodeId>)> {
+        let inner = self.inner.read().unwrap();
+        inner
+            .ring
+            .vnodes
+            .iter()
+            .map(|vn| {
+                let mut layout = (Vec::new(), Vec::new(), Vec::new());
+                for (&node, &status) in vn.owners.iter() {
+                    match status {
+                        Owner => layout.0.push(node),
+                        Pending => layout.1.push(node),
+                        Retiring => layout.2.push(node),
+                    }
+                }
+                layout
+            })
+            .collect()
+    }
+
     pub fn slots(&self) -> BTreeMap<(u16, u16), Vec<(NodeId, (SocketAddr, T))>> {
-        let slots_per_partition = HASH_SLOTS / self.partitions() as u16;
         let mut result = BTreeMap::new();
         let inner = self.inner.read().unwrap();
         for (vn_no, vn) in inner.ring.vnodes.iter().enumerate() {
@@ -963,14 +1321,7 @@ impl<T: Metadata> DHT<T> {
                 let node = inner.ring.nodes.get(&node_id).unwrap();
                 members.push((node_id, (node.addr, node.meta.clone())));
             }
-            let vn_no = vn_no as u16;
-            result.insert(
-                (
-                    vn_no * slots_per_partition,
-                    (vn_no + 1) * slots_per_partition - 1,
-                ),
-                members,
-            );
+            result.insert(inner.ring.vnode_slots(vn_no as VNodeNo), members);
         }
         result
     }
@@ -983,6 +1334,16 @@ impl<T: Metadata> DHT<T> {
         })
     }
 
+    /// Doubles the partitions, the second half of the slots of each vnode moves to a new
+    /// vnode with the same replicas. Refused while the ring is rebalancing.
+    pub fn split(&self) -> Result<(), GenericError> {
+        info!("Splitting ring partitions");
+        self.propose(|mut ring| {
+            ring.split(self.node)?;
+            Ok(ring)
+        })
+    }
+
     #[cfg(test)]
     pub fn finish_rebalance(&self) -> Result<(), GenericError> {
         info!("Finish Rebalancing ring");
@@ -1000,10 +1361,17 @@ impl<T: Metadata> DHT<T> {
         })
     }
 
-    pub fn join_node(&self, node: NodeId, addr: SocketAddr, meta: T) -> Result<(), GenericError> {
-        info!("Joining node {}", node);
+    pub fn join_node(
+        &self,
+        node: NodeId,
+        addr: SocketAddr,
+        zone: &str,
+        meta: T,
+    ) -> Result<(), GenericError> {
+        info!("Joining node {} zone {:?}", node, zone);
         self.propose(|mut ring| {
             ring.join_node(self.node, node, addr, meta)?;
+            ring.set_zone(self.node, node, zone);
             Ok(ring)
         })
     }
@@ -1013,11 +1381,13 @@ impl<T: Metadata> DHT<T> {
         old_node: NodeId,
         node: NodeId,
         addr: SocketAddr,
+        zone: &str,
         meta: T,
     ) -> Result<(), GenericError> {
-        info!("Replacing node {} with {}", old_node, node);
+        info!("Replacing node {} with {} zone {:?}", old_node, node, zone);
         self.propose(|mut ring| {
             ring.replace_node(self.node, old_node, node, addr, meta)?;
+            ring.set_zone(self.node, node, zone);
             Ok(ring)
         })
     }
@@ -1038,6 +1408,15 @@ impl<T: Metadata> DHT<T> {
         })
     }
 
+    /// The node gives up its ownership of the vnode, see Ring::decline_vnode
+    pub fn decline_vnode(&self, node: NodeId, vnode: VNodeNo) -> Result<(), GenericError> {
+        info!("Node {} declining vnode {}", node, vnode);
+        self.propose(|mut ring| {
+            ring.decline_vnode(self.node, node, vnode)?;
+            Ok(ring)
+        })
+    }
+
     fn propose<C>(&self, proposal: C) -> Result<(), GenericError>
     where
         C: FnOnce(Ring<T>) -> Result<Ring<T>, GenericError>,
@@ -1062,9 +1441,94 @@ mod tests {
     use utils::join_u64;
     use utils::sleep_ms;
 
+    #[test]
+    fn test_ring_hash_algorithm() {
+        let mut ring = Ring::new("", 64, 3, HashAlgorithm::Crc16);
+        let addr = "127.0.0.1:1999".parse().unwrap();
+        ring.join_node(0, join_u64(0, 1), addr, ()).unwrap();
+
+        // rings serialized before the hash algorithm was recorded
+        let v0 = bincode::serialize(&(
+            &ring.vnodes,
+            &ring.nodes,
+            ring.replication_factor,
+            &ring.version,
+            &ring.cluster,
+        )).unwrap();
+        let restored = Ring::<()>::deserialize(&v0).unwrap();
+        assert_eq!(restored.hash_algorithm, HashAlgorithm::Crc16);
+        assert_eq!(restored.nodes.len(), 1);
+        assert!(Ring::<()>::deserialize(&v0[..v0.len() - 1]).is_err());
+
+        let mut other = Ring::new("", 64, 3, HashAlgorithm::XxHash);
+        other.join_node(0, join_u64(1, 1), addr, ()).unwrap();
+        let other = Ring::deserialize(&Ring::serialize(&other).unwrap()).unwrap();
+        assert_eq!(other.hash_algorithm, HashAlgorithm::XxHash);
+        assert!(ring.merge(other).is_err());
+    }
+
+    #[test]
+    fn test_ring_split() {
+        let mut ring = Ring::new("", 64, 3, HashAlgorithm::Crc16);
+        let addr = "127.0.0.1:1999".parse().unwrap();
+        ring.join_node(0, join_u64(0, 1), addr, ()).unwrap();
+        ring.rebalance(0).unwrap();
+        ring.finish_rebalance(0).unwrap();
+        let saved = ring.clone();
+
+        ring.split(0).unwrap();
+        ring.split(0).unwrap();
+        assert_eq!(ring.vnodes.len(), 256);
+        for slot in 0..HASH_SLOTS {
+            let vnode = ring.vnode_for_slot(slot);
+            // the new vnodes are numbered after the ones they split from
+            assert_eq!(vnode % 64, saved.vnode_for_slot(slot));
+            let (start, end) = ring.vnode_slots(vnode);
+            assert!(start <= slot && slot <= end);
+            assert_eq!(end - start + 1, HASH_SLOTS / 256);
+        }
+        // the 4 quarters of vnode 0
+        assert_eq!(ring.vnode_for_slot(0), 0);
+        assert_eq!(ring.vnode_for_slot(64), 128);
+        assert_eq!(ring.vnode_for_slot(128), 64);
+        assert_eq!(ring.vnode_for_slot(192), 192);
+        assert_eq!(ring.vnode_for_slot(256), 1);
+        assert_eq!(ring.vnodes[192].owners, saved.vnodes[0].owners);
+
+        // rings saved before the splits were recorded
+        let v2 = bincode::serialize(&(
+            &saved.vnodes,
+            &saved.nodes,
+            saved.replication_factor,
+            &saved.version,
+            &saved.cluster,
+            saved.hash_algorithm,
+            &saved.zones,
+        )).unwrap();
+        let restored = Ring::<()>::deserialize(&v2).unwrap();
+        assert_eq!(restored.splits, 0);
+        assert_eq!(restored.vnodes.len(), 64);
+
+        // rings that missed the splits are split while merging
+        let mut other = saved.clone();
+        assert!(other.merge(ring.clone()).unwrap());
+        assert_eq!(other.vnodes.len(), 256);
+        let mut other = ring.clone();
+        assert!(!other.merge(saved.clone()).unwrap());
+        assert_eq!(other.vnodes.len(), 256);
+        let mut other = Ring::new("", 128, 3, HashAlgorithm::Crc16);
+        other.join_node(0, join_u64(1, 1), addr, ()).unwrap();
+        assert!(other.merge(ring.clone()).is_err());
+
+        let mut rebalancing = saved.clone();
+        rebalancing.join_node(0, join_u64(1, 1), addr, ()).unwrap();
+        rebalancing.rebalance(0).unwrap();
+        assert!(rebalancing.split(0).is_err());
+    }
+
     #[test]
     fn test_ring_dup_join() {
-        let mut ring = Ring::new("", 64, 3);
+        let mut ring = Ring::new("", 64, 3, HashAlgorithm::Crc16);
         let addr = "127.0.0.1:1999".parse().unwrap();
         ring.join_node(0, join_u64(0, 1), addr, ()).unwrap();
         ring.join_node(0, join_u64(1, 1), addr, ()).unwrap();
@@ -1086,7 +1550,7 @@ mod tests {
 
     #[test]
     fn test_ring_dup_replace() {
-        let mut ring = Ring::new("", 64, 3);
+        let mut ring = Ring::new("", 64, 3, HashAlgorithm::Crc16);
         let addr = "127.0.0.1:1999".parse().unwrap();
         ring.join_node(0, join_u64(0, 1), addr, ()).unwrap();
         ring.join_node(0, join_u64(1, 1), addr, ()).unwrap();
@@ -1133,7 +1597,8 @@ mod tests {
         let dht1 = DHT::init(fabric1, &config1, (), RingDescription::new(2, 32), None).unwrap();
 
         let fabric2 = Arc::new(Fabric::new(join_u64(1, 0), &config2).unwrap());
-        let dht2 = DHT::join_cluster(fabric2, &config2, (), &[config1.fabric_addr], None).unwrap();
+        let seeds = [config1.fabric_addr.into()];
+        let dht2 = DHT::join_cluster(fabric2, &config2, (), &seeds, None).unwrap();
 
         sleep_ms(100);
         for dht in &[&dht1, &dht2] {
@@ -1210,7 +1675,8 @@ mod tests {
         let _dht1 = DHT::init(fabric1, &config1, (), RingDescription::new(2, 32), None).unwrap();
 
         let fabric2 = Arc::new(Fabric::new(2, &config2).unwrap());
-        let _dht2 = DHT::join_cluster(fabric2, &config2, (), &[config1.fabric_addr], None).unwrap();
+        let seeds = [config1.fabric_addr.into()];
+        let _dht2 = DHT::join_cluster(fabric2, &config2, (), &seeds, None).unwrap();
 
         sleep_ms(100);
     }
@@ -1231,8 +1697,8 @@ mod tests {
         let dht1 = DHT::init(fabric1, &config1, (), RingDescription::new(2, 32), None).unwrap();
 
         let fabric2 = Arc::new(Fabric::new(join_u64(1, 0), &config2).unwrap());
-        let _dht2 =
-            DHT::join_cluster(fabric2.clone(), &config2, (), &[config1.fabric_addr], None).unwrap();
+        let seeds = [config1.fabric_addr.into()];
+        let _dht2 = DHT::join_cluster(fabric2.clone(), &config2, (), &seeds, None).unwrap();
 
         sleep_ms(100);
 
@@ -1264,7 +1730,9 @@ mod tests {
         let addr = "0.0.0.0:0".parse().unwrap();
         for _ in 0..1_000 {
             let partitions = 32;
-            let mut ring = Ring::new("", partitions as u16, 1 + thread_rng().gen::<u8>() % 4);
+            let replication_factor = 1 + thread_rng().gen::<u8>() % 4;
+            let mut ring =
+                Ring::new("", partitions as u16, replication_factor, HashAlgorithm::Crc16);
             for i in 0..1 + thread_rng().gen::<u64>() % partitions as u64 {
                 ring.join_node(0, join_u64(i as _, 0), addr, ()).unwrap();
             }
@@ -1287,7 +1755,9 @@ mod tests {
         let addr = "0.0.0.0:0".parse().unwrap();
         for _ in 0..1_000 {
             let partitions = 32;
-            let mut ring = Ring::new("", partitions as u16, 1 + thread_rng().gen::<u8>() % 4);
+            let replication_factor = 1 + thread_rng().gen::<u8>() % 4;
+            let mut ring =
+                Ring::new("", partitions as u16, replication_factor, HashAlgorithm::Crc16);
             for i in 0..1 + thread_rng().gen::<u64>() % partitions as u64 {
                 ring.join_node(0, join_u64(i as _, 0), addr, ()).unwrap();
             }
@@ -1303,4 +1773,54 @@ mod tests {
             ring.finish_rebalance(0).unwrap();
         }
     }
+
+    #[test]
+    fn test_rebalance_zones() {
+        let _ = env_logger::try_init();
+        let addr = "0.0.0.0:0".parse().unwrap();
+        let zones_of = |ring: &Ring<()>, vn: &VNode| {
+            let mut zones: Vec<_> = vn.owners.keys().map(|n| ring.zones[n].clone()).collect();
+            zones.sort();
+            zones.dedup();
+            zones.len()
+        };
+
+        // enough zones, every vnode gets one replica in each
+        let mut ring = Ring::new("", 32, 3, HashAlgorithm::Crc16);
+        for i in 0..6 {
+            ring.join_node(0, join_u64(i, 0), addr, ()).unwrap();
+            ring.set_zone(0, join_u64(i, 0), &format!("zone{}", i % 3));
+        }
+        ring.rebalance(0).unwrap();
+        ring.finish_rebalance(0).unwrap();
+        for vn in &ring.vnodes {
+            assert_eq!(zones_of(&ring, vn), 3);
+        }
+
+        // the zones survive the serialization, older rings have none
+        let restored = Ring::<()>::deserialize(&Ring::serialize(&ring).unwrap()).unwrap();
+        assert_eq!(restored.zones, ring.zones);
+        let v1 = bincode::serialize(&(
+            &ring.vnodes,
+            &ring.nodes,
+            ring.replication_factor,
+            &ring.version,
+            &ring.cluster,
+            ring.hash_algorithm,
+        )).unwrap();
+        assert!(Ring::<()>::deserialize(&v1).unwrap().zones.is_empty());
+
+        // not enough zones, replicas share them but still use all of them
+        let mut ring = Ring::new("", 32, 3, HashAlgorithm::Crc16);
+        for i in 0..4 {
+            ring.join_node(0, join_u64(i, 0), addr, ()).unwrap();
+            ring.set_zone(0, join_u64(i, 0), &format!("zone{}", i % 2));
+        }
+        ring.rebalance(0).unwrap();
+        ring.finish_rebalance(0).unwrap();
+        for vn in &ring.vnodes {
+            assert_eq!(vn.owners.len(), 3);
+            assert_eq!(zones_of(&ring, vn), 2);
+        }
+    }
 }
@@ -1,44 +1,90 @@
 use std::collections::hash_map::Entry as HMEntry;
+use std::collections::VecDeque;
+use std::io::{Read, Write};
 use std::net::SocketAddr;
+use std::path::Path;
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::{mpsc, Arc, RwLock};
+use std::sync::{mpsc, Arc, Mutex, RwLock};
 use std::time::Duration;
-use std::{io, thread};
+use std::{fs, io, mem, thread};
 
 use bincode;
-use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use byteorder::{ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
 use bytes::{BufMut, Bytes, BytesMut};
 use linear_map::LinearMap;
-use rand::{thread_rng, Rng};
+use rand::{thread_rng, OsRng, Rng};
+use sha2::Sha256;
+use snap;
 
-use futures::future::Either;
+use futures::future::{self, Either};
 use futures::sync::mpsc as fmpsc;
 use futures::sync::oneshot as foneshot;
-use futures::{Future, Sink, Stream};
+use futures::{Async, AsyncSink, Future, Poll, Sink, StartSend, Stream};
 use tokio_core as tokio;
 use tokio_io::codec;
 use tokio_io::{io as tokio_io, AsyncRead};
 
-use config::Config;
+use config::{Config, FabricCompression};
 use database::NodeId;
 pub use fabric_msg::*;
+use hmac::{Hmac, Mac};
+use lz4;
+use metrics::{self, Meter};
+use storage::StorageFormat;
 use utils::{into_io_error, GenericError, IdHashMap};
 
-// u32(le) payload len + bincode payload
-struct FramedBincodeCodec;
+// With Framing::Plain u32(le) payload len + payload, like older versions.
+// With Framing::Flagged u32(le) frame len + u8 compression + payload (possibly compressed)
+// or u32(le) frame len + FRAME_BATCH + the frames of 2 or more msgs.
+// the payload is encoded with the fabric_format, both agreed on by the handshake
+struct FramedCodec {
+    format: StorageFormat,
+    framing: Framing,
+    // msgs of the last batch frame not returned yet
+    batched: VecDeque<FabricMsg>,
+}
+
+/// How the frames of a connection are laid out, see FramedCodec
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Framing {
+    Plain,
+    // allows compression and batches, used if both nodes want it
+    Flagged,
+}
+
+const FRAME_UNCOMPRESSED: u8 = 0;
+const FRAME_SNAPPY: u8 = 1;
+const FRAME_BATCH: u8 = 2;
+const FRAME_LZ4: u8 = 3;
+// compressed frames from peers inflating to more than this are refused
+const FRAME_DECOMPRESSED_MAX: usize = 256 * 1024 * 1024;
 
-impl codec::Decoder for FramedBincodeCodec {
+impl codec::Decoder for FramedCodec {
     type Item = FabricMsg;
     type Error = io::Error;
 
     fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<Self::Item>> {
+        if let Some(msg) = self.batched.pop_front() {
+            return Ok(Some(msg));
+        }
         let (consumed, result) = {
             let mut bytes: &[u8] = &*src;
-            if let Ok(msg_len) = bytes.read_u32::<LittleEndian>() {
-                if bytes.len() >= msg_len as usize {
-                    match bincode::deserialize_from(&mut bytes) {
-                        Ok(v) => (4 + msg_len as usize, Ok(Some(v))),
-                        Err(e) => (0, Err(into_io_error(e))),
+            if let Ok(frame_len) = bytes.read_u32::<LittleEndian>() {
+                if bytes.len() >= frame_len as usize {
+                    let frame = &bytes[..frame_len as usize];
+                    let result = match self.framing {
+                        Framing::Plain => Self::deserialize_payload(self.format, frame).map(Some),
+                        Framing::Flagged if frame.first() == Some(&FRAME_BATCH) => {
+                            Self::deserialize_batch(self.format, &frame[1..]).map(|msgs| {
+                                self.batched = msgs;
+                                self.batched.pop_front()
+                            })
+                        }
+                        Framing::Flagged => Self::deserialize(self.format, frame).map(Some),
+                    };
+                    match result {
+                        Ok(v) => (4 + frame_len as usize, Ok(v)),
+                        Err(e) => (0, Err(e)),
                     }
                 } else {
                     (0, Ok(None))
@@ -52,17 +98,160 @@ impl codec::Decoder for FramedBincodeCodec {
     }
 }
 
-impl FramedBincodeCodec {
-    fn serialize(item: FabricMsgRef) -> Bytes {
-        let item_size = bincode::serialized_size(&item).unwrap();
-        let mut dst = BytesMut::with_capacity(item_size as usize + 4);
-        dst.put_u32_le(item_size as u32);
+impl FramedCodec {
+    fn new(format: StorageFormat, framing: Framing) -> Self {
+        FramedCodec {
+            format: format,
+            framing: framing,
+            batched: Default::default(),
+        }
+    }
+
+    fn deserialize_batch(
+        format: StorageFormat,
+        mut payload: &[u8],
+    ) -> io::Result<VecDeque<FabricMsg>> {
+        let mut msgs = VecDeque::new();
+        while !payload.is_empty() {
+            let frame_len = payload.read_u32::<LittleEndian>()? as usize;
+            if payload.len() < frame_len {
+                return Err(io::ErrorKind::InvalidData.into());
+            }
+            msgs.push_back(Self::deserialize(format, &payload[..frame_len])?);
+            payload = &payload[frame_len..];
+        }
+        if msgs.len() < 2 {
+            return Err(io::ErrorKind::InvalidData.into());
+        }
+        Ok(msgs)
+    }
+
+    fn deserialize(format: StorageFormat, frame: &[u8]) -> io::Result<FabricMsg> {
+        let (&compression, payload) = frame
+            .split_first()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidData))?;
+        let decompressed;
+        let payload = match compression {
+            FRAME_UNCOMPRESSED => payload,
+            FRAME_SNAPPY => {
+                check_decompressed_len(snap::decompress_len(payload).map_err(into_io_error)?)?;
+                decompressed = snap::Decoder::new()
+                    .decompress_vec(payload)
+                    .map_err(into_io_error)?;
+                &decompressed[..]
+            }
+            FRAME_LZ4 => {
+                decompressed = lz4_decompress(payload)?;
+                &decompressed[..]
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Unknown fabric frame compression {}", compression),
+                ))
+            }
+        };
+        Self::deserialize_payload(format, payload)
+    }
+
+    fn deserialize_payload(format: StorageFormat, payload: &[u8]) -> io::Result<FabricMsg> {
+        format
+            .deserialize(payload)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    fn serialize(
+        item: FabricMsgRef,
+        format: StorageFormat,
+        framing: Framing,
+        compression: FabricCompression,
+        threshold: usize,
+    ) -> Bytes {
+        // the msgpack size is only known once serialized
+        let (item_size, serialized) = match format {
+            StorageFormat::Bincode => (bincode::serialized_size(&item).unwrap() as usize, None),
+            StorageFormat::MsgPack => {
+                let payload = format.serialize(&item).unwrap();
+                (payload.len(), Some(payload))
+            }
+        };
+        // plain frames don't have the compression byte
+        let header_len = match framing {
+            Framing::Plain => 4,
+            Framing::Flagged => 5,
+        };
+        // crud msgs are small and latency sensitive, never worth compressing
+        let compress = match (item.get_type(), framing) {
+            (FabricMsgType::Crud, _) | (_, Framing::Plain) => false,
+            _ => compression != FabricCompression::None && item_size >= threshold,
+        };
+        if compress || serialized.is_some() {
+            let payload = serialized.unwrap_or_else(|| format.serialize(&item).unwrap());
+            let (compression, payload) = if compress {
+                let (flag, compressed) = match compression {
+                    FabricCompression::Lz4 => (FRAME_LZ4, lz4_compress(&payload)),
+                    _ => (
+                        FRAME_SNAPPY,
+                        snap::Encoder::new().compress_vec(&payload).unwrap(),
+                    ),
+                };
+                // incompressible payloads are sent as is
+                if compressed.len() < payload.len() {
+                    (flag, compressed)
+                } else {
+                    (FRAME_UNCOMPRESSED, payload)
+                }
+            } else {
+                (FRAME_UNCOMPRESSED, payload)
+            };
+            let mut dst = BytesMut::with_capacity(payload.len() + header_len);
+            dst.put_u32_le((payload.len() + header_len - 4) as u32);
+            if framing == Framing::Flagged {
+                dst.put_u8(compression);
+            }
+            dst.put_slice(&payload);
+            return dst.into();
+        }
+        // bincode is serialized in place, without an intermediate buffer
+        let mut dst = BytesMut::with_capacity(item_size + header_len);
+        dst.put_u32_le((item_size + header_len - 4) as u32);
+        if framing == Framing::Flagged {
+            dst.put_u8(FRAME_UNCOMPRESSED);
+        }
         bincode::serialize_into(&mut (&mut dst).writer(), &item).unwrap();
         dst.into()
     }
 }
 
-impl codec::Encoder for FramedBincodeCodec {
+fn check_decompressed_len(len: usize) -> io::Result<()> {
+    if len > FRAME_DECOMPRESSED_MAX {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Fabric frame inflating to {} bytes", len),
+        ));
+    }
+    Ok(())
+}
+
+// a lz4 block after the u32(le) uncompressed len, as blocks don't record it
+fn lz4_compress(payload: &[u8]) -> Vec<u8> {
+    let mut compressed = Vec::with_capacity(4 + payload.len());
+    compressed.write_u32::<LittleEndian>(payload.len() as u32).unwrap();
+    assert!(lz4::compress_block(payload, &mut compressed), "Can't lz4 compress");
+    compressed
+}
+
+fn lz4_decompress(compressed: &[u8]) -> io::Result<Vec<u8>> {
+    if compressed.len() < 4 {
+        return Err(io::ErrorKind::InvalidData.into());
+    }
+    let len = LittleEndian::read_u32(&compressed[..4]) as usize;
+    check_decompressed_len(len)?;
+    lz4::decompress_block(&compressed[4..], len)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Invalid lz4 fabric frame"))
+}
+
+impl codec::Encoder for FramedCodec {
     type Item = Bytes;
     type Error = io::Error;
 
@@ -76,20 +265,249 @@ impl codec::Encoder for FramedBincodeCodec {
 pub type FabricMsgFn = Box<Fn(NodeId, FabricMsg) + Sync + Send>;
 pub type FabricConFn = Box<Fn(NodeId) + Sync + Send>;
 
-type SenderChan = fmpsc::UnboundedSender<Bytes>;
+// a serialized msg, queued to be written by the connection
+struct QueuedFrame {
+    frame: Bytes,
+    // crud msgs are latency sensitive and never batched
+    batchable: bool,
+    // for send_msg_confirmed, who to notify once it's flushed
+    confirm: Option<foneshot::Sender<()>>,
+}
+
+// the channel is unbounded but the queued messages are counted,
+// so send_msg can refuse to queue more than fabric_send_queue_max
+struct SenderChan {
+    chan: fmpsc::UnboundedSender<QueuedFrame>,
+    queued: Arc<AtomicUsize>,
+    // the frames are serialized for the connection's framing
+    framing: Framing,
+}
+
+// Picks the connection to queue a msg of the type into, if any has room.
+// Crud msgs go to the least loaded connection, preferring the oldest one on ties,
+// other msgs to a random connection other than the oldest, unless it's the only one.
+fn select_connection(
+    connections: &[(usize, SenderChan)],
+    msg_type: FabricMsgType,
+    queue_max: usize,
+) -> Option<&(usize, SenderChan)> {
+    let has_room =
+        |&&(_, ref c): &&(usize, SenderChan)| c.queued.load(Ordering::Relaxed) < queue_max;
+    if let FabricMsgType::Crud = msg_type {
+        return connections
+            .iter()
+            .filter(has_room)
+            .min_by_key(|&&(id, ref c)| (c.queued.load(Ordering::Relaxed), id));
+    }
+    let latency_id = if connections.len() > 1 {
+        connections.iter().map(|&(id, _)| id).min()
+    } else {
+        None
+    };
+    let bulk: Vec<_> = connections
+        .iter()
+        .filter(|&&(id, _)| Some(id) != latency_id)
+        .filter(has_room)
+        .collect();
+    thread_rng().choose(&bulk).cloned()
+}
+
+// Wraps the socket sink, coalescing batchable frames into batch frames
+// of up to batch_max bytes (0 disables it). A batch is written once full
+// or when the writer runs out of queued frames, so it never waits for more.
+// It also completes the confirmations of the frames once the sink reports
+// them flushed. If the connection drops first the pending confirmations
+// are dropped, failing their receivers.
+struct FabricSink<S> {
+    inner: S,
+    pending: Vec<foneshot::Sender<()>>,
+    batch_max: usize,
+    // frames of the batch being built, after a placeholder frame header
+    batch: BytesMut,
+    batch_len: usize,
+    // a complete batch that the inner sink didn't take yet
+    batch_ready: Option<Bytes>,
+}
+
+impl<S: Sink<SinkItem = Bytes>> FabricSink<S> {
+    fn new(inner: S, batch_max: usize) -> Self {
+        FabricSink {
+            inner: inner,
+            pending: Vec::new(),
+            batch_max: batch_max,
+            batch: BytesMut::new(),
+            batch_len: 0,
+            batch_ready: None,
+        }
+    }
+
+    fn flush_batch(&mut self) -> Poll<(), S::SinkError> {
+        if self.batch_ready.is_none() && self.batch_len != 0 {
+            let mut batch = mem::replace(&mut self.batch, BytesMut::new());
+            if self.batch_len == 1 {
+                // a lonely frame goes as is
+                batch.split_to(5);
+            } else {
+                let frame_len = batch.len() as u32 - 4;
+                LittleEndian::write_u32(&mut batch[..4], frame_len);
+                batch[4] = FRAME_BATCH;
+            }
+            self.batch_len = 0;
+            self.batch_ready = Some(batch.freeze());
+        }
+        if let Some(batch) = self.batch_ready.take() {
+            if let AsyncSink::NotReady(batch) = self.inner.start_send(batch)? {
+                self.batch_ready = Some(batch);
+                return Ok(Async::NotReady);
+            }
+        }
+        Ok(Async::Ready(()))
+    }
+
+    fn confirm(&mut self, flushed: Async<()>) -> Poll<(), S::SinkError> {
+        if flushed.is_ready() {
+            for confirm in self.pending.drain(..) {
+                let _ = confirm.send(());
+            }
+        }
+        Ok(flushed)
+    }
+}
+
+impl<S: Sink<SinkItem = Bytes>> Sink for FabricSink<S> {
+    type SinkItem = QueuedFrame;
+    type SinkError = S::SinkError;
+
+    fn start_send(&mut self, item: QueuedFrame) -> StartSend<QueuedFrame, S::SinkError> {
+        if item.batchable && self.batch_max != 0 {
+            if self.batch_len != 0 && self.batch.len() + item.frame.len() > self.batch_max {
+                if self.flush_batch()?.is_not_ready() {
+                    return Ok(AsyncSink::NotReady(item));
+                }
+            }
+            if self.batch_len == 0 {
+                self.batch.put_slice(&[0u8; 5]);
+            }
+            self.batch.extend_from_slice(&item.frame);
+            self.batch_len += 1;
+        } else if let AsyncSink::NotReady(frame) = self.inner.start_send(item.frame)? {
+            return Ok(AsyncSink::NotReady(QueuedFrame { frame, ..item }));
+        }
+        if let Some(confirm) = item.confirm {
+            self.pending.push(confirm);
+        }
+        Ok(AsyncSink::Ready)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), S::SinkError> {
+        if self.flush_batch()?.is_not_ready() {
+            return Ok(Async::NotReady);
+        }
+        let flushed = self.inner.poll_complete()?;
+        self.confirm(flushed)
+    }
+
+    fn close(&mut self) -> Poll<(), S::SinkError> {
+        if self.flush_batch()?.is_not_ready() {
+            return Ok(Async::NotReady);
+        }
+        let flushed = self.inner.close()?;
+        self.confirm(flushed)
+    }
+}
 type InitType = io::Result<(Arc<SharedContext>, foneshot::Sender<()>)>;
+// an identified (and authenticated if required) connection
+type Handshaked = (tokio::net::TcpStream, NodeId, Arc<SharedContext>, Framing);
 
 const FABRIC_KEEPALIVE_MS: u64 = 1000;
 const FABRIC_RECONNECT_INTERVAL_MS: u64 = 1000;
+// accepted connections that don't complete the handshake by then are dropped
+const FABRIC_HANDSHAKE_TIMEOUT_MS: u64 = 5000;
+// sent instead of the node id by handshakes that carry more than the id
+const FABRIC_HANDSHAKE_EXTENDED: u64 = u64::max_value();
+// set in the format byte of the extended handshake when the node requires fabric_secret
+const FABRIC_HANDSHAKE_AUTH: u8 = 0x80;
+// set in the format byte of the extended handshake when the node wants Framing::Flagged
+const FABRIC_HANDSHAKE_FLAGGED: u8 = 0x40;
+const FABRIC_AUTH_NONCE_LEN: usize = 32;
+// hmac-sha256
+const FABRIC_AUTH_PROOF_LEN: usize = 32;
+// the proofs of each side differ, so one can't be passed off as the other
+const FABRIC_AUTH_INITIATOR: &[u8] = b"sucredb fabric initiator";
+const FABRIC_AUTH_ACCEPTOR: &[u8] = b"sucredb fabric acceptor";
+
+// the fabric_format as sent in the handshake
+fn format_to_byte(format: StorageFormat) -> u8 {
+    match format {
+        StorageFormat::Bincode => 0,
+        StorageFormat::MsgPack => 1,
+    }
+}
+
+fn format_from_byte(byte: u8) -> io::Result<StorageFormat> {
+    match byte {
+        0 => Ok(StorageFormat::Bincode),
+        1 => Ok(StorageFormat::MsgPack),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Unknown fabric format {}", byte),
+        )),
+    }
+}
+
+// the hmac of a side of the fabric authentication, see Fabric::authenticate
+fn auth_proof(
+    secret: &[u8],
+    label: &[u8],
+    initiator_nonce: &[u8],
+    acceptor_nonce: &[u8],
+    initiator: NodeId,
+    acceptor: NodeId,
+) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_varkey(secret).expect("Invalid hmac key");
+    let mut ids = [0u8; 16];
+    LittleEndian::write_u64(&mut ids[..8], initiator);
+    LittleEndian::write_u64(&mut ids[8..], acceptor);
+    mac.input(label);
+    mac.input(initiator_nonce);
+    mac.input(acceptor_nonce);
+    mac.input(&ids);
+    mac
+}
+
+/// Name of the file (inside the data dir) where received messages are captured
+/// when `fabric_capture` is enabled. It contains a sequence of
+/// u64(le) sender + u32(le) payload len + bincode payload
+pub const FABRIC_CAPTURE_FILE: &str = "fabric_capture";
+
+/// Reads the messages captured with `fabric_capture`, in the order they were received
+pub fn read_capture<P: AsRef<Path>>(path: P) -> Result<Vec<(NodeId, FabricMsg)>, GenericError> {
+    let mut reader = io::BufReader::new(fs::File::open(path)?);
+    let mut msgs = Vec::new();
+    loop {
+        let peer = match reader.read_u64::<LittleEndian>() {
+            Ok(peer) => peer,
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        };
+        let len = reader.read_u32::<LittleEndian>()?;
+        let mut payload = vec![0u8; len as usize];
+        reader.read_exact(&mut payload)?;
+        msgs.push((peer, bincode::deserialize(&payload)?));
+    }
+    Ok(msgs)
+}
 
 /// The messaging network that encompasses all nodes of the cluster
 /// using the fabric you can send messages (best-effort delivery)
 /// to any registered node.
-/// Currently each node keeps a connection to every other node. Due to the
-/// full-duplex nature of tcp this gives 2 pipes to each server, both are
-/// used to make better use of the socket buffers (is this a good idea though?).
-/// This also helps parallelism as an eventual big message won't affect
-/// the latency as much.
+/// Currently each node keeps `fabric_connections_per_peer` connections to every
+/// other node. Due to the full-duplex nature of tcp this gives (at least) 2 pipes
+/// to each server, all are used to make better use of the socket buffers
+/// (is this a good idea though?).
+/// The oldest pipe is kept for latency sensitive (crud) messages, which go to
+/// the least loaded pipe, while bulk messages (syncs, etc) use the others.
+/// So an eventual big message won't affect the latency as much.
 pub struct Fabric {
     context: Arc<SharedContext>,
     loop_thread: Option<(
@@ -115,21 +533,78 @@ struct SharedContext {
     loop_remote: tokio::reactor::Remote,
     msg_handlers: RwLock<LinearMap<u8, FabricMsgFn>>,
     con_handlers: RwLock<Vec<FabricConFn>>,
+    discon_handlers: RwLock<Vec<FabricConFn>>,
     // TODO: unify nodes_addr and connections maps
     nodes_addr: RwLock<IdHashMap<NodeId, SocketAddr>>,
+    // generation of the connect loops of each node, loops of older generations
+    // stop reconnecting so there are never more than connections_per_peer.
+    // Removing a node starts a generation without loops, so the ones mid-flight
+    // give up. Entries outlive the node so a re-added node doesn't revive old loops.
+    connectors: Mutex<IdHashMap<NodeId, usize>>,
+    connections_per_peer: usize,
+    // connect loops that are connecting, connected or waiting to reconnect
+    connect_loops: AtomicUsize,
+    // accepted connections, from the accept until they're closed
+    incoming: AtomicUsize,
+    incoming_max: usize,
     connections: RwLock<IdHashMap<NodeId, Vec<(usize, SenderChan)>>>,
     connection_gen: AtomicUsize,
+    capture: Option<Mutex<fs::File>>,
+    format: StorageFormat,
+    compression: FabricCompression,
+    compression_threshold: usize,
+    send_queue_max: usize,
+    batch_max: usize,
+    // peers must prove they know one of them during the handshake, see Fabric::authenticate.
+    // This node proves it knows the first one, empty if there's no authentication.
+    secrets: RwLock<Vec<Vec<u8>>>,
 }
 
 impl SharedContext {
+    fn auth(&self) -> bool {
+        !self.secrets.read().unwrap().is_empty()
+    }
+
+    // compression and batches need the flagged framing
+    fn wants_flagged_framing(&self) -> bool {
+        self.compression != FabricCompression::None || self.batch_max != 0
+    }
+
+    fn capture_msg(&self, peer: NodeId, msg: &FabricMsg) {
+        if let Some(ref capture) = self.capture {
+            let payload = bincode::serialize(msg).unwrap();
+            let mut frame = Vec::with_capacity(12 + payload.len());
+            frame.write_u64::<LittleEndian>(peer).unwrap();
+            frame.write_u32::<LittleEndian>(payload.len() as u32).unwrap();
+            frame.extend_from_slice(&payload);
+            // a single write per frame, so frames from different connections don't interleave
+            if let Err(e) = capture.lock().unwrap().write_all(&frame) {
+                warn!("Can't capture fabric msg: {:?}", e);
+            }
+        }
+    }
+
     fn register_node(&self, peer: NodeId, peer_addr: SocketAddr) -> Option<SocketAddr> {
         self.nodes_addr.write().unwrap().insert(peer, peer_addr)
     }
 
     fn remove_node(&self, peer: NodeId) -> Option<SocketAddr> {
+        self.next_connector_gen(peer);
         self.nodes_addr.write().unwrap().remove(&peer)
     }
 
+    // starts a new generation of connect loops for the node, the previous ones stop
+    fn next_connector_gen(&self, peer: NodeId) -> usize {
+        let mut locked = self.connectors.lock().unwrap();
+        let generation = locked.get(&peer).map_or(0, |g| g + 1);
+        locked.insert(peer, generation);
+        generation
+    }
+
+    fn is_current_connector(&self, peer: NodeId, generation: usize) -> bool {
+        self.connectors.lock().unwrap().get(&peer) == Some(&generation)
+    }
+
     fn register_connection(&self, peer: NodeId, sender: SenderChan) -> usize {
         let connection_id = self.connection_gen.fetch_add(1, Ordering::Relaxed);
         debug!(
@@ -153,20 +628,29 @@ impl SharedContext {
 
     fn remove_connection(&self, peer: NodeId, connection_id: usize) {
         debug!("Remove_connection peer: {}, id: {:?}", peer, connection_id);
-        let mut locked = self.connections.write().unwrap();
-        if let HMEntry::Occupied(mut o) = locked.entry(peer) {
-            let p = o
-                .get()
-                .iter()
-                .position(|x| x.0 == connection_id)
-                .expect("connection_id not found");
-            o.get_mut().swap_remove(p);
-            // cleanup entry if empty
-            if o.get().is_empty() {
-                o.remove();
+        let is_last = {
+            let mut locked = self.connections.write().unwrap();
+            if let HMEntry::Occupied(mut o) = locked.entry(peer) {
+                let p = o
+                    .get()
+                    .iter()
+                    .position(|x| x.0 == connection_id)
+                    .expect("connection_id not found");
+                o.get_mut().swap_remove(p);
+                // cleanup entry if empty
+                let is_last = o.get().is_empty();
+                if is_last {
+                    o.remove();
+                }
+                is_last
+            } else {
+                panic!("Peer not found in connections");
+            }
+        };
+        if is_last {
+            for handler in &*self.discon_handlers.read().unwrap() {
+                handler(peer);
             }
-        } else {
-            panic!("Peer not found in connections");
         }
     }
 }
@@ -189,6 +673,7 @@ impl ReaderContext {
             .get(&(msg_type as u8))
         {
             trace!("recv from {:?} {:?}", self.peer, msg);
+            self.context.capture_msg(self.peer, &msg);
             handler(self.peer, msg);
         } else {
             error!("No handler for msg type {:?}", msg_type);
@@ -224,14 +709,36 @@ impl Fabric {
         let fut = listener
             .incoming()
             .for_each(move |(socket, addr)| {
+                let incoming = context.incoming.load(Ordering::Relaxed);
+                if context.incoming_max != 0 && incoming >= context.incoming_max {
+                    warn!("Refusing connection from {:?}, incoming limit reached", addr);
+                    metrics::FABRIC_REJECTED.mark(1);
+                    return Ok(());
+                }
                 debug!("Accepting connection from {:?}", addr);
+                metrics::FABRIC_ACCEPTED.mark(1);
+                context.incoming.fetch_add(1, Ordering::Relaxed);
                 let context_cloned = context.clone();
+                let context_closed = context.clone();
+                let handshake_timeout = tokio::reactor::Timeout::new(
+                    Duration::from_millis(FABRIC_HANDSHAKE_TIMEOUT_MS),
+                    &handle,
+                ).expect("Can't create handshake timeout");
                 handle.spawn(
-                    Self::handshake(socket, context_cloned)
-                        .and_then(move |(s, peer_id, context)| {
-                            Self::steady_connection(s, peer_id, context)
+                    Self::handshake(socket, context_cloned, false)
+                        .select2(handshake_timeout)
+                        .then(|r| match r {
+                            Ok(Either::A((r, _))) => Ok(r),
+                            Ok(Either::B(_)) => Err(io::ErrorKind::TimedOut.into()),
+                            Err(either) => Err(either.split().0),
+                        })
+                        .and_then(move |(s, peer_id, context, framing)| {
+                            Self::steady_connection(s, peer_id, context, framing)
                         })
-                        .then(|_| Ok(())),
+                        .then(move |_| {
+                            context_closed.incoming.fetch_sub(1, Ordering::Relaxed);
+                            Ok(())
+                        }),
                 );
                 Ok(())
             })
@@ -239,17 +746,16 @@ impl Fabric {
         Box::new(fut)
     }
 
-    fn connect(
-        expected_node: Option<NodeId>,
-        addr: SocketAddr,
-        context: Arc<SharedContext>,
+    // tries the addresses in order until one of them accepts the connection
+    fn connect_any(
+        mut addrs: Vec<SocketAddr>,
         handle: tokio::reactor::Handle,
-    ) -> Box<Future<Item = (), Error = ()>> {
-        debug!("Connecting to node {:?}: {:?}", expected_node, addr);
-        let context1 = context.clone();
+    ) -> Box<Future<Item = tokio::net::TcpStream, Error = io::Error>> {
+        if addrs.is_empty() {
+            return Box::new(future::err(io::ErrorKind::NotFound.into()));
+        }
+        let addr = addrs.remove(0);
         let handle1 = handle.clone();
-        let handle2 = handle.clone();
-
         let fut = tokio::net::TcpStream::connect(&addr, &handle)
             .select2(
                 tokio::reactor::Timeout::new(
@@ -262,52 +768,249 @@ impl Fabric {
                 Ok(Either::B(_)) => Err(io::ErrorKind::TimedOut.into()),
                 Err(either) => Err(either.split().0),
             })
-            .and_then(move |s| Self::handshake(s, context))
-            .and_then(move |(s, peer_id, context)| Self::steady_connection(s, peer_id, context))
-            .then(move |_| {
-                tokio::reactor::Timeout::new(
-                    Duration::from_millis(FABRIC_RECONNECT_INTERVAL_MS),
-                    &handle1,
-                ).expect("Can't create reconnect timeout")
+            .or_else(move |e| -> Box<Future<Item = tokio::net::TcpStream, Error = io::Error>> {
+                if addrs.is_empty() {
+                    return Box::new(future::err(e));
+                }
+                debug!("Can't connect to {:?}: {:?}, trying {:?}", addr, e, addrs[0]);
+                Self::connect_any(addrs, handle1)
+            });
+        Box::new(fut)
+    }
+
+    fn connect(
+        expected_node: Option<NodeId>,
+        generation: usize,
+        addrs: Vec<SocketAddr>,
+        context: Arc<SharedContext>,
+        handle: tokio::reactor::Handle,
+    ) -> Box<Future<Item = (), Error = ()>> {
+        debug!("Connecting to node {:?}: {:?}", expected_node, addrs);
+        let context1 = context.clone();
+        let handle1 = handle.clone();
+        let handle2 = handle.clone();
+
+        let fut = Self::connect_any(addrs, handle.clone())
+            .and_then(move |s| Self::handshake(s, context, true))
+            .and_then(move |(s, peer_id, context, framing)| -> Box<Future<Item = _, Error = _>> {
+                // the node may have been removed (or its address changed) while connecting
+                if expected_node.map_or(false, |n| !context.is_current_connector(n, generation)) {
+                    debug!("Dropping connection to superseded node {}", peer_id);
+                    return Box::new(future::err(io::ErrorKind::ConnectionAborted.into()));
+                }
+                Self::steady_connection(s, peer_id, context, framing)
             })
-            .and_then(move |_| {
-                let node = expected_node.ok_or(io::ErrorKind::NotFound)?;
-                let addr_opt = {
-                    let locked = context1.nodes_addr.read().unwrap();
-                    locked.get(&node).cloned()
+            .then(move |_| -> Box<Future<Item = (), Error = ()>> {
+                // seeds and loops superseded since they started stop right away
+                let node = match expected_node {
+                    Some(node) if context1.is_current_connector(node, generation) => node,
+                    _ => {
+                        debug!("Connect loop to node {:?} done", expected_node);
+                        context1.connect_loops.fetch_sub(1, Ordering::Relaxed);
+                        return Box::new(future::ok(()));
+                    }
                 };
-                if let Some(addr) = addr_opt {
-                    debug!("Reconnecting fabric connection to {:?}", addr);
-                    handle2.spawn(Self::connect(
-                        expected_node,
-                        addr,
-                        context1,
-                        handle2.clone(),
-                    ));
-                }
-                Ok(())
+                let timeout = tokio::reactor::Timeout::new(
+                    Duration::from_millis(FABRIC_RECONNECT_INTERVAL_MS),
+                    &handle1,
+                ).expect("Can't create reconnect timeout");
+                Box::new(timeout.then(move |_| {
+                    let addr_opt = if context1.is_current_connector(node, generation) {
+                        context1.nodes_addr.read().unwrap().get(&node).cloned()
+                    } else {
+                        None
+                    };
+                    if let Some(addr) = addr_opt {
+                        debug!("Reconnecting fabric connection to {:?}", addr);
+                        handle2.spawn(Self::connect(
+                            expected_node,
+                            generation,
+                            vec![addr],
+                            context1,
+                            handle2.clone(),
+                        ));
+                    } else {
+                        debug!("Connect loop to node {} done", node);
+                        context1.connect_loops.fetch_sub(1, Ordering::Relaxed);
+                    }
+                    Ok(())
+                }))
             });
-        Box::new(fut.map_err(|_| ()))
+        Box::new(fut)
     }
 
+    // the initiator is the side that connected
     fn handshake(
         socket: tokio::net::TcpStream,
         context: Arc<SharedContext>,
-    ) -> Box<Future<Item = (tokio::net::TcpStream, NodeId, Arc<SharedContext>), Error = io::Error>>
-    {
+        initiator: bool,
+    ) -> Box<Future<Item = Handshaked, Error = io::Error>> {
         debug!("Stablished connection with {:?}", socket.peer_addr());
         let _ = socket.set_nodelay(true);
         let _ = socket.set_keepalive(Some(Duration::from_millis(FABRIC_KEEPALIVE_MS)));
-        let mut buffer = [0u8; 8];
-        (&mut buffer[..])
-            .write_u64::<LittleEndian>(context.node)
-            .unwrap();
+        let peer_addr = socket.peer_addr().ok();
+        // with the default format it's just the node id, like older versions, otherwise
+        // FABRIC_HANDSHAKE_EXTENDED + node id + format. So nodes using different formats
+        // refuse each other instead of misreading the msgs.
+        // The flagged framing is used if both want it, otherwise frames are plain.
+        let flagged = context.wants_flagged_framing();
+        let extended = context.format != StorageFormat::Bincode || context.auth() || flagged;
+        let mut buffer = Vec::with_capacity(17);
+        if extended {
+            buffer
+                .write_u64::<LittleEndian>(FABRIC_HANDSHAKE_EXTENDED)
+                .unwrap();
+        }
+        buffer.write_u64::<LittleEndian>(context.node).unwrap();
+        if extended {
+            let auth = if context.auth() {
+                FABRIC_HANDSHAKE_AUTH
+            } else {
+                0
+            };
+            let framing = if flagged { FABRIC_HANDSHAKE_FLAGGED } else { 0 };
+            buffer
+                .write_u8(format_to_byte(context.format) | auth | framing)
+                .unwrap();
+        }
         let fut = tokio_io::write_all(socket, buffer)
-            .and_then(|(s, b)| tokio_io::read_exact(s, b))
-            .and_then(move |(s, b)| {
-                let peer_id = (&b[..]).read_u64::<LittleEndian>().unwrap();
-                debug!("Identified connection to node {}", peer_id);
-                Ok((s, peer_id, context))
+            .and_then(|(s, _)| tokio_io::read_exact(s, [0u8; 8]))
+            .and_then(|(s, b)| -> Box<Future<Item = _, Error = _>> {
+                let first = (&b[..]).read_u64::<LittleEndian>().unwrap();
+                if first != FABRIC_HANDSHAKE_EXTENDED {
+                    return Box::new(future::ok((s, first, StorageFormat::Bincode, 0)));
+                }
+                Box::new(tokio_io::read_exact(s, [0u8; 9]).and_then(|(s, b)| {
+                    let peer_id = (&b[..8]).read_u64::<LittleEndian>().unwrap();
+                    let flags = b[8] & (FABRIC_HANDSHAKE_AUTH | FABRIC_HANDSHAKE_FLAGGED);
+                    Ok((s, peer_id, format_from_byte(b[8] & !flags)?, flags))
+                }))
+            })
+            .and_then(move |(s, peer_id, format, flags)| -> Box<Future<Item = _, Error = _>> {
+                let auth = flags & FABRIC_HANDSHAKE_AUTH != 0;
+                if format != context.format {
+                    warn!(
+                        "Refusing connection to node {}, it uses the {:?} fabric format",
+                        peer_id, format
+                    );
+                    return Box::new(future::err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "Fabric format mismatch",
+                    )));
+                }
+                if auth != context.auth() {
+                    warn!(
+                        "Refusing connection to node {} at {:?}, only one side has a fabric_secret",
+                        peer_id, peer_addr
+                    );
+                    return Box::new(future::err(io::Error::from(io::ErrorKind::PermissionDenied)));
+                }
+                let framing = if flagged && flags & FABRIC_HANDSHAKE_FLAGGED != 0 {
+                    Framing::Flagged
+                } else {
+                    Framing::Plain
+                };
+                if !auth {
+                    debug!("Identified connection to node {}", peer_id);
+                    return Box::new(future::ok((s, peer_id, context, framing)));
+                }
+                Box::new(
+                    Self::authenticate(s, peer_id, peer_addr, context, initiator)
+                        .map(move |(s, peer_id, context)| (s, peer_id, context, framing)),
+                )
+            });
+
+        Box::new(fut)
+    }
+
+    // Challenge-response after the ids are exchanged: both sides send a random nonce, then
+    // the initiator sends the hmac (keyed with the secret) of its role, both nonces and both
+    // ids. The acceptor only sends its own, with its role, once the initiator's checks out.
+    // The nonces make the proofs fresh and the roles and ids tie them to this connection,
+    // so proofs obtained from other connections (or relayed between nodes) don't match.
+    // While a secret is rotated either side may know two, the initiator proves it knows its
+    // first one and the acceptor answers with the secret that matched it.
+    fn authenticate(
+        socket: tokio::net::TcpStream,
+        peer_id: NodeId,
+        peer_addr: Option<SocketAddr>,
+        context: Arc<SharedContext>,
+        initiator: bool,
+    ) -> Box<Future<Item = (tokio::net::TcpStream, NodeId, Arc<SharedContext>), Error = io::Error>>
+    {
+        if peer_id == context.node {
+            debug!("Refusing authenticated connection to self at {:?}", peer_addr);
+            return Box::new(future::err(io::Error::from(io::ErrorKind::PermissionDenied)));
+        }
+        let mut nonce = [0u8; FABRIC_AUTH_NONCE_LEN];
+        match OsRng::new() {
+            Ok(mut rng) => rng.fill_bytes(&mut nonce),
+            Err(e) => return Box::new(future::err(into_io_error(e))),
+        }
+        let refused = move || {
+            warn!(
+                "Refusing connection to node {} at {:?}, fabric authentication failed",
+                peer_id, peer_addr
+            );
+            io::Error::from(io::ErrorKind::PermissionDenied)
+        };
+        let secrets = context.secrets.read().unwrap().clone();
+        let fut = tokio_io::write_all(socket, nonce)
+            .and_then(|(s, _)| tokio_io::read_exact(s, [0u8; FABRIC_AUTH_NONCE_LEN]))
+            .and_then(move |(s, peer_nonce)| {
+                let proof = |secret: &[u8], label: &[u8]| {
+                    if initiator {
+                        auth_proof(secret, label, &nonce, &peer_nonce, context.node, peer_id)
+                    } else {
+                        auth_proof(secret, label, &peer_nonce, &nonce, peer_id, context.node)
+                    }
+                };
+                // (initiator, acceptor) proofs for each of the secrets
+                let proofs: Vec<_> = secrets
+                    .iter()
+                    .map(|s| {
+                        (
+                            proof(&s[..], FABRIC_AUTH_INITIATOR),
+                            proof(&s[..], FABRIC_AUTH_ACCEPTOR),
+                        )
+                    })
+                    .collect();
+                let context_ = context.clone();
+                if initiator {
+                    let own_proof = proofs[0].0.clone().result().code().to_vec();
+                    let fut = tokio_io::write_all(s, own_proof)
+                        .and_then(|(s, _)| tokio_io::read_exact(s, [0u8; FABRIC_AUTH_PROOF_LEN]))
+                        .and_then(move |(s, peer_proof)| {
+                            // whichever of the secrets the acceptor shares
+                            let verified = proofs
+                                .into_iter()
+                                .any(|(_, proof)| proof.verify(&peer_proof).is_ok());
+                            if !verified {
+                                return Err(refused());
+                            }
+                            Ok((s, context_))
+                        });
+                    Either::A(fut)
+                } else {
+                    let fut = tokio_io::read_exact(s, [0u8; FABRIC_AUTH_PROOF_LEN])
+                        .and_then(move |(s, peer_proof)| {
+                            let acceptor_proof = proofs
+                                .into_iter()
+                                .filter_map(|(initiator_proof, acceptor_proof)| {
+                                    initiator_proof.verify(&peer_proof).ok().map(|_| acceptor_proof)
+                                })
+                                .next()
+                                .ok_or_else(refused)?;
+                            Ok((s, acceptor_proof.result().code().to_vec()))
+                        })
+                        .and_then(|(s, own_proof)| tokio_io::write_all(s, own_proof))
+                        .map(move |(s, _)| (s, context_));
+                    Either::B(fut)
+                }
+            })
+            .map(move |(s, context)| {
+                debug!("Identified and authenticated connection to node {}", peer_id);
+                (s, peer_id, context)
             });
 
         Box::new(fut)
@@ -317,11 +1020,33 @@ impl Fabric {
         socket: tokio::net::TcpStream,
         peer: NodeId,
         context: Arc<SharedContext>,
+        framing: Framing,
     ) -> Box<Future<Item = (), Error = io::Error>> {
+        debug!("Connection to node {} uses {:?} framing", peer, framing);
         let (socket_rx, socket_tx) = socket.split();
-        let socket_tx = codec::FramedWrite::new(socket_tx, FramedBincodeCodec);
-        let socket_rx = codec::FramedRead::new(socket_rx, FramedBincodeCodec);
+        // batches need the flagged framing
+        let batch_max = match framing {
+            Framing::Plain => 0,
+            Framing::Flagged => context.batch_max,
+        };
+        let socket_tx = FabricSink::new(
+            codec::FramedWrite::new(socket_tx, FramedCodec::new(context.format, framing)),
+            batch_max,
+        );
+        let socket_rx =
+            codec::FramedRead::new(socket_rx, FramedCodec::new(context.format, framing));
         let (chan_tx, chan_rx) = fmpsc::unbounded();
+        let queued = Arc::new(AtomicUsize::new(0));
+        let queued_rx = queued.clone();
+        let chan_rx = chan_rx.map(move |frame| {
+            queued_rx.fetch_sub(1, Ordering::Relaxed);
+            frame
+        });
+        let chan_tx = SenderChan {
+            chan: chan_tx,
+            queued,
+            framing,
+        };
 
         let ctx_rx = ReaderContext::new(context.clone(), peer);
         let fut_rx = socket_rx.for_each(move |msg| {
@@ -346,15 +1071,45 @@ impl Fabric {
         config: Config,
         handle: tokio::reactor::Handle,
     ) -> Result<Arc<SharedContext>, GenericError> {
+        let capture = if config.fabric_capture {
+            let path = config.data_dir.join(FABRIC_CAPTURE_FILE);
+            warn!("Capturing received fabric messages to {:?}", path);
+            let file = fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)?;
+            Some(Mutex::new(file))
+        } else {
+            None
+        };
         let context = Arc::new(SharedContext {
             node: node,
             addr: config.fabric_addr,
             loop_remote: handle.remote().clone(),
             nodes_addr: Default::default(),
+            connectors: Default::default(),
+            connections_per_peer: config.fabric_connections_per_peer as usize,
+            connect_loops: Default::default(),
+            incoming: Default::default(),
+            incoming_max: config.fabric_incoming_max as usize,
             msg_handlers: Default::default(),
             con_handlers: Default::default(),
+            discon_handlers: Default::default(),
             connections: Default::default(),
             connection_gen: Default::default(),
+            capture: capture,
+            format: config.fabric_format,
+            compression: config.fabric_compression,
+            compression_threshold: config.fabric_compression_threshold as usize,
+            send_queue_max: config.fabric_send_queue_max as usize,
+            batch_max: config.fabric_batch_max as usize,
+            secrets: RwLock::new(
+                [config.fabric_secret, config.fabric_secret_accept]
+                    .iter()
+                    .filter(|s| !s.is_empty())
+                    .map(|s| s.as_bytes().to_vec())
+                    .collect(),
+            ),
         });
 
         let listener = tokio::net::TcpListener::bind(&context.addr, &handle)?;
@@ -398,18 +1153,48 @@ impl Fabric {
             .insert(msg_type as u8, handler);
     }
 
+    /// Replaces the secret this node proves it knows when connecting to its peers, and
+    /// optionally another one accepted from them (see fabric_secret_accept). Only the
+    /// following handshakes use them. Authentication can't be enabled or disabled this way.
+    pub fn set_secret(&self, secret: &[u8], accept: Option<&[u8]>) -> Result<(), GenericError> {
+        let mut secrets = self.context.secrets.write().unwrap();
+        if secrets.is_empty() {
+            return Err("Fabric authentication is disabled".into());
+        }
+        if secret.is_empty() || accept.map_or(false, |a| a.is_empty()) {
+            return Err("The secrets can't be empty".into());
+        }
+        *secrets = Some(secret).into_iter().chain(accept).map(|s| s.to_vec()).collect();
+        info!("Fabric secret replaced");
+        Ok(())
+    }
+
+    /// Drops the incoming messages from now on, the handlers are replaced by no-ops
+    pub fn discard_msgs(&self) {
+        for (_, handler) in self.context.msg_handlers.write().unwrap().iter_mut() {
+            *handler = Box::new(|_, _| ());
+        }
+    }
+
+    /// Called when a peer gets its first connection
     pub fn register_con_handler(&self, handler: FabricConFn) {
         self.context.con_handlers.write().unwrap().push(handler);
     }
 
-    pub fn register_seed(&self, addr: SocketAddr) {
-        self.start_connect(None, addr)
+    /// Called when the last connection to a peer is closed
+    pub fn register_discon_handler(&self, handler: FabricConFn) {
+        self.context.discon_handlers.write().unwrap().push(handler);
+    }
+
+    /// Connects to a seed, trying each of the addresses its host resolved to in order
+    pub fn register_seed(&self, addrs: Vec<SocketAddr>) {
+        self.start_connect(None, addrs)
     }
 
     pub fn register_node(&self, node: NodeId, addr: SocketAddr) {
         let prev = self.context.register_node(node, addr);
         if prev != Some(addr) {
-            self.start_connect(Some(node), addr);
+            self.start_connect(Some(node), vec![addr]);
         }
     }
 
@@ -417,6 +1202,16 @@ impl Fabric {
         self.context.remove_node(node);
     }
 
+    /// Whether there's an established connection to the node, cheaper than connections()
+    pub fn is_connected(&self, node: NodeId) -> bool {
+        self.context
+            .connections
+            .read()
+            .unwrap()
+            .get(&node)
+            .map_or(false, |c| !c.is_empty())
+    }
+
     pub fn connections(&self) -> Vec<NodeId> {
         let writers = self.context.connections.read().unwrap();
         writers
@@ -436,21 +1231,35 @@ impl Fabric {
             if node != self.context.node {
                 x_nodes.remove(&node);
                 if nodes.insert(node, addr) != Some(addr) {
-                    self.start_connect(Some(node), addr);
+                    self.start_connect(Some(node), vec![addr]);
                 }
             }
         }
         for (node, _) in x_nodes {
+            self.context.next_connector_gen(node);
             nodes.remove(&node);
         }
     }
 
-    fn start_connect(&self, expected_node: Option<NodeId>, addr: SocketAddr) {
-        let context = self.context.clone();
-        let context_cloned = context.clone();
-        context
-            .loop_remote
-            .spawn(move |h| Self::connect(expected_node, addr, context_cloned, h.clone()));
+    // seeds get a single connection, as the node id is unknown until the handshake,
+    // while nodes get connections_per_peer connect loops replacing any previous ones
+    fn start_connect(&self, expected_node: Option<NodeId>, addrs: Vec<SocketAddr>) {
+        let (generation, count) = if let Some(node) = expected_node {
+            (
+                self.context.next_connector_gen(node),
+                self.context.connections_per_peer,
+            )
+        } else {
+            (0, 1)
+        };
+        self.context.connect_loops.fetch_add(count, Ordering::Relaxed);
+        for _ in 0..count {
+            let context_cloned = self.context.clone();
+            let addrs = addrs.clone();
+            self.context.loop_remote.spawn(move |h| {
+                Self::connect(expected_node, generation, addrs, context_cloned, h.clone())
+            });
+        }
     }
 
     // TODO: take msgs as references and buffer serialized bytes instead
@@ -459,7 +1268,30 @@ impl Fabric {
         node: NodeId,
         msg: T,
     ) -> Result<(), FabricError> {
-        let msg = msg.into();
+        self.queue_msg(node, msg.into(), None)
+    }
+
+    /// Like send_msg but the returned future only resolves once the msg
+    /// is flushed to the socket. It fails with NoRoute if the connection
+    /// drops before that, or with the send_msg error if it can't be queued.
+    pub fn send_msg_confirmed<'a, T: Into<FabricMsgRef<'a>>>(
+        &'a self,
+        node: NodeId,
+        msg: T,
+    ) -> Box<Future<Item = (), Error = FabricError> + Send> {
+        let (confirm_tx, confirm_rx) = foneshot::channel();
+        match self.queue_msg(node, msg.into(), Some(confirm_tx)) {
+            Ok(()) => Box::new(confirm_rx.map_err(|_| FabricError::NoRoute)),
+            Err(e) => Box::new(future::err(e)),
+        }
+    }
+
+    fn queue_msg(
+        &self,
+        node: NodeId,
+        msg: FabricMsgRef,
+        confirm: Option<foneshot::Sender<()>>,
+    ) -> Result<(), FabricError> {
         debug!("send_msg node:{} {:?}", node, msg);
         if node == self.context.node {
             panic!("Can't send message to self");
@@ -476,20 +1308,45 @@ impl Fabric {
                     .unwrap_or(0.0);
                 if fabric_drop > 0.0 && thread_rng().gen::<f64>() < fabric_drop {
                     warn!("Fabric msg droped due to FABRIC_DROP: {:?}", msg);
+                    // as if it was lost in the network, after being flushed
+                    if let Some(confirm) = confirm {
+                        let _ = confirm.send(());
+                    }
                     return Ok(());
                 }
             }
         }
 
-        let serialized_msg = FramedBincodeCodec::serialize(msg);
+        let msg_type = msg.get_type();
         let connections = self.context.connections.read().unwrap();
         if let Some(o) = connections.get(&node) {
-            if let Some(&(connection_id, ref chan)) = thread_rng().choose::<(_, _)>(o) {
-                if let Err(_) = chan.unbounded_send(serialized_msg) {
+            let available = select_connection(o, msg_type, self.context.send_queue_max);
+            if let Some(&(connection_id, ref sender)) = available {
+                let serialized_msg = FramedCodec::serialize(
+                    msg,
+                    self.context.format,
+                    sender.framing,
+                    self.context.compression,
+                    self.context.compression_threshold,
+                );
+                sender.queued.fetch_add(1, Ordering::Relaxed);
+                let queued_frame = QueuedFrame {
+                    frame: serialized_msg,
+                    batchable: match msg_type {
+                        FabricMsgType::Crud => false,
+                        _ => true,
+                    },
+                    confirm: confirm,
+                };
+                if let Err(_) = sender.chan.unbounded_send(queued_frame) {
+                    sender.queued.fetch_sub(1, Ordering::Relaxed);
                     warn!("Can't send to fabric {}-{} chan", node, connection_id,);
                 } else {
                     return Ok(());
                 }
+            } else if !o.is_empty() {
+                debug!("DROPING MSG - Send queues full for {:?}", node);
+                return Err(FabricError::QueueFull);
             } else {
                 warn!("DROPING MSG - No channel available for {:?}", node);
             }
@@ -514,8 +1371,9 @@ impl Drop for Fabric {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use config::Config;
+    use config::{Config, HostAddr};
     use env_logger;
+    use tokio_io::codec::Decoder;
     use std::sync::{atomic, Arc};
     use std::thread;
     use std::time::Duration;
@@ -536,6 +1394,8 @@ mod tests {
         fabric1.register_node(2, "127.0.0.1:6482".parse().unwrap());
         fabric2.register_node(1, "127.0.0.1:6481".parse().unwrap());
         thread::sleep(Duration::from_millis(10));
+        assert!(fabric1.is_connected(2));
+        assert!(!fabric1.is_connected(3));
 
         let counter = Arc::new(atomic::AtomicUsize::new(0));
         let counter_ = counter.clone();
@@ -560,4 +1420,624 @@ mod tests {
         thread::sleep(Duration::from_millis(10));
         assert_eq!(counter.load(atomic::Ordering::Relaxed), 3);
     }
+
+    #[test]
+    fn test_send_queue_full() {
+        let _ = env_logger::try_init();
+        let config1 = Config {
+            fabric_addr: "127.0.0.1:6483".parse().unwrap(),
+            fabric_send_queue_max: 0,
+            ..Default::default()
+        };
+        let config2 = Config {
+            fabric_addr: "127.0.0.1:6484".parse().unwrap(),
+            ..Default::default()
+        };
+        let fabric1 = Fabric::new(1, &config1).unwrap();
+        let fabric2 = Fabric::new(2, &config2).unwrap();
+        fabric1.register_node(2, "127.0.0.1:6484".parse().unwrap());
+        fabric2.register_node(1, "127.0.0.1:6483".parse().unwrap());
+        thread::sleep(Duration::from_millis(10));
+
+        let msg = MsgRemoteSetAck {
+            cookie: Default::default(),
+            vnode: Default::default(),
+            result: Ok(Vec::new()),
+        };
+        assert_eq!(fabric1.send_msg(2, &msg), Err(FabricError::QueueFull));
+        assert_eq!(fabric2.send_msg(1, &msg), Ok(()));
+        assert_eq!(fabric1.send_msg(3, &msg), Err(FabricError::NoRoute));
+    }
+
+    #[test]
+    fn test_register_seed() {
+        let _ = env_logger::try_init();
+        let config1 = Config {
+            fabric_addr: "127.0.0.1:6487".parse().unwrap(),
+            ..Default::default()
+        };
+        let config2 = Config {
+            fabric_addr: "127.0.0.1:6488".parse().unwrap(),
+            ..Default::default()
+        };
+        let fabric1 = Fabric::new(1, &config1).unwrap();
+        let _fabric2 = Fabric::new(2, &config2).unwrap();
+        // nothing listens on the first address
+        fabric1.register_seed(vec![
+            "127.0.0.1:6489".parse().unwrap(),
+            "127.0.0.1:6488".parse().unwrap(),
+        ]);
+        thread::sleep(Duration::from_millis(50));
+        assert!(fabric1.is_connected(2));
+
+        let seed: HostAddr = "127.0.0.1:6488".parse().unwrap();
+        assert_eq!(seed.resolve().unwrap(), vec![config2.fabric_addr]);
+        assert!("[::1]:6488".parse::<HostAddr>().is_ok());
+        assert!("node1.internal:16379".parse::<HostAddr>().is_ok());
+        assert!("::1:6488".parse::<HostAddr>().is_err());
+        assert!("node1.internal".parse::<HostAddr>().is_err());
+    }
+
+    #[test]
+    fn test_format_handshake() {
+        let _ = env_logger::try_init();
+        let config = |port: u16, format| Config {
+            fabric_addr: format!("127.0.0.1:{}", port).parse().unwrap(),
+            fabric_format: format,
+            ..Default::default()
+        };
+        let fabric1 = Fabric::new(1, &config(6490, StorageFormat::MsgPack)).unwrap();
+        let fabric2 = Fabric::new(2, &config(6491, StorageFormat::MsgPack)).unwrap();
+        let fabric3 = Fabric::new(3, &config(6492, StorageFormat::Bincode)).unwrap();
+        fabric1.register_node(2, "127.0.0.1:6491".parse().unwrap());
+        fabric1.register_node(3, "127.0.0.1:6492".parse().unwrap());
+        fabric3.register_node(2, "127.0.0.1:6491".parse().unwrap());
+        thread::sleep(Duration::from_millis(50));
+        assert!(fabric1.is_connected(2));
+        assert!(!fabric1.is_connected(3));
+        assert!(!fabric3.is_connected(2));
+
+        let counter = Arc::new(atomic::AtomicUsize::new(0));
+        let counter_ = counter.clone();
+        fabric2.register_msg_handler(
+            FabricMsgType::Crud,
+            Box::new(move |_, _| {
+                counter_.fetch_add(1, atomic::Ordering::Relaxed);
+            }),
+        );
+        let msg = MsgRemoteSetAck {
+            cookie: Default::default(),
+            vnode: Default::default(),
+            result: Ok(Vec::new()),
+        };
+        fabric1.send_msg(2, &msg).unwrap();
+        thread::sleep(Duration::from_millis(10));
+        assert_eq!(counter.load(atomic::Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_auth_handshake() {
+        let _ = env_logger::try_init();
+        let config = |port: u16, secret: &str| Config {
+            fabric_addr: format!("127.0.0.1:{}", port).parse().unwrap(),
+            fabric_secret: secret.into(),
+            ..Default::default()
+        };
+        let fabric1 = Fabric::new(1, &config(6493, "secret")).unwrap();
+        let fabric2 = Fabric::new(2, &config(6494, "secret")).unwrap();
+        let fabric3 = Fabric::new(3, &config(6495, "wrong")).unwrap();
+        let fabric4 = Fabric::new(4, &config(6496, "")).unwrap();
+        fabric1.register_node(2, "127.0.0.1:6494".parse().unwrap());
+        fabric1.register_node(3, "127.0.0.1:6495".parse().unwrap());
+        fabric1.register_node(4, "127.0.0.1:6496".parse().unwrap());
+        fabric4.register_node(3, "127.0.0.1:6495".parse().unwrap());
+        thread::sleep(Duration::from_millis(50));
+        assert!(fabric1.is_connected(2));
+        assert!(!fabric1.is_connected(3));
+        assert!(!fabric1.is_connected(4));
+        assert!(!fabric4.is_connected(3));
+        assert!(!fabric3.is_connected(1));
+
+        let counter = Arc::new(atomic::AtomicUsize::new(0));
+        let counter_ = counter.clone();
+        fabric2.register_msg_handler(
+            FabricMsgType::Crud,
+            Box::new(move |_, _| {
+                counter_.fetch_add(1, atomic::Ordering::Relaxed);
+            }),
+        );
+        let msg = MsgRemoteSetAck {
+            cookie: Default::default(),
+            vnode: Default::default(),
+            result: Ok(Vec::new()),
+        };
+        fabric1.send_msg(2, &msg).unwrap();
+        thread::sleep(Duration::from_millis(10));
+        assert_eq!(counter.load(atomic::Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_auth_rotation() {
+        let _ = env_logger::try_init();
+        let config = |port: u16, secret: &str, accept: &str| Config {
+            fabric_addr: format!("127.0.0.1:{}", port).parse().unwrap(),
+            fabric_secret: secret.into(),
+            fabric_secret_accept: accept.into(),
+            ..Default::default()
+        };
+        // midway through a rotation, nodes on either side of it still connect
+        let fabric1 = Fabric::new(1, &config(6509, "old", "new")).unwrap();
+        let fabric2 = Fabric::new(2, &config(6510, "new", "old")).unwrap();
+        let fabric3 = Fabric::new(3, &config(6511, "old", "")).unwrap();
+        let fabric4 = Fabric::new(4, &config(6512, "new", "")).unwrap();
+        fabric1.register_node(2, "127.0.0.1:6510".parse().unwrap());
+        fabric2.register_node(3, "127.0.0.1:6511".parse().unwrap());
+        fabric1.register_node(4, "127.0.0.1:6512".parse().unwrap());
+        thread::sleep(Duration::from_millis(50));
+        assert!(fabric1.is_connected(2));
+        assert!(fabric2.is_connected(3));
+        // 4 doesn't accept the old secret 1 proves it knows
+        assert!(!fabric1.is_connected(4));
+
+        // once rotated the old secret is refused, established connections are kept
+        fabric2.set_secret(b"new", None).unwrap();
+        let fabric5 = Fabric::new(5, &config(6513, "old", "")).unwrap();
+        fabric5.register_node(2, "127.0.0.1:6510".parse().unwrap());
+        fabric4.register_node(2, "127.0.0.1:6510".parse().unwrap());
+        thread::sleep(Duration::from_millis(50));
+        assert!(fabric2.is_connected(3));
+        assert!(!fabric5.is_connected(2));
+        assert!(fabric4.is_connected(2));
+
+        assert!(fabric2.set_secret(b"", None).is_err());
+        let fabric6 = Fabric::new(6, &config(6514, "", "")).unwrap();
+        assert!(fabric6.set_secret(b"new", None).is_err());
+    }
+
+    #[test]
+    fn test_auth_relay() {
+        use std::io::{Read, Write};
+        use std::net::TcpStream;
+        let _ = env_logger::try_init();
+        let config = |port: u16| Config {
+            fabric_addr: format!("127.0.0.1:{}", port).parse().unwrap(),
+            fabric_secret: "secret".into(),
+            ..Default::default()
+        };
+        let fabric2 = Fabric::new(2, &config(6504)).unwrap();
+        let fabric3 = Fabric::new(3, &config(6505)).unwrap();
+        // a relay without the secret connects to 2 claiming to be 3 and vice versa,
+        // passing the nonces of each one to the other
+        let connect = |addr: &str, claimed_id: NodeId| {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_millis(200)))
+                .unwrap();
+            let mut handshake = Vec::new();
+            handshake
+                .write_u64::<LittleEndian>(FABRIC_HANDSHAKE_EXTENDED)
+                .unwrap();
+            handshake.write_u64::<LittleEndian>(claimed_id).unwrap();
+            handshake
+                .write_u8(format_to_byte(StorageFormat::Bincode) | FABRIC_HANDSHAKE_AUTH)
+                .unwrap();
+            stream.write_all(&handshake).unwrap();
+            stream.read_exact(&mut [0u8; 17]).unwrap();
+            let mut nonce = [0u8; FABRIC_AUTH_NONCE_LEN];
+            stream.read_exact(&mut nonce).unwrap();
+            (stream, nonce)
+        };
+        let (mut to2, nonce2) = connect("127.0.0.1:6504", 3);
+        let (mut to3, nonce3) = connect("127.0.0.1:6505", 2);
+        to2.write_all(&nonce3).unwrap();
+        to3.write_all(&nonce2).unwrap();
+        // the acceptors don't reveal their proofs before getting a valid one
+        let mut proof = [0u8; FABRIC_AUTH_PROOF_LEN];
+        assert!(to2.read_exact(&mut proof).is_err());
+        assert!(to3.read_exact(&mut proof).is_err());
+        to2.write_all(&proof).unwrap();
+        to3.write_all(&proof).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(to2.read(&mut proof).ok(), Some(0));
+        assert_eq!(to3.read(&mut proof).ok(), Some(0));
+        assert!(!fabric2.is_connected(3));
+        assert!(!fabric3.is_connected(2));
+    }
+
+    #[test]
+    fn test_con_handlers() {
+        let _ = env_logger::try_init();
+        let config = |port: u16| Config {
+            fabric_addr: format!("127.0.0.1:{}", port).parse().unwrap(),
+            ..Default::default()
+        };
+        let fabric1 = Fabric::new(1, &config(6497)).unwrap();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let events_ = events.clone();
+        fabric1.register_con_handler(Box::new(move |peer| {
+            events_.lock().unwrap().push((peer, true));
+        }));
+        let events_ = events.clone();
+        fabric1.register_discon_handler(Box::new(move |peer| {
+            events_.lock().unwrap().push((peer, false));
+        }));
+
+        let fabric2 = Fabric::new(2, &config(6498)).unwrap();
+        fabric1.register_node(2, "127.0.0.1:6498".parse().unwrap());
+        fabric2.register_node(1, "127.0.0.1:6497".parse().unwrap());
+        thread::sleep(Duration::from_millis(50));
+        assert!(fabric1.is_connected(2));
+        // a single event per peer, however many connections it has
+        assert_eq!(*events.lock().unwrap(), [(2, true)]);
+
+        drop(fabric2);
+        thread::sleep(Duration::from_millis(50));
+        assert!(!fabric1.is_connected(2));
+        assert_eq!(*events.lock().unwrap(), [(2, true), (2, false)]);
+    }
+
+    #[test]
+    fn test_connections_per_peer() {
+        let _ = env_logger::try_init();
+        let config1 = Config {
+            fabric_addr: "127.0.0.1:6499".parse().unwrap(),
+            fabric_connections_per_peer: 3,
+            ..Default::default()
+        };
+        let config2 = Config {
+            fabric_addr: "127.0.0.1:6500".parse().unwrap(),
+            ..Default::default()
+        };
+        let fabric1 = Fabric::new(1, &config1).unwrap();
+        let fabric2 = Fabric::new(2, &config2).unwrap();
+        fabric1.register_node(2, "127.0.0.1:6500".parse().unwrap());
+        fabric2.register_node(1, "127.0.0.1:6499".parse().unwrap());
+        thread::sleep(Duration::from_millis(50));
+        let count = |f: &Fabric, n: NodeId| f.context.connections.read().unwrap()[&n].len();
+        // 3 outgoing from fabric1 plus 1 outgoing from fabric2
+        assert_eq!(count(&fabric1, 2), 4);
+        assert_eq!(count(&fabric2, 1), 4);
+    }
+
+    #[test]
+    fn test_remove_node_reconnects() {
+        let _ = env_logger::try_init();
+        let config = |port: u16| Config {
+            fabric_addr: format!("127.0.0.1:{}", port).parse().unwrap(),
+            ..Default::default()
+        };
+        let fabric1 = Fabric::new(1, &config(6501)).unwrap();
+        let fabric2 = Fabric::new(2, &config(6502)).unwrap();
+        let addr2 = "127.0.0.1:6502".parse().unwrap();
+        for _ in 0..20 {
+            fabric1.register_node(2, addr2);
+            fabric1.remove_node(2);
+            fabric1.set_nodes(vec![(2, addr2)].into_iter());
+            fabric1.set_nodes(vec![].into_iter());
+        }
+        thread::sleep(Duration::from_millis(100));
+        assert!(!fabric1.is_connected(2));
+        assert!(!fabric2.is_connected(1));
+        assert_eq!(fabric1.context.connect_loops.load(atomic::Ordering::Relaxed), 0);
+
+        // added back it connects as usual
+        fabric1.register_node(2, addr2);
+        thread::sleep(Duration::from_millis(50));
+        assert!(fabric1.is_connected(2));
+        assert_eq!(fabric1.context.connect_loops.load(atomic::Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_incoming_max() {
+        use std::net::TcpStream;
+        let _ = env_logger::try_init();
+        let config = Config {
+            fabric_addr: "127.0.0.1:6503".parse().unwrap(),
+            fabric_incoming_max: 1,
+            ..Default::default()
+        };
+        let fabric = Fabric::new(1, &config).unwrap();
+        let mut buffer = [0u8; 8];
+        // the accepted one gets the handshake, the one past the limit is closed
+        let mut accepted = TcpStream::connect("127.0.0.1:6503").unwrap();
+        accepted.read_exact(&mut buffer).unwrap();
+        assert_eq!(LittleEndian::read_u64(&buffer), 1);
+        let mut refused = TcpStream::connect("127.0.0.1:6503").unwrap();
+        assert_eq!(refused.read(&mut buffer).unwrap(), 0);
+        assert_eq!(fabric.context.incoming.load(atomic::Ordering::Relaxed), 1);
+
+        drop(accepted);
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(fabric.context.incoming.load(atomic::Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn test_send_msg_confirmed() {
+        let _ = env_logger::try_init();
+        let config1 = Config {
+            fabric_addr: "127.0.0.1:6485".parse().unwrap(),
+            ..Default::default()
+        };
+        let config2 = Config {
+            fabric_addr: "127.0.0.1:6486".parse().unwrap(),
+            ..Default::default()
+        };
+        let fabric1 = Fabric::new(1, &config1).unwrap();
+        let fabric2 = Fabric::new(2, &config2).unwrap();
+        fabric1.register_node(2, "127.0.0.1:6486".parse().unwrap());
+        fabric2.register_node(1, "127.0.0.1:6485".parse().unwrap());
+        thread::sleep(Duration::from_millis(10));
+
+        let msg = MsgRemoteSetAck {
+            cookie: Default::default(),
+            vnode: Default::default(),
+            result: Ok(Vec::new()),
+        };
+        assert_eq!(fabric1.send_msg_confirmed(2, &msg).wait(), Ok(()));
+        assert_eq!(
+            fabric1.send_msg_confirmed(3, &msg).wait(),
+            Err(FabricError::NoRoute)
+        );
+
+        drop(fabric1);
+        thread::sleep(Duration::from_millis(10));
+        assert_eq!(
+            fabric2.send_msg_confirmed(1, &msg).wait(),
+            Err(FabricError::NoRoute)
+        );
+    }
+
+    #[test]
+    fn test_select_connection() {
+        let connection = |id: usize, queued: usize| {
+            let (chan, _) = fmpsc::unbounded();
+            let queued = Arc::new(AtomicUsize::new(queued));
+            let framing = Framing::Plain;
+            (id, SenderChan { chan, queued, framing })
+        };
+        let selected = |connections: &[(usize, SenderChan)], msg_type| {
+            select_connection(connections, msg_type, 2).map(|&(id, _)| id)
+        };
+
+        let connections = vec![connection(5, 1), connection(3, 1)];
+        assert_eq!(selected(&connections, FabricMsgType::Crud), Some(3));
+        for _ in 0..10 {
+            assert_eq!(selected(&connections, FabricMsgType::Synch), Some(5));
+        }
+        connections[1].1.queued.store(2, Ordering::Relaxed);
+        assert_eq!(selected(&connections, FabricMsgType::Crud), Some(5));
+        connections[0].1.queued.store(2, Ordering::Relaxed);
+        assert_eq!(selected(&connections, FabricMsgType::Crud), None);
+        assert_eq!(selected(&connections, FabricMsgType::Synch), None);
+
+        // a single connection takes everything
+        let connections = vec![connection(3, 0)];
+        assert_eq!(selected(&connections, FabricMsgType::Crud), Some(3));
+        assert_eq!(selected(&connections, FabricMsgType::Synch), Some(3));
+    }
+
+    #[test]
+    fn test_batching() {
+        let send = |seq: u64| MsgSyncSend {
+            vnode: 1,
+            cookie: Default::default(),
+            seq: seq,
+            key: vec![b'k'; 100].into(),
+            value: Default::default(),
+            estimated_keys: None,
+        };
+        let ack = MsgRemoteSetAck {
+            cookie: Default::default(),
+            vnode: Default::default(),
+            result: Ok(Vec::new()),
+        };
+        let queued = |msg: FabricMsgRef, batchable| QueuedFrame {
+            frame: FramedCodec::serialize(
+                msg,
+                StorageFormat::Bincode,
+                Framing::Flagged,
+                FabricCompression::None,
+                0,
+            ),
+            batchable: batchable,
+            confirm: None,
+        };
+
+        let mut sink = FabricSink::new(Vec::new(), 1024);
+        let (confirm_tx, mut confirm_rx) = foneshot::channel();
+        let mut confirm_tx = Some(confirm_tx);
+        for seq in 0..3 {
+            let mut item = queued((&send(seq)).into(), true);
+            if seq == 2 {
+                item.confirm = Some(confirm_tx.take().unwrap());
+            }
+            assert!(sink.start_send(item).unwrap().is_ready());
+        }
+        assert!(sink.start_send(queued((&ack).into(), false)).unwrap().is_ready());
+        // only the crud msg was written so far
+        assert_eq!(sink.inner.len(), 1);
+        assert!(confirm_rx.poll().unwrap().is_not_ready());
+        assert!(sink.poll_complete().unwrap().is_ready());
+        assert_eq!(sink.inner.len(), 2);
+        assert_eq!(sink.inner[1][4], FRAME_BATCH);
+        assert!(confirm_rx.poll().unwrap().is_ready());
+
+        // over batch_max
+        for seq in 3..20 {
+            assert!(sink.start_send(queued((&send(seq)).into(), true)).unwrap().is_ready());
+        }
+        assert!(sink.poll_complete().unwrap().is_ready());
+        assert!(sink.inner[2..].iter().all(|f| f.len() <= 1024));
+
+        let mut src = BytesMut::new();
+        for frame in &sink.inner {
+            src.extend_from_slice(frame);
+        }
+        let mut codec = FramedCodec::new(StorageFormat::Bincode, Framing::Flagged);
+        let mut seqs = Vec::new();
+        while let Some(msg) = codec.decode(&mut src).unwrap() {
+            match msg {
+                FabricMsg::SyncSend(m) => seqs.push(m.seq),
+                FabricMsg::RemoteSetAck(_) => (),
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+        assert!(src.is_empty());
+        assert_eq!(seqs, (0..20).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_codec_compression() {
+        let msg = MsgSyncSend {
+            vnode: 1,
+            cookie: Default::default(),
+            seq: 2,
+            key: vec![b'k'; 10_000].into(),
+            value: Default::default(),
+            estimated_keys: None,
+        };
+        for &(format, compression, flag) in &[
+            (StorageFormat::Bincode, FabricCompression::None, FRAME_UNCOMPRESSED),
+            (StorageFormat::Bincode, FabricCompression::Snappy, FRAME_SNAPPY),
+            (StorageFormat::Bincode, FabricCompression::Lz4, FRAME_LZ4),
+            (StorageFormat::MsgPack, FabricCompression::None, FRAME_UNCOMPRESSED),
+            (StorageFormat::MsgPack, FabricCompression::Snappy, FRAME_SNAPPY),
+            (StorageFormat::MsgPack, FabricCompression::Lz4, FRAME_LZ4),
+        ] {
+            let frame = FramedCodec::serialize(
+                (&msg).into(),
+                format,
+                Framing::Flagged,
+                compression,
+                1024,
+            );
+            assert_eq!(frame[4], flag);
+            let mut src = BytesMut::from(&frame[..]);
+            match FramedCodec::new(format, Framing::Flagged)
+                .decode(&mut src)
+                .unwrap()
+            {
+                Some(FabricMsg::SyncSend(decoded)) => {
+                    assert_eq!(decoded.seq, msg.seq);
+                    assert_eq!(decoded.key, msg.key);
+                }
+                other => panic!("unexpected {:?}", other),
+            }
+            assert!(src.is_empty());
+        }
+
+        // crud msgs are never compressed
+        let ack = MsgRemoteSetAck {
+            cookie: Default::default(),
+            vnode: Default::default(),
+            result: Ok(Vec::new()),
+        };
+        let frame = FramedCodec::serialize(
+            (&ack).into(),
+            StorageFormat::Bincode,
+            Framing::Flagged,
+            FabricCompression::Snappy,
+            0,
+        );
+        assert_eq!(frame[4], FRAME_UNCOMPRESSED);
+
+        // the formats can't read each other
+        let frame = FramedCodec::serialize(
+            (&ack).into(),
+            StorageFormat::MsgPack,
+            Framing::Flagged,
+            FabricCompression::None,
+            0,
+        );
+        let mut src = BytesMut::from(&frame[..]);
+        assert!(FramedCodec::new(StorageFormat::Bincode, Framing::Flagged)
+            .decode(&mut src)
+            .is_err());
+
+        // plain frames are never compressed and are like older versions' frames
+        let frame = FramedCodec::serialize(
+            (&msg).into(),
+            StorageFormat::Bincode,
+            Framing::Plain,
+            FabricCompression::Lz4,
+            1024,
+        );
+        let payload = bincode::serialize(&FabricMsgRef::from(&msg)).unwrap();
+        assert_eq!(LittleEndian::read_u32(&frame[..4]) as usize, payload.len());
+        assert_eq!(&frame[4..], &payload[..]);
+        let mut src = BytesMut::from(&frame[..]);
+        match FramedCodec::new(StorageFormat::Bincode, Framing::Plain)
+            .decode(&mut src)
+            .unwrap()
+        {
+            Some(FabricMsg::SyncSend(decoded)) => assert_eq!(decoded.key, msg.key),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_codec_decompressed_max() {
+        let inflating = |flag: u8, compressed: &[u8]| {
+            let mut frame = BytesMut::new();
+            frame.put_u32_le(compressed.len() as u32 + 1);
+            frame.put_u8(flag);
+            frame.put_slice(compressed);
+            FramedCodec::new(StorageFormat::Bincode, Framing::Flagged).decode(&mut frame)
+        };
+        // the uncompressed lens are checked before inflating anything
+        let mut lz4 = vec![0u8; 16];
+        LittleEndian::write_u32(&mut lz4[..4], FRAME_DECOMPRESSED_MAX as u32 + 1);
+        assert!(inflating(FRAME_LZ4, &lz4).is_err());
+        // snappy starts with the varint of the len, 1GB here
+        assert!(inflating(FRAME_SNAPPY, &[0x80, 0x80, 0x80, 0x80, 0x04, 0]).is_err());
+    }
+
+    #[test]
+    fn test_framing_handshake() {
+        let _ = env_logger::try_init();
+        let config = |port: u16, compression: FabricCompression| Config {
+            fabric_addr: format!("127.0.0.1:{}", port).parse().unwrap(),
+            fabric_compression: compression,
+            fabric_compression_threshold: 0,
+            ..Default::default()
+        };
+        let fabric1 = Fabric::new(1, &config(6506, FabricCompression::Lz4)).unwrap();
+        let fabric2 = Fabric::new(2, &config(6507, FabricCompression::Snappy)).unwrap();
+        let fabric3 = Fabric::new(3, &config(6508, FabricCompression::None)).unwrap();
+        fabric1.register_node(2, "127.0.0.1:6507".parse().unwrap());
+        fabric1.register_node(3, "127.0.0.1:6508".parse().unwrap());
+        thread::sleep(Duration::from_millis(50));
+        let framings = |fabric: &Fabric, node: NodeId| -> Vec<Framing> {
+            let connections = fabric.context.connections.read().unwrap();
+            connections[&node].iter().map(|c| c.1.framing).collect()
+        };
+        // compression is only used if both nodes enable it, otherwise frames are plain
+        assert_eq!(framings(&fabric1, 2), [Framing::Flagged]);
+        assert_eq!(framings(&fabric2, 1), [Framing::Flagged]);
+        assert_eq!(framings(&fabric1, 3), [Framing::Plain]);
+        assert_eq!(framings(&fabric3, 1), [Framing::Plain]);
+
+        let counter = Arc::new(atomic::AtomicUsize::new(0));
+        for fabric in &[&fabric2, &fabric3] {
+            let counter_ = counter.clone();
+            fabric.register_msg_handler(
+                FabricMsgType::Synch,
+                Box::new(move |_, _| {
+                    counter_.fetch_add(1, atomic::Ordering::Relaxed);
+                }),
+            );
+        }
+        let msg = MsgSyncSend {
+            vnode: 1,
+            cookie: Default::default(),
+            seq: 2,
+            key: vec![b'k'; 10_000].into(),
+            value: Default::default(),
+            estimated_keys: None,
+        };
+        fabric1.send_msg(2, &msg).unwrap();
+        fabric1.send_msg(3, &msg).unwrap();
+        thread::sleep(Duration::from_millis(10));
+        assert_eq!(counter.load(atomic::Ordering::Relaxed), 2);
+    }
 }
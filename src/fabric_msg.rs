@@ -19,6 +19,9 @@ pub enum FabricError {
     NotReady,
     SyncInterrupted,
     StorageError,
+    QueueFull,
+    // the vnode doesn't hold (some of) the keys, the nodes disagree on a ring split
+    WrongVNode,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -31,8 +34,13 @@ pub enum FabricMsg {
     SyncSend(MsgSyncSend),
     SyncAck(MsgSyncAck),
     SyncFin(MsgSyncFin),
+    ClockProbe(MsgClockProbe),
+    ClockProbeAck(MsgClockProbeAck),
+    AAETreeRequest(MsgAAETreeRequest),
+    AAETree(MsgAAETree),
     DHTAE(VersionVector),
     DHTSync(Bytes),
+    SyncSendBatch(MsgSyncSendBatch),
     Unknown,
 }
 
@@ -46,8 +54,13 @@ pub enum FabricMsgRef<'a> {
     SyncSend(&'a MsgSyncSend),
     SyncAck(&'a MsgSyncAck),
     SyncFin(&'a MsgSyncFin),
+    ClockProbe(&'a MsgClockProbe),
+    ClockProbeAck(&'a MsgClockProbeAck),
+    AAETreeRequest(&'a MsgAAETreeRequest),
+    AAETree(&'a MsgAAETree),
     DHTAE(&'a VersionVector),
     DHTSync(&'a Bytes),
+    SyncSendBatch(&'a MsgSyncSendBatch),
     Unknown,
 }
 
@@ -60,12 +73,38 @@ impl FabricMsg {
             | FabricMsg::RemoteSetAck(..) => FabricMsgType::Crud,
             FabricMsg::SyncStart(..)
             | FabricMsg::SyncSend(..)
+            | FabricMsg::SyncSendBatch(..)
             | FabricMsg::SyncAck(..)
-            | FabricMsg::SyncFin(..) => FabricMsgType::Synch,
+            | FabricMsg::SyncFin(..)
+            | FabricMsg::ClockProbe(..)
+            | FabricMsg::ClockProbeAck(..)
+            | FabricMsg::AAETreeRequest(..)
+            | FabricMsg::AAETree(..) => FabricMsgType::Synch,
             FabricMsg::DHTSync(..) | FabricMsg::DHTAE(..) => FabricMsgType::DHT,
             _ => unreachable!(),
         }
     }
+
+    /// The vnode the msg is for, None for the dht msgs
+    pub fn vnode(&self) -> Option<VNodeNo> {
+        let vnode = match *self {
+            FabricMsg::RemoteGet(ref m) => m.vnode,
+            FabricMsg::RemoteGetAck(ref m) => m.vnode,
+            FabricMsg::RemoteSet(ref m) => m.vnode,
+            FabricMsg::RemoteSetAck(ref m) => m.vnode,
+            FabricMsg::SyncStart(ref m) => m.vnode,
+            FabricMsg::SyncSend(ref m) => m.vnode,
+            FabricMsg::SyncSendBatch(ref m) => m.vnode,
+            FabricMsg::SyncAck(ref m) => m.vnode,
+            FabricMsg::SyncFin(ref m) => m.vnode,
+            FabricMsg::ClockProbe(ref m) => m.vnode,
+            FabricMsg::ClockProbeAck(ref m) => m.vnode,
+            FabricMsg::AAETreeRequest(ref m) => m.vnode,
+            FabricMsg::AAETree(ref m) => m.vnode,
+            _ => return None,
+        };
+        Some(vnode)
+    }
 }
 
 impl<'a> FabricMsgRef<'a> {
@@ -77,8 +116,13 @@ impl<'a> FabricMsgRef<'a> {
             | FabricMsgRef::RemoteSetAck(..) => FabricMsgType::Crud,
             FabricMsgRef::SyncStart(..)
             | FabricMsgRef::SyncSend(..)
+            | FabricMsgRef::SyncSendBatch(..)
             | FabricMsgRef::SyncAck(..)
-            | FabricMsgRef::SyncFin(..) => FabricMsgType::Synch,
+            | FabricMsgRef::SyncFin(..)
+            | FabricMsgRef::ClockProbe(..)
+            | FabricMsgRef::ClockProbeAck(..)
+            | FabricMsgRef::AAETreeRequest(..)
+            | FabricMsgRef::AAETree(..) => FabricMsgType::Synch,
             FabricMsgRef::DHTSync(..) | FabricMsgRef::DHTAE(..) => FabricMsgType::DHT,
             _ => unreachable!(),
         }
@@ -90,6 +134,10 @@ pub struct MsgRemoteGet {
     pub vnode: VNodeNo,
     pub cookie: Cookie,
     pub keys: Vec<Bytes>,
+    // the receiver coordinates the read at this consistency and replies the merged values
+    pub coordinate: Option<ConsistencyLevel>,
+    // a receiver still bootstrapping replies what it has so far, see bootstrap_stale_reads
+    pub stale: bool,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -120,6 +168,11 @@ pub struct MsgSyncStart {
     pub cookie: Cookie,
     pub clocks_in_peer: BitmappedVersionVector,
     pub target: Option<NodeId>,
+    // compare hash trees first and only stream the keys that differ
+    pub aae: bool,
+    // bootstraps split into count disjoint slices of the keys, this one
+    // streams the (index, count) slice. (0, 1) is all the keys.
+    pub slice: (u16, u16),
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -136,6 +189,32 @@ pub struct MsgSyncSend {
     pub seq: u64,
     pub key: Bytes,
     pub value: Cube,
+    // keys a bootstrap sender expects to stream, only in the first msg
+    pub estimated_keys: Option<u64>,
+}
+
+// consecutive keys of a sync in a single msg, they take the seqs from `seq` on
+// and the msg is acked as a whole with the first one
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MsgSyncSendBatch {
+    pub vnode: VNodeNo,
+    pub cookie: Cookie,
+    pub seq: u64,
+    pub entries: Vec<(Bytes, Cube)>,
+    // keys a bootstrap sender expects to stream, only in the first msg
+    pub estimated_keys: Option<u64>,
+}
+
+impl From<MsgSyncSend> for MsgSyncSendBatch {
+    fn from(msg: MsgSyncSend) -> Self {
+        MsgSyncSendBatch {
+            vnode: msg.vnode,
+            cookie: msg.cookie,
+            seq: msg.seq,
+            entries: vec![(msg.key, msg.value)],
+            estimated_keys: msg.estimated_keys,
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -145,6 +224,37 @@ pub struct MsgSyncAck {
     pub seq: u64,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MsgClockProbe {
+    pub vnode: VNodeNo,
+    pub cookie: Cookie,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MsgClockProbeAck {
+    pub vnode: VNodeNo,
+    pub cookie: Cookie,
+    pub result: Result<BitmappedVersionVector, FabricError>,
+}
+
+// asks an anti-entropy receiver for the hashes of these nodes of its tree
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MsgAAETreeRequest {
+    pub vnode: VNodeNo,
+    pub cookie: Cookie,
+    pub level: u8,
+    pub nodes: Vec<u32>,
+}
+
+// hashes of the requested nodes, in the same order
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MsgAAETree {
+    pub vnode: VNodeNo,
+    pub cookie: Cookie,
+    pub level: u8,
+    pub hashes: Vec<u64>,
+}
+
 impl<'a> Into<FabricMsgRef<'a>> for &'a FabricMsg {
     fn into(self) -> FabricMsgRef<'a> {
         match self {
@@ -154,8 +264,13 @@ impl<'a> Into<FabricMsgRef<'a>> for &'a FabricMsg {
             &FabricMsg::RemoteSetAck(ref a) => FabricMsgRef::RemoteSetAck(a),
             &FabricMsg::SyncStart(ref a) => FabricMsgRef::SyncStart(a),
             &FabricMsg::SyncSend(ref a) => FabricMsgRef::SyncSend(a),
+            &FabricMsg::SyncSendBatch(ref a) => FabricMsgRef::SyncSendBatch(a),
             &FabricMsg::SyncAck(ref a) => FabricMsgRef::SyncAck(a),
             &FabricMsg::SyncFin(ref a) => FabricMsgRef::SyncFin(a),
+            &FabricMsg::ClockProbe(ref a) => FabricMsgRef::ClockProbe(a),
+            &FabricMsg::ClockProbeAck(ref a) => FabricMsgRef::ClockProbeAck(a),
+            &FabricMsg::AAETreeRequest(ref a) => FabricMsgRef::AAETreeRequest(a),
+            &FabricMsg::AAETree(ref a) => FabricMsgRef::AAETree(a),
             &FabricMsg::DHTSync(ref a) => FabricMsgRef::DHTSync(a),
             &FabricMsg::DHTAE(ref a) => FabricMsgRef::DHTAE(a),
             _ => unreachable!(),
@@ -184,5 +299,10 @@ impl_into!(RemoteSet, MsgRemoteSet);
 impl_into!(RemoteSetAck, MsgRemoteSetAck);
 impl_into!(SyncAck, MsgSyncAck);
 impl_into!(SyncSend, MsgSyncSend);
+impl_into!(SyncSendBatch, MsgSyncSendBatch);
 impl_into!(SyncFin, MsgSyncFin);
 impl_into!(SyncStart, MsgSyncStart);
+impl_into!(ClockProbe, MsgClockProbe);
+impl_into!(ClockProbeAck, MsgClockProbeAck);
+impl_into!(AAETreeRequest, MsgAAETreeRequest);
+impl_into!(AAETree, MsgAAETree);
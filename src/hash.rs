@@ -1,24 +1,120 @@
+use byteorder::{ByteOrder, LittleEndian};
 use crc16;
+use std::str::FromStr;
+use utils::GenericError;
 
 pub const HASH_SLOTS: u16 = 16384;
 
-/// RedisCluster style partitioning
-pub fn hash_slot(mut key: &[u8]) -> u16 {
+/// How keys are mapped to hash slots, chosen with `hash_algorithm` when the cluster
+/// is initialized and recorded in the ring, as changing it moves every key.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum HashAlgorithm {
+    /// Same as RedisCluster, so cluster aware clients route keys to the right node
+    Crc16,
+    /// xxHash32, faster for longer keys but the slots don't match RedisCluster ones
+    XxHash,
+}
+
+impl HashAlgorithm {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            HashAlgorithm::Crc16 => "crc16",
+            HashAlgorithm::XxHash => "xxhash",
+        }
+    }
+
+    /// RedisCluster style partitioning, including the {hash tags}
+    pub fn hash_slot(self, key: &[u8]) -> u16 {
+        let key = hash_tag(key);
+        match self {
+            HashAlgorithm::Crc16 => crc16::State::<crc16::XMODEM>::calculate(key) % HASH_SLOTS,
+            HashAlgorithm::XxHash => (xxh32(key, 0) % HASH_SLOTS as u32) as u16,
+        }
+    }
+}
+
+impl FromStr for HashAlgorithm {
+    type Err = GenericError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "crc16" => Ok(HashAlgorithm::Crc16),
+            "xxhash" => Ok(HashAlgorithm::XxHash),
+            _ => Err(format!("Unknown hash algorithm `{}`", s).into()),
+        }
+    }
+}
+
+// the part of the key between the first { and the next }, if not empty
+fn hash_tag(key: &[u8]) -> &[u8] {
     if let Some(open) = key.iter().position(|&x| x == b'{') {
         // note that close will be relative to open due to the skip()
         if let Some(close) = key[open + 1..].iter().position(|&x| x == b'}') {
             if close > 0 {
                 // found  { and } with something in between
-                key = &key[open + 1..open + 1 + close];
+                return &key[open + 1..open + 1 + close];
+            }
+        }
+    }
+    key
+}
+
+const XXH_PRIME32_1: u32 = 2654435761;
+const XXH_PRIME32_2: u32 = 2246822519;
+const XXH_PRIME32_3: u32 = 3266489917;
+const XXH_PRIME32_4: u32 = 668265263;
+const XXH_PRIME32_5: u32 = 374761393;
+
+fn xxh32_round(acc: u32, input: u32) -> u32 {
+    acc.wrapping_add(input.wrapping_mul(XXH_PRIME32_2))
+        .rotate_left(13)
+        .wrapping_mul(XXH_PRIME32_1)
+}
+
+// the reference XXH32, stable across nodes and versions
+pub fn xxh32(input: &[u8], seed: u32) -> u32 {
+    let mut rest = input;
+    let mut hash = if input.len() >= 16 {
+        let mut lanes = [
+            seed.wrapping_add(XXH_PRIME32_1).wrapping_add(XXH_PRIME32_2),
+            seed.wrapping_add(XXH_PRIME32_2),
+            seed,
+            seed.wrapping_sub(XXH_PRIME32_1),
+        ];
+        while rest.len() >= 16 {
+            for (i, lane) in lanes.iter_mut().enumerate() {
+                *lane = xxh32_round(*lane, LittleEndian::read_u32(&rest[i * 4..]));
             }
+            rest = &rest[16..];
         }
+        lanes[0]
+            .rotate_left(1)
+            .wrapping_add(lanes[1].rotate_left(7))
+            .wrapping_add(lanes[2].rotate_left(12))
+            .wrapping_add(lanes[3].rotate_left(18))
+    } else {
+        seed.wrapping_add(XXH_PRIME32_5)
+    };
+    hash = hash.wrapping_add(input.len() as u32);
+    while rest.len() >= 4 {
+        hash = hash.wrapping_add(LittleEndian::read_u32(rest).wrapping_mul(XXH_PRIME32_3));
+        hash = hash.rotate_left(17).wrapping_mul(XXH_PRIME32_4);
+        rest = &rest[4..];
+    }
+    for &byte in rest {
+        hash = hash.wrapping_add((byte as u32).wrapping_mul(XXH_PRIME32_5));
+        hash = hash.rotate_left(11).wrapping_mul(XXH_PRIME32_1);
     }
-    crc16::State::<crc16::XMODEM>::calculate(key) % HASH_SLOTS
+    hash ^= hash >> 15;
+    hash = hash.wrapping_mul(XXH_PRIME32_2);
+    hash ^= hash >> 13;
+    hash = hash.wrapping_mul(XXH_PRIME32_3);
+    hash ^ (hash >> 16)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use test::{black_box, Bencher};
 
     fn raw_hash(key: &[u8]) -> u16 {
         crc16::State::<crc16::XMODEM>::calculate(key) % HASH_SLOTS
@@ -26,6 +122,7 @@ mod tests {
 
     #[test]
     fn test_hash_slot() {
+        let hash_slot = |key: &[u8]| HashAlgorithm::Crc16.hash_slot(key);
         assert_eq!(hash_slot(b"{}"), raw_hash(b"{}"));
         assert_eq!(hash_slot(b"_{abc}"), raw_hash(b"abc"));
         assert_eq!(hash_slot(b"{abc}_"), raw_hash(b"abc"));
@@ -35,4 +132,60 @@ mod tests {
         assert_eq!(hash_slot(b"{abc}{}"), raw_hash(b"abc"));
         assert_eq!(hash_slot(b"{{abc}}"), raw_hash(b"{abc"));
     }
+
+    #[test]
+    fn test_xxh32() {
+        assert_eq!(xxh32(b"", 0), 0x02CC5D05);
+        assert_eq!(xxh32(b"a", 0), 0x550D7456);
+        assert_eq!(xxh32(b"abc", 0), 0x32D153FF);
+        assert_eq!(xxh32(b"Nobody inspects the spammish repetition", 0), 0xE2293B2F);
+        assert_eq!(
+            HashAlgorithm::XxHash.hash_slot(b"_{abc}_"),
+            (0x32D153FF % HASH_SLOTS as u32) as u16
+        );
+        assert_eq!("xxhash".parse::<HashAlgorithm>().unwrap(), HashAlgorithm::XxHash);
+        assert!("siphash".parse::<HashAlgorithm>().is_err());
+    }
+
+    fn bench_hash_slot(b: &mut Bencher, algorithm: HashAlgorithm, key_len: usize) {
+        let keys: Vec<Vec<u8>> = (0..100u8)
+            .map(|i| (0..key_len).map(|j| i.wrapping_add(j as u8)).collect())
+            .collect();
+        b.bytes = (keys.len() * key_len) as u64;
+        b.iter(|| {
+            for key in &keys {
+                black_box(algorithm.hash_slot(black_box(key)));
+            }
+        });
+    }
+
+    #[bench]
+    fn bench_crc16_8(b: &mut Bencher) {
+        bench_hash_slot(b, HashAlgorithm::Crc16, 8);
+    }
+
+    #[bench]
+    fn bench_xxhash_8(b: &mut Bencher) {
+        bench_hash_slot(b, HashAlgorithm::XxHash, 8);
+    }
+
+    #[bench]
+    fn bench_crc16_32(b: &mut Bencher) {
+        bench_hash_slot(b, HashAlgorithm::Crc16, 32);
+    }
+
+    #[bench]
+    fn bench_xxhash_32(b: &mut Bencher) {
+        bench_hash_slot(b, HashAlgorithm::XxHash, 32);
+    }
+
+    #[bench]
+    fn bench_crc16_128(b: &mut Bencher) {
+        bench_hash_slot(b, HashAlgorithm::Crc16, 128);
+    }
+
+    #[bench]
+    fn bench_xxhash_128(b: &mut Bencher) {
+        bench_hash_slot(b, HashAlgorithm::XxHash, 128);
+    }
 }
@@ -0,0 +1,135 @@
+use bytes::Bytes;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::Hasher;
+
+const SKETCH_DEPTH: usize = 4;
+const SKETCH_WIDTH: usize = 512;
+// the counts are halved after this many samples, so the estimates favor recent accesses
+const DECAY_SAMPLES: u64 = 64 * 1024;
+
+/// Estimates the most accessed keys out of a sample of the accesses.
+///
+/// One in `sample_rate` accesses is counted in a count-min sketch and the keys with
+/// the highest estimates are kept in a small list. The sketch only overestimates,
+/// keys colliding with hot ones may show up with inflated counts.
+#[derive(Debug)]
+pub struct HotKeys {
+    sample_rate: u32,
+    // accesses since the last sampled one
+    skipped: u32,
+    samples: u64,
+    sketch: Vec<u32>,
+    top: Vec<(Bytes, u32)>,
+    top_max: usize,
+}
+
+impl HotKeys {
+    /// Samples one in `sample_rate` accesses and tracks up to `top_max` keys
+    pub fn new(sample_rate: u32, top_max: usize) -> Self {
+        assert!(sample_rate > 0);
+        HotKeys {
+            sample_rate: sample_rate,
+            skipped: 0,
+            samples: 0,
+            sketch: vec![0; SKETCH_DEPTH * SKETCH_WIDTH],
+            top: Vec::with_capacity(top_max),
+            top_max: top_max,
+        }
+    }
+
+    pub fn record(&mut self, key: &[u8]) {
+        self.skipped += 1;
+        if self.skipped < self.sample_rate {
+            return;
+        }
+        self.skipped = 0;
+        self.samples += 1;
+        if self.samples % DECAY_SAMPLES == 0 {
+            self.decay();
+        }
+
+        let estimate = self.increment(key);
+        if let Some(p) = self.top.iter().position(|&(ref k, _)| &k[..] == key) {
+            self.top[p].1 = estimate;
+        } else if self.top.len() < self.top_max {
+            self.top.push((Bytes::from(key), estimate));
+        } else if let Some(p) = (0..self.top.len()).min_by_key(|&p| self.top[p].1) {
+            if estimate > self.top[p].1 {
+                self.top[p] = (Bytes::from(key), estimate);
+            }
+        }
+    }
+
+    // double hashing like the bloom filter, returns the new estimate
+    fn increment(&mut self, key: &[u8]) -> u32 {
+        let mut hasher = DefaultHasher::new();
+        hasher.write(key);
+        let h1 = hasher.finish();
+        hasher.write_u64(h1);
+        let h2 = hasher.finish() | 1;
+        let mut estimate = u32::max_value();
+        for row in 0..SKETCH_DEPTH {
+            let col = h1.wrapping_add((row as u64).wrapping_mul(h2)) % SKETCH_WIDTH as u64;
+            let counter = &mut self.sketch[row * SKETCH_WIDTH + col as usize];
+            *counter = counter.saturating_add(1);
+            estimate = estimate.min(*counter);
+        }
+        estimate
+    }
+
+    fn decay(&mut self) {
+        for counter in &mut self.sketch {
+            *counter /= 2;
+        }
+        for entry in &mut self.top {
+            entry.1 /= 2;
+        }
+        self.top.retain(|&(_, count)| count != 0);
+    }
+
+    /// The tracked keys, hottest first, with their estimated (unsampled) accesses
+    pub fn top(&self) -> Vec<(Bytes, u64)> {
+        let mut top: Vec<_> = self
+            .top
+            .iter()
+            .map(|&(ref k, count)| (k.clone(), count as u64 * self.sample_rate as u64))
+            .collect();
+        top.sort_by(|a, b| b.1.cmp(&a.1));
+        top
+    }
+
+    pub fn clear(&mut self) {
+        for counter in &mut self.sketch {
+            *counter = 0;
+        }
+        self.top.clear();
+        self.skipped = 0;
+        self.samples = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hot_keys() {
+        let mut hot_keys = HotKeys::new(2, 3);
+        for i in 0..1000 {
+            hot_keys.record(b"hot");
+            if i % 2 == 0 {
+                hot_keys.record(b"warm");
+            }
+            hot_keys.record(format!("cold{}", i).as_bytes());
+        }
+        let top = hot_keys.top();
+        assert_eq!(top.len(), 3);
+        assert_eq!(top[0].0, Bytes::from("hot"));
+        assert!(top[0].1 >= 1000);
+        assert_eq!(top[1].0, Bytes::from("warm"));
+        assert!(top[1].1 >= 500 && top[1].1 < top[0].1);
+
+        hot_keys.clear();
+        assert!(hot_keys.top().is_empty());
+    }
+}
@@ -1,9 +1,10 @@
 use std::cmp::Ordering;
 use std::collections::hash_map::{Entry, RandomState};
-use std::collections::{BinaryHeap, HashMap};
+use std::collections::{BinaryHeap, HashMap, HashSet};
 use std::fmt;
 use std::hash::{BuildHasher, Hash};
 use std::ops::Deref;
+use std::vec;
 
 // TODO: need a more efficient implementation and possibly more flexibility
 
@@ -57,6 +58,19 @@ impl<K: Hash + Eq + Copy + fmt::Debug, V, T: Ord + Copy, H: BuildHasher + Defaul
         result
     }
 
+    /// Like insert, but with `max` entries or more the one expiring first is dropped
+    /// to make room for the new one.
+    pub fn insert_bounded(&mut self, key: K, value: V, expire: T, max: usize) -> &mut V {
+        if self.map.len() >= max {
+            while let Some(Pair(_, k)) = self.heap.pop() {
+                if self.map.remove(&k).is_some() {
+                    break;
+                }
+            }
+        }
+        self.insert(key, value, expire)
+    }
+
     pub fn pop_expired(&mut self, now: T) -> Option<(K, V)> {
         loop {
             let key = match self.heap.peek() {
@@ -70,6 +84,35 @@ impl<K: Hash + Eq + Copy + fmt::Debug, V, T: Ord + Copy, H: BuildHasher + Defaul
         }
     }
 
+    /// The entries with their deadline, the ones expiring first come first.
+    /// Like in `pop_expired` a key inserted more than once uses its earliest deadline.
+    pub fn iter(&self) -> vec::IntoIter<(K, &V, T)> {
+        let mut pairs: Vec<&Pair<T, K>> = self.heap.iter().collect();
+        pairs.sort_by(|a, b| a.0.cmp(&b.0));
+        let mut seen = HashSet::with_capacity(self.map.len());
+        pairs
+            .into_iter()
+            .filter_map(|&Pair(e, k)| self.map.get(&k).map(|v| (k, v, e)))
+            .filter(|&(k, _, _)| seen.insert(k))
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    /// The earliest deadline of the entries, without removing anything
+    pub fn next_deadline(&self) -> Option<T> {
+        match self.heap.peek() {
+            Some(&Pair(e, k)) if self.map.contains_key(&k) => Some(e),
+            // the top belongs to a removed entry
+            Some(_) => self
+                .heap
+                .iter()
+                .filter(|p| self.map.contains_key(&p.1))
+                .map(|p| p.0)
+                .min(),
+            None => None,
+        }
+    }
+
     pub fn touch_expired(&mut self, now: T, expire: T) -> Option<(K, &V)> {
         loop {
             let key = match self.heap.peek() {
@@ -117,3 +160,63 @@ impl<T: Ord, V> Ord for Pair<T, V> {
         other.0.cmp(&self.0)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_iter() {
+        let mut map: InFlightMap<u64, &str, u64> = InFlightMap::new();
+        assert_eq!(map.next_deadline(), None);
+        assert_eq!(map.iter().count(), 0);
+
+        map.insert(1, "a", 30);
+        map.insert(2, "b", 10);
+        map.insert(3, "c", 20);
+        assert_eq!(map.next_deadline(), Some(10));
+        assert_eq!(
+            map.iter().collect::<Vec<_>>(),
+            [(2, &"b", 10), (3, &"c", 20), (1, &"a", 30)]
+        );
+
+        // removed entries leave their deadline behind in the heap
+        map.remove(&2);
+        assert_eq!(map.next_deadline(), Some(20));
+        map.insert(4, "d", 5);
+        map.remove(&4);
+        map.insert(4, "d", 25);
+        assert_eq!(map.next_deadline(), Some(5));
+        assert_eq!(
+            map.iter().collect::<Vec<_>>(),
+            [(4, &"d", 5), (3, &"c", 20), (1, &"a", 30)]
+        );
+
+        // iterating doesn't change what expires
+        assert_eq!(map.pop_expired(20), Some((4, "d")));
+        assert_eq!(map.pop_expired(20), Some((3, "c")));
+        assert_eq!(map.pop_expired(20), None);
+        assert_eq!(map.next_deadline(), Some(30));
+        assert_eq!(map.iter().collect::<Vec<_>>(), [(1, &"a", 30)]);
+        assert_eq!(map.touch_expired(30, 40), Some((1, &"a")));
+        assert_eq!(map.next_deadline(), Some(40));
+        assert_eq!(map.pop_expired(40), Some((1, "a")));
+        assert_eq!(map.next_deadline(), None);
+    }
+
+    #[test]
+    fn test_insert_bounded() {
+        let mut map: InFlightMap<u64, &str, u64> = InFlightMap::new();
+        map.insert_bounded(1, "a", 30, 2);
+        map.insert_bounded(2, "b", 10, 2);
+        map.remove(&2);
+        map.insert_bounded(3, "c", 20, 2);
+        // the removed entry doesn't count
+        map.insert_bounded(4, "d", 40, 2);
+        assert_eq!(map.len(), 2);
+        assert_eq!(
+            map.iter().collect::<Vec<_>>(),
+            [(1, &"a", 30), (4, &"d", 40)]
+        );
+    }
+}
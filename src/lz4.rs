@@ -0,0 +1,71 @@
+use lz4_sys;
+use std::os::raw::{c_char, c_int};
+
+// the largest input lz4 takes, LZ4_MAX_INPUT_SIZE in lz4.h
+const MAX_INPUT_SIZE: usize = 0x7E00_0000;
+
+/// Appends `src` compressed as a lz4 block to `dst`.
+/// Returns false, leaving `dst` as it was, if `src` is too large for lz4.
+pub fn compress_block(src: &[u8], dst: &mut Vec<u8>) -> bool {
+    if src.len() > MAX_INPUT_SIZE {
+        return false;
+    }
+    let bound = unsafe { lz4_sys::LZ4_compressBound(src.len() as c_int) };
+    let start = dst.len();
+    dst.resize(start + bound as usize, 0);
+    // writes at most `bound` bytes, the room left after `start`
+    let len = unsafe {
+        lz4_sys::LZ4_compress_default(
+            src.as_ptr() as *const c_char,
+            dst[start..].as_mut_ptr() as *mut c_char,
+            src.len() as c_int,
+            bound,
+        )
+    };
+    if len <= 0 {
+        dst.truncate(start);
+        return false;
+    }
+    dst.truncate(start + len as usize);
+    true
+}
+
+/// Decompresses a lz4 block that inflates to exactly `len` bytes,
+/// None if it's malformed or inflates to something else.
+pub fn decompress_block(src: &[u8], len: usize) -> Option<Vec<u8>> {
+    if src.len() > c_int::max_value() as usize || len > MAX_INPUT_SIZE {
+        return None;
+    }
+    let mut dst = vec![0u8; len];
+    // never reads past `src` nor writes past `dst`, fails instead
+    let decompressed = unsafe {
+        lz4_sys::LZ4_decompress_safe(
+            src.as_ptr() as *const c_char,
+            dst.as_mut_ptr() as *mut c_char,
+            src.len() as c_int,
+            len as c_int,
+        )
+    };
+    if decompressed < 0 || decompressed as usize != len {
+        return None;
+    }
+    Some(dst)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_block() {
+        let src: Vec<u8> = (0..100).flat_map(|_| b"sucredb ".iter().cloned()).collect();
+        let mut dst = vec![1, 2];
+        assert!(compress_block(&src, &mut dst));
+        assert_eq!(&dst[..2], &[1u8, 2]);
+        assert!(dst.len() < src.len());
+        assert_eq!(decompress_block(&dst[2..], src.len()), Some(src.clone()));
+        assert_eq!(decompress_block(&dst[2..], src.len() - 1), None);
+        let truncated = &dst[2..dst.len() - 1];
+        assert_eq!(decompress_block(truncated, src.len()), None);
+    }
+}
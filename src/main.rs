@@ -1,4 +1,5 @@
 #![feature(fnbox, try_from)]
+#![cfg_attr(test, feature(test))]
 #![allow(dead_code)]
 #![cfg_attr(feature = "cargo-clippy", allow(cast_lossless))]
 
@@ -17,15 +18,19 @@ extern crate bytes;
 extern crate clap;
 extern crate crc16;
 extern crate futures;
+extern crate hmac;
 #[macro_use]
 extern crate lazy_static;
 extern crate linear_map;
 #[macro_use]
 extern crate log;
 extern crate log4rs;
+extern crate log_mdc;
+extern crate lz4_sys;
 extern crate metrics as rust_metrics;
 extern crate num_cpus;
 extern crate rand;
+extern crate rmp_serde;
 extern crate roaring;
 extern crate rocksdb;
 extern crate serde;
@@ -33,23 +38,32 @@ extern crate serde;
 extern crate serde_derive;
 extern crate crossbeam_channel;
 extern crate serde_yaml;
+extern crate sha2;
+extern crate signal_hook;
+extern crate snap;
 extern crate tokio_core;
 extern crate tokio_io;
 
 #[cfg(test)]
 extern crate env_logger;
+#[cfg(test)]
+extern crate test;
 
 #[macro_use]
 mod utils;
 mod types;
 mod version_vector;
+mod bloom;
 // mod gossip;
 mod cubes;
 mod dht;
 mod fabric;
 mod fabric_msg;
 mod hash;
+mod hotkeys;
 mod inflightmap;
+mod lz4;
+mod merkle;
 mod storage;
 #[macro_use]
 mod database;
@@ -79,7 +93,8 @@ fn configure() -> config::Config {
                 .long_help(
                     "Path to the .yaml config file. Note that configuration \
                      set through the command line will take precedence \
-                     over the config file.",
+                     over the SUCREDB_ prefixed environment variables, \
+                     which take precedence over the config file.",
                 )
                 .display_order(0),
         )
@@ -111,6 +126,15 @@ fn configure() -> config::Config {
                 .help("Fabric listen addr")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("log_json")
+                .long("log-json")
+                .help("Log records as JSON objects")
+                .long_help(
+                    "Log records as JSON objects. Only used without a config file, \
+                     otherwise set a json encoder in the logging section.",
+                ),
+        )
         .arg(
             Arg::with_name("seed_nodes")
                 .short("s")
@@ -126,6 +150,7 @@ fn configure() -> config::Config {
                     Arg::with_name("replication_factor")
                         .short("r")
                         .help("Number of replicas")
+                        .env("SUCREDB_REPLICATION_FACTOR")
                         .default_value(DEFAULT_REPLICATION_FACTOR),
                 )
                 .arg(
@@ -136,6 +161,7 @@ fn configure() -> config::Config {
                             "Number of partitions, the recommended value is \
                              `expected node count * 10` rounded up to the next power of 2.",
                         )
+                        .env("SUCREDB_PARTITIONS")
                         .default_value(DEFAULT_PARTITIONS),
                 )
                 .display_order(0),
@@ -147,9 +173,11 @@ fn configure() -> config::Config {
     if let Some(v) = matches.value_of("config_file") {
         read_config_file(Path::new(v), &mut config);
     } else {
-        setup_default_logging();
+        setup_default_logging(matches.is_present("log_json"));
     }
 
+    read_config_env(&mut config);
+
     if let Some(v) = matches.value_of("data_dir") {
         config.data_dir = v.into();
     }
@@ -159,7 +187,7 @@ fn configure() -> config::Config {
     }
 
     if let Some(v) = matches.value_of("listen_addr") {
-        config.listen_addr = v.parse().expect("Can't parse listen_addr");
+        config.listen_addr = resolve_addr(v).expect("Can't parse listen_addr");
     }
 
     if let Some(v) = matches.values_of("seed_nodes") {
@@ -169,7 +197,7 @@ fn configure() -> config::Config {
     }
 
     if let Some(v) = matches.value_of("fabric_addr") {
-        config.fabric_addr = v.parse().expect("Can't parse fabric_addr");
+        config.fabric_addr = resolve_addr(v).expect("Can't parse fabric_addr");
     }
 
     if let Some(sub) = matches.subcommand_matches("init") {
@@ -187,6 +215,10 @@ fn configure() -> config::Config {
         });
     }
 
+    if let Err(e) = config.validate() {
+        panic!("Invalid configuration: {}", e);
+    }
+
     config
 }
 
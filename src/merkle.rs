@@ -0,0 +1,116 @@
+use byteorder::{ByteOrder, LittleEndian};
+use cubes::Cube;
+use std::ops::Range;
+
+/// Children of each inner node
+pub const MERKLE_FANOUT: u32 = 16;
+/// Levels bellow the root, the last one has the leaves
+pub const MERKLE_DEPTH: u8 = 3;
+
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+// stable across nodes and versions, unlike the std hashers
+fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes
+        .iter()
+        .fold(hash, |h, &b| (h ^ b as u64).wrapping_mul(FNV_PRIME))
+}
+
+/// Hash tree over the keys of a vnode, used by anti-entropy syncs to find
+/// which keys differ between replicas without exchanging all of them.
+///
+/// Keys are assigned to leaves by their hash and each key contributes the xor
+/// of the hashes of its dots to its leaf and all the nodes above it.
+/// So replicas with the same live versions have the same tree regardless of
+/// the order of the writes, and a write only needs to xor the difference
+/// between the old and the new digest of the key.
+#[derive(Debug)]
+pub struct MerkleTree {
+    // levels[0] is the root, levels[MERKLE_DEPTH] the leaves
+    levels: Vec<Vec<u64>>,
+}
+
+impl MerkleTree {
+    pub fn new() -> Self {
+        MerkleTree {
+            levels: (0..MERKLE_DEPTH as u32 + 1)
+                .map(|l| vec![0; MERKLE_FANOUT.pow(l) as usize])
+                .collect(),
+        }
+    }
+
+    /// The leaf that holds the key
+    pub fn leaf(key: &[u8]) -> u32 {
+        (fnv1a(FNV_OFFSET, key) % MERKLE_FANOUT.pow(MERKLE_DEPTH as u32) as u64) as u32
+    }
+
+    /// Digest of the live versions of a key, 0 if it has none
+    pub fn digest(key: &[u8], cube: &Cube) -> u64 {
+        if let Cube::Void(_) = *cube {
+            return 0;
+        }
+        let key_hash = fnv1a(FNV_OFFSET, key);
+        let mut digest = 0;
+        cube.for_each_dot(|i, v| {
+            let mut dot = [0u8; 16];
+            LittleEndian::write_u64(&mut dot[..8], i);
+            LittleEndian::write_u64(&mut dot[8..], v);
+            digest ^= fnv1a(key_hash, &dot);
+        });
+        digest
+    }
+
+    /// Replaces the contribution of a key, from the old digest to the new one
+    pub fn update(&mut self, key: &[u8], old: u64, new: u64) {
+        let diff = old ^ new;
+        if diff == 0 {
+            return;
+        }
+        let mut index = Self::leaf(key) as usize;
+        for level in self.levels.iter_mut().rev() {
+            level[index] ^= diff;
+            index /= MERKLE_FANOUT as usize;
+        }
+    }
+
+    pub fn hash(&self, level: u8, index: u32) -> Option<u64> {
+        self.levels
+            .get(level as usize)
+            .and_then(|l| l.get(index as usize))
+            .cloned()
+    }
+
+    /// Indexes of the children of a node, in the next level
+    pub fn children(index: u32) -> Range<u32> {
+        index * MERKLE_FANOUT..(index + 1) * MERKLE_FANOUT
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use version_vector::BitmappedVersionVector;
+
+    #[test]
+    fn test_merkle() {
+        let key = b"key";
+        let cube = Cube::new(&BitmappedVersionVector::new());
+        assert_eq!(MerkleTree::digest(key, &cube), 0);
+
+        let mut a = MerkleTree::new();
+        let mut b = MerkleTree::new();
+        a.update(key, 0, 1);
+        a.update(b"other", 0, 2);
+        b.update(b"other", 0, 2);
+        assert_ne!(a.hash(0, 0), b.hash(0, 0));
+        b.update(key, 0, 3);
+        b.update(key, 3, 1);
+        assert_eq!(a.hash(0, 0), b.hash(0, 0));
+
+        let leaf = MerkleTree::leaf(key);
+        assert_eq!(a.hash(MERKLE_DEPTH, leaf), Some(1));
+        assert_eq!(a.hash(MERKLE_DEPTH, 1 << 20), None);
+        assert!(MerkleTree::children(leaf / MERKLE_FANOUT).any(|c| c == leaf));
+    }
+}
@@ -5,12 +5,24 @@ use std::sync::Arc;
 // TODO: Expose these metrics
 lazy_static! {
     pub static ref CLIENT_CONNECTION: Arc<StdGauge> = { StdGauge::new() };
+    pub static ref CLIENT_ACCEPTED: Arc<StdMeter> = { StdMeter::new() };
+    pub static ref CLIENT_REJECTED: Arc<StdMeter> = { StdMeter::new() };
+    pub static ref FABRIC_ACCEPTED: Arc<StdMeter> = { StdMeter::new() };
+    pub static ref FABRIC_REJECTED: Arc<StdMeter> = { StdMeter::new() };
     pub static ref REQUEST_GET: Arc<Meter> = { StdMeter::new() };
     pub static ref REQUEST_SET: Arc<StdMeter> = { StdMeter::new() };
     pub static ref REQUEST_DEL: Arc<StdMeter> = { StdMeter::new() };
+    pub static ref READ_REPAIR: Arc<StdMeter> = { StdMeter::new() };
     pub static ref SYNC_SEND: Arc<StdMeter> = { StdMeter::new() };
     pub static ref SYNC_RECV: Arc<StdMeter> = { StdMeter::new() };
     pub static ref SYNC_RESEND: Arc<StdMeter> = { StdMeter::new() };
+    pub static ref SYNC_ACK_DELAYED: Arc<StdMeter> = { StdMeter::new() };
+    // synced keys not stored for being over the key or value size limits
+    pub static ref SYNC_SKIPPED: Arc<StdMeter> = { StdMeter::new() };
     pub static ref SYNC_OUTGOING: Arc<StdGauge> = { StdGauge::new() };
     pub static ref SYNC_INCOMING: Arc<StdGauge> = { StdGauge::new() };
+    pub static ref BOOTSTRAP_FAILED: Arc<StdGauge> = { StdGauge::new() };
+    pub static ref VNODES_REFUSED: Arc<StdGauge> = { StdGauge::new() };
+    pub static ref WORKER_PANIC: Arc<StdMeter> = { StdMeter::new() };
+    pub static ref STORAGE_EVICTION: Arc<StdMeter> = { StdMeter::new() };
 }
@@ -2,21 +2,36 @@ use std::cell::RefCell;
 use std::collections::VecDeque;
 use std::io;
 use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use bytes::{BufMut, BytesMut};
 use database::{Context as DbContext, Database, Token, WorkerMsg};
 use futures::sync::mpsc as fmpsc;
 use futures::{Future, Sink, Stream};
+use signal_hook;
 use tokio_core as tokio;
 use tokio_io::{codec, AsyncRead};
 use workers::WorkerSender;
 
 use config::Config;
-use metrics::{self, Gauge};
+use metrics::{self, Gauge, Meter};
 use resp::{self, RespValue};
 use utils::IdHashMap;
 
+const SHUTDOWN_CHECK_INTERVAL_MS: u64 = 100;
+
+// the flag is set by SIGTERM/SIGINT, the server checks it periodically
+fn register_shutdown_signals() -> Arc<AtomicBool> {
+    let requested = Arc::new(AtomicBool::new(false));
+    for &signal in &[signal_hook::SIGINT, signal_hook::SIGTERM] {
+        signal_hook::flag::register(signal, requested.clone())
+            .expect("Can't register the shutdown signal handler");
+    }
+    requested
+}
+
 struct RespCodec;
 
 impl codec::Decoder for RespCodec {
@@ -74,6 +89,7 @@ impl Context {
         chan_tx: fmpsc::UnboundedSender<DbContext>,
     ) -> Self {
         metrics::CLIENT_CONNECTION.inc();
+        context.database.signal_client_connected();
         context.token_chans.lock().unwrap().insert(token, chan_tx);
         Context {
             context: context,
@@ -118,6 +134,7 @@ impl Context {
 impl Drop for Context {
     fn drop(&mut self) {
         self.context.token_chans.lock().unwrap().remove(&self.token);
+        self.context.database.signal_client_disconnected();
         metrics::CLIENT_CONNECTION.dec();
     }
 }
@@ -190,15 +207,17 @@ impl Server {
         let listener =
             tokio::net::TcpListener::bind(&self.config.listen_addr, &core.handle()).unwrap();
         let listener_fut = listener.incoming().for_each(|(socket, addr)| {
-            if context.token_chans.lock().unwrap().len()
+            if context.database.client_connections()
                 >= context.database.config.client_connection_max as usize
             {
                 info!(
                     "Refusing connection from {:?}, connection limit reached",
                     addr
                 );
+                metrics::CLIENT_REJECTED.mark(1);
                 return Ok(());
             }
+            metrics::CLIENT_ACCEPTED.mark(1);
             info!("Token {} accepting connection from {:?}", next_token, addr);
             let conn_ctx = context.clone();
             handle.spawn(
@@ -211,6 +230,16 @@ impl Server {
             Ok(())
         });
 
-        core.run(listener_fut).unwrap();
+        // runs until SIGTERM/SIGINT, then shuts the database down cleanly
+        let shutdown_requested = register_shutdown_signals();
+        let shutdown_interval = Duration::from_millis(SHUTDOWN_CHECK_INTERVAL_MS);
+        let shutdown_fut = tokio::reactor::Interval::new(shutdown_interval, &handle)
+            .unwrap()
+            .take_while(|_| Ok(!shutdown_requested.load(Ordering::SeqCst)))
+            .for_each(|_| Ok(()));
+
+        core.run(listener_fut.select(shutdown_fut).map_err(|(e, _)| e))
+            .unwrap();
+        context.database.shutdown();
     }
 }
@@ -1,8 +1,18 @@
-use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use bincode;
+use byteorder::{BigEndian, ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
+use hash::xxh32;
+use metrics::{self, Meter};
+use rmp_serde;
 use rocksdb::{self, Writable};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::Bound::{self, Excluded, Included, Unbounded};
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
 use std::io::Write;
 use std::path::Path;
-use std::sync::Arc;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
 use std::{mem, str};
 use utils::*;
 
@@ -18,78 +28,250 @@ impl rocksdb::SliceTransform for U16BeSuffixTransform {
     }
 }
 
-pub struct StorageManager {
-    db: Arc<rocksdb::DB>,
+/// Encoding of the values (cubes) and vnode states persisted to disk.
+/// It's chosen when the data directory is created and recorded in the metadata,
+/// so a node always reads data with the format it was written with.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum StorageFormat {
+    Bincode,
+    MsgPack,
 }
 
-#[inline]
-fn build_key<'a>(buffer: &'a mut [u8], num: u16, key: &[u8]) -> &'a [u8] {
-    (&mut buffer[..2]).write_u16::<BigEndian>(num).unwrap();
-    (&mut buffer[2..]).write_all(key).unwrap();
-    &buffer[..2 + key.len()]
+/// Version byte prepended to the stored values (cubes), so their layout can change
+/// without misreading the existing data. Values written before versioning have
+/// no such byte, they start with the bincode variant index or a msgpack array,
+/// so the unused msgpack marker 0xc1 can't be confused with them. Newer versions
+/// take the following bytes, up to 0xcf, which also never start an old value.
+///
+/// Since 0xc2 the version is followed by the xxh32 (little endian) of the rest,
+/// so corrupted values are detected instead of being decoded as garbage.
+pub const VALUE_FORMAT_VERSION: u8 = 0xc2;
+// the first version, without checksum
+const VALUE_FORMAT_VERSION_UNCHECKED: u8 = 0xc1;
+const VALUE_FORMAT_VERSION_MAX: u8 = 0xcf;
+const VALUE_HEADER_LEN: usize = 5;
+
+impl StorageFormat {
+    pub fn serialize<T: Serialize>(self, value: &T) -> Result<Vec<u8>, GenericError> {
+        Ok(match self {
+            StorageFormat::Bincode => bincode::serialize(value)?,
+            StorageFormat::MsgPack => rmp_serde::to_vec(value)?,
+        })
+    }
+
+    pub fn deserialize<T: DeserializeOwned>(self, bytes: &[u8]) -> Result<T, GenericError> {
+        Ok(match self {
+            StorageFormat::Bincode => bincode::deserialize(bytes)?,
+            StorageFormat::MsgPack => rmp_serde::from_slice(bytes)?,
+        })
+    }
+
+    /// Like serialize but prefixed with VALUE_FORMAT_VERSION and the checksum
+    pub fn serialize_value<T: Serialize>(self, value: &T) -> Result<Vec<u8>, GenericError> {
+        let mut bytes = vec![VALUE_FORMAT_VERSION, 0, 0, 0, 0];
+        match self {
+            StorageFormat::Bincode => bincode::serialize_into(&mut bytes, value)?,
+            StorageFormat::MsgPack => rmp_serde::encode::write(&mut bytes, value)?,
+        }
+        let checksum = xxh32(&bytes[VALUE_HEADER_LEN..], 0);
+        LittleEndian::write_u32(&mut bytes[1..VALUE_HEADER_LEN], checksum);
+        Ok(bytes)
+    }
+
+    /// Reads values written by serialize_value and the older unversioned ones,
+    /// the later are rewritten with the version byte on their next update.
+    /// Fails if the checksum doesn't match the value.
+    pub fn deserialize_value<T: DeserializeOwned>(self, bytes: &[u8]) -> Result<T, GenericError> {
+        match bytes.first() {
+            Some(&VALUE_FORMAT_VERSION) => {
+                if bytes.len() < VALUE_HEADER_LEN {
+                    return Err("Truncated value".into());
+                }
+                let checksum = LittleEndian::read_u32(&bytes[1..VALUE_HEADER_LEN]);
+                if xxh32(&bytes[VALUE_HEADER_LEN..], 0) != checksum {
+                    return Err("Value checksum mismatch".into());
+                }
+                self.deserialize(&bytes[VALUE_HEADER_LEN..])
+            }
+            Some(&VALUE_FORMAT_VERSION_UNCHECKED) => self.deserialize(&bytes[1..]),
+            Some(&v) if v > VALUE_FORMAT_VERSION && v <= VALUE_FORMAT_VERSION_MAX => {
+                Err(format!("Unsupported value format version {:#x}", v).into())
+            }
+            _ => self.deserialize(bytes),
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            StorageFormat::Bincode => "bincode",
+            StorageFormat::MsgPack => "msgpack",
+        }
+    }
 }
 
-#[inline]
-fn build_log_key<'a>(buffer: &'a mut [u8], num: u16, log_key: (u64, u64)) -> &'a [u8] {
-    (&mut buffer[..2]).write_u16::<BigEndian>(num).unwrap();
-    (&mut buffer[2..2 + 8])
-        .write_u64::<BigEndian>(log_key.0)
-        .unwrap();
-    (&mut buffer[2 + 8..2 + 8 + 8])
-        .write_u64::<BigEndian>(log_key.1)
-        .unwrap();
-    &buffer[..2 + 8 + 8]
+impl FromStr for StorageFormat {
+    type Err = GenericError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bincode" => Ok(StorageFormat::Bincode),
+            "msgpack" => Ok(StorageFormat::MsgPack),
+            _ => Err(format!("Unknown storage format `{}`", s).into()),
+        }
+    }
 }
 
-#[inline]
-fn build_log_prefix<'a>(buffer: &'a mut [u8], num: u16, prefix: u64) -> &'a [u8] {
-    (&mut buffer[..2]).write_u16::<BigEndian>(num).unwrap();
-    (&mut buffer[2..2 + 8])
-        .write_u64::<BigEndian>(prefix)
-        .unwrap();
-    &buffer[..2 + 8]
+/// Where the vnodes data is kept, chosen with `storage_backend`.
+/// The memory backend loses everything on restart, it's meant for tests and caches.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum StorageBackend {
+    RocksDb,
+    Memory,
 }
 
-// TODO: support TTL
-// TODO: specific comparator for log cf
-// TODO: merge operator could be a big win
-pub struct Storage {
-    db: Arc<rocksdb::DB>,
-    cf: &'static rocksdb::CFHandle,
-    log_cf: &'static rocksdb::CFHandle,
-    num: u16,
+impl StorageBackend {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            StorageBackend::RocksDb => "rocksdb",
+            StorageBackend::Memory => "memory",
+        }
+    }
 }
 
-unsafe impl Sync for Storage {}
-unsafe impl Send for Storage {}
+impl FromStr for StorageBackend {
+    type Err = GenericError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rocksdb" => Ok(StorageBackend::RocksDb),
+            "memory" => Ok(StorageBackend::Memory),
+            _ => Err(format!("Unknown storage backend `{}`", s).into()),
+        }
+    }
+}
 
-pub struct StorageBatch<'a> {
-    storage: &'a Storage,
-    wb: rocksdb::WriteBatch,
+/// How a bounded memory backend picks the keys to evict, see `max_memory`
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    /// Least recently read or written first
+    Lru,
+    /// Least often read or written first, the least recent of those first
+    Lfu,
 }
 
-pub struct SendableStorageBatch(rocksdb::WriteBatch);
+impl EvictionPolicy {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            EvictionPolicy::Lru => "lru",
+            EvictionPolicy::Lfu => "lfu",
+        }
+    }
+}
 
-impl<'a> From<StorageBatch<'a>> for SendableStorageBatch {
-    fn from(sb: StorageBatch<'a>) -> Self {
-        SendableStorageBatch(sb.wb)
+impl FromStr for EvictionPolicy {
+    type Err = GenericError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lru" => Ok(EvictionPolicy::Lru),
+            "lfu" => Ok(EvictionPolicy::Lfu),
+            _ => Err(format!("Unknown eviction policy `{}`", s).into()),
+        }
     }
 }
 
-struct GenericIterator {
+/// Storage number the database keeps its own state (node id, ring, vnode states) in
+pub const META_STORAGE_NUM: u16 = ::std::u16::MAX;
+/// Storage number for transient data, like a vnode dump being checked before a restore
+pub const SCRATCH_STORAGE_NUM: u16 = META_STORAGE_NUM - 1;
+
+// how long the log column keeps its entries by default, in seconds
+const DEFAULT_LOG_RETENTION: u64 = 72 * 3600;
+// size the memory backend log is trimmed to, like the rocksdb fifo compaction default
+const MEMORY_LOG_MAX_SIZE: u64 = 1024 * 1024 * 1024;
+
+// keyspaces of a backend, values go to Data and the (dot -> key) entries to Log
+#[derive(Debug, Copy, Clone)]
+enum Column {
+    Data,
+    Log,
+}
+
+// Operations the storages need from a backend. Keys are prefixed with the vnode
+// number so all the vnodes of a node share a single backend.
+trait Backend: Send + Sync {
+    fn get(
+        &self,
+        column: Column,
+        key: &[u8],
+        callback: &mut FnMut(&[u8]),
+    ) -> Result<(), GenericError>;
+
+    fn batch_new(&self, reserve: usize) -> Box<BackendBatch>;
+
+    // positioned at (or right after) `start`, stops before `end` if any
+    fn iterator(
+        &self,
+        column: Column,
+        start: &[u8],
+        end: Option<&[u8]>,
+    ) -> Box<BackendIterator>;
+
+    // removes the keys in [from, to)
+    fn clear(&self, column: Column, from: &[u8], to: &[u8]);
+
+    fn approximate_size(&self, column: Column, from: &[u8], to: &[u8]) -> u64;
+
+    // keys evicted from the storage `num` and their values size since the last call
+    fn take_evicted(&self, num: u16) -> (u64, u64);
+
+    fn sync(&self) -> Result<(), GenericError>;
+}
+
+trait BackendBatch: Send {
+    fn put(&mut self, column: Column, key: &[u8], value: &[u8]);
+
+    fn delete(&mut self, column: Column, key: &[u8]);
+
+    fn is_empty(&self) -> bool;
+
+    fn write(self: Box<Self>, sync: bool) -> Result<(), GenericError>;
+}
+
+// works like the rocksdb iterators, key() and value() are only valid while valid()
+trait BackendIterator: Send {
+    fn seek(&mut self, key: &[u8]);
+
+    fn valid(&self) -> bool;
+
+    fn next(&mut self);
+
+    fn key(&self) -> &[u8];
+
+    fn value(&self) -> &[u8];
+}
+
+struct RocksDbBackend {
     db: Arc<rocksdb::DB>,
-    iterator: rocksdb::rocksdb::DBIterator<Arc<rocksdb::DB>>,
-    first: bool,
+    cf: &'static rocksdb::CFHandle,
+    log_cf: &'static rocksdb::CFHandle,
 }
 
-pub struct StorageIterator(GenericIterator);
+unsafe impl Sync for RocksDbBackend {}
+unsafe impl Send for RocksDbBackend {}
 
-pub struct LogStorageIterator(GenericIterator);
+struct RocksDbBatch {
+    db: Arc<rocksdb::DB>,
+    cf: &'static rocksdb::CFHandle,
+    log_cf: &'static rocksdb::CFHandle,
+    wb: rocksdb::WriteBatch,
+}
 
-unsafe impl Send for GenericIterator {}
+unsafe impl Send for RocksDbBatch {}
 
-impl StorageManager {
-    pub fn new<P: AsRef<Path>>(path: P) -> Result<StorageManager, GenericError> {
+struct RocksDbIterator(rocksdb::rocksdb::DBIterator<Arc<rocksdb::DB>>);
+
+unsafe impl Send for RocksDbIterator {}
+
+impl RocksDbBackend {
+    fn new<P: AsRef<Path>>(path: P, log_retention: u64) -> Result<RocksDbBackend, GenericError> {
         let mut opts = rocksdb::DBOptions::new();
         opts.create_if_missing(true);
         opts.set_max_background_jobs(4);
@@ -119,7 +301,7 @@ impl StorageManager {
         let mut log_cf_opts = rocksdb::ColumnFamilyOptions::new();
         log_cf_opts.compression(rocksdb::DBCompressionType::No);
         let mut fifo_opts = rocksdb::FifoCompactionOptions::new();
-        fifo_opts.set_ttl(3600 * 72); // 72 hours
+        fifo_opts.set_ttl(log_retention);
         log_cf_opts.set_fifo_compaction_options(fifo_opts);
         log_cf_opts.set_compaction_style(rocksdb::DBCompactionStyle::Fifo);
         log_cf_opts.set_write_buffer_size(32 * 1024 * 1024);
@@ -150,62 +332,755 @@ impl StorageManager {
             Ok(db)
         })?;
 
-        Ok(StorageManager { db: Arc::new(db) })
+        let db = Arc::new(db);
+        let cf = unsafe { mem::transmute(db.cf_handle("default").unwrap()) };
+        let log_cf = unsafe { mem::transmute(db.cf_handle("log").unwrap()) };
+        Ok(RocksDbBackend {
+            db: db,
+            cf: cf,
+            log_cf: log_cf,
+        })
     }
 
-    pub fn open(&self, db_num: u16) -> Result<Storage, GenericError> {
-        Ok(Storage {
+    fn cf(&self, column: Column) -> &'static rocksdb::CFHandle {
+        match column {
+            Column::Data => self.cf,
+            Column::Log => self.log_cf,
+        }
+    }
+}
+
+impl Backend for RocksDbBackend {
+    fn get(
+        &self,
+        column: Column,
+        key: &[u8],
+        callback: &mut FnMut(&[u8]),
+    ) -> Result<(), GenericError> {
+        if let Some(value) = self.db.get_cf(self.cf(column), key)? {
+            callback(&*value);
+        }
+        Ok(())
+    }
+
+    fn batch_new(&self, reserve: usize) -> Box<BackendBatch> {
+        Box::new(RocksDbBatch {
             db: self.db.clone(),
-            cf: unsafe { mem::transmute(self.db.cf_handle("default").unwrap()) },
-            log_cf: unsafe { mem::transmute(self.db.cf_handle("log").unwrap()) },
-            num: db_num,
+            cf: self.cf,
+            log_cf: self.log_cf,
+            wb: rocksdb::WriteBatch::with_capacity(reserve),
         })
     }
 
-    pub fn batch_write(&self, batch: SendableStorageBatch) -> Result<(), GenericError> {
-        Ok(self.db.write(batch.0)?)
+    fn iterator(
+        &self,
+        column: Column,
+        start: &[u8],
+        end: Option<&[u8]>,
+    ) -> Box<BackendIterator> {
+        let mut ro = rocksdb::ReadOptions::new();
+        if let Column::Data = column {
+            ro.set_total_order_seek(false);
+            ro.set_prefix_same_as_start(true);
+        }
+        if let Some(end) = end {
+            ro.set_iterate_upper_bound(end);
+        }
+        let mut iterator = rocksdb::DBIterator::new_cf(self.db.clone(), self.cf(column), ro);
+        iterator.seek(rocksdb::SeekKey::Key(start));
+        Box::new(RocksDbIterator(iterator))
+    }
+
+    fn clear(&self, column: Column, from: &[u8], to: &[u8]) {
+        let cf = self.cf(column);
+        self.db
+            .delete_files_in_range_cf(cf, from, to, false)
+            .unwrap();
+        let mut ro = rocksdb::ReadOptions::new();
+        ro.set_total_order_seek(false);
+        ro.set_prefix_same_as_start(true);
+        ro.set_iterate_upper_bound(to);
+        let mut iter = self.db.iter_cf_opt(cf, ro);
+        iter.seek(rocksdb::SeekKey::Key(from));
+        while iter.valid() {
+            self.db.delete_cf(cf, iter.key()).unwrap();
+            iter.next();
+        }
+    }
+
+    // from the sst files and memtables metadata so it's cheap,
+    // but may lag behind recent writes and deletes
+    fn approximate_size(&self, column: Column, from: &[u8], to: &[u8]) -> u64 {
+        let cf = self.cf(column);
+        let range = rocksdb::Range::new(from, to);
+        let sst_size = self
+            .db
+            .get_approximate_sizes_cf(cf, &[range])
+            .pop()
+            .unwrap_or(0);
+        let (_, memtable_size) = self.db.get_approximate_memtable_stats_cf(cf, &range);
+        sst_size + memtable_size
+    }
+
+    fn take_evicted(&self, _num: u16) -> (u64, u64) {
+        (0, 0)
+    }
+
+    fn sync(&self) -> Result<(), GenericError> {
+        Ok(self.db.sync_wal()?)
+    }
+}
+
+impl BackendBatch for RocksDbBatch {
+    fn put(&mut self, column: Column, key: &[u8], value: &[u8]) {
+        let cf = match column {
+            Column::Data => self.cf,
+            Column::Log => self.log_cf,
+        };
+        self.wb.put_cf(cf, key, value).unwrap();
+    }
+
+    fn delete(&mut self, column: Column, key: &[u8]) {
+        let cf = match column {
+            Column::Data => self.cf,
+            Column::Log => self.log_cf,
+        };
+        self.wb.delete_cf(cf, key).unwrap();
+    }
+
+    fn is_empty(&self) -> bool {
+        self.wb.is_empty()
+    }
+
+    fn write(self: Box<Self>, sync: bool) -> Result<(), GenericError> {
+        let RocksDbBatch { db, wb, .. } = *self;
+        let mut wo = rocksdb::WriteOptions::new();
+        wo.set_sync(sync);
+        Ok(db.write_opt(wb, &wo)?)
+    }
+}
+
+impl BackendIterator for RocksDbIterator {
+    fn seek(&mut self, key: &[u8]) {
+        self.0.seek(rocksdb::SeekKey::Key(key));
+    }
+
+    fn valid(&self) -> bool {
+        self.0.valid()
+    }
+
+    fn next(&mut self) {
+        self.0.next();
+    }
+
+    fn key(&self) -> &[u8] {
+        self.0.key()
+    }
+
+    fn value(&self) -> &[u8] {
+        self.0.value()
+    }
+}
+
+type MemoryTable = Arc<RwLock<BTreeMap<Vec<u8>, Vec<u8>>>>;
+
+struct MemoryBackend {
+    data: MemoryTable,
+    log: MemoryTable,
+    // set if the data is bounded, locked after the data table when both are
+    evictor: Option<Arc<Mutex<Evictor>>>,
+    // locked after the log table
+    log_trimmer: Arc<Mutex<LogTrimmer>>,
+}
+
+struct MemoryBatch {
+    data: MemoryTable,
+    log: MemoryTable,
+    evictor: Option<Arc<Mutex<Evictor>>>,
+    log_trimmer: Arc<Mutex<LogTrimmer>>,
+    // None values are deletes
+    ops: Vec<(Column, Vec<u8>, Option<Vec<u8>>)>,
+}
+
+// Keeps the data of a memory backend under max_memory bytes (keys plus values,
+// like approximate_size) by evicting whole keys, ranked by the policy.
+// Only the data counts: the log is bounded separately (see LogTrimmer) and the
+// ranks and order below, which copy every key twice, aren't counted either.
+// Evicted keys are simply dropped: no tombstone is written and the vnode clocks
+// and log are left alone, so it's never replicated as a delete. To the vnode an
+// evicted key reads like one it never had, as an expired one does. The vnode
+// picks up the evictions from its storage (see Storage::take_evicted) to keep its
+// stats and hash tree in line. The keys of the meta storage are never evicted.
+struct Evictor {
+    policy: EvictionPolicy,
+    max_memory: u64,
+    used: u64,
+    accesses: u64,
+    ranks: HashMap<Vec<u8>, (u64, u64)>,
+    // the lowest ranked key is evicted first
+    order: BTreeSet<((u64, u64), Vec<u8>)>,
+    // keys evicted and their values size by storage number, until taken
+    evicted: HashMap<u16, (u64, u64)>,
+}
+
+impl Evictor {
+    fn new(max_memory: u64, policy: EvictionPolicy) -> Self {
+        Evictor {
+            policy: policy,
+            max_memory: max_memory,
+            used: 0,
+            accesses: 0,
+            ranks: Default::default(),
+            order: Default::default(),
+            evicted: Default::default(),
+        }
+    }
+
+    fn evictable(key: &[u8]) -> bool {
+        let num = BigEndian::read_u16(key);
+        num != META_STORAGE_NUM && num != SCRATCH_STORAGE_NUM
+    }
+
+    fn rerank(&mut self, key: &[u8]) {
+        self.accesses += 1;
+        let prev = self.ranks.get(key).cloned();
+        let rank = match self.policy {
+            EvictionPolicy::Lru => (self.accesses, 0),
+            EvictionPolicy::Lfu => (prev.map_or(0, |r| r.0) + 1, self.accesses),
+        };
+        if let Some(prev) = prev {
+            self.order.remove(&(prev, key.to_vec()));
+        }
+        self.order.insert((rank, key.to_vec()));
+        self.ranks.insert(key.to_vec(), rank);
+    }
+
+    // a read of the key, scans don't count
+    fn touch(&mut self, key: &[u8]) {
+        if self.ranks.contains_key(key) {
+            self.rerank(key);
+        }
+    }
+
+    fn put(&mut self, key: &[u8], old_value: Option<&[u8]>, value: &[u8]) {
+        if !Self::evictable(key) {
+            return;
+        }
+        self.used = (self.used + (key.len() + value.len()) as u64)
+            .saturating_sub(old_value.map_or(0, |v| (key.len() + v.len()) as u64));
+        self.rerank(key);
+    }
+
+    fn remove(&mut self, key: &[u8], old_value: &[u8]) {
+        if !Self::evictable(key) {
+            return;
+        }
+        self.used = self
+            .used
+            .saturating_sub((key.len() + old_value.len()) as u64);
+        if let Some(rank) = self.ranks.remove(key) {
+            self.order.remove(&(rank, key.to_vec()));
+        }
+    }
+
+    fn evict(&mut self, data: &mut BTreeMap<Vec<u8>, Vec<u8>>) {
+        let mut evicted = 0;
+        while self.used > self.max_memory {
+            let (rank, key) = match self.order.iter().next() {
+                Some(first) => first.clone(),
+                None => break,
+            };
+            self.order.remove(&(rank, key.clone()));
+            self.ranks.remove(&key);
+            if let Some(value) = data.remove(&key) {
+                self.used = self
+                    .used
+                    .saturating_sub((key.len() + value.len()) as u64);
+                let stats = self
+                    .evicted
+                    .entry(BigEndian::read_u16(&key))
+                    .or_insert((0, 0));
+                stats.0 += 1;
+                stats.1 += value.len() as u64;
+            }
+            evicted += 1;
+        }
+        if evicted != 0 {
+            debug!("Evicted {} keys, {} bytes in use", evicted, self.used);
+            metrics::STORAGE_EVICTION.mark(evicted);
+        }
+    }
+}
+
+// Drops the oldest entries of a memory backend log once they're older than the log
+// retention or the log grows past MEMORY_LOG_MAX_SIZE, as the fifo compaction of the
+// rocksdb log column does. Syncs send the whole vnode to peers missing dots the log
+// no longer has.
+struct LogTrimmer {
+    retention: Duration,
+    size: u64,
+    // log keys in the order they were written, with the write time and entry size
+    written: VecDeque<(Instant, Vec<u8>, u64)>,
+}
+
+impl LogTrimmer {
+    fn new(retention: u64) -> Self {
+        LogTrimmer {
+            retention: Duration::from_secs(retention),
+            size: 0,
+            written: Default::default(),
+        }
+    }
+
+    fn put(&mut self, now: Instant, key: &[u8], value: &[u8]) {
+        let size = (key.len() + value.len()) as u64;
+        self.size += size;
+        self.written.push_back((now, key.to_vec(), size));
+    }
+
+    fn clear(&mut self, from: &[u8], to: &[u8]) {
+        let size = &mut self.size;
+        self.written.retain(|&(_, ref key, entry_size)| {
+            let cleared = &key[..] >= from && &key[..] < to;
+            if cleared {
+                *size -= entry_size;
+            }
+            !cleared
+        });
+    }
+
+    fn trim(&mut self, now: Instant, log: &mut BTreeMap<Vec<u8>, Vec<u8>>) {
+        let mut trimmed = 0;
+        while let Some((time, key, size)) = self.written.pop_front() {
+            if now.duration_since(time) < self.retention && self.size <= MEMORY_LOG_MAX_SIZE {
+                self.written.push_front((time, key, size));
+                break;
+            }
+            self.size -= size;
+            log.remove(&key);
+            trimmed += 1;
+        }
+        if trimmed != 0 {
+            debug!("Trimmed {} log entries, {} bytes in use", trimmed, self.size);
+        }
+    }
+}
+
+struct MemoryIterator {
+    table: MemoryTable,
+    end: Option<Vec<u8>>,
+    // copy of the current entry, so the table isn't locked between calls
+    entry: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+impl MemoryBackend {
+    fn new(log_retention: u64) -> Self {
+        MemoryBackend {
+            data: Default::default(),
+            log: Default::default(),
+            evictor: None,
+            log_trimmer: Arc::new(Mutex::new(LogTrimmer::new(log_retention))),
+        }
+    }
+
+    fn bounded(log_retention: u64, max_memory: u64, policy: EvictionPolicy) -> Self {
+        MemoryBackend {
+            evictor: Some(Arc::new(Mutex::new(Evictor::new(max_memory, policy)))),
+            ..Self::new(log_retention)
+        }
+    }
+
+    fn table(&self, column: Column) -> &MemoryTable {
+        match column {
+            Column::Data => &self.data,
+            Column::Log => &self.log,
+        }
+    }
+}
+
+impl Backend for MemoryBackend {
+    fn get(
+        &self,
+        column: Column,
+        key: &[u8],
+        callback: &mut FnMut(&[u8]),
+    ) -> Result<(), GenericError> {
+        let found = if let Some(value) = self.table(column).read().unwrap().get(key) {
+            callback(value);
+            true
+        } else {
+            false
+        };
+        if let (true, Column::Data, Some(evictor)) = (found, column, self.evictor.as_ref()) {
+            evictor.lock().unwrap().touch(key);
+        }
+        Ok(())
+    }
+
+    fn batch_new(&self, reserve: usize) -> Box<BackendBatch> {
+        Box::new(MemoryBatch {
+            data: self.data.clone(),
+            log: self.log.clone(),
+            evictor: self.evictor.clone(),
+            log_trimmer: self.log_trimmer.clone(),
+            ops: Vec::with_capacity(reserve),
+        })
+    }
+
+    fn iterator(
+        &self,
+        column: Column,
+        start: &[u8],
+        end: Option<&[u8]>,
+    ) -> Box<BackendIterator> {
+        let mut iterator = MemoryIterator {
+            table: self.table(column).clone(),
+            end: end.map(|end| end.to_vec()),
+            entry: None,
+        };
+        iterator.seek(start);
+        Box::new(iterator)
+    }
+
+    fn clear(&self, column: Column, from: &[u8], to: &[u8]) {
+        let mut table = self.table(column).write().unwrap();
+        let keys: Vec<Vec<u8>> = table
+            .range::<Vec<u8>, _>((Included(from.to_vec()), Excluded(to.to_vec())))
+            .map(|(k, _)| k.clone())
+            .collect();
+        if let Column::Log = column {
+            self.log_trimmer.lock().unwrap().clear(from, to);
+        }
+        let mut evictor = match (column, self.evictor.as_ref()) {
+            (Column::Data, Some(evictor)) => Some(evictor.lock().unwrap()),
+            _ => None,
+        };
+        if let Some(ref mut evictor) = evictor {
+            // the keys they were counted in are gone as well
+            evictor.evicted.remove(&BigEndian::read_u16(from));
+        }
+        for key in keys {
+            let old_value = table.remove(&key);
+            if let (Some(evictor), Some(old_value)) = (evictor.as_mut(), old_value) {
+                evictor.remove(&key, &old_value);
+            }
+        }
+    }
+
+    fn approximate_size(&self, column: Column, from: &[u8], to: &[u8]) -> u64 {
+        self.table(column)
+            .read()
+            .unwrap()
+            .range::<Vec<u8>, _>((Included(from.to_vec()), Excluded(to.to_vec())))
+            .map(|(k, v)| (k.len() + v.len()) as u64)
+            .sum()
+    }
+
+    fn take_evicted(&self, num: u16) -> (u64, u64) {
+        match self.evictor {
+            Some(ref evictor) => evictor
+                .lock()
+                .unwrap()
+                .evicted
+                .remove(&num)
+                .unwrap_or((0, 0)),
+            None => (0, 0),
+        }
+    }
+
+    fn sync(&self) -> Result<(), GenericError> {
+        Ok(())
+    }
+}
+
+impl BackendBatch for MemoryBatch {
+    fn put(&mut self, column: Column, key: &[u8], value: &[u8]) {
+        self.ops.push((column, key.to_vec(), Some(value.to_vec())));
+    }
+
+    fn delete(&mut self, column: Column, key: &[u8]) {
+        self.ops.push((column, key.to_vec(), None));
+    }
+
+    fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    fn write(self: Box<Self>, _sync: bool) -> Result<(), GenericError> {
+        let MemoryBatch {
+            data,
+            log,
+            evictor,
+            log_trimmer,
+            ops,
+        } = *self;
+        // both locked so the batch is applied atomically
+        let mut data = data.write().unwrap();
+        let mut log = log.write().unwrap();
+        let mut evictor = evictor.as_ref().map(|e| e.lock().unwrap());
+        let mut log_trimmer = log_trimmer.lock().unwrap();
+        let now = Instant::now();
+        for (column, key, value) in ops {
+            match (column, evictor.as_mut()) {
+                (Column::Data, Some(evictor)) => {
+                    if let Some(value) = value {
+                        evictor.put(&key, data.get(&key).map(|v| &v[..]), &value);
+                        data.insert(key, value);
+                    } else if let Some(old_value) = data.remove(&key) {
+                        evictor.remove(&key, &old_value);
+                    }
+                    continue;
+                }
+                _ => (),
+            }
+            let table = match column {
+                Column::Data => &mut data,
+                Column::Log => {
+                    if let Some(ref value) = value {
+                        log_trimmer.put(now, &key, value);
+                    }
+                    &mut log
+                }
+            };
+            if let Some(value) = value {
+                table.insert(key, value);
+            } else {
+                table.remove(&key);
+            }
+        }
+        if let Some(ref mut evictor) = evictor {
+            evictor.evict(&mut data);
+        }
+        log_trimmer.trim(now, &mut log);
+        Ok(())
+    }
+}
+
+impl MemoryIterator {
+    fn find(&self, from: Bound<Vec<u8>>) -> Option<(Vec<u8>, Vec<u8>)> {
+        let table = self.table.read().unwrap();
+        let next = table.range::<Vec<u8>, _>((from, Unbounded)).next();
+        match next {
+            Some((k, v)) if self.end.as_ref().map_or(true, |end| k < end) => {
+                Some((k.clone(), v.clone()))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl BackendIterator for MemoryIterator {
+    fn seek(&mut self, key: &[u8]) {
+        self.entry = self.find(Included(key.to_vec()));
+    }
+
+    fn valid(&self) -> bool {
+        self.entry.is_some()
+    }
+
+    fn next(&mut self) {
+        let entry = match self.entry {
+            Some((ref key, _)) => self.find(Excluded(key.clone())),
+            None => None,
+        };
+        self.entry = entry;
+    }
+
+    fn key(&self) -> &[u8] {
+        &self.entry.as_ref().unwrap().0
+    }
+
+    fn value(&self) -> &[u8] {
+        &self.entry.as_ref().unwrap().1
+    }
+}
+
+pub struct StorageManager {
+    backend: Arc<Backend>,
+    format: StorageFormat,
+}
+
+#[inline]
+fn build_key<'a>(buffer: &'a mut [u8], num: u16, key: &[u8]) -> &'a [u8] {
+    (&mut buffer[..2]).write_u16::<BigEndian>(num).unwrap();
+    (&mut buffer[2..]).write_all(key).unwrap();
+    &buffer[..2 + key.len()]
+}
+
+// smallest key greater than all keys starting with `prefix`, if any
+fn prefix_successor(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut successor = prefix.to_vec();
+    while let Some(last) = successor.pop() {
+        if last != 0xFF {
+            successor.push(last + 1);
+            return Some(successor);
+        }
+    }
+    None
+}
+
+// smallest key greater than all keys of the vnode, none for the last one
+fn vnode_successor(num: u16) -> Option<[u8; 2]> {
+    num.checked_add(1).map(|next| {
+        let mut key = [0u8; 2];
+        build_key(&mut key, next, b"");
+        key
+    })
+}
+
+#[inline]
+fn build_log_key<'a>(buffer: &'a mut [u8], num: u16, log_key: (u64, u64)) -> &'a [u8] {
+    (&mut buffer[..2]).write_u16::<BigEndian>(num).unwrap();
+    (&mut buffer[2..2 + 8])
+        .write_u64::<BigEndian>(log_key.0)
+        .unwrap();
+    (&mut buffer[2 + 8..2 + 8 + 8])
+        .write_u64::<BigEndian>(log_key.1)
+        .unwrap();
+    &buffer[..2 + 8 + 8]
+}
+
+#[inline]
+fn build_log_prefix<'a>(buffer: &'a mut [u8], num: u16, prefix: u64) -> &'a [u8] {
+    (&mut buffer[..2]).write_u16::<BigEndian>(num).unwrap();
+    (&mut buffer[2..2 + 8])
+        .write_u64::<BigEndian>(prefix)
+        .unwrap();
+    &buffer[..2 + 8]
+}
+
+// TODO: support TTL
+// TODO: specific comparator for log cf
+// TODO: merge operator could be a big win
+#[derive(Clone)]
+pub struct Storage {
+    backend: Arc<Backend>,
+    num: u16,
+    format: StorageFormat,
+}
+
+pub struct StorageBatch<'a> {
+    storage: &'a Storage,
+    wb: Box<BackendBatch>,
+}
+
+struct GenericIterator {
+    iterator: Box<BackendIterator>,
+    first: bool,
+}
+
+// also holds the vnode number, used to build the keys for seek()
+pub struct StorageIterator(GenericIterator, u16);
+
+pub struct LogStorageIterator(GenericIterator);
+
+impl StorageManager {
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<StorageManager, GenericError> {
+        Self::new_with(path, StorageBackend::RocksDb, DEFAULT_LOG_RETENTION)
+    }
+
+    // the path is ignored by the memory backend, the log retention is in seconds
+    pub fn new_with<P: AsRef<Path>>(
+        path: P,
+        backend: StorageBackend,
+        log_retention: u64,
+    ) -> Result<StorageManager, GenericError> {
+        let backend: Arc<Backend> = match backend {
+            StorageBackend::RocksDb => Arc::new(RocksDbBackend::new(path, log_retention)?),
+            StorageBackend::Memory => Arc::new(MemoryBackend::new(log_retention)),
+        };
+        Ok(StorageManager {
+            backend: backend,
+            format: StorageFormat::Bincode,
+        })
+    }
+
+    /// A memory backend holding up to `max_memory` bytes of data, evicting keys
+    /// past that with the given policy (see Evictor)
+    pub fn new_bounded(
+        log_retention: u64,
+        max_memory: u64,
+        policy: EvictionPolicy,
+    ) -> StorageManager {
+        StorageManager {
+            backend: Arc::new(MemoryBackend::bounded(log_retention, max_memory, policy)),
+            format: StorageFormat::Bincode,
+        }
+    }
+
+    // format used by storages opened afterwards
+    pub fn set_format(&mut self, format: StorageFormat) {
+        self.format = format;
+    }
+
+    pub fn open(&self, db_num: u16) -> Result<Storage, GenericError> {
+        Ok(Storage {
+            backend: self.backend.clone(),
+            num: db_num,
+            format: self.format,
+        })
     }
 }
 
 impl Drop for StorageManager {
     fn drop(&mut self) {
-        let sc = Arc::strong_count(&self.db);
-        let wc = Arc::weak_count(&self.db);
+        let sc = Arc::strong_count(&self.backend);
+        let wc = Arc::weak_count(&self.backend);
         assert_eq!(wc, 0);
         assert_eq!(sc, 1);
     }
 }
 
 impl Storage {
+    pub fn format(&self) -> StorageFormat {
+        self.format
+    }
+
+    pub fn set_format(&mut self, format: StorageFormat) {
+        self.format = format;
+    }
+
     pub fn iterator(&self) -> StorageIterator {
-        let mut key_prefix = [0u8; 2];
-        build_key(&mut key_prefix, self.num, b"");
-        let mut ro = rocksdb::ReadOptions::new();
-        ro.set_total_order_seek(false);
-        ro.set_prefix_same_as_start(true);
-        let mut iterator = rocksdb::DBIterator::new_cf(self.db.clone(), self.cf, ro);
-        iterator.seek(rocksdb::SeekKey::Key(&key_prefix[..]));
-        StorageIterator(GenericIterator {
-            db: self.db.clone(),
-            iterator: iterator,
-            first: true,
-        })
+        self.iterator_from(b"")
+    }
+
+    // like iterator() but starts at (or right after) the given key
+    pub fn iterator_from(&self, start: &[u8]) -> StorageIterator {
+        let mut buffer = [0u8; 512];
+        let start_key = build_key(&mut buffer, self.num, start);
+        let end_key = vnode_successor(self.num);
+        let iterator = self.backend.iterator(
+            Column::Data,
+            start_key,
+            end_key.as_ref().map(|k| &k[..]),
+        );
+        StorageIterator(GenericIterator::new(iterator), self.num)
+    }
+
+    // iterates the keys starting with `prefix`, the upper bound is pushed down
+    // to the backend so it doesn't read past the last matching key
+    pub fn iterator_prefix(&self, prefix: &[u8]) -> StorageIterator {
+        let mut buffer = [0u8; 512];
+        let start_key = build_key(&mut buffer, self.num, prefix);
+        let end_key = prefix_successor(start_key);
+        let iterator = self.backend.iterator(
+            Column::Data,
+            start_key,
+            end_key.as_ref().map(|k| &k[..]),
+        );
+        StorageIterator(GenericIterator::new(iterator), self.num)
     }
 
     pub fn log_iterator_all(&self) -> LogStorageIterator {
         let mut key_prefix = [0u8; 2];
         build_key(&mut key_prefix, self.num, b"");
-        let mut end_prefix = [0u8; 2];
-        build_key(&mut end_prefix, self.num + 1, b"");
-        let mut ro = rocksdb::ReadOptions::new();
-        ro.set_iterate_upper_bound(&end_prefix[..]);
-        let mut iterator = rocksdb::DBIterator::new_cf(self.db.clone(), self.log_cf, ro);
-        iterator.seek(rocksdb::SeekKey::Key(&key_prefix[..]));
-        LogStorageIterator(GenericIterator {
-            db: self.db.clone(),
-            iterator: iterator,
-            first: true,
-        })
+        let end_prefix = vnode_successor(self.num);
+        let iterator = self.backend.iterator(
+            Column::Log,
+            &key_prefix[..],
+            end_prefix.as_ref().map(|k| &k[..]),
+        );
+        LogStorageIterator(GenericIterator::new(iterator))
     }
 
     pub fn log_iterator(&self, prefix: u64, start: u64) -> LogStorageIterator {
@@ -213,15 +1088,10 @@ impl Storage {
         build_log_prefix(&mut end_prefix, self.num, prefix + 1);
         let mut start_key = [0u8; 2 + 8 + 8];
         build_log_key(&mut start_key, self.num, (prefix, start));
-        let mut ro = rocksdb::ReadOptions::new();
-        ro.set_iterate_upper_bound(&end_prefix[..]);
-        let mut iterator = rocksdb::DBIterator::new_cf(self.db.clone(), self.log_cf, ro);
-        iterator.seek(rocksdb::SeekKey::Key(&start_key[..]));
-        LogStorageIterator(GenericIterator {
-            db: self.db.clone(),
-            iterator: iterator,
-            first: true,
-        })
+        let iterator = self
+            .backend
+            .iterator(Column::Log, &start_key[..], Some(&end_prefix[..]));
+        LogStorageIterator(GenericIterator::new(iterator))
     }
 
     pub fn get<R, F: FnOnce(&[u8]) -> R>(
@@ -231,13 +1101,13 @@ impl Storage {
     ) -> Result<Option<R>, GenericError> {
         let mut buffer = [0u8; 512];
         let buffer = build_key(&mut buffer, self.num, key);
-        let r = self.db.get_cf(self.cf, buffer)?;
-        trace!(
-            "get {:?} ({:?} bytes)",
-            str::from_utf8(key),
-            r.as_ref().map(|x| x.len())
-        );
-        Ok(r.map(|r| callback(&*r)))
+        let mut callback = Some(callback);
+        let mut r = None;
+        self.backend.get(Column::Data, buffer, &mut |v| {
+            trace!("get {:?} ({:?} bytes)", str::from_utf8(key), v.len());
+            r = callback.take().map(|c| c(v));
+        })?;
+        Ok(r)
     }
 
     pub fn log_get<R, F: FnOnce(&[u8]) -> R>(
@@ -247,13 +1117,13 @@ impl Storage {
     ) -> Result<Option<R>, GenericError> {
         let mut buffer = [0u8; 2 + 8 + 8];
         let buffer = build_log_key(&mut buffer, self.num, log_key);
-        let r = self.db.get_cf(self.log_cf, buffer)?;
-        trace!(
-            "log_get {:?} ({:?} bytes)",
-            log_key,
-            r.as_ref().map(|x| x.len())
-        );
-        Ok(r.map(|r| callback(&*r)))
+        let mut callback = Some(callback);
+        let mut r = None;
+        self.backend.get(Column::Log, buffer, &mut |v| {
+            trace!("log_get {:?} ({:?} bytes)", log_key, v.len());
+            r = callback.take().map(|c| c(v));
+        })?;
+        Ok(r)
     }
 
     pub fn get_vec(&self, key: &[u8]) -> Result<Option<Vec<u8>>, GenericError> {
@@ -279,12 +1149,17 @@ impl Storage {
     pub fn batch_new(&self, reserve: usize) -> StorageBatch {
         StorageBatch {
             storage: self,
-            wb: rocksdb::WriteBatch::with_capacity(reserve),
+            wb: self.backend.batch_new(reserve),
         }
     }
 
     pub fn batch_write(&self, batch: StorageBatch) -> Result<(), GenericError> {
-        Ok(self.db.write(batch.wb)?)
+        batch.wb.write(false)
+    }
+
+    // like batch_write but optionally waits for the wal to be fsynced
+    pub fn batch_write_opt(&self, batch: StorageBatch, sync: bool) -> Result<(), GenericError> {
+        batch.wb.write(sync)
     }
 
     pub fn clear(&self) {
@@ -294,26 +1169,40 @@ impl Storage {
         (&mut from[..]).write_u16::<BigEndian>(self.num).unwrap();
         (&mut to[..]).write_u16::<BigEndian>(self.num + 1).unwrap();
 
-        for &cf in &[self.cf, self.log_cf] {
-            self.db
-                .delete_files_in_range_cf(cf, &from[..], &to[..], false)
-                .unwrap();
-            let mut ro = rocksdb::ReadOptions::new();
-            ro.set_total_order_seek(false);
-            ro.set_prefix_same_as_start(true);
-            ro.set_iterate_upper_bound(&to[..]);
-            let mut iter = self.db.iter_cf_opt(cf, ro);
-            iter.seek(rocksdb::SeekKey::Key(&from[..]));
-            while iter.valid() {
-                self.db.delete_cf(cf, iter.key()).unwrap();
-                iter.next();
-            }
+        for &column in &[Column::Data, Column::Log] {
+            self.backend.clear(column, &from[..], &to[..]);
         }
     }
 
+    // drops the whole log, like its retention would
+    #[cfg(test)]
+    pub fn log_clear(&self) {
+        let mut from = [0u8; 2];
+        let mut to = [0u8; 2];
+        (&mut from[..]).write_u16::<BigEndian>(self.num).unwrap();
+        (&mut to[..]).write_u16::<BigEndian>(self.num + 1).unwrap();
+        self.backend.clear(Column::Log, &from[..], &to[..]);
+    }
+
+    // approximate size in bytes of the vnode keys, cheap but with the
+    // rocksdb backend it may lag behind recent writes and deletes
+    pub fn approximate_size(&self) -> u64 {
+        let mut from = [0u8; 2];
+        let mut to = [0u8; 2];
+        (&mut from[..]).write_u16::<BigEndian>(self.num).unwrap();
+        (&mut to[..]).write_u16::<BigEndian>(self.num + 1).unwrap();
+        self.backend.approximate_size(Column::Data, &from[..], &to[..])
+    }
+
+    /// Number of keys evicted (see `max_memory`) and the size of their values
+    /// since the last call
+    pub fn take_evicted(&self) -> (u64, u64) {
+        self.backend.take_evicted(self.num)
+    }
+
     pub fn sync(&self) -> Result<(), GenericError> {
         debug!("sync");
-        Ok(self.db.sync_wal()?)
+        self.backend.sync()
     }
 }
 
@@ -326,25 +1215,32 @@ impl<'a> StorageBatch<'a> {
         trace!("set {:?} ({} bytes)", str::from_utf8(key), value.len());
         let mut buffer = [0u8; 512];
         let buffer = build_key(&mut buffer, self.storage.num, key);
-        self.wb.put_cf(self.storage.cf, buffer, value).unwrap();
+        self.wb.put(Column::Data, buffer, value);
     }
 
     pub fn log_set(&mut self, key: (u64, u64), value: &[u8]) {
         trace!("log_set {:?} ({} bytes)", key, value.len());
         let mut buffer = [0u8; 2 + 8 + 8];
         let buffer = build_log_key(&mut buffer, self.storage.num, key);
-        self.wb.put_cf(self.storage.log_cf, buffer, value).unwrap();
+        self.wb.put(Column::Log, buffer, value);
     }
 
     pub fn del(&mut self, key: &[u8]) {
         trace!("del {:?}", str::from_utf8(key));
         let mut buffer = [0u8; 512];
         let buffer = build_key(&mut buffer, self.storage.num, key);
-        self.wb.delete_cf(self.storage.cf, buffer).unwrap()
+        self.wb.delete(Column::Data, buffer)
     }
 }
 
 impl GenericIterator {
+    fn new(iterator: Box<BackendIterator>) -> Self {
+        GenericIterator {
+            iterator: iterator,
+            first: true,
+        }
+    }
+
     pub fn iter<'a>(&'a mut self) -> GenericIteratorIter<'a> {
         GenericIteratorIter { it: self }
     }
@@ -389,6 +1285,15 @@ impl StorageIterator {
     pub fn iter<'a>(&'a mut self) -> StorageIteratorIter<'a> {
         StorageIteratorIter(self.0.iter())
     }
+
+    /// Repositions the iterator at (or right after) the given key,
+    /// bounds set at creation still apply
+    pub fn seek(&mut self, key: &[u8]) {
+        let mut buffer = [0u8; 512];
+        let key = build_key(&mut buffer, self.1, key);
+        self.0.iterator.seek(key);
+        self.0.first = true;
+    }
 }
 
 impl<'a> Iterator for StorageIteratorIter<'a> {
@@ -437,6 +1342,73 @@ mod tests {
         assert_eq!(storage.get_vec(b"sample").unwrap(), None);
     }
 
+    #[test]
+    fn test_formats() {
+        let value = (1u64, "value".to_string(), vec![1u8, 2, 3]);
+        for &format in &[StorageFormat::Bincode, StorageFormat::MsgPack] {
+            let bytes = format.serialize(&value).unwrap();
+            let de: (u64, String, Vec<u8>) = format.deserialize(&bytes).unwrap();
+            assert_eq!(de, value);
+            assert_eq!(format.as_str().parse::<StorageFormat>().unwrap(), format);
+        }
+    }
+
+    #[test]
+    fn test_value_versions() {
+        let value = (1u64, "a".to_string());
+        for &format in &[StorageFormat::Bincode, StorageFormat::MsgPack] {
+            let bytes = format.serialize_value(&value).unwrap();
+            assert_eq!(bytes[0], VALUE_FORMAT_VERSION);
+            let de: (u64, String) = format.deserialize_value(&bytes).unwrap();
+            assert_eq!(de, value);
+        }
+
+        // values written before versioning
+        let bincode_blob = [1, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, b'a'];
+        let de: (u64, String) = StorageFormat::Bincode
+            .deserialize_value(&bincode_blob)
+            .unwrap();
+        assert_eq!(de, value);
+        let msgpack_blob = [0x92, 0x01, 0xa1, b'a'];
+        let de: (u64, String) = StorageFormat::MsgPack
+            .deserialize_value(&msgpack_blob)
+            .unwrap();
+        assert_eq!(de, value);
+
+        // versioned but without checksum
+        let mut unchecked = vec![VALUE_FORMAT_VERSION_UNCHECKED];
+        unchecked.extend_from_slice(&bincode_blob);
+        let de: (u64, String) = StorageFormat::Bincode
+            .deserialize_value(&unchecked)
+            .unwrap();
+        assert_eq!(de, value);
+
+        // from a newer version
+        let mut bytes = StorageFormat::Bincode.serialize_value(&value).unwrap();
+        bytes[0] = VALUE_FORMAT_VERSION + 1;
+        assert!(StorageFormat::Bincode
+            .deserialize_value::<(u64, String)>(&bytes)
+            .is_err());
+    }
+
+    #[test]
+    fn test_value_checksum() {
+        let value = (1u64, "a".to_string());
+        let bytes = StorageFormat::Bincode.serialize_value(&value).unwrap();
+        for i in 1..bytes.len() {
+            let mut corrupted = bytes.clone();
+            corrupted[i] ^= 0x10;
+            assert!(StorageFormat::Bincode
+                .deserialize_value::<(u64, String)>(&corrupted)
+                .is_err());
+        }
+        for len in 0..bytes.len() {
+            assert!(StorageFormat::Bincode
+                .deserialize_value::<(u64, String)>(&bytes[..len])
+                .is_err());
+        }
+    }
+
     #[test]
     fn test_simple_log() {
         let _ = fs::remove_dir_all("t/test_simple_log");
@@ -471,6 +1443,66 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_iter_from() {
+        let _ = fs::remove_dir_all("t/test_iter_from");
+        let sm = StorageManager::new("t/test_iter_from").unwrap();
+        for &i in &[0, 1, 2] {
+            let storage = sm.open(i).unwrap();
+            storage.set(b"1", b"1").unwrap();
+            storage.set(b"2", b"2").unwrap();
+            storage.set(b"3", b"3").unwrap();
+        }
+        let storage = sm.open(1).unwrap();
+        let results: Vec<Vec<u8>> = storage
+            .iterator_from(b"2")
+            .iter()
+            .map(|(k, _)| k.into())
+            .collect();
+        assert_eq!(results, vec![b"2".to_vec(), b"3".to_vec()]);
+        let results: Vec<Vec<u8>> = storage
+            .iterator_from(b"20")
+            .iter()
+            .map(|(k, _)| k.into())
+            .collect();
+        assert_eq!(results, vec![b"3".to_vec()]);
+    }
+
+    #[test]
+    fn test_iter_prefix() {
+        let _ = fs::remove_dir_all("t/test_iter_prefix");
+        let sm = StorageManager::new("t/test_iter_prefix").unwrap();
+        for &i in &[0, 1, 2] {
+            let storage = sm.open(i).unwrap();
+            for key in &[&b"a"[..], b"ab", b"ab\xff", b"abc", b"ac", b"b"] {
+                storage.set(key, b"").unwrap();
+            }
+        }
+        let storage = sm.open(1).unwrap();
+        let results: Vec<Vec<u8>> = storage
+            .iterator_prefix(b"ab")
+            .iter()
+            .map(|(k, _)| k.into())
+            .collect();
+        assert_eq!(
+            results,
+            vec![b"ab".to_vec(), b"ab\xff".to_vec(), b"abc".to_vec()]
+        );
+
+        let mut iterator = storage.iterator_prefix(b"ab");
+        iterator.seek(b"abb");
+        let results: Vec<Vec<u8>> = iterator.iter().map(|(k, _)| k.into()).collect();
+        assert_eq!(results, vec![b"abc".to_vec()]);
+
+        // doesn't leak into the next vnode
+        let results: Vec<Vec<u8>> = storage
+            .iterator_prefix(b"b")
+            .iter()
+            .map(|(k, _)| k.into())
+            .collect();
+        assert_eq!(results, vec![b"b".to_vec()]);
+    }
+
     #[test]
     fn test_iter_log() {
         let _ = fs::remove_dir_all("t/test_iter_log");
@@ -524,6 +1556,132 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_memory() {
+        let sm =
+            StorageManager::new_with("", StorageBackend::Memory, DEFAULT_LOG_RETENTION).unwrap();
+        for &i in &[0u64, 1, 2] {
+            let storage = sm.open(i as u16).unwrap();
+            let mut b = storage.batch_new(0);
+            for key in &[&b"1"[..], b"2", b"21", b"3"] {
+                b.set(key, i.to_string().as_bytes());
+            }
+            b.log_set((i, 1), b"1");
+            b.log_set((i, 2), b"2");
+            b.log_set((i + 1, 1), b"3");
+            storage.batch_write(b).unwrap();
+        }
+
+        let storage = sm.open(1).unwrap();
+        assert_eq!(storage.get_vec(b"1").unwrap().unwrap(), b"1");
+        storage.del(b"1").unwrap();
+        assert_eq!(storage.get_vec(b"1").unwrap(), None);
+        assert_eq!(storage.log_get_vec((1, 2)).unwrap().unwrap(), b"2");
+
+        let keys: Vec<Vec<u8>> = storage.iterator().iter().map(|(k, _)| k.into()).collect();
+        assert_eq!(keys, vec![b"2".to_vec(), b"21".to_vec(), b"3".to_vec()]);
+        let mut iterator = storage.iterator_prefix(b"2");
+        assert_eq!(iterator.iter().count(), 2);
+        iterator.seek(b"20");
+        let keys: Vec<Vec<u8>> = iterator.iter().map(|(k, _)| k.into()).collect();
+        assert_eq!(keys, vec![b"21".to_vec()]);
+
+        // streams through writes done in between, as bootstrap does
+        let mut iterator = storage.iterator_from(b"21");
+        assert_eq!(iterator.iter().next().unwrap().0, b"21");
+        storage.set(b"22", b"").unwrap();
+        let keys: Vec<Vec<u8>> = iterator.iter().map(|(k, _)| k.into()).collect();
+        assert_eq!(keys, vec![b"22".to_vec(), b"3".to_vec()]);
+
+        assert_eq!(storage.log_iterator(1, 2).iter().count(), 1);
+        assert_eq!(storage.log_iterator_all().iter().count(), 3);
+        assert!(storage.approximate_size() > 0);
+
+        storage.clear();
+        assert_eq!(storage.iterator().iter().count(), 0);
+        assert_eq!(storage.log_iterator_all().iter().count(), 0);
+        assert_eq!(sm.open(2).unwrap().iterator().iter().count(), 4);
+        assert_eq!(
+            "memory".parse::<StorageBackend>().unwrap(),
+            StorageBackend::Memory
+        );
+    }
+
+    #[test]
+    fn test_memory_log_retention() {
+        let sm = StorageManager::new_with("", StorageBackend::Memory, 1).unwrap();
+        let storage = sm.open(1).unwrap();
+        let mut b = storage.batch_new(0);
+        b.log_set((1, 1), b"1");
+        b.log_set((1, 2), b"2");
+        storage.batch_write(b).unwrap();
+        assert_eq!(storage.log_iterator_all().iter().count(), 2);
+
+        ::std::thread::sleep(Duration::from_millis(1100));
+        let mut b = storage.batch_new(0);
+        b.log_set((1, 3), b"3");
+        storage.batch_write(b).unwrap();
+        assert_eq!(storage.log_get_vec((1, 2)).unwrap(), None);
+        assert_eq!(storage.log_get_vec((1, 3)).unwrap().unwrap(), b"3");
+        assert_eq!(storage.log_iterator_all().iter().count(), 1);
+    }
+
+    #[test]
+    fn test_memory_eviction() {
+        // each key takes 10 bytes, 2 of the vnode prefix + "kN" + "valueN"
+        let fill = |storage: &Storage| {
+            for i in 0..5 {
+                let (key, value) = (format!("k{}", i), format!("value{}", i));
+                storage.set(key.as_bytes(), value.as_bytes()).unwrap();
+            }
+        };
+        let is_stored = |storage: &Storage, key: &[u8]| storage.get_vec(key).unwrap().is_some();
+
+        let sm = StorageManager::new_bounded(DEFAULT_LOG_RETENTION, 50, EvictionPolicy::Lru);
+        let meta = sm.open(META_STORAGE_NUM).unwrap();
+        meta.set(b"node", &[0u8; 100]).unwrap();
+        let storage = sm.open(1).unwrap();
+        fill(&storage);
+        assert!(storage.get_vec(b"k0").unwrap().is_some());
+        storage.set(b"k5", b"value5").unwrap();
+        assert!(!is_stored(&storage, b"k1"));
+        for key in &[&b"k0"[..], b"k2", b"k3", b"k4", b"k5"] {
+            assert!(is_stored(&storage, key));
+        }
+        assert_eq!(storage.take_evicted(), (1, 6));
+        assert_eq!(storage.take_evicted(), (0, 0));
+        // overwrites and deletes free their space
+        storage.del(b"k0").unwrap();
+        storage.set(b"k6", b"value6").unwrap();
+        assert!(is_stored(&storage, b"k2"));
+        assert!(is_stored(&meta, b"node"));
+        // log entries aren't counted or evicted
+        let mut b = storage.batch_new(0);
+        b.log_set((1, 1), &[0u8; 100]);
+        storage.batch_write(b).unwrap();
+        assert!(is_stored(&storage, b"k2"));
+        storage.clear();
+        fill(&storage);
+        assert!(is_stored(&storage, b"k0"));
+
+        let sm = StorageManager::new_bounded(DEFAULT_LOG_RETENTION, 50, EvictionPolicy::Lfu);
+        let storage = sm.open(1).unwrap();
+        fill(&storage);
+        for key in &[&b"k0"[..], b"k0", b"k1", b"k2", b"k3"] {
+            assert!(is_stored(&storage, key));
+        }
+        storage.set(b"k5", b"value5").unwrap();
+        assert!(!is_stored(&storage, b"k4"));
+        // the newest key is the least frequent now
+        storage.set(b"k6", b"value6").unwrap();
+        assert!(!is_stored(&storage, b"k5"));
+        assert!(is_stored(&storage, b"k6"));
+        assert_eq!(
+            "lfu".parse::<EvictionPolicy>().unwrap(),
+            EvictionPolicy::Lfu
+        );
+    }
+
     #[test]
     fn test_open_all() {
         let _ = fs::remove_dir_all("t/test_open_all");
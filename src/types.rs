@@ -29,7 +29,7 @@ impl fmt::Debug for Cookie {
 }
 
 /// Consistency Level as in Dynamo/Riak/Cassandra style
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum ConsistencyLevel {
     One,
     Quorum,
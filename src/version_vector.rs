@@ -79,6 +79,26 @@ impl BitmappedVersion {
         self.norm();
     }
 
+    // keeps only the versions other also has
+    pub fn intersect(&mut self, other: &Self) {
+        let mut bitmap = self.bitmap.clone();
+        bitmap &= &other.bitmap;
+        {
+            // the versions up to the higher base are in both if the lower one has them
+            let (lower, higher_base) = if self.base < other.base {
+                (&self.bitmap, other.base)
+            } else {
+                (&other.bitmap, self.base)
+            };
+            for version in lower.iter().take_while(|&v| v <= higher_base) {
+                bitmap.insert(version);
+            }
+        }
+        self.base = cmp::min(self.base, other.base);
+        self.bitmap = bitmap;
+        self.norm();
+    }
+
     pub fn add(&mut self, version: Version) -> bool {
         if version == self.base + 1 {
             self.base += 1;
@@ -120,6 +140,11 @@ impl BitmappedVersion {
         self.base
     }
 
+    // highest version seen, including the ones after holes
+    pub fn max(&self) -> Version {
+        cmp::max(self.bitmap.max().unwrap_or(0), self.base)
+    }
+
     pub fn contains(&self, version: Version) -> bool {
         self.base >= version || self.bitmap.contains(version)
     }
@@ -128,6 +153,11 @@ impl BitmappedVersion {
         self.base >= version
     }
 
+    // number of versions contained
+    pub fn count(&self) -> u64 {
+        self.base + self.bitmap.len()
+    }
+
     /// self - other
     pub fn delta(&self, other: &Self) -> BitmappedVersionDelta {
         if self.base < other.base {
@@ -323,6 +353,18 @@ impl BitmappedVersionVector {
         }
     }
 
+    // keeps only the versions other also has
+    pub fn intersect(&mut self, other: &Self) {
+        let ids: Vec<Id> = self.0.keys().cloned().collect();
+        for id in ids {
+            if let Some(other_bv) = other.get(id) {
+                self.0.get_mut(&id).unwrap().intersect(other_bv);
+            } else {
+                self.0.remove(&id);
+            }
+        }
+    }
+
     pub fn event(&mut self, id: Id) -> Version {
         match self.0.entry(id) {
             LMEntry::Vacant(vac) => {
@@ -675,6 +717,31 @@ impl<T> DotMap<T> {
         self.0.insert((id, version), value);
     }
 
+    pub fn remove(&mut self, id: Id, version: Version) -> Option<T> {
+        self.0.remove(&(id, version))
+    }
+
+    pub fn retain<F: FnMut(&(Id, Version), &mut T) -> bool>(&mut self, f: F) {
+        self.0.retain(f);
+    }
+
+    /// Discards the oldest entries so that at most `max` remain.
+    /// The oldest are the ones with the lowest version (dot counter),
+    /// ties are broken by the lowest node id, so every replica discards the same ones.
+    /// The owner should keep the discarded dots in its causal context,
+    /// otherwise merges with other replicas would bring them back.
+    pub fn discard_oldest(&mut self, max: usize) {
+        if self.0.len() <= max {
+            return;
+        }
+        let mut dots: Vec<_> = self.0.keys().map(|&(id, version)| (version, id)).collect();
+        dots.sort();
+        let excess = dots.len() - max;
+        for &(version, id) in &dots[..excess] {
+            self.0.remove(&(id, version));
+        }
+    }
+
     pub fn into_iter(self) -> impl Iterator<Item = ((Id, Version), T)> {
         self.0.into_iter()
     }
@@ -900,6 +967,23 @@ mod test_bvv {
         assert_eq!(a.get(2).unwrap(), &BitmappedVersion::new(2, 4));
     }
 
+    #[test]
+    fn intersect() {
+        let mut a = BitmappedVersionVector::new();
+        a.0.insert(1, BitmappedVersion::new(5, 0b10));
+        a.0.insert(2, BitmappedVersion::new(7, 0));
+        a.0.insert(3, BitmappedVersion::new(1, 0));
+        let mut b = BitmappedVersionVector::new();
+        b.0.insert(1, BitmappedVersion::new(2, 0b100));
+        b.0.insert(2, BitmappedVersion::new(2, 0b1001));
+        b.0.insert(4, BitmappedVersion::new(1, 0));
+        a.intersect(&b);
+        assert_eq!(a.get(1).unwrap(), &BitmappedVersion::new(2, 0b100));
+        assert_eq!(a.get(2).unwrap(), &BitmappedVersion::new(3, 0b100));
+        assert!(a.get(3).is_none());
+        assert!(a.get(4).is_none());
+    }
+
     #[test]
     fn event() {
         let mut a = BitmappedVersionVector::new();
@@ -1007,6 +1091,36 @@ mod test_vv {
         assert!(!a1.contains(3, 1));
     }
 }
+
+#[cfg(test)]
+mod test_dotmap {
+    use super::*;
+
+    #[test]
+    fn discard_oldest() {
+        let mut vv = VersionVector::new();
+        let mut a = DotMap::new();
+        for &(id, version) in &[(1, 1), (2, 3), (1, 5), (3, 3)] {
+            a.insert(id, version, ());
+            vv.add(id, version);
+        }
+        let mut b = a.clone();
+        a.discard_oldest(4);
+        assert_eq!(a.len(), 4);
+        // lowest versions first, ties by node id
+        a.discard_oldest(2);
+        let mut dots = a.keys().cloned().collect::<Vec<_>>();
+        dots.sort();
+        assert_eq!(dots, vec![(1, 5), (3, 3)]);
+
+        // the causal context still covers the discarded versions,
+        // so merging with a replica that has them doesn't bring them back
+        a.merge(&mut b, &vv, &vv);
+        let mut dots = a.keys().cloned().collect::<Vec<_>>();
+        dots.sort();
+        assert_eq!(dots, vec![(1, 5), (3, 3)]);
+    }
+}
 //
 // #[cfg(test)]
 // mod test_dcc {
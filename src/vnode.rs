@@ -1,23 +1,45 @@
 use bincode;
+use bloom::BloomFilter;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use bytes::Bytes;
-use command::CommandError;
+use command::{split_keyspace_key, CommandError};
+use config::ReplicaPolicy;
 use cubes::*;
 use database::*;
 use fabric::*;
-use hash::hash_slot;
+use hotkeys::HotKeys;
 use inflightmap::InFlightMap;
+use merkle::MerkleTree;
+use metrics::{self, Gauge};
 use rand::{thread_rng, Rng};
+use resp::RespValue;
+use std::cell::RefCell;
 use std::collections::hash_map::Entry as HMEntry;
+use std::io::{Read, Write};
 use std::time::{Duration, Instant};
 use storage::*;
-use utils::{join_u64, split_u64};
+use utils::{join_u64, split_u64, GenericError};
 use utils::{replace_default, IdHashMap, IdHashSet, IdHasherBuilder};
 use version_vector::*;
 use vnode_sync::*;
 
-const ZOMBIE_TIMEOUT_MS: u64 = 60 * 1_000;
+const FINISHED_SYNCS_MAX: usize = 1_000;
+// keys read from the storage at a time when moving them to a new vnode after a split
+const SPLIT_BATCH_KEYS: usize = 1_000;
 
-#[derive(Debug, PartialEq, Copy, Clone)]
+// "SUCRSNAP", the first bytes of a vnode snapshot, see VNode::snapshot
+const SNAPSHOT_MAGIC: u64 = 0x5355_4352_534e_4150;
+const SNAPSHOT_VERSION: u32 = 1;
+const SNAPSHOT_BATCH_KEYS: usize = 1_000;
+// bigger frames can only come from a corrupted dump
+const SNAPSHOT_FRAME_MAX: usize = 256 * 1024 * 1024;
+
+lazy_static! {
+    // restores share the scratch storage
+    static ref RESTORE_LOCK: ::std::sync::Mutex<()> = Default::default();
+}
+
+#[derive(Debug, PartialEq, Copy, Clone, Serialize)]
 pub enum VNodeStatus {
     /* TODO: consider adding an status for a node that just came back up and
         is still part of the cluster, so it potentially has highly stale data */
@@ -32,10 +54,62 @@ pub enum VNodeStatus {
     Absent,
 }
 
+/// Point in time summary of a vnode, see DEBUG VNODES
+pub struct VNodeSummary {
+    pub status: VNodeStatus,
+    pub status_age: Duration,
+    // stored keys, including the ones only holding tombstones
+    pub keys: u64,
+    // max version seen from each node
+    pub clocks: Vec<(NodeId, Version)>,
+    pub syncs: usize,
+    pub pending_bootstrap: bool,
+    pub bootstrap_failures: u32,
+    // left behind by a panicking worker, it's reloaded by the next tick
+    pub pending_recovery: bool,
+}
+
+/// Point in time metrics of a vnode, see Database::metrics
+#[derive(Debug, Serialize)]
+pub struct VNodeMetrics {
+    pub vnode: VNodeNo,
+    pub status: VNodeStatus,
+    pub inflight_requests: usize,
+    // until the next inflight request times out, in millis
+    pub next_request_timeout: Option<u64>,
+    pub syncs_incoming: usize,
+    pub syncs_outgoing: usize,
+    // versions from each node with a key in the local log
+    pub log_sizes: Vec<(NodeId, u64)>,
+    // versions seen from each node up to the first hole
+    pub clocks: Vec<(NodeId, Version)>,
+    pub approximate_size: u64,
+    // stored keys (including the ones only holding tombstones) and their values size
+    pub keys: u64,
+    pub value_bytes: u64,
+    pub sync_progress: Vec<SyncProgress>,
+    // estimated most accessed keys and their accesses, if hot_keys_sample_rate is set
+    pub hot_keys: Vec<(Bytes, u64)>,
+}
+
 pub struct VNode {
     state: VNodeState,
     syncs: IdHashMap<Cookie, Synchronization>,
+    // recently finished syncs, so retransmitted starts don't spawn them again
+    finished_syncs: InFlightMap<Cookie, (), Instant, IdHasherBuilder>,
     requests: InFlightMap<Cookie, ReqState, Instant, IdHasherBuilder>,
+    waits: InFlightMap<Cookie, WaitState, Instant, IdHasherBuilder>,
+    aae: Option<AaeState>,
+    // latest clocks received from peers through clock probes
+    peer_clocks: IdHashMap<NodeId, (BitmappedVersionVector, Instant)>,
+    // when to recompute the tombstone safe point and probe the replicas again
+    tombstone_gc_at: Instant,
+    // when to checkpoint the state again and the coordinated writes since the last one,
+    // see checkpoint_tick
+    checkpoint_at: Instant,
+    checkpoint_writes: u32,
+    // keys with corrupted values skipped by scans, read from the replicas by the next tick
+    corrupted: Vec<Bytes>,
 }
 
 pub struct VNodeState {
@@ -43,12 +117,44 @@ pub struct VNodeState {
     num: u16,
     status: VNodeStatus,
     last_status_change: Instant,
+    // moved off this node but still finishing the inflight requests, until the deadline
+    drain_until: Option<Instant>,
     pub clocks: BitmappedVersionVector,
     pub log_clocks: BitmappedVersionVector,
     pub storage: Storage,
+    // optional filter over the stored keys, used to skip storage lookups
+    bloom: Option<BloomFilter>,
+    // sampled key accesses, updated by the reads as well (hence the RefCell)
+    hot_keys: Option<RefCell<HotKeys>>,
+    // hash tree of the stored keys, built by the first anti-entropy sync
+    // and kept up to date by the writes after that
+    merkle: Option<MerkleTree>,
+    // running count of the stored keys and values size, updated by every write
+    key_stats: KeyStats,
+    // concurrent versions kept per value, see Cube::discard_oldest_versions
+    value_version_max: usize,
+    // keys scanned per tick by the expiration sweep and where the next one starts
+    expire_sweep_keys: usize,
+    expire_sweep_cursor: Option<Bytes>,
+    // versions every replica has, tombstones in it are removed by the sweep
+    tombstone_safe_clocks: Option<BitmappedVersionVector>,
     // state for syncs
     pub pending_bootstrap: bool,
     pub sync_nodes: IdHashSet<NodeId>,
+    // last peer a receiver gave up on for not sending anything, tried last by the next syncs
+    pub stalled_peer: Option<NodeId>,
+    // consecutive failed bootstraps, when to retry and
+    // whether it's failed enough times to be reported
+    bootstrap_failures: u32,
+    bootstrap_retry_at: Option<Instant>,
+    bootstrap_failed: bool,
+    // the storage holds a partial bootstrap (restarted or failed midway),
+    // the next attempt only asks for the dots missing from log_clocks
+    pub bootstrap_resume: bool,
+    // a bootstrap streams a slice of the keys from each source replica,
+    // the intersection of their clocks is merged once all of them finish
+    pub bootstrap_clocks: Option<BitmappedVersionVector>,
+    bootstrap_stream_failed: bool,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -57,6 +163,41 @@ struct SavedVNodeState {
     clocks: BitmappedVersionVector,
     log_clocks: BitmappedVersionVector,
     clean_shutdown: bool,
+    // None if saved before the stats were kept, they're recounted on load
+    key_stats: Option<KeyStats>,
+}
+
+// the first frame of a vnode snapshot, the clocks when it started
+#[derive(Debug, Serialize, Deserialize)]
+struct SnapshotHeader {
+    version: u32,
+    num: u16,
+    clocks: BitmappedVersionVector,
+}
+
+// states saved before the key stats were added
+#[derive(Deserialize)]
+struct SavedVNodeStateV0 {
+    id: NodeId,
+    clocks: BitmappedVersionVector,
+    log_clocks: BitmappedVersionVector,
+    clean_shutdown: bool,
+}
+
+/// Number of stored keys and the size of their (serialized) values
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyStats {
+    pub keys: u64,
+    pub value_bytes: u64,
+}
+
+impl KeyStats {
+    /// Accounts for a key whose stored value went from old_size to new_size bytes,
+    /// a size of 0 meaning the key isn't stored.
+    pub fn replace(&mut self, old_size: u64, new_size: u64) {
+        self.keys = (self.keys + (new_size != 0) as u64).saturating_sub((old_size != 0) as u64);
+        self.value_bytes = (self.value_bytes + new_size).saturating_sub(old_size);
+    }
 }
 
 struct ReqState {
@@ -65,6 +206,46 @@ struct ReqState {
     required: u8,
     total: u8,
     context: Context,
+    // replicas queried and when, for the latency of the replies
+    sent: Vec<(NodeId, Instant)>,
+    // replicas not queried yet, asked in turn if a queried one fails (reads only)
+    spares: Vec<NodeId>,
+    keys: Vec<Bytes>,
+    // cubes returned by each replica, compared to the merged result for read repair
+    replicas: Vec<(NodeId, Vec<Cube>)>,
+    // keys (and their index) corrupted in this node, rewritten with the merged result
+    heal: Vec<(usize, Bytes)>,
+}
+
+// state for WAIT, replicas are probed until enough of them
+// have a version of the key that descends the given version vector
+struct WaitState {
+    key: Bytes,
+    vv: VersionVector,
+    required: usize,
+    confirmed: IdHashSet<NodeId>,
+    context: Context,
+}
+
+// state for an on demand anti-entropy job (CLUSTER AAE),
+// the vnode syncs with each of the other replicas in turn
+struct AaeState {
+    job: u64,
+    pending: Vec<NodeId>,
+    running: Option<Cookie>,
+    synced: u32,
+    failed: u32,
+    received: u64,
+}
+
+/// Progress of an on demand anti-entropy job in a vnode
+#[derive(Debug, Clone, PartialEq)]
+pub struct AaeProgress {
+    pub done: bool,
+    pub running: bool,
+    pub synced: u32,
+    pub failed: u32,
+    pub received: u64,
 }
 
 #[cfg(test)]
@@ -117,6 +298,11 @@ impl ReqState {
             replies: 0,
             succesfull: 0,
             context,
+            sent: Vec::new(),
+            spares: Vec::new(),
+            keys: Vec::new(),
+            replicas: Vec::new(),
+            heal: Vec::new(),
         }
     }
 
@@ -129,16 +315,24 @@ impl ReqState {
     }
 }
 
+impl AaeState {
+    fn sync_removed(&mut self, cookie: Cookie, result: SyncResult, received: u64) {
+        if self.running != Some(cookie) {
+            return;
+        }
+        self.running = None;
+        self.received += received;
+        if result == SyncResult::Done {
+            self.synced += 1;
+        } else {
+            self.failed += 1;
+        }
+    }
+}
+
 impl VNode {
     pub fn new(db: &Database, num: u16, status: VNodeStatus) -> VNode {
-        let state = VNodeState::load(num, db, status);
-        state.save(db, false);
-
-        let mut vnode = VNode {
-            state: state,
-            requests: InFlightMap::new(),
-            syncs: Default::default(),
-        };
+        let mut vnode = Self::with_state(db, VNodeState::load(num, db, status));
 
         match vnode.status() {
             VNodeStatus::Ready | VNodeStatus::Absent => (),
@@ -152,14 +346,277 @@ impl VNode {
         vnode
     }
 
+    /// Stands in for the vnode `num` until the vnode it splits from is split,
+    /// see `split`. Its storage and saved state are left as they are.
+    pub fn split_placeholder(db: &Database, num: u16) -> VNode {
+        let storage = db.storage_manager.open(num).expect("Can't open storage");
+        Self::from_state(
+            db,
+            VNodeState::with_storage(num, db, VNodeStatus::Absent, storage),
+        )
+    }
+
+    fn with_state(db: &Database, state: VNodeState) -> VNode {
+        state.save(db, false);
+        Self::from_state(db, state)
+    }
+
+    fn from_state(db: &Database, state: VNodeState) -> VNode {
+        VNode {
+            state: state,
+            requests: InFlightMap::new(),
+            waits: InFlightMap::new(),
+            syncs: Default::default(),
+            finished_syncs: InFlightMap::new(),
+            aae: None,
+            peer_clocks: Default::default(),
+            tombstone_gc_at: Instant::now(),
+            checkpoint_at: Instant::now()
+                + Duration::from_millis(db.config.checkpoint_interval as _),
+            checkpoint_writes: 0,
+            corrupted: Vec::new(),
+        }
+    }
+
+    /// Splits off the new vnode `num` after the ring partitions were doubled,
+    /// moving the keys that now belong to it. The keys that stay aren't rewritten.
+    /// The syncs are canceled, their peers retry them with the new vnodes.
+    pub fn split(&mut self, db: &Database, num: u16) -> VNode {
+        self.cancel_syncs(db, SyncDirection::Incomming);
+        self.cancel_syncs(db, SyncDirection::Outgoing);
+        let status = match self.status() {
+            VNodeStatus::Bootstrap => {
+                // only part of the keys is here, the dht change that follows
+                // bootstraps both halves from scratch
+                self.state.set_status(db, VNodeStatus::Absent);
+                VNodeStatus::Absent
+            }
+            status => status,
+        };
+        let mut child = if status == VNodeStatus::Absent {
+            VNodeState::new_empty(num, db, status)
+        } else {
+            // keys moved before a restart interrupted the split are kept
+            let storage = db.storage_manager.open(num).expect("Can't open storage");
+            let mut child = VNodeState::with_storage(num, db, status, storage);
+            match self.state.split_keys(db, &mut child) {
+                Ok(moved) => info!(
+                    "vnode:{:?} moved {} keys to vnode {:?}",
+                    self.state.num, moved, num
+                ),
+                // the replicas have them, the syncs bring them back eventually
+                Err(e) => error!(
+                    "vnode:{:?} failed to move keys to vnode {:?}: {:?}",
+                    self.state.num, num, e
+                ),
+            }
+            child
+        };
+        self.state.save(db, false);
+        // zombies are kept around until their timeout like the vnode they split from
+        child.last_status_change = self.state.last_status_change;
+        child.drain_until = self.state.drain_until;
+        Self::with_state(db, child)
+    }
+
     pub fn save(&mut self, db: &Database, shutdown: bool) {
+        if shutdown {
+            // the data must be durable before the state claims a clean shutdown
+            self.state.storage.sync().expect("Can't sync storage");
+        }
         self.state.save(db, shutdown);
     }
 
+    /// Writes a dump of the vnode to `writer`: the magic, a header with the clocks,
+    /// a length-framed (key, cube) entry per stored key (bincode encoded regardless of
+    /// the storage format), an empty frame and the number of keys. Returns that number.
+    /// The vnode is locked while it runs, so the keys are consistent with the clocks.
+    pub fn snapshot<W: Write>(&self, writer: &mut W) -> Result<u64, GenericError> {
+        match self.status() {
+            VNodeStatus::Ready | VNodeStatus::Zombie => (),
+            status => return Err(format!("Can't snapshot a vnode in {:?}", status).into()),
+        }
+        let header = SnapshotHeader {
+            version: SNAPSHOT_VERSION,
+            num: self.state.num,
+            clocks: self.state.clocks.clone(),
+        };
+        writer.write_u64::<LittleEndian>(SNAPSHOT_MAGIC)?;
+        write_frame(writer, &bincode::serialize(&header)?)?;
+        let format = self.state.storage.format();
+        let mut keys = 0u64;
+        let mut iterator = self.state.storage.iterator();
+        for (k, v) in iterator.iter() {
+            let cube = format.deserialize_value::<Cube>(v)?;
+            write_frame(writer, &bincode::serialize(&(Bytes::from(k), cube))?)?;
+            keys += 1;
+        }
+        write_frame(writer, &[])?;
+        writer.write_u64::<LittleEndian>(keys)?;
+        writer.flush()?;
+        info!("Snapshot of vnode {} with {} keys", self.state.num, keys);
+        Ok(keys)
+    }
+
+    /// Rebuilds vnode `num` from a dump written by snapshot, replacing whatever this
+    /// node stored for it, so callers swap it in place of the old one while holding its
+    /// lock. The result is Ready with a new id, its writes can't reuse the dots the
+    /// dumped vnode handed out after the snapshot.
+    /// The dump is first loaded into a scratch storage and checked against its key
+    /// count, an invalid one returns an error with the vnode storage left untouched.
+    pub fn restore<R: Read>(
+        db: &Database,
+        num: u16,
+        reader: &mut R,
+    ) -> Result<VNode, GenericError> {
+        let _restore_lock = RESTORE_LOCK.lock().unwrap_or_else(|p| p.into_inner());
+        let scratch = db.storage_manager.open(SCRATCH_STORAGE_NUM)?;
+        scratch.clear();
+        let header = match Self::restore_scratch(&scratch, num, reader) {
+            Ok(header) => header,
+            Err(e) => {
+                scratch.clear();
+                return Err(e);
+            }
+        };
+
+        let mut state = VNodeState::new_empty(num, db, VNodeStatus::Ready);
+        let format = state.storage.format();
+        let mut keys = 0u64;
+        let mut iterator = scratch.iterator();
+        let mut entries = iterator.iter();
+        let mut done = false;
+        while !done {
+            let mut batch = state.storage.batch_new(0);
+            let mut batch_keys = 0;
+            while batch_keys < SNAPSHOT_BATCH_KEYS {
+                let (key, value) = match entries.next() {
+                    Some(entry) => entry,
+                    None => {
+                        done = true;
+                        break;
+                    }
+                };
+                let cube: Cube = format.deserialize_value(value)?;
+                {
+                    let log_clocks = &mut state.log_clocks;
+                    cube.for_each_dot(|i, v| {
+                        log_clocks.add(i, v);
+                        batch.log_set((i, v), key);
+                    });
+                }
+                batch.set(key, value);
+                if let Some(ref mut bloom) = state.bloom {
+                    bloom.insert(key);
+                }
+                state.key_stats.replace(0, value.len() as u64);
+                batch_keys += 1;
+            }
+            state.storage.batch_write(batch)?;
+            keys += batch_keys as u64;
+        }
+        scratch.clear();
+        state.clocks = header.clocks;
+        state.clocks.merge(&state.log_clocks);
+        info!("Restored vnode {} with {} keys", num, keys);
+        Ok(Self::with_state(db, state))
+    }
+
+    // reads and checks a whole dump into the scratch storage, returning its header
+    fn restore_scratch<R: Read>(
+        scratch: &Storage,
+        num: u16,
+        reader: &mut R,
+    ) -> Result<SnapshotHeader, GenericError> {
+        if reader.read_u64::<LittleEndian>()? != SNAPSHOT_MAGIC {
+            return Err("Not a vnode snapshot".into());
+        }
+        let header: SnapshotHeader = bincode::deserialize(&read_frame(reader)?)?;
+        if header.version != SNAPSHOT_VERSION {
+            return Err(format!("Unsupported snapshot version {}", header.version).into());
+        }
+        if header.num != num {
+            return Err(format!("Snapshot is of vnode {}, not {}", header.num, num).into());
+        }
+
+        let format = scratch.format();
+        let mut keys = 0u64;
+        let mut done = false;
+        while !done {
+            let mut batch = scratch.batch_new(0);
+            let mut batch_keys = 0;
+            while batch_keys < SNAPSHOT_BATCH_KEYS {
+                let frame = read_frame(reader)?;
+                if frame.is_empty() {
+                    done = true;
+                    break;
+                }
+                let (key, cube): (Bytes, Cube) = bincode::deserialize(&frame)?;
+                batch.set(&key, &format.serialize_value(&cube)?);
+                batch_keys += 1;
+            }
+            scratch.batch_write(batch)?;
+            keys += batch_keys as u64;
+        }
+        let expected_keys = reader.read_u64::<LittleEndian>()?;
+        if keys != expected_keys {
+            return Err(format!("Snapshot has {} keys, expected {}", keys, expected_keys).into());
+        }
+        Ok(header)
+    }
+
     pub fn status(&self) -> VNodeStatus {
         self.state.status
     }
 
+    #[cfg(test)]
+    pub fn _id(&self) -> NodeId {
+        self.state.id
+    }
+
+    #[cfg(test)]
+    pub fn _clocks(&self) -> BitmappedVersionVector {
+        self.state.clocks.clone()
+    }
+
+    #[cfg(test)]
+    pub fn _saved_clocks(&self, db: &Database) -> BitmappedVersionVector {
+        let format = db.meta_storage.format();
+        db.meta_storage
+            .get(self.state.num.to_string().as_bytes(), |bytes| {
+                format.deserialize::<SavedVNodeState>(bytes).unwrap().clocks
+            })
+            .unwrap()
+            .unwrap()
+    }
+
+    #[cfg(test)]
+    pub fn _is_stored(&self, key: &[u8]) -> bool {
+        self.state.storage.get(key, |_| ()).unwrap().is_some()
+    }
+
+    #[cfg(test)]
+    pub fn _corrupt(&self, key: &[u8]) {
+        self.state.storage.set(key, b"garbage").unwrap();
+    }
+
+    #[cfg(test)]
+    pub fn _lose(&mut self, key: &[u8]) {
+        self.state.storage.del(key).unwrap();
+        // rebuilt from the storage, like after a restart
+        self.state.merkle = None;
+    }
+
+    #[cfg(test)]
+    pub fn _expire_log(&mut self) {
+        self.state.storage.log_clear();
+    }
+
+    #[cfg(test)]
+    pub fn _merkle_root(&mut self) -> u64 {
+        self.state.merkle_tree().unwrap().hash(0, 0).unwrap()
+    }
+
     #[cfg(test)]
     pub fn _dump_log(&self) -> Vec<((u64, u64), Vec<u8>)> {
         self.state
@@ -170,16 +627,69 @@ impl VNode {
             .collect()
     }
 
+    pub fn summary(&self) -> VNodeSummary {
+        VNodeSummary {
+            status: self.state.status,
+            status_age: self.state.last_status_change.elapsed(),
+            keys: self.state.key_stats.keys,
+            clocks: self
+                .state
+                .clocks
+                .iter()
+                .map(|(&node, bv)| (node, bv.max()))
+                .collect(),
+            syncs: self.syncs.len(),
+            pending_bootstrap: self.state.pending_bootstrap,
+            bootstrap_failures: self.state.bootstrap_failures,
+            pending_recovery: false,
+        }
+    }
+
+    pub fn metrics(&self) -> VNodeMetrics {
+        let (syncs_incoming, syncs_outgoing) = self.syncs_inflight();
+        let now = Instant::now();
+        let next_request_timeout = self.requests.next_deadline().map(|deadline| {
+            let left = if deadline > now { deadline - now } else { Duration::from_millis(0) };
+            left.as_secs() * 1000 + left.subsec_nanos() as u64 / 1_000_000
+        });
+        VNodeMetrics {
+            vnode: self.state.num,
+            status: self.state.status,
+            inflight_requests: self.inflight_requests(),
+            next_request_timeout: next_request_timeout,
+            syncs_incoming: syncs_incoming,
+            syncs_outgoing: syncs_outgoing,
+            log_sizes: self
+                .state
+                .log_clocks
+                .iter()
+                .map(|(&node, bv)| (node, bv.count()))
+                .collect(),
+            clocks: self
+                .state
+                .clocks
+                .iter()
+                .map(|(&node, bv)| (node, bv.base()))
+                .collect(),
+            approximate_size: self.state.storage.approximate_size(),
+            keys: self.state.key_stats.keys,
+            value_bytes: self.state.key_stats.value_bytes,
+            sync_progress: self.syncs.values().map(|s| s.progress()).collect(),
+            hot_keys: self.state.hot_keys(),
+        }
+    }
+
     pub fn syncs_inflight(&self) -> (usize, usize) {
         let pend = if self.state.pending_bootstrap { 1 } else { 0 };
         self.syncs
             .values()
             .fold((pend, 0), |(inc, out), s| match *s {
                 Synchronization::BootstrapReceiver { .. }
-                | Synchronization::SyncReceiver { .. } => (inc + 1, out),
-                Synchronization::BootstrapSender { .. } | Synchronization::SyncSender { .. } => {
-                    (inc, out + 1)
-                }
+                | Synchronization::SyncReceiver { .. }
+                | Synchronization::AAEReceiver { .. } => (inc + 1, out),
+                Synchronization::BootstrapSender { .. }
+                | Synchronization::SyncSender { .. }
+                | Synchronization::AAESender { .. } => (inc, out + 1),
             })
     }
 
@@ -198,29 +708,25 @@ impl VNode {
         match (status, x_status) {
             (VNodeStatus::Ready, VNodeStatus::Absent)
             | (VNodeStatus::Bootstrap, VNodeStatus::Absent) => {
-                {
-                    // cancel incomming syncs
-                    let state = &mut self.state;
-                    let canceled = self
-                        .syncs
-                        .iter_mut()
-                        .filter_map(|(&cookie, m)| {
-                            if let SyncDirection::Incomming = m.direction() {
-                                m.on_cancel(db, state);
-                                Some(cookie)
-                            } else {
-                                None
-                            }
-                        })
-                        .collect::<Vec<_>>();
-                    for cookie in canceled {
-                        self.syncs.remove(&cookie).unwrap().on_remove(db, state);
-                    }
-                }
+                // incomming syncs can't finish once the vnode moves away
+                self.cancel_syncs(db, SyncDirection::Incomming);
 
                 // vnode goes into zombie unless it was bootstraping
                 let new_status = if status == VNodeStatus::Bootstrap {
                     VNodeStatus::Absent
+                } else if !self.requests.is_empty() {
+                    // drain first, replicated writes from coordinators that didn't see
+                    // the change yet are still accepted while the requests finish
+                    if self.state.drain_until.is_none() {
+                        info!(
+                            "vnode:{:?} draining {} requests before going zombie",
+                            self.state.num(),
+                            self.requests.len()
+                        );
+                        let timeout = db.config.read_timeout().max(db.config.write_timeout());
+                        self.state.drain_until = Some(Instant::now() + timeout);
+                    }
+                    return;
                 } else {
                     VNodeStatus::Zombie
                 };
@@ -245,6 +751,10 @@ impl VNode {
                     self.start_bootstrap(db);
                 }
             }
+            (VNodeStatus::Ready, VNodeStatus::Ready) => {
+                // moved back before the drain finished
+                self.state.drain_until = None;
+            }
             (a, b) if a == b => (), // nothing to do
             (a, b) => panic!("Invalid status change from dht {:?} -> {:?}", a, b),
         }
@@ -263,100 +773,634 @@ impl VNode {
                 .collect::<Vec<_>>()
         };
         for (cookie, result) in terminated_syncs {
-            self.syncs
-                .remove(&cookie)
-                .unwrap()
-                .on_remove(db, &mut self.state);
-            if self.status() == VNodeStatus::Bootstrap {
-                self.handle_bootstrap_result(db, result);
-            }
+            self.remove_sync(db, cookie, result);
         }
 
         let now = Instant::now();
+        while self.finished_syncs.pop_expired(now).is_some() {}
         while let Some((cookie, mut req)) = self.requests.pop_expired(now) {
             debug!(
                 "Request cookie:{:?} token:{} timed out",
                 cookie, req.context.token
             );
-            req.context.clear();
-            db.respond_error(&mut req.context, CommandError::Timeout);
+            // the replica that offloaded the read times out on its own
+            if req.context.proxied.is_none() {
+                req.context.clear();
+                db.respond_error(&mut req.context, CommandError::Timeout);
+            }
+        }
+
+        while let Some((_, mut wait)) = self.waits.pop_expired(now) {
+            let confirmed = wait.confirmed.len();
+            db.respond_int(&mut wait.context, confirmed as _);
+        }
+        let waits = self.waits.keys().cloned().collect::<Vec<_>>();
+        for cookie in waits {
+            self.probe_wait(db, cookie);
+        }
+
+        if let Some(until) = self.state.drain_until {
+            if self.requests.is_empty() || now >= until {
+                // none should have started while draining, but zombies can't receive
+                self.cancel_syncs(db, SyncDirection::Incomming);
+                self.state.set_status(db, VNodeStatus::Zombie);
+            }
+        }
+
+        self.aae_tick(db);
+
+        self.state.take_evicted();
+        if self.status() == VNodeStatus::Ready {
+            self.checkpoint_tick(db, now);
+            self.tombstone_gc_tick(db, now);
+            self.heal_tick(db);
+            if let Err(e) = self.state.sweep_keys() {
+                warn!("vnode:{:?} failed to sweep keys: {:?}", self.state.num(), e);
+            }
         }
 
         if self.state.pending_bootstrap {
             // check if there's a pending bootstrap we need to start
-            self.start_bootstrap(db);
+            if self.state.bootstrap_retry_at.map_or(true, |at| now >= at) {
+                self.start_bootstrap(db);
+            }
         } else if self.status() == VNodeStatus::Zombie
             && self.requests.is_empty()
             && self.syncs.is_empty()
-            && self.state.last_status_change.elapsed() > Duration::from_millis(ZOMBIE_TIMEOUT_MS)
+            && self.state.last_status_change.elapsed()
+                > Duration::from_millis(db.config.zombie_timeout as _)
         {
             // go absent when zombie timeout
             self.state.set_status(db, VNodeStatus::Absent);
         }
     }
 
-    // CLIENT CRUD
-    pub fn do_get(
+    // CLIENT CRUD
+    pub fn inflight_requests(&self) -> usize {
+        self.requests.len()
+    }
+
+    // new requests are refused once inflight_requests_max are waiting for replicas
+    fn check_inflight(&self, db: &Database) -> Result<(), CommandError> {
+        let max = db.config.inflight_requests_max as usize;
+        if max != 0 && self.requests.len() >= max {
+            debug!("vnode:{:?} too many inflight requests", self.state.num());
+            Err(CommandError::Overloaded)
+        } else {
+            Ok(())
+        }
+    }
+
+    // keys mapped to this vnode right before it split, see Database::key_vnode
+    fn check_keys<'a, I: IntoIterator<Item = &'a Bytes>>(
+        &self,
+        db: &Database,
+        keys: I,
+    ) -> Result<(), CommandError> {
+        if keys.into_iter().all(|k| db.key_vnode(k) == self.state.num) {
+            Ok(())
+        } else {
+            debug!("vnode:{:?} keys moved by a split", self.state.num);
+            Err(CommandError::Unavailable)
+        }
+    }
+
+    pub fn do_get(
+        &mut self,
+        db: &Database,
+        context: &mut Context,
+        keys: &[&Bytes],
+        consistency: ConsistencyLevel,
+        mut response_fn: ResponseFn,
+    ) -> Result<(), CommandError> {
+        debug!(
+            "vnode:{:?} do_get ({:?}) {:?}",
+            self.state.num(),
+            context.token,
+            consistency
+        );
+        self.check_keys(db, keys.iter().map(|&k| k))?;
+        if context.stale_reads
+            && consistency == ConsistencyLevel::One
+            && self.status() == VNodeStatus::Bootstrap
+        {
+            return self.do_get_stale(db, context, keys, response_fn);
+        }
+        self.check_inflight(db)?;
+        let nodes = db.dht.nodes_for_vnode(self.state.num, false, true);
+        if nodes.is_empty() {
+            debug!("vnode:{:?} no nodes", self.state.num());
+            return Err(CommandError::Unavailable);
+        }
+        if consistency.required(nodes.len() as u8) as usize > db.live_replicas(&nodes) {
+            debug!("vnode:{:?} not enough replicas", self.state.num());
+            return Err(CommandError::NotEnoughReplicas);
+        }
+        if nodes == &[db.dht.node()] {
+            return self.do_get_local(db, context, keys, response_fn);
+        }
+        if let Some(preferred) = context.read_affinity {
+            if consistency == ConsistencyLevel::One
+                && preferred != db.dht.node()
+                && nodes.contains(&preferred)
+            {
+                // if the preferred replica isn't reachable fall back to the regular path
+                match self.get_from_replica(db, context, keys, preferred, None, false, response_fn)
+                {
+                    Ok(()) => return Ok(()),
+                    Err(r) => response_fn = r,
+                }
+            } else if context.stale_reads
+                && consistency == ConsistencyLevel::One
+                && preferred != db.dht.node()
+                && db
+                    .dht
+                    .nodes_for_vnode(self.state.num, true, false)
+                    .contains(&preferred)
+            {
+                // the preferred replica is still bootstrapping, it serves what it has so far
+                match self.get_from_replica(db, context, keys, preferred, None, true, response_fn) {
+                    Ok(()) => return Ok(()),
+                    Err(r) => response_fn = r,
+                }
+            }
+        }
+        if context.proxied.is_none() {
+            if let Some(coordinator) = self.offload_coordinator(db, &nodes) {
+                let coordinate = Some(consistency);
+                match self.get_from_replica(
+                    db,
+                    context,
+                    keys,
+                    coordinator,
+                    coordinate,
+                    false,
+                    response_fn,
+                ) {
+                    Ok(()) => return Ok(()),
+                    Err(r) => response_fn = r,
+                }
+            }
+        }
+        let mut participate = nodes.contains(&db.dht.node());
+        let cookie = self.gen_cookie();
+        let expire = Instant::now() + db.config.read_timeout();
+
+        // with storage workers this node replies like the remote replicas do
+        let storage_sender = if participate && keys.iter().any(|k| self.state.maybe_stored(k)) {
+            db.storage_sender()
+        } else {
+            None
+        };
+        let mut local = Vec::with_capacity(keys.len());
+        let mut heal = Vec::new();
+        if participate && storage_sender.is_none() {
+            for (i, &key) in keys.iter().enumerate() {
+                match self.state.storage_get(key) {
+                    Ok(cube) => local.push(cube),
+                    Err(CommandError::Corrupted) => heal.push((i, key.clone())),
+                    Err(e) => return Err(e),
+                }
+            }
+            // this node doesn't count as a replica of this read,
+            // the value read from the others replaces the corrupted one
+            participate = heal.is_empty();
+        }
+
+        // only query the replicas needed to satisfy the consistency,
+        // the others are kept as spares in case any of those fails
+        let mut remotes: Vec<_> = nodes
+            .iter()
+            .cloned()
+            .filter(|&n| n != db.dht.node())
+            .collect();
+        db.order_read_replicas(&mut remotes);
+        let spares = if db.config.read_replica_policy == ReplicaPolicy::All {
+            Vec::new()
+        } else {
+            let required = consistency.required(nodes.len() as u8) as usize;
+            let queried = required.saturating_sub(participate as usize).min(remotes.len());
+            let mut spares = remotes.split_off(queried);
+            // spares are popped from the back
+            spares.reverse();
+            spares
+        };
+
+        let mut response_fn = Some(response_fn);
+        let mut local = local.into_iter();
+        for _ in keys {
+            let value = if participate && storage_sender.is_none() {
+                local.next().unwrap()
+            } else {
+                Default::default()
+            };
+            context.reads.push(ContextRead {
+                cube: value,
+                response: response_fn.take(),
+            });
+        }
+
+        let msg = MsgRemoteGet {
+            cookie: cookie,
+            vnode: self.state.num,
+            keys: keys.iter().map(|&x| x.clone()).collect(),
+            coordinate: None,
+            stale: false,
+        };
+        let mut req = ReqState::new(replace_default(context), nodes.len(), consistency);
+        if !heal.is_empty() {
+            // won't get a reply from this node
+            req.total -= 1;
+            req.heal = heal;
+        }
+        let now = Instant::now();
+        req.sent = remotes.iter().map(|&n| (n, now)).collect();
+        if !spares.is_empty() || db.config.read_repair {
+            req.keys = msg.keys.clone();
+        }
+        if participate && storage_sender.is_none() && db.config.read_repair {
+            let local = req.context.reads.iter().map(|r| r.cube.clone()).collect();
+            req.replicas.push((db.dht.node(), local));
+        }
+        req.spares = spares;
+        self.requests.insert(cookie, req, expire);
+
+        if let Some(sender) = storage_sender {
+            let local_msg = MsgRemoteGet {
+                cookie: cookie,
+                vnode: self.state.num,
+                keys: msg.keys.clone(),
+                coordinate: None,
+                stale: false,
+            };
+            let void = Cube::new(&self.state.clocks);
+            sender.send(StorageMsg::Get(
+                db.dht.node(),
+                self.state.storage.clone(),
+                void,
+                local_msg,
+            ));
+        } else if participate {
+            // register the results added above
+            if self.process_get::<Option<_>>(db, cookie, db.dht.node(), Ok(None)) {
+                return Ok(());
+            }
+        }
+
+        for node in remotes {
+            if let Err(err) = db.fabric.send_msg(node, &msg) {
+                if self.process_get::<Option<_>>(db, cookie, node, Err(err)) {
+                    return Ok(());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // The whole read is answered by a single replica, with its own values or, if `coordinate`
+    // is set, with the result of coordinating the read. If `stale` is set the replica may be
+    // bootstrapping and the results are marked as stale. The response_fn is given back if the
+    // replica isn't reachable.
+    fn get_from_replica(
+        &mut self,
+        db: &Database,
+        context: &mut Context,
+        keys: &[&Bytes],
+        node: NodeId,
+        coordinate: Option<ConsistencyLevel>,
+        stale: bool,
+        response_fn: ResponseFn,
+    ) -> Result<(), ResponseFn> {
+        let msg = MsgRemoteGet {
+            cookie: self.gen_cookie(),
+            vnode: self.state.num,
+            keys: keys.iter().map(|&x| x.clone()).collect(),
+            coordinate: coordinate,
+            stale: stale,
+        };
+        if db.fabric.send_msg(node, &msg).is_err() {
+            return Err(response_fn);
+        }
+        let response_fn = if stale {
+            stale_response(response_fn)
+        } else {
+            response_fn
+        };
+        let mut response_fn = Some(response_fn);
+        for _ in keys {
+            context.reads.push(ContextRead {
+                cube: Default::default(),
+                response: response_fn.take(),
+            });
+        }
+        let expire = Instant::now() + db.config.read_timeout();
+        let req = ReqState::new(replace_default(context), 1, ConsistencyLevel::One);
+        self.requests.insert(msg.cookie, req, expire);
+        Ok(())
+    }
+
+    // Reads of a vnode with coordinator_offload_inflight requests inflight are coordinated
+    // by the first of the other live replicas, by read_replica_policy. Only if this node is
+    // a replica, the load is spread between the replicas.
+    // The load is the number of requests inflight in this vnode (reads and writes it
+    // coordinates, and reads offloaded to it), the other replicas' load isn't known here.
+    // Writes aren't offloaded: their mutators are closures over the parsed command,
+    // run on the coordinator value with a dot of its clock, so offloading them means
+    // shipping the command and the connection state instead, and this node still has
+    // to store the write as a replica. Only the quorum bookkeeping would move.
+    fn offload_coordinator(&self, db: &Database, nodes: &[NodeId]) -> Option<NodeId> {
+        let threshold = db.config.coordinator_offload_inflight as usize;
+        if threshold == 0 || self.requests.len() < threshold || !nodes.contains(&db.dht.node()) {
+            return None;
+        }
+        let mut remotes: Vec<_> = nodes
+            .iter()
+            .cloned()
+            .filter(|&n| n != db.dht.node() && db.fabric.is_connected(n))
+            .collect();
+        db.order_read_replicas(&mut remotes);
+        remotes.first().cloned()
+    }
+
+    // coordinates a read offloaded by another replica, see offload_coordinator
+    fn do_get_proxied(&mut self, db: &Database, from: NodeId, msg: MsgRemoteGet) {
+        let consistency = msg.coordinate.expect("Not a coordinated read");
+        let result = {
+            let mut context = Context::default();
+            context.proxied = Some((from, msg.cookie));
+            let keys: Vec<_> = msg.keys.iter().collect();
+            let response_fn: ResponseFn = Box::new(|_| RespValue::Nil);
+            self.do_get(db, &mut context, &keys, consistency, response_fn)
+        };
+        if let Err(e) = result {
+            debug!("vnode:{:?} coordinated get failed: {:?}", self.state.num, e);
+            let _ = fabric_send_error!(db, from, msg, MsgRemoteGetAck, FabricError::NotReady);
+        }
+    }
+
+    // a replica failed to reply a read, query one of the spares instead (if any)
+    fn get_from_spare(&mut self, db: &Database, cookie: Cookie) {
+        let (node, msg) = match self.requests.entry(cookie) {
+            HMEntry::Occupied(mut o) => {
+                let state = o.get_mut();
+                let node = match state.spares.pop() {
+                    Some(node) => node,
+                    None => return,
+                };
+                state.sent.push((node, Instant::now()));
+                let msg = MsgRemoteGet {
+                    cookie: cookie,
+                    vnode: self.state.num,
+                    keys: state.keys.clone(),
+                    coordinate: None,
+                    stale: false,
+                };
+                (node, msg)
+            }
+            HMEntry::Vacant(_) => return,
+        };
+        debug!("vnode:{:?} get {:?} from spare {}", self.state.num, cookie, node);
+        if let Err(err) = db.fabric.send_msg(node, &msg) {
+            self.process_get::<Option<_>>(db, cookie, node, Err(err));
+        }
+    }
+
+    // fast path for when this node is the only replica,
+    // no need to go through the request machinery
+    fn do_get_local(
+        &mut self,
+        db: &Database,
+        context: &mut Context,
+        keys: &[&Bytes],
+        mut response_fn: ResponseFn,
+    ) -> Result<(), CommandError> {
+        let start = Instant::now();
+        for key in keys {
+            let cube = self.state.storage_get(key)?;
+            context.response.push(response_fn(cube));
+        }
+        if start.elapsed() > db.config.read_timeout() {
+            return Err(CommandError::Timeout);
+        }
+        db.respond(context);
+        Ok(())
+    }
+
+    // serves whatever was already transfered to a bootstrapping vnode,
+    // each result is marked as [STALE, result] as keys may be missing or outdated
+    fn do_get_stale(
+        &mut self,
+        db: &Database,
+        context: &mut Context,
+        keys: &[&Bytes],
+        response_fn: ResponseFn,
+    ) -> Result<(), CommandError> {
+        debug!("vnode:{:?} stale read ({:?})", self.state.num(), context.token);
+        self.do_get_local(db, context, keys, stale_response(response_fn))
+    }
+
+    // returns up to `count` local (key, cube) pairs with keys after `start`,
+    // optionally starting with `prefix`, and whether the storage was exhausted.
+    // Only this vnode storage is read, no replicas are consulted.
+    // the stored keys (from start, excluded) starting with prefix and in keyspace
+    pub fn do_scan(
+        &mut self,
+        _db: &Database,
+        start: &[u8],
+        prefix: &[u8],
+        keyspace: u16,
+        count: usize,
+    ) -> Result<(Vec<(Bytes, Cube)>, bool), CommandError> {
+        match self.status() {
+            VNodeStatus::Ready | VNodeStatus::Zombie => (),
+            _ => return Err(CommandError::Unavailable),
+        }
+        let mut results = Vec::with_capacity(count);
+        let mut iterator = if prefix.is_empty() {
+            self.state.storage.iterator_from(start)
+        } else {
+            let mut iterator = self.state.storage.iterator_prefix(prefix);
+            if start > prefix {
+                iterator.seek(start);
+            }
+            iterator
+        };
+        let format = self.state.storage.format();
+        let now = now_millis();
+        for (k, v) in iterator.iter() {
+            // the keys of the other keyspaces sort after the ones of the default keyspace
+            if keyspace == 0 && k >= &b"\xFF\x00\x01"[..] {
+                break;
+            }
+            if (!start.is_empty() && k == start) || split_keyspace_key(k).0 != keyspace {
+                continue;
+            }
+            if results.len() >= count {
+                return Ok((results, false));
+            }
+            let mut cube = match format.deserialize_value::<Cube>(v) {
+                Ok(cube) => cube,
+                Err(e) => {
+                    self.skip_corrupted(k, e);
+                    continue;
+                }
+            };
+            // like storage_get
+            cube.expire(now);
+            results.push((Bytes::from(k), cube));
+        }
+        Ok((results, true))
+    }
+
+    // the local keys starting with `prefix` that have values, for DELRANGE.
+    // Vnodes that aren't ready in this node have none.
+    pub fn do_delrange_keys(
+        &mut self,
+        db: &Database,
+        prefix: &[u8],
+    ) -> Result<Vec<Bytes>, CommandError> {
+        if self.status() != VNodeStatus::Ready {
+            return Ok(Vec::new());
+        }
+        let mut keys = Vec::new();
+        let mut iterator = self.state.storage.iterator_prefix(prefix);
+        let format = self.state.storage.format();
+        let now = now_millis();
+        for (k, v) in iterator.iter() {
+            // keys moved by a split are deleted by the vnode they moved to
+            if db.key_vnode(k) != self.state.num {
+                continue;
+            }
+            let mut cube = match format.deserialize_value::<Cube>(v) {
+                Ok(cube) => cube,
+                Err(e) => {
+                    self.skip_corrupted(k, e);
+                    continue;
+                }
+            };
+            cube.expire(now);
+            if cube.has_values() {
+                keys.push(Bytes::from(k));
+            }
+        }
+        Ok(keys)
+    }
+
+    // the key is left out of the scan, the next tick heals it
+    fn skip_corrupted<E: ::std::fmt::Display>(&mut self, key: &[u8], error: E) {
+        warn!(
+            "vnode:{:?} skipping the corrupted value of {:?}: {}",
+            self.state.num(),
+            String::from_utf8_lossy(key),
+            error
+        );
+        if !self.corrupted.iter().any(|k| k == key) {
+            self.corrupted.push(Bytes::from(key));
+        }
+    }
+
+    // reads the keys skipped by the scans, the read replaces the corrupted values
+    // with the ones of the other replicas (see ReqState::heal)
+    fn heal_tick(&mut self, db: &Database) {
+        for key in replace_default(&mut self.corrupted) {
+            let response_fn: ResponseFn = Box::new(|_| RespValue::Nil);
+            let mut context = Context::detached();
+            let result = self.do_get(db, &mut context, &[&key], ConsistencyLevel::One, response_fn);
+            if let Err(e) = result {
+                warn!(
+                    "vnode:{:?} can't heal {:?}: {:?}",
+                    self.state.num(),
+                    String::from_utf8_lossy(&key),
+                    e
+                );
+            }
+        }
+    }
+
+    pub fn do_wait(
         &mut self,
         db: &Database,
         context: &mut Context,
-        keys: &[&Bytes],
-        consistency: ConsistencyLevel,
-        response_fn: ResponseFn,
+        key: &Bytes,
+        vv: VersionVector,
+        required: usize,
+        timeout: Duration,
     ) -> Result<(), CommandError> {
-        debug!(
-            "vnode:{:?} do_get ({:?}) {:?}",
-            self.state.num(),
-            context.token,
-            consistency
-        );
-        let nodes = db.dht.nodes_for_vnode(self.state.num, false, true);
-        if nodes.is_empty() {
+        self.check_keys(db, Some(key))?;
+        if db.dht.nodes_for_vnode(self.state.num, false, true).is_empty() {
             debug!("vnode:{:?} no nodes", self.state.num());
             return Err(CommandError::Unavailable);
         }
-        let participate = nodes.contains(&db.dht.node());
         let cookie = self.gen_cookie();
-        let expire = Instant::now() + Duration::from_millis(db.config.request_timeout as _);
+        let wait = WaitState {
+            key: key.clone(),
+            vv: vv,
+            required: required,
+            confirmed: Default::default(),
+            context: replace_default(context),
+        };
+        self.waits.insert(cookie, wait, Instant::now() + timeout);
+        self.probe_wait(db, cookie);
+        Ok(())
+    }
 
-        let mut response_fn = Some(response_fn);
-        for key in keys {
-            let value = if participate {
-                self.state.storage_get(key).unwrap()
+    // asks the replicas that didn't confirm yet for their version of the key
+    fn probe_wait(&mut self, db: &Database, cookie: Cookie) {
+        let nodes = db.dht.nodes_for_vnode(self.state.num, false, true);
+        let (key, pending) = match self.waits.get(&cookie) {
+            Some(wait) => (
+                wait.key.clone(),
+                nodes
+                    .into_iter()
+                    .filter(|n| !wait.confirmed.contains(n))
+                    .collect::<Vec<_>>(),
+            ),
+            None => return,
+        };
+        for node in pending {
+            if node == db.dht.node() {
+                let result = self
+                    .state
+                    .storage_get(&key)
+                    .map(|cube| vec![cube])
+                    .map_err(|_| FabricError::StorageError);
+                self.process_wait(db, cookie, node, result);
             } else {
-                Default::default()
-            };
-            context.reads.push(ContextRead {
-                cube: value,
-                response: response_fn.take(),
-            });
-        }
-
-        let req = ReqState::new(replace_default(context), nodes.len(), consistency);
-        self.requests.insert(cookie, req, expire);
-
-        if participate {
-            // register the results added above
-            if self.process_get::<Option<_>>(db, cookie, Ok(None)) {
-                return Ok(());
+                let msg = MsgRemoteGet {
+                    cookie: cookie,
+                    vnode: self.state.num,
+                    keys: vec![key.clone()],
+                    coordinate: None,
+                    stale: false,
+                };
+                let _ = db.fabric.send_msg(node, &msg);
             }
         }
+    }
 
-        let msg = MsgRemoteGet {
-            cookie: cookie,
-            vnode: self.state.num,
-            keys: keys.iter().map(|&x| x.clone()).collect(),
-        };
-        for node in nodes {
-            if node != db.dht.node() {
-                if let Err(err) = db.fabric.send_msg(node, &msg) {
-                    if self.process_get::<Option<_>>(db, cookie, Err(err)) {
-                        return Ok(());
+    fn process_wait(
+        &mut self,
+        db: &Database,
+        cookie: Cookie,
+        from: NodeId,
+        response: Result<Vec<Cube>, FabricError>,
+    ) {
+        if let HMEntry::Occupied(mut o) = self.waits.entry(cookie) {
+            let done = {
+                let wait = o.get_mut();
+                if let Ok(cubes) = response {
+                    if cubes.first().map_or(false, |c| c.descends(&wait.vv)) {
+                        wait.confirmed.insert(from);
                     }
                 }
+                wait.confirmed.len() >= wait.required
+            };
+            if done {
+                let mut wait = o.remove();
+                let confirmed = wait.confirmed.len();
+                db.respond_int(&mut wait.context, confirmed as _);
             }
         }
-        Ok(())
     }
 
     fn respond_cant_coordinate(
@@ -365,7 +1409,7 @@ impl VNode {
         context: &mut Context,
         status: VNodeStatus,
     ) {
-        let hash_slot = hash_slot(&context.writes[0].key);
+        let hash_slot = db.dht.key_slot(&context.writes[0].key);
         let mut nodes = db.dht.nodes_for_vnode_ex(self.state.num(), true, false);
         thread_rng().shuffle(&mut nodes);
         for (node, (_, addr)) in nodes {
@@ -392,17 +1436,26 @@ impl VNode {
         consistency: ConsistencyLevel,
     ) -> Result<(), CommandError> {
         match self.status() {
+            VNodeStatus::Ready if self.state.drain_until.is_some() => {
+                return Ok(self.respond_cant_coordinate(db, context, VNodeStatus::Zombie))
+            }
             VNodeStatus::Ready => (),
             status => return Ok(self.respond_cant_coordinate(db, context, status)),
         }
+        self.check_keys(db, context.writes.iter().map(|w| &w.key))?;
+        self.check_inflight(db)?;
+
+        // fail before touching the clocks if the consistency can't be satisfied
+        let nodes = db.dht.nodes_for_vnode(self.state.num, true, true);
+        if consistency.required(nodes.len() as u8) as usize > db.live_replicas(&nodes) {
+            debug!("vnode:{:?} not enough replicas", self.state.num());
+            return Err(CommandError::NotEnoughReplicas);
+        }
 
+        let start = Instant::now();
         let mut error = None;
         for write in &mut context.writes {
-            let old_cube = match self
-                .state
-                .storage_get(&write.key)
-                .map_err(|_| CommandError::StorageError)
-            {
+            let old_cube = match self.state.storage_get(&write.key) {
                 Ok(old_cube) => old_cube,
                 Err(e) => {
                     error = Some(e);
@@ -429,20 +1482,45 @@ impl VNode {
             return Err(e);
         }
 
-        let cookie = self.gen_cookie();
-        let expire = Instant::now() + Duration::from_millis(db.config.request_timeout as _);
-        let nodes = db.dht.nodes_for_vnode(self.state.num, true, true);
-
         match self.state.storage_set_local(
             db,
             context
                 .writes
-                .iter()
-                .map(|w| (w.version, &w.key[..], &w.cube)),
+                .iter_mut()
+                .map(|w| (w.version, &w.key[..], &mut w.cube)),
+            context.durable,
         ) {
             Ok(()) => (),
             Err(e) => return Err(e),
         };
+        self.checkpoint_writes += context.writes.len() as u32;
+        if db.config.checkpoint_writes != 0 && self.checkpoint_writes >= db.config.checkpoint_writes
+        {
+            self.checkpoint(db, Instant::now());
+        }
+
+        if nodes == &[db.dht.node()] {
+            // fast path for when this node is the only replica,
+            // the local write is all that's needed. It's committed even if it
+            // timed out, like the writes that time out waiting for the replicas
+            if start.elapsed() > db.config.write_timeout() {
+                return Err(CommandError::Timeout);
+            }
+            context.response.extend(context.writes.drain(..).map(|w| {
+                let ContextWrite {
+                    response,
+                    response_fn,
+                    cube,
+                    ..
+                } = w;
+                response.unwrap_or_else(|| response_fn.expect("No ResponseFn")(cube))
+            }));
+            db.respond(context);
+            return Ok(());
+        }
+
+        let cookie = self.gen_cookie();
+        let expire = Instant::now() + db.config.write_timeout();
 
         // The code bellow is carefully ordered to move Cubes around without cloning
 
@@ -484,16 +1562,27 @@ impl VNode {
         &mut self,
         db: &Database,
         cookie: Cookie,
+        from: NodeId,
         response: Result<I, FabricError>,
     ) -> bool {
-        if let HMEntry::Occupied(mut o) = self.requests.entry(cookie) {
+        let failed = response.is_err();
+        let mut retry = false;
+        let mut repairs = Vec::new();
+        let mut repairs_wait = None;
+        let mut heals = Vec::new();
+        let mut proxied_ack = None;
+        let done = if let HMEntry::Occupied(mut o) = self.requests.entry(cookie) {
             debug!("process_get {:?}", cookie);
             let done = {
                 let state = o.get_mut();
                 state.replies += 1;
                 if let Ok(response) = response {
                     state.succesfull += 1;
-                    for (response, read) in response.into_iter().zip(&mut state.context.reads) {
+                    let cubes: Vec<Cube> = response.into_iter().collect();
+                    if db.config.read_repair && !cubes.is_empty() {
+                        state.replicas.push((from, cubes.clone()));
+                    }
+                    for (response, read) in cubes.into_iter().zip(&mut state.context.reads) {
                         let cube = replace_default(&mut read.cube);
                         read.cube = cube.merge(response);
                     }
@@ -504,9 +1593,36 @@ impl VNode {
                 let mut state = o.remove();
                 if !state.satisfied() {
                     debug!("get {:?} done but not satisfied", cookie);
-                    state.context.clear();
-                    db.respond_error(&mut state.context, CommandError::Unavailable);
+                    if let Some((origin, origin_cookie)) = state.context.proxied {
+                        proxied_ack = Some((origin, origin_cookie, Err(FabricError::NotReady)));
+                    } else {
+                        state.context.clear();
+                        db.respond_error(&mut state.context, CommandError::Unavailable);
+                    }
+                } else if let Some((origin, origin_cookie)) = state.context.proxied {
+                    if state.replicas.len() > 1 {
+                        repairs = Self::read_repairs(&state);
+                    }
+                    heals = replace_default(&mut state.heal)
+                        .into_iter()
+                        .map(|(i, key)| (key, state.context.reads[i].cube.clone()))
+                        .collect();
+                    let cubes = state.context.reads.drain(..).map(|r| r.cube).collect();
+                    proxied_ack = Some((origin, origin_cookie, Ok(cubes)));
                 } else {
+                    if state.replicas.len() > 1 {
+                        repairs = Self::read_repairs(&state);
+                    }
+                    heals = replace_default(&mut state.heal)
+                        .into_iter()
+                        .map(|(i, key)| (key, state.context.reads[i].cube.clone()))
+                        .collect();
+                    // quorum reads can wait for the repairs to reach a quorum as well
+                    let quorum = ConsistencyLevel::Quorum.required(state.total);
+                    let sync_repair = (db.config.read_repair_sync || state.context.sync_repair)
+                        && state.required >= quorum
+                        && !repairs.is_empty();
+                    let up_to_date = (state.replicas.len() - repairs.len()) as u8;
                     let ReqState { mut context, .. } = state;
                     let mut render_fn = None;
                     context.response.extend(context.reads.drain(..).map(|r| {
@@ -515,13 +1631,151 @@ impl VNode {
                         }
                         render_fn.as_mut().expect("No ResponseFn")(r.cube)
                     }));
-                    db.respond(&mut context);
+                    if sync_repair {
+                        repairs_wait = Some((context, quorum, up_to_date));
+                    } else {
+                        db.respond(&mut context);
+                    }
                 }
+            } else if failed {
+                retry = true;
             }
             done
         } else {
             debug!("process_get cookie not found {:?}", cookie);
             true
+        };
+        if retry {
+            self.get_from_spare(db, cookie);
+        }
+        if let Some((origin, origin_cookie, result)) = proxied_ack {
+            let _ = db.fabric.send_msg(
+                origin,
+                &MsgRemoteGetAck {
+                    vnode: self.state.num,
+                    cookie: origin_cookie,
+                    result: result,
+                },
+            );
+        }
+        if let Some((context, required, up_to_date)) = repairs_wait {
+            self.send_read_repairs_sync(db, context, required, up_to_date, repairs);
+        } else if !repairs.is_empty() {
+            self.send_read_repairs(db, repairs);
+        }
+        for (key, cube) in heals {
+            if let Err(e) = self.state.storage_heal(&key, cube) {
+                warn!("vnode:{:?} healing failed: {:?}", self.state.num(), e);
+            }
+        }
+        done
+    }
+
+    // the merged cubes that each replica is missing, compared by their dots
+    fn read_repairs(state: &ReqState) -> Vec<(NodeId, Vec<(Bytes, Cube, bool)>)> {
+        let merged: Vec<_> = state
+            .keys
+            .iter()
+            .zip(&state.context.reads)
+            .map(|(key, read)| MerkleTree::digest(key, &read.cube))
+            .collect();
+        state
+            .replicas
+            .iter()
+            .filter_map(|&(node, ref cubes)| {
+                let writes: Vec<_> = state
+                    .keys
+                    .iter()
+                    .zip(cubes)
+                    .zip(&state.context.reads)
+                    .zip(&merged)
+                    .filter(|&(((key, cube), _), &digest)| MerkleTree::digest(key, cube) != digest)
+                    .map(|(((key, _), read), _)| (key.clone(), read.cube.clone(), false))
+                    .collect();
+                if writes.is_empty() {
+                    None
+                } else {
+                    Some((node, writes))
+                }
+            })
+            .collect()
+    }
+
+    // fire and forget, the client already got the response
+    fn send_read_repairs(&mut self, db: &Database, repairs: Vec<(NodeId, Vec<(Bytes, Cube, bool)>)>) {
+        for (node, writes) in repairs {
+            debug!(
+                "vnode:{:?} read repair of {} keys in {}",
+                self.state.num(),
+                writes.len(),
+                node
+            );
+            metrics::READ_REPAIR.mark(writes.len() as _);
+            if node == db.dht.node() {
+                if let Err(e) = self.state.storage_set_remote(db, writes) {
+                    warn!("vnode:{:?} read repair failed: {:?}", self.state.num(), e);
+                }
+            } else {
+                let msg = MsgRemoteSet {
+                    cookie: self.gen_cookie(),
+                    vnode: self.state.num,
+                    writes,
+                    reply: false,
+                };
+                let _ = db.fabric.send_msg(node, &msg);
+            }
+        }
+    }
+
+    // like send_read_repairs but the response (already in the context) is sent once
+    // `required` replicas have the repaired values, counting the `up_to_date` ones.
+    // It's tracked like a write, so failures and timeouts respond with an error.
+    fn send_read_repairs_sync(
+        &mut self,
+        db: &Database,
+        context: Context,
+        required: u8,
+        up_to_date: u8,
+        repairs: Vec<(NodeId, Vec<(Bytes, Cube, bool)>)>,
+    ) {
+        let cookie = self.gen_cookie();
+        let expire = Instant::now() + db.config.write_timeout();
+        let mut req = ReqState::new(context, 0, ConsistencyLevel::One);
+        req.required = required;
+        req.total = up_to_date + repairs.len() as u8;
+        req.replies = up_to_date;
+        req.succesfull = up_to_date;
+        self.requests.insert(cookie, req, expire);
+
+        for (node, writes) in repairs {
+            debug!(
+                "vnode:{:?} read repair of {} keys in {}, waiting for it",
+                self.state.num(),
+                writes.len(),
+                node
+            );
+            metrics::READ_REPAIR.mark(writes.len() as _);
+            let done = if node == db.dht.node() {
+                let result = self.state.storage_set_remote(db, writes).map_err(|e| {
+                    warn!("vnode:{:?} read repair failed: {:?}", self.state.num(), e);
+                    FabricError::StorageError
+                });
+                self.process_set(db, cookie, result)
+            } else {
+                let msg = MsgRemoteSet {
+                    cookie: cookie,
+                    vnode: self.state.num,
+                    writes,
+                    reply: true,
+                };
+                match db.fabric.send_msg(node, &msg) {
+                    Ok(()) => false,
+                    Err(err) => self.process_set::<Option<_>>(db, cookie, Err(err)),
+                }
+            };
+            if done {
+                return;
+            }
         }
     }
 
@@ -575,40 +1829,184 @@ impl VNode {
     }
 
     // CRUD HANDLERS
-    pub fn handler_get_remote_ack(&mut self, db: &Database, _from: NodeId, msg: MsgRemoteGetAck) {
-        self.process_get(db, msg.cookie, msg.result);
+    pub fn handler_get_remote_ack(&mut self, db: &Database, from: NodeId, msg: MsgRemoteGetAck) {
+        if self.waits.contains_key(&msg.cookie) {
+            self.process_wait(db, msg.cookie, from, msg.result);
+        } else {
+            if let Some(state) = self.requests.get(&msg.cookie) {
+                if let Some(&(_, sent_at)) = state.sent.iter().find(|&&(n, _)| n == from) {
+                    // failures count as slow as it gets
+                    let latency = if msg.result.is_ok() {
+                        sent_at.elapsed()
+                    } else {
+                        db.config.read_timeout()
+                    };
+                    db.observe_replica_latency(from, latency);
+                }
+            }
+            self.process_get(db, msg.cookie, from, msg.result);
+        }
     }
 
     pub fn handler_get_remote(&mut self, db: &Database, from: NodeId, msg: MsgRemoteGet) {
-        // accept zombie to reduce chance of timeouts due to races on cluster change
+        // stale reads are served with the data transfered so far, like do_get_stale
+        let stale = msg.stale
+            && msg.coordinate.is_none()
+            && db.config.bootstrap_stale_reads
+            && self.status() == VNodeStatus::Bootstrap;
+        if !stale {
+            // accept zombie to reduce chance of timeouts due to races on cluster change
+            check_status!(
+                self,
+                VNodeStatus::Ready | VNodeStatus::Zombie,
+                db,
+                from,
+                msg,
+                MsgRemoteGetAck,
+                inflight_get
+            );
+        }
+        if !msg.keys.iter().all(|k| db.key_vnode(k) == self.state.num) {
+            let _ = fabric_send_error!(db, from, msg, MsgRemoteGetAck, FabricError::WrongVNode);
+            return;
+        }
+        if msg.coordinate.is_some() {
+            return self.do_get_proxied(db, from, msg);
+        }
+        if msg.keys.iter().any(|key| self.state.maybe_stored(key)) {
+            if let Some(sender) = db.storage_sender() {
+                let void = Cube::new(&self.state.clocks);
+                sender.send(StorageMsg::Get(from, self.state.storage.clone(), void, msg));
+                return;
+            }
+        }
+        // a failed (e.g. corrupted) key fails the whole reply,
+        // so the coordinator doesn't take it as a missing value
+        let result = msg
+            .keys
+            .iter()
+            .map(|key| self.state.storage_get(key))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| FabricError::StorageError);
+        let _ = db.fabric.send_msg(
+            from,
+            &MsgRemoteGetAck {
+                cookie: msg.cookie,
+                vnode: msg.vnode,
+                result: result,
+            },
+        );
+    }
+
+    // CLOCK PROBES
+    /// Asks `node` for its clocks of this vnode, no data is transfered and
+    /// no sync state is created on either side. The reply is available
+    /// through `peer_clock` once it arrives.
+    pub fn probe_clock(&mut self, db: &Database, node: NodeId) {
+        let msg = MsgClockProbe {
+            vnode: self.state.num,
+            cookie: self.gen_cookie(),
+        };
+        if let Err(e) = db.fabric.send_msg(node, &msg) {
+            debug!("Can't send clock probe to {}: {:?}", node, e);
+        }
+    }
+
+    /// Latest clocks received from `node` for this vnode and when they were received
+    pub fn peer_clock(&self, node: NodeId) -> Option<&(BitmappedVersionVector, Instant)> {
+        self.peer_clocks.get(&node)
+    }
+
+    pub fn handler_clock_probe(&mut self, db: &Database, from: NodeId, msg: MsgClockProbe) {
+        // bootstraping vnodes have partial clocks
         check_status!(
             self,
             VNodeStatus::Ready | VNodeStatus::Zombie,
             db,
             from,
             msg,
-            MsgRemoteGetAck,
-            inflight_get
+            MsgClockProbeAck,
+            clock_probe
         );
-        let mut result = Vec::with_capacity(msg.keys.len());
-        for key in &msg.keys {
-            let value = self
-                .state
-                .storage_get(&key)
-                .map_err(|_| FabricError::StorageError)
-                .unwrap();
-            result.push(value);
-        }
         let _ = db.fabric.send_msg(
             from,
-            &MsgRemoteGetAck {
-                cookie: msg.cookie,
+            &MsgClockProbeAck {
                 vnode: msg.vnode,
-                result: Ok(result),
+                cookie: msg.cookie,
+                result: Ok(self.state.clocks.clone()),
             },
         );
     }
 
+    pub fn handler_clock_probe_ack(
+        &mut self,
+        _db: &Database,
+        from: NodeId,
+        msg: MsgClockProbeAck,
+    ) {
+        match msg.result {
+            Ok(clocks) => {
+                self.peer_clocks.insert(from, (clocks, Instant::now()));
+            }
+            Err(e) => {
+                debug!("Clock probe {:?} to {} failed: {:?}", msg.cookie, from, e);
+                self.peer_clocks.remove(&from);
+            }
+        }
+    }
+
+    // TOMBSTONE GC
+    /// Deleting a value leaves a version without value (a tombstone) that supersedes
+    /// the deleted versions in the replicas that haven't seen the delete yet.
+    /// Once its dot is in the clocks of every replica all of them applied the delete,
+    /// so the sweep can remove it and the key goes away with the last version.
+    ///
+    /// Saves the state periodically, as a checkpoint for the recovery after an
+    /// unclean shutdown, which only scans the logs above the saved clocks.
+    /// Also after `checkpoint_writes` coordinated writes, whichever comes first.
+    fn checkpoint_tick(&mut self, db: &Database, now: Instant) {
+        if db.config.checkpoint_interval == 0 || now < self.checkpoint_at {
+            return;
+        }
+        self.checkpoint(db, now);
+    }
+
+    fn checkpoint(&mut self, db: &Database, now: Instant) {
+        self.checkpoint_at = now + Duration::from_millis(db.config.checkpoint_interval as _);
+        self.checkpoint_writes = 0;
+        // the saved clocks can't claim writes that could still be lost
+        if let Err(e) = self.state.storage.sync() {
+            warn!("vnode:{:?} failed to sync storage: {:?}", self.state.num(), e);
+            return;
+        }
+        self.state.save(db, false);
+    }
+
+    /// The safe point is the intersection of the local clocks with the latest clocks
+    /// of every other replica (including pending and retiring ones) from clock probes.
+    /// Clocks only grow so an old reply is still a lower bound, while a replica without
+    /// a reply (e.g. bootstrapping or unreachable) holds the collection back.
+    fn tombstone_gc_tick(&mut self, db: &Database, now: Instant) {
+        if db.config.tombstone_gc_interval == 0 || now < self.tombstone_gc_at {
+            return;
+        }
+        self.tombstone_gc_at = now + Duration::from_millis(db.config.tombstone_gc_interval as _);
+        let mut peers = db.dht.nodes_for_vnode(self.state.num, true, true);
+        peers.retain(|&x| x != db.dht.node());
+        let mut safe_clocks = Some(self.state.clocks.clone());
+        for peer in &peers {
+            match (self.peer_clocks.get(peer), safe_clocks.as_mut()) {
+                (Some(&(ref clocks, _)), Some(safe_clocks)) => safe_clocks.intersect(clocks),
+                _ => safe_clocks = None,
+            }
+        }
+        self.state.tombstone_safe_clocks = safe_clocks;
+        // replies are used by the next round
+        for peer in peers {
+            self.probe_clock(db, peer);
+        }
+    }
+
     pub fn handler_set_remote(&mut self, db: &Database, from: NodeId, msg: MsgRemoteSet) {
         check_status!(
             self,
@@ -619,6 +2017,12 @@ impl VNode {
             MsgRemoteSetAck,
             inflight_set
         );
+        if !msg.writes.iter().all(|w| db.key_vnode(&w.0) == self.state.num) {
+            if msg.reply {
+                let _ = fabric_send_error!(db, from, msg, MsgRemoteSetAck, FabricError::WrongVNode);
+            }
+            return;
+        }
         let MsgRemoteSet {
             writes,
             vnode,
@@ -665,14 +2069,16 @@ impl VNode {
         if !(self.state.status == VNodeStatus::Ready
             || (self.state.status == VNodeStatus::Zombie
                 && self.state.last_status_change.elapsed()
-                    < Duration::from_millis(ZOMBIE_TIMEOUT_MS)))
+                    < Duration::from_millis(db.config.zombie_timeout as _)))
         {
             debug!("Can't start sync when {:?}", self.state.status);
             let _ = fabric_send_error!(db, from, msg, MsgSyncFin, FabricError::BadVNodeStatus);
+        } else if self.finished_syncs.contains_key(&msg.cookie) {
+            debug!("Ignoring start for finished sync {:?}", msg.cookie);
         } else if !self.syncs.contains_key(&msg.cookie) {
             if !db.signal_sync_start(SyncDirection::Outgoing) {
-                debug!("Refusing remote sync request, limit exceeded");
-                let _ = fabric_send_error!(db, from, msg, MsgSyncFin, FabricError::NotReady);
+                debug!("Queueing remote sync request, limit exceeded");
+                db.queue_sync_start(from, msg);
                 return;
             }
 
@@ -682,6 +2088,11 @@ impl VNode {
                     info!("Starting bootstrap sender {:?} peer:{}", cookie, from);
                     Synchronization::new_bootstrap_sender(db, &mut self.state, from, msg)
                 }
+                Some(target) if msg.aae => {
+                    assert_eq!(target, db.dht.node());
+                    info!("Starting aae sender {:?} peer:{}", cookie, from);
+                    Synchronization::new_aae_sender(db, &mut self.state, from, msg)
+                }
                 Some(target) => {
                     assert_eq!(target, db.dht.node());
                     info!("Starting sync sender {:?} peer:{}", cookie, from);
@@ -697,17 +2108,28 @@ impl VNode {
         }
     }
 
-    pub fn handler_sync_send(&mut self, db: &Database, from: NodeId, msg: MsgSyncSend) {
-        forward!(
+    pub fn handler_sync_send(&mut self, db: &Database, from: NodeId, msg: MsgSyncSendBatch) {
+        check_status!(
             self,
             VNodeStatus::Ready | VNodeStatus::Bootstrap,
             db,
             from,
             msg,
             MsgSyncFin,
-            syncs,
-            on_msg_send
+            syncs
         );
+        let cookie = msg.cookie;
+        let result = match self.syncs.get_mut(&cookie) {
+            Some(sync) => sync.on_msg_send(db, &mut self.state, msg),
+            None => {
+                let _ = fabric_send_error!(db, from, msg, MsgSyncFin, FabricError::CookieNotFound);
+                return;
+            }
+        };
+        if result != SyncResult::Continue {
+            info!("Removing sync/bootstrap {:?}", cookie);
+            self.remove_sync(db, cookie, result);
+        }
     }
 
     pub fn handler_sync_ack(&mut self, db: &Database, from: NodeId, msg: MsgSyncAck) {
@@ -723,6 +2145,37 @@ impl VNode {
         );
     }
 
+    pub fn handler_aae_tree_request(
+        &mut self,
+        db: &Database,
+        from: NodeId,
+        msg: MsgAAETreeRequest,
+    ) {
+        forward!(
+            self,
+            VNodeStatus::Ready,
+            db,
+            from,
+            msg,
+            MsgSyncFin,
+            syncs,
+            on_msg_aae_tree_request
+        );
+    }
+
+    pub fn handler_aae_tree(&mut self, db: &Database, from: NodeId, msg: MsgAAETree) {
+        forward!(
+            self,
+            VNodeStatus::Ready | VNodeStatus::Zombie,
+            db,
+            from,
+            msg,
+            MsgSyncFin,
+            syncs,
+            on_msg_aae_tree
+        );
+    }
+
     pub fn handler_sync_fin(&mut self, db: &Database, from: NodeId, msg: MsgSyncFin) {
         check_status!(
             self,
@@ -739,7 +2192,11 @@ impl VNode {
             match result {
                 SyncResult::Done | SyncResult::Error => {
                     info!("Removing sync/bootstrap {:?}", cookie);
-                    o.remove().on_remove(db, &mut self.state);
+                    let mut sync = o.remove();
+                    if let Some(aae) = self.aae.as_mut() {
+                        aae.sync_removed(cookie, result, sync.recv_count());
+                    }
+                    sync.on_remove(db, &mut self.state);
                 }
                 SyncResult::Continue => (),
             }
@@ -752,18 +2209,116 @@ impl VNode {
                 let _ = fabric_send_error!(db, from, msg, MsgSyncFin, FabricError::CookieNotFound);
             }
             return;
-        };
-
-        if self.status() == VNodeStatus::Bootstrap {
-            self.handle_bootstrap_result(db, result);
+        };
+
+        if result != SyncResult::Continue {
+            self.remember_finished_sync(db, cookie);
+        }
+        if self.status() == VNodeStatus::Bootstrap {
+            self.bootstrap_stream_done(db, result);
+        }
+    }
+
+    // stops the syncs going in the direction, telling their peers. Like remove_sync but
+    // without the bootstrap accounting, the callers are moving the vnode away.
+    // Each sync is unregistered before anything else, so the handlers of msgs
+    // that arrive later can't find it and on_remove runs exactly once.
+    fn cancel_syncs(&mut self, db: &Database, direction: SyncDirection) {
+        let canceled: Vec<Cookie> = self
+            .syncs
+            .iter()
+            .filter(|&(_, s)| s.direction() == direction)
+            .map(|(&cookie, _)| cookie)
+            .collect();
+        for cookie in canceled {
+            info!("Canceling sync/bootstrap {:?}", cookie);
+            let mut sync = self.syncs.remove(&cookie).unwrap();
+            sync.on_cancel(db, &mut self.state);
+            if let Some(aae) = self.aae.as_mut() {
+                aae.sync_removed(cookie, SyncResult::Error, sync.recv_count());
+            }
+            sync.on_remove(db, &mut self.state);
+            self.remember_finished_sync(db, cookie);
+        }
+    }
+
+    // cleans up a sync that terminated other than by receiving its fin
+    fn remove_sync(&mut self, db: &Database, cookie: Cookie, result: SyncResult) {
+        let mut sync = self.syncs.remove(&cookie).unwrap();
+        if let Some(aae) = self.aae.as_mut() {
+            aae.sync_removed(cookie, result, sync.recv_count());
+        }
+        sync.on_remove(db, &mut self.state);
+        self.remember_finished_sync(db, cookie);
+        if self.status() == VNodeStatus::Bootstrap {
+            self.bootstrap_stream_done(db, result);
+        }
+    }
+
+    fn remember_finished_sync(&mut self, db: &Database, cookie: Cookie) {
+        let sync_timeout = Duration::from_millis(db.config.effective_sync_timeout() as _);
+        let expire = Instant::now() + sync_timeout;
+        if !self.finished_syncs.contains_key(&cookie) {
+            self.finished_syncs
+                .insert_bounded(cookie, (), expire, FINISHED_SYNCS_MAX);
+        }
+    }
+
+    // the bootstrap finishes with the last of its streams
+    fn bootstrap_stream_done(&mut self, db: &Database, result: SyncResult) {
+        match result {
+            SyncResult::Continue => return,
+            SyncResult::Error => self.state.bootstrap_stream_failed = true,
+            SyncResult::Done => (),
+        }
+        if self
+            .syncs
+            .values()
+            .any(|s| match *s {
+                Synchronization::BootstrapReceiver { .. } => true,
+                _ => false,
+            }) {
+            return;
+        }
+        if self.state.bootstrap_stream_failed {
+            self.state.bootstrap_stream_failed = false;
+            self.state.bootstrap_clocks = None;
+            self.handle_bootstrap_result(db, SyncResult::Error);
+        } else {
+            if let Some(bootstrap_clocks) = self.state.bootstrap_clocks.take() {
+                self.state.clocks.merge(&bootstrap_clocks);
+                self.state.save(db, false);
+            }
+            self.handle_bootstrap_result(db, SyncResult::Done);
         }
     }
 
     fn handle_bootstrap_result(&mut self, db: &Database, result: SyncResult) {
         match result {
             SyncResult::Error => {
-                info!("Retrying bootstrap");
-                self.start_bootstrap(db);
+                let state = &mut self.state;
+                state.bootstrap_failures += 1;
+                let backoff = bootstrap_backoff(
+                    state.bootstrap_failures,
+                    db.config.bootstrap_retry_backoff,
+                    db.config.bootstrap_retry_backoff_max,
+                );
+                if !state.bootstrap_failed
+                    && state.bootstrap_failures >= db.config.bootstrap_failures_max
+                {
+                    error!(
+                        "vnode {} bootstrap failed {} times, retrying every {:?}",
+                        state.num, state.bootstrap_failures, backoff
+                    );
+                    state.bootstrap_failed = true;
+                    metrics::BOOTSTRAP_FAILED.inc();
+                } else {
+                    info!("Retrying bootstrap in {:?}", backoff);
+                }
+                // the tick will pick it up
+                state.bootstrap_retry_at = Some(Instant::now() + backoff);
+                state.pending_bootstrap = true;
+                state.bootstrap_resume = true;
             }
             SyncResult::Done => {
                 match db.dht.promote_pending_node(db.dht.node(), self.state.num()) {
@@ -796,37 +2351,71 @@ impl VNode {
         assert_eq!(self.state.status, VNodeStatus::Bootstrap);
         assert_eq!(self.syncs.len(), 0);
         self.state.pending_bootstrap = false;
-        let cookie = self.gen_cookie();
         let mut nodes = db.dht.nodes_for_vnode(self.state.num, false, true);
         let connected_nodes = db.fabric.connections();
-        nodes.retain(|x| connected_nodes.contains(x));
-        if nodes.is_empty() || nodes == &[db.dht.node()] {
+        nodes.retain(|&x| x != db.dht.node() && connected_nodes.contains(&x));
+        if nodes.is_empty() {
             // nothing to boostrap from
             self.handle_bootstrap_result(db, SyncResult::Done);
             return;
         }
 
+        // stream from as many sources as the incoming sync slots allow
         thread_rng().shuffle(&mut nodes);
-        for node in nodes {
-            if node == db.dht.node() {
-                continue;
-            }
-            if !db.signal_sync_start(SyncDirection::Incomming) {
-                debug!("Bootstrap not allowed to start, go pending");
-                self.state.pending_bootstrap = true;
-                return;
-            }
-            info!("starting bootstrap receiver {:?} peer:{}", cookie, node);
-            let sync = Synchronization::new_bootstrap_receiver(db, &mut self.state, node, cookie);
+        self.state.stalled_peer_last(&mut nodes);
+        let mut streams = 0;
+        while streams < nodes.len() && db.signal_sync_start(SyncDirection::Incomming) {
+            streams += 1;
+        }
+        if streams == 0 {
+            debug!("Bootstrap not allowed to start, go pending");
+            self.state.pending_bootstrap = true;
+            return;
+        }
+
+        self.state.bootstrap_clocks = None;
+        self.state.bootstrap_stream_failed = false;
+        for (i, &node) in nodes[..streams].iter().enumerate() {
+            let cookie = self.gen_cookie();
+            let slice = (i as u16, streams as u16);
+            info!(
+                "starting bootstrap receiver {:?} peer:{} slice:{:?}",
+                cookie, node, slice
+            );
+            let sync =
+                Synchronization::new_bootstrap_receiver(db, &mut self.state, node, cookie, slice);
             match self.syncs.entry(cookie) {
                 HMEntry::Vacant(v) => {
                     v.insert(sync).on_start(db, &mut self.state);
                 }
                 HMEntry::Occupied(_) => unreachable!(),
             }
-            return;
         }
-        unreachable!();
+    }
+
+    // whether bootstrapping this vnode failed repeatedly
+    pub fn bootstrap_failed(&self) -> bool {
+        self.state.bootstrap_failed
+    }
+
+    #[cfg(test)]
+    pub fn _bootstrap_failures(&self) -> (u32, bool, Option<Instant>) {
+        (
+            self.state.bootstrap_failures,
+            self.state.bootstrap_failed,
+            self.state.bootstrap_retry_at,
+        )
+    }
+
+    #[cfg(test)]
+    pub fn _handle_bootstrap_result(&mut self, db: &Database, result: SyncResult) {
+        assert_any!(self.state.status, VNodeStatus::Bootstrap);
+        self.handle_bootstrap_result(db, result)
+    }
+
+    #[cfg(test)]
+    pub fn _set_status(&mut self, db: &Database, status: VNodeStatus) {
+        self.state.set_status(db, status)
     }
 
     pub fn start_sync_if_ready(&mut self, db: &Database) -> bool {
@@ -836,6 +2425,28 @@ impl VNode {
         }
     }
 
+    #[cfg(test)]
+    pub fn _sync_nodes(&self) -> usize {
+        self.state.sync_nodes.len()
+    }
+
+    #[cfg(test)]
+    pub fn _stalled_peer(&self) -> Option<NodeId> {
+        self.state.stalled_peer
+    }
+
+    #[cfg(test)]
+    pub fn _start_sync_receiver(&mut self, db: &Database, node: NodeId) -> Cookie {
+        assert!(db.signal_sync_start(SyncDirection::Incomming));
+        self.start_sync_receiver(db, node, false)
+    }
+
+    #[cfg(test)]
+    pub fn _start_aae_receiver(&mut self, db: &Database, node: NodeId) -> Cookie {
+        assert!(db.signal_sync_start(SyncDirection::Incomming));
+        self.start_sync_receiver(db, node, true)
+    }
+
     #[cfg(test)]
     pub fn _start_sync(&mut self, db: &Database) -> bool {
         assert_any!(self.state.status, VNodeStatus::Ready);
@@ -844,10 +2455,15 @@ impl VNode {
 
     fn do_start_sync(&mut self, db: &Database) -> bool {
         trace!("do_start_sync vn:{}", self.state.num);
+        if self.state.drain_until.is_some() {
+            // moving away, it'd be canceled before finishing
+            return false;
+        }
         let mut nodes = db.dht.nodes_for_vnode(self.state.num, false, true);
         let connected_nodes = db.fabric.connections();
         nodes.retain(|x| connected_nodes.contains(x));
         thread_rng().shuffle(&mut nodes);
+        self.state.stalled_peer_last(&mut nodes);
         for node in nodes {
             if node == db.dht.node() {
                 continue;
@@ -857,20 +2473,124 @@ impl VNode {
                 continue;
             }
 
-            let cookie = self.gen_cookie();
-            self.state.sync_nodes.insert(node);
-            info!("Starting sync receiver {:?} peer:{}", cookie, node);
-            let sync = Synchronization::new_sync_receiver(db, &mut self.state, node, cookie);
-            match self.syncs.entry(cookie) {
-                HMEntry::Vacant(v) => {
-                    v.insert(sync).on_start(db, &mut self.state);
-                }
-                HMEntry::Occupied(_) => unreachable!(),
-            }
+            self.start_sync_receiver(db, node, false);
             return true;
         }
         false
     }
+
+    /// Starts a sync with `peer` right away instead of waiting for the tick to pick it,
+    /// returns the cookie of the sync, which shows up in the vnode `sync_progress`.
+    pub fn trigger_sync(&mut self, db: &Database, peer: NodeId) -> Result<Cookie, GenericError> {
+        if self.status() != VNodeStatus::Ready || self.state.drain_until.is_some() {
+            return Err(format!("vnode {} isn't ready to sync", self.state.num).into());
+        }
+        let peers = db.dht.nodes_for_vnode(self.state.num, false, true);
+        if peer == db.dht.node() || !peers.contains(&peer) {
+            return Err(format!("{} is not a peer of vnode {}", peer, self.state.num).into());
+        }
+        if !db.fabric.connections().contains(&peer) {
+            return Err(format!("{} isn't connected", peer).into());
+        }
+        if self.state.sync_nodes.contains(&peer) {
+            return Err(format!("vnode {} is already syncing with {}", self.state.num, peer).into());
+        }
+        if !db.signal_sync_start(SyncDirection::Incomming) {
+            return Err("Too many syncs inflight".into());
+        }
+        Ok(self.start_sync_receiver(db, peer, false))
+    }
+
+    // aae receivers compare hash trees with the peer before receiving any key
+    fn start_sync_receiver(&mut self, db: &Database, node: NodeId, aae: bool) -> Cookie {
+        let cookie = self.gen_cookie();
+        self.state.sync_nodes.insert(node);
+        let sync = if aae {
+            info!("Starting aae receiver {:?} peer:{}", cookie, node);
+            Synchronization::new_aae_receiver(db, &mut self.state, node, cookie)
+        } else {
+            info!("Starting sync receiver {:?} peer:{}", cookie, node);
+            Synchronization::new_sync_receiver(db, &mut self.state, node, cookie)
+        };
+        match self.syncs.entry(cookie) {
+            HMEntry::Vacant(v) => {
+                v.insert(sync).on_start(db, &mut self.state);
+            }
+            HMEntry::Occupied(_) => unreachable!(),
+        }
+        cookie
+    }
+
+    // ON DEMAND ANTI-ENTROPY
+    /// Schedules a sync with every other replica of this vnode as part of `job`,
+    /// replacing any previous job. Returns false if the vnode isn't Ready.
+    pub fn aae_start(&mut self, db: &Database, job: u64) -> bool {
+        if self.status() != VNodeStatus::Ready {
+            return false;
+        }
+        let mut peers = db.dht.nodes_for_vnode(self.state.num, false, true);
+        peers.retain(|&x| x != db.dht.node());
+        debug!("vnode:{} aae job {} peers {:?}", self.state.num, job, peers);
+        self.aae = Some(AaeState {
+            job: job,
+            pending: peers,
+            running: None,
+            synced: 0,
+            failed: 0,
+            received: 0,
+        });
+        true
+    }
+
+    pub fn aae_progress(&self, job: u64) -> Option<AaeProgress> {
+        self.aae.as_ref().filter(|aae| aae.job == job).map(|aae| AaeProgress {
+            done: aae.running.is_none() && aae.pending.is_empty(),
+            running: aae.running.is_some(),
+            synced: aae.synced,
+            failed: aae.failed,
+            received: aae.received,
+        })
+    }
+
+    // starts the next sync of the aae job, if any and the incomming sync limit allows it
+    fn aae_tick(&mut self, db: &Database) {
+        if let Some(ref mut aae) = self.aae {
+            // the running sync may be canceled without going through the sync handlers
+            if aae.running.map_or(false, |c| !self.syncs.contains_key(&c)) {
+                aae.running = None;
+                aae.failed += 1;
+            }
+        }
+        let node = match self.aae {
+            Some(ref aae) if aae.running.is_none() && !aae.pending.is_empty() => {
+                aae.pending[aae.pending.len() - 1]
+            }
+            _ => return,
+        };
+        if self.status() != VNodeStatus::Ready || self.state.drain_until.is_some() {
+            // the vnode moved away, give up on the remaining peers
+            let aae = self.aae.as_mut().unwrap();
+            aae.failed += aae.pending.len() as u32;
+            aae.pending.clear();
+            return;
+        }
+        if !db.fabric.connections().contains(&node) {
+            debug!("vnode:{} aae peer {} isn't connected", self.state.num, node);
+            let aae = self.aae.as_mut().unwrap();
+            aae.pending.pop();
+            aae.failed += 1;
+            return;
+        }
+        if self.state.sync_nodes.contains(&node) || !db.signal_sync_start(SyncDirection::Incomming)
+        {
+            // try again next tick
+            return;
+        }
+        let cookie = self.start_sync_receiver(db, node, true);
+        let aae = self.aae.as_mut().unwrap();
+        aae.pending.pop();
+        aae.running = Some(cookie);
+    }
 }
 
 impl Drop for VNode {
@@ -879,6 +2599,7 @@ impl Drop for VNode {
         // clean up any references to the storage
         self.requests.clear();
         self.syncs.clear();
+        self.state.reset_bootstrap_failures();
     }
 }
 
@@ -891,9 +2612,173 @@ impl VNodeState {
         self.status
     }
 
+    // stored keys, including the ones only holding tombstones
+    pub fn keys(&self) -> u64 {
+        self.key_stats.keys
+    }
+
     pub fn clear(&mut self) {
         self.clocks.clear();
         self.storage.clear();
+        if let Some(ref mut bloom) = self.bloom {
+            bloom.clear();
+        }
+        if let Some(ref hot_keys) = self.hot_keys {
+            hot_keys.borrow_mut().clear();
+        }
+        self.merkle = None;
+        self.key_stats = Default::default();
+        self.expire_sweep_cursor = None;
+        self.tombstone_safe_clocks = None;
+    }
+
+    fn reset_bootstrap_failures(&mut self) {
+        if self.bootstrap_failed {
+            metrics::BOOTSTRAP_FAILED.dec();
+        }
+        self.bootstrap_failures = 0;
+        self.bootstrap_retry_at = None;
+        self.bootstrap_failed = false;
+    }
+
+    fn new_bloom(db: &Database) -> Option<BloomFilter> {
+        if db.config.bloom_filter_keys != 0 {
+            Some(BloomFilter::new(
+                db.config.bloom_filter_keys,
+                db.config.bloom_filter_fp_rate,
+            ))
+        } else {
+            None
+        }
+    }
+
+    fn new_hot_keys(db: &Database) -> Option<RefCell<HotKeys>> {
+        if db.config.hot_keys_sample_rate != 0 {
+            Some(RefCell::new(HotKeys::new(
+                db.config.hot_keys_sample_rate,
+                db.config.hot_keys_max as usize,
+            )))
+        } else {
+            None
+        }
+    }
+
+    // estimated most accessed keys, hottest first
+    pub fn hot_keys(&self) -> Vec<(Bytes, u64)> {
+        self.hot_keys
+            .as_ref()
+            .map_or_else(Vec::new, |hot_keys| hot_keys.borrow().top())
+    }
+
+    fn record_access(&self, key: &[u8]) {
+        if let Some(ref hot_keys) = self.hot_keys {
+            hot_keys.borrow_mut().record(key);
+        }
+    }
+
+    // fills the bloom filter and recounts the key stats if asked to, in a single pass
+    fn scan_storage(&mut self, count_keys: bool) {
+        if self.bloom.is_none() && !count_keys {
+            return;
+        }
+        let mut key_stats = KeyStats::default();
+        {
+            let mut iterator = self.storage.iterator();
+            for (k, v) in iterator.iter() {
+                if let Some(ref mut bloom) = self.bloom {
+                    bloom.insert(k);
+                }
+                key_stats.replace(0, v.len() as u64);
+            }
+        }
+        if count_keys {
+            info!("Counted {:?} for vnode {}", key_stats, self.num);
+            self.key_stats = key_stats;
+        }
+    }
+
+    // Moves the keys that belong to the child vnode after a split, along with their log
+    // entries, in batches. Returns how many were moved.
+    fn split_keys(&mut self, db: &Database, child: &mut VNodeState) -> Result<u64, CommandError> {
+        // the vnodes are split in levels, each doubles the count so far (see DHT::split)
+        let partitions = child.num as usize - self.num as usize;
+        let format = self.storage.format();
+        let mut cursor: Option<Bytes> = None;
+        let mut moved_count = 0;
+        loop {
+            let mut moved = Vec::new();
+            let next_cursor = {
+                let mut iterator = match cursor {
+                    Some(ref cursor) => self.storage.iterator_from(cursor),
+                    None => self.storage.iterator(),
+                };
+                let mut next_cursor = None;
+                for (i, (k, v)) in iterator.iter().enumerate() {
+                    if i == SPLIT_BATCH_KEYS {
+                        next_cursor = Some(Bytes::from(k));
+                        break;
+                    }
+                    if db.dht.key_vnode(k) as usize % (partitions * 2) == child.num as usize {
+                        moved.push((Bytes::from(k), v.to_vec()));
+                    }
+                }
+                next_cursor
+            };
+
+            if !moved.is_empty() {
+                let mut batch = self.storage.batch_new(0);
+                let mut child_batch = child.storage.batch_new(0);
+                for &(ref key, ref value) in &moved {
+                    let cube = format
+                        .deserialize_value::<Cube>(value)
+                        .map_err(|_| CommandError::StorageError)?;
+                    cube.for_each_dot(|i, v| child_batch.log_set((i, v), key));
+                    child_batch.set(key, value);
+                    batch.del(key);
+                }
+                // the child first, if it fails the keys are still here
+                child
+                    .storage
+                    .batch_write(child_batch)
+                    .map_err(|_| CommandError::StorageError)?;
+                self.storage
+                    .batch_write(batch)
+                    .map_err(|_| CommandError::StorageError)?;
+                for &(_, ref value) in &moved {
+                    self.key_stats.replace(value.len() as u64, 0);
+                }
+                moved_count += moved.len() as u64;
+            }
+
+            cursor = next_cursor;
+            if cursor.is_none() {
+                break;
+            }
+        }
+        self.merkle = None;
+
+        // the child state comes from all its keys, including the ones moved before a restart
+        {
+            let mut iterator = child.storage.iterator();
+            for (k, v) in iterator.iter() {
+                let cube = format
+                    .deserialize_value::<Cube>(v)
+                    .map_err(|_| CommandError::StorageError)?;
+                let log_clocks = &mut child.log_clocks;
+                cube.for_each_dot(|i, v| {
+                    log_clocks.add(i, v);
+                });
+                if let Some(ref mut bloom) = child.bloom {
+                    bloom.insert(k);
+                }
+                child.key_stats.replace(0, v.len() as u64);
+            }
+        }
+        // the causal context covers the moved keys as well,
+        // the dots of the keys that stayed don't matter to the child
+        child.clocks = self.clocks.clone();
+        child.clocks.merge(&child.log_clocks);
+        Ok(moved_count)
     }
 
     fn generate_id(base: NodeId) -> NodeId {
@@ -923,38 +2808,85 @@ impl VNodeState {
         }
 
         self.last_status_change = Instant::now();
+        self.drain_until = None;
         self.pending_bootstrap = false;
+        self.bootstrap_resume = false;
+        self.bootstrap_clocks = None;
+        self.bootstrap_stream_failed = false;
+        self.reset_bootstrap_failures();
         self.status = new;
         // not important in all cases but nice to do
         self.save(db, false);
     }
 
+    // moves the stalled peer to the end of the candidates, if it's one of them
+    fn stalled_peer_last(&self, nodes: &mut Vec<NodeId>) {
+        if let Some(i) = nodes.iter().position(|&n| Some(n) == self.stalled_peer) {
+            let node = nodes.remove(i);
+            nodes.push(node);
+        }
+    }
+
     fn new_empty(num: u16, db: &Database, status: VNodeStatus) -> Self {
         db.meta_storage
             .del(num.to_string().as_bytes())
             .expect("Can't del vnode state");
         let storage = db.storage_manager.open(num).expect("Can't open storage");
         storage.clear();
+        Self::with_storage(num, db, status, storage)
+    }
 
+    // a new state over the storage, as is
+    fn with_storage(num: u16, db: &Database, status: VNodeStatus, storage: Storage) -> Self {
         VNodeState {
             id: Self::generate_id(db.dht.node()),
             num: num,
             status: status,
             last_status_change: Instant::now(),
+            drain_until: None,
             clocks: Default::default(),
             log_clocks: Default::default(),
             storage: storage,
+            bloom: Self::new_bloom(db),
+            hot_keys: Self::new_hot_keys(db),
+            merkle: None,
+            key_stats: Default::default(),
+            value_version_max: db.config.value_version_max.max(1) as usize,
+            expire_sweep_keys: db.config.expire_sweep_keys as usize,
+            expire_sweep_cursor: None,
+            tombstone_safe_clocks: None,
             pending_bootstrap: false,
             sync_nodes: Default::default(),
+            stalled_peer: None,
+            bootstrap_failures: 0,
+            bootstrap_retry_at: None,
+            bootstrap_failed: false,
+            bootstrap_resume: false,
+            bootstrap_clocks: None,
+            bootstrap_stream_failed: false,
         }
     }
 
     fn load(num: u16, db: &Database, status: VNodeStatus) -> Self {
         info!("Loading vnode {} state", num);
+        let format = db.meta_storage.format();
         let saved_state_opt = db
             .meta_storage
             .get(num.to_string().as_bytes(), |bytes| {
-                bincode::deserialize(bytes).expect("Can't deserialize vnode state")
+                format
+                    .deserialize::<SavedVNodeState>(bytes)
+                    .or_else(|e| {
+                        format
+                            .deserialize::<SavedVNodeStateV0>(bytes)
+                            .map(|v0| SavedVNodeState {
+                                id: v0.id,
+                                clocks: v0.clocks,
+                                log_clocks: v0.log_clocks,
+                                clean_shutdown: v0.clean_shutdown,
+                                key_stats: None,
+                            })
+                            .map_err(|_| e)
+                    })
             })
             .expect("Can't read saved vnode state");
 
@@ -962,14 +2894,23 @@ impl VNodeState {
             info!("No saved state");
             return Self::new_empty(num, db, status);
         };
+        let saved_state = match saved_state_opt.unwrap() {
+            Ok(saved_state) => saved_state,
+            Err(e) => {
+                error!("Saved state of vnode {} is corrupted: {}", num, e);
+                return Self::recover_from_keys(num, db, status);
+            }
+        };
 
-        assert_eq!(status, VNodeStatus::Ready);
+        // a saved state while bootstrapping means the node restarted midway
+        assert!(status == VNodeStatus::Ready || status == VNodeStatus::Bootstrap);
         let SavedVNodeState {
             mut id,
             clocks,
             log_clocks,
             clean_shutdown,
-        } = saved_state_opt.unwrap();
+            key_stats,
+        } = saved_state;
 
         let storage = db.storage_manager.open(num).expect("Can't open storage");
 
@@ -982,27 +2923,99 @@ impl VNodeState {
             num: num,
             status: status,
             last_status_change: Instant::now(),
+            drain_until: None,
             clocks: clocks,
             log_clocks: log_clocks,
             storage: storage,
+            bloom: Self::new_bloom(db),
+            hot_keys: Self::new_hot_keys(db),
+            merkle: None,
+            key_stats: Default::default(),
+            value_version_max: db.config.value_version_max.max(1) as usize,
+            expire_sweep_keys: db.config.expire_sweep_keys as usize,
+            expire_sweep_cursor: None,
+            tombstone_safe_clocks: None,
             sync_nodes: Default::default(),
+            stalled_peer: None,
             pending_bootstrap: false,
+            bootstrap_failures: 0,
+            bootstrap_retry_at: None,
+            bootstrap_failed: false,
+            bootstrap_resume: status == VNodeStatus::Bootstrap,
+            bootstrap_clocks: None,
+            bootstrap_stream_failed: false,
         };
 
+        // the stats of a checkpoint are outdated, a clean shutdown saves the final ones
+        let count_keys = match key_stats {
+            Some(key_stats) if clean_shutdown => {
+                state.key_stats = key_stats;
+                false
+            }
+            _ => true,
+        };
         if !clean_shutdown {
             info!("Unclean shutdown, recovering from the storage");
             state.recover_dots();
         }
+        state.scan_storage(count_keys);
+        state
+    }
+
+    // Without a saved state the clocks are rebuilt from the dots of the stored keys,
+    // under a new id. The dots of the keys deleted since are lost, the peers resend them.
+    // Corrupted values are skipped, reads report them so they're repaired from the replicas.
+    fn recover_from_keys(num: u16, db: &Database, status: VNodeStatus) -> Self {
+        info!("Recovering vnode {} from its keys", num);
+        let storage = db.storage_manager.open(num).expect("Can't open storage");
+        let mut state = Self::with_storage(num, db, status, storage);
+        let format = state.storage.format();
+        let mut corrupted = 0;
+        {
+            let mut iterator = state.storage.iterator();
+            for (k, v) in iterator.iter() {
+                if let Some(ref mut bloom) = state.bloom {
+                    bloom.insert(k);
+                }
+                state.key_stats.replace(0, v.len() as u64);
+                match format.deserialize_value::<Cube>(v) {
+                    Ok(cube) => {
+                        let log_clocks = &mut state.log_clocks;
+                        cube.for_each_dot(|i, v| {
+                            log_clocks.add(i, v);
+                        });
+                    }
+                    Err(e) => {
+                        warn!(
+                            "Skipping the corrupted value of {:?} in vnode {}: {}",
+                            String::from_utf8_lossy(k),
+                            num,
+                            e
+                        );
+                        corrupted += 1;
+                    }
+                }
+            }
+        }
+        state.clocks = state.log_clocks.clone();
+        info!(
+            "Recovered {:?} for vnode {}, {} corrupted",
+            state.key_stats, num, corrupted
+        );
         state
     }
 
+    // only the log entries above the saved clocks (the last checkpoint) are scanned
     fn recover_dots(&mut self) {
+        let mut recovered = 0;
         for (&node, bv) in self.clocks.iter_mut() {
             let mut iterator = self.storage.log_iterator(node, bv.base() + 1);
             for ((_, dot), _) in iterator.iter() {
                 bv.add(dot);
+                recovered += 1;
             }
         }
+        info!("Recovered {} dots for vnode {}", recovered, self.num);
     }
 
     pub fn save(&self, db: &Database, shutdown: bool) {
@@ -1011,46 +3024,244 @@ impl VNodeState {
             clocks: self.clocks.clone(),
             log_clocks: self.log_clocks.clone(),
             clean_shutdown: shutdown,
+            key_stats: Some(self.key_stats),
         };
         debug!("Saving state for vnode {:?} {:?}", self.num, saved_state);
-        let serialized_saved_state =
-            bincode::serialize(&saved_state).expect("Can't serialize vnode state");
+        let serialized_saved_state = db
+            .meta_storage
+            .format()
+            .serialize(&saved_state)
+            .expect("Can't serialize vnode state");
         db.meta_storage
             .set(self.num.to_string().as_bytes(), &serialized_saved_state)
             .expect("Can't save vnode state");
     }
 
     // STORAGE
-    pub fn storage_get(&self, key: &[u8]) -> Result<Cube, ()> {
-        let result = self.storage.get(key, |v| bincode::deserialize::<Cube>(v));
-        match result {
-            Ok(Some(Ok(cube))) => Ok(cube),
-            Ok(Some(Err(_de))) => Err(()),
-            Ok(None) => Ok(Cube::new(&self.clocks)),
-            Err(_se) => Err(()),
+    // expired versions are removed from the result, see sweep_keys
+    pub fn storage_get(&self, key: &[u8]) -> Result<Cube, CommandError> {
+        self.record_access(key);
+        let mut cube = self.storage_get_stored(key)?;
+        cube.expire(now_millis());
+        Ok(cube)
+    }
+
+    // the cube as stored, which the merkle tree digests are based on
+    fn storage_get_stored(&self, key: &[u8]) -> Result<Cube, CommandError> {
+        self.storage_get_stored_sized(key).map(|(cube, _)| cube)
+    }
+
+    // like storage_get_stored but also returns the stored size, 0 if the key isn't stored
+    fn storage_get_stored_sized(&self, key: &[u8]) -> Result<(Cube, u64), CommandError> {
+        if !self.maybe_stored(key) {
+            return Ok((Cube::new(&self.clocks), 0));
+        }
+        Ok(storage_read_sized(&self.storage, key)?
+            .unwrap_or_else(|| (Cube::new(&self.clocks), 0)))
+    }
+
+    // size of the stored value of a key, 0 if it isn't stored
+    fn storage_size(&self, key: &[u8]) -> Result<u64, CommandError> {
+        if !self.maybe_stored(key) {
+            return Ok(0);
+        }
+        self.storage
+            .get(key, |v| v.len() as u64)
+            .map(|size| size.unwrap_or(0))
+            .map_err(|_| CommandError::StorageError)
+    }
+
+    // false if the key is definitely not in the storage
+    fn maybe_stored(&self, key: &[u8]) -> bool {
+        self.bloom.as_ref().map_or(true, |bloom| bloom.contains(key))
+    }
+
+    /// Overwrites the corrupted value of a key with the one read from the other replicas
+    pub fn storage_heal(&mut self, key: &[u8], cube: Cube) -> Result<(), CommandError> {
+        let old_size = self.storage_size(key)?;
+        let new_size;
+        let mut batch = self.storage.batch_new(0);
+        {
+            let clocks = &mut self.clocks;
+            let log_clocks = &mut self.log_clocks;
+            cube.for_each_dot(|i, v| {
+                if log_clocks.add(i, v) {
+                    clocks.add(i, v);
+                    batch.log_set((i, v), key);
+                }
+            });
+        }
+        if cube.is_subsumed(&self.clocks) {
+            batch.del(key);
+            new_size = 0;
+        } else {
+            let serialized = self
+                .storage
+                .format()
+                .serialize_value(&cube)
+                .expect("Can't serialize Cube");
+            batch.set(key, &serialized);
+            new_size = serialized.len() as u64;
+        }
+        self.storage
+            .batch_write(batch)
+            .map_err(|_| CommandError::StorageError)?;
+        self.key_stats.replace(old_size, new_size);
+        // the digest of the corrupted value is unknown, rebuild the tree when needed
+        self.merkle = None;
+        info!("vnode:{:?} healed {:?}", self.num(), String::from_utf8_lossy(key));
+        Ok(())
+    }
+
+    // accounts for the keys evicted from a bounded memory storage, see max_memory
+    fn take_evicted(&mut self) {
+        let (keys, value_bytes) = self.storage.take_evicted();
+        if keys != 0 {
+            self.key_stats.keys = self.key_stats.keys.saturating_sub(keys);
+            self.key_stats.value_bytes = self.key_stats.value_bytes.saturating_sub(value_bytes);
+            // the digests of the evicted keys are unknown, rebuild the tree when needed
+            self.merkle = None;
+        }
+    }
+
+    /// Removes the expired versions and the collectable tombstones (see `tombstone_gc_tick`)
+    /// of up to `expire_sweep_keys` keys, continuing from where the previous call stopped.
+    pub fn sweep_keys(&mut self) -> Result<(), CommandError> {
+        if self.expire_sweep_keys == 0 {
+            return Ok(());
+        }
+        let now = now_millis();
+        let format = self.storage.format();
+        let mut expired = Vec::new();
+        let next_cursor = {
+            let mut iterator = match self.expire_sweep_cursor {
+                Some(ref cursor) => self.storage.iterator_from(cursor),
+                None => self.storage.iterator(),
+            };
+            let mut next_cursor = None;
+            for (i, (k, v)) in iterator.iter().enumerate() {
+                if i == self.expire_sweep_keys {
+                    next_cursor = Some(Bytes::from(k));
+                    break;
+                }
+                let mut cube = format
+                    .deserialize_value::<Cube>(v)
+                    .map_err(|_| CommandError::StorageError)?;
+                let old_digest = MerkleTree::digest(k, &cube);
+                let mut changed = cube.expire(now);
+                if let Some(ref safe_clocks) = self.tombstone_safe_clocks {
+                    changed |= cube.discard_tombstones(safe_clocks);
+                }
+                if changed {
+                    expired.push((Bytes::from(k), cube, old_digest, v.len() as u64));
+                }
+            }
+            next_cursor
+        };
+        self.expire_sweep_cursor = next_cursor;
+        if expired.is_empty() {
+            return Ok(());
+        }
+
+        debug!("vnode:{:?} sweeping {} keys", self.num, expired.len());
+        let mut batch = self.storage.batch_new(0);
+        let mut merkle_updates = Vec::with_capacity(expired.len());
+        let mut size_updates = Vec::with_capacity(expired.len());
+        for (key, cube, old_digest, old_size) in expired {
+            // no new dots, the expired ones remain in the causal context
+            let subsumed = cube.is_subsumed(&self.clocks);
+            if subsumed {
+                batch.del(&key);
+                size_updates.push((old_size, 0));
+            } else {
+                let bytes = format.serialize_value(&cube).expect("Can't serialize Cube");
+                batch.set(&key, &bytes);
+                size_updates.push((old_size, bytes.len() as u64));
+            }
+            let new_digest = if subsumed { 0 } else { MerkleTree::digest(&key, &cube) };
+            merkle_updates.push((key, old_digest, new_digest));
+        }
+        self.storage
+            .batch_write(batch)
+            .map_err(|_| CommandError::StorageError)?;
+        for (old_size, new_size) in size_updates {
+            self.key_stats.replace(old_size, new_size);
+        }
+        if let Some(ref mut merkle) = self.merkle {
+            for (key, old, new) in merkle_updates {
+                merkle.update(&key, old, new);
+            }
+        }
+        Ok(())
+    }
+
+    /// The hash tree of the stored keys, built from the storage if needed
+    pub fn merkle_tree(&mut self) -> Result<&MerkleTree, ()> {
+        if self.merkle.is_none() {
+            debug!("Building merkle tree for vnode {}", self.num);
+            let mut tree = MerkleTree::new();
+            let format = self.storage.format();
+            let mut iterator = self.storage.iterator();
+            for (k, v) in iterator.iter() {
+                let cube = format.deserialize_value::<Cube>(v).map_err(|_| ())?;
+                tree.update(k, 0, MerkleTree::digest(k, &cube));
+            }
+            self.merkle = Some(tree);
         }
+        Ok(self.merkle.as_ref().unwrap())
     }
 
-    pub fn storage_set_local<'a, I: Iterator<Item = (Version, &'a [u8], &'a Cube)>>(
+    pub fn storage_set_local<'a, I: Iterator<Item = (Version, &'a [u8], &'a mut Cube)>>(
         &mut self,
         _db: &Database,
         writes: I,
+        sync: bool,
     ) -> Result<(), CommandError> {
         let mut batch = self.storage.batch_new(0);
+        let mut merkle_updates = Vec::new();
+        let mut size_updates = Vec::new();
         for (version, key, cube) in writes {
+            self.record_access(key);
+            cube.discard_oldest_versions(self.value_version_max);
+            let subsumed = cube.is_subsumed(&self.clocks);
+            let old_size = if self.merkle.is_some() {
+                let (old, old_size) = self.storage_get_stored_sized(key)?;
+                let new = if subsumed { 0 } else { MerkleTree::digest(key, cube) };
+                merkle_updates.push((key, MerkleTree::digest(key, &old), new));
+                old_size
+            } else {
+                self.storage_size(key)?
+            };
             // TODO: integrate is_subsumed logic into the result of merge and MutatorFn
-            if cube.is_subsumed(&self.clocks) {
+            if subsumed {
                 batch.del(key);
+                size_updates.push((old_size, 0));
             } else {
-                let bytes = bincode::serialize(cube).expect("Can't serialize Cube");
+                let bytes = self
+                    .storage
+                    .format()
+                    .serialize_value(&*cube)
+                    .expect("Can't serialize Cube");
                 batch.set(key, &bytes);
+                size_updates.push((old_size, bytes.len() as u64));
+                if let Some(ref mut bloom) = self.bloom {
+                    bloom.insert(key);
+                }
             }
 
             batch.log_set((self.id, version), key);
         }
         self.storage
-            .batch_write(batch)
+            .batch_write_opt(batch, sync)
             .map_err(|_| CommandError::StorageError)?;
+        for (old_size, new_size) in size_updates {
+            self.key_stats.replace(old_size, new_size);
+        }
+        if let Some(ref mut merkle) = self.merkle {
+            for (key, old, new) in merkle_updates {
+                merkle.update(key, old, new);
+            }
+        }
 
         Ok(())
     }
@@ -1062,10 +3273,18 @@ impl VNodeState {
     ) -> Result<Vec<Option<Cube>>, ()> {
         let mut batch = self.storage.batch_new(0);
         let mut results = Vec::with_capacity(writes.len());
+        let mut merkle_updates = Vec::new();
+        let mut size_updates = Vec::new();
         for (key, proposed, reply_result) in writes {
             // need to fetch old before adding any dot
             // otherwise the dots might be added to Void cubes
-            let old = self.storage_get(&key).map_err(|_| ())?;
+            let (mut old, old_size) = self.storage_get_stored_sized(&key).map_err(|_| ())?;
+            let old_digest = if self.merkle.is_some() {
+                MerkleTree::digest(&key, &old)
+            } else {
+                0
+            };
+            let expired = old.expire(now_millis());
 
             let mut empty = true;
             {
@@ -1086,20 +3305,117 @@ impl VNodeState {
                 continue;
             }
 
-            let new = old.merge(proposed);
+            let mut new = old.merge(proposed);
+            new.discard_oldest_versions(self.value_version_max);
 
-            if !empty {
-                if new.is_subsumed(&self.clocks) {
+            if !empty || expired {
+                let subsumed = new.is_subsumed(&self.clocks);
+                if subsumed {
                     batch.del(&key);
+                    size_updates.push((old_size, 0));
                 } else {
-                    let serialized = bincode::serialize(&new).expect("Can't serialize Cube");
+                    let serialized = self
+                        .storage
+                        .format()
+                        .serialize_value(&new)
+                        .expect("Can't serialize Cube");
                     batch.set(&key, &serialized);
+                    size_updates.push((old_size, serialized.len() as u64));
+                    if let Some(ref mut bloom) = self.bloom {
+                        bloom.insert(&key);
+                    }
+                }
+                if self.merkle.is_some() {
+                    let new_digest = if subsumed { 0 } else { MerkleTree::digest(&key, &new) };
+                    merkle_updates.push((key.clone(), old_digest, new_digest));
                 }
             }
 
             results.push(if reply_result { Some(new) } else { None });
         }
         self.storage.batch_write(batch).map_err(|_| ())?;
+        for (old_size, new_size) in size_updates {
+            self.key_stats.replace(old_size, new_size);
+        }
+        if let Some(ref mut merkle) = self.merkle {
+            for (key, old, new) in merkle_updates {
+                merkle.update(&key, old, new);
+            }
+        }
         Ok(results)
     }
 }
+
+// the cube as stored, None if the key isn't in the storage
+fn storage_read(storage: &Storage, key: &[u8]) -> Result<Option<Cube>, CommandError> {
+    storage_read_sized(storage, key).map(|r| r.map(|(cube, _)| cube))
+}
+
+// like storage_read but also returns the size of the stored value
+fn storage_read_sized(storage: &Storage, key: &[u8]) -> Result<Option<(Cube, u64)>, CommandError> {
+    let format = storage.format();
+    match storage.get(key, |v| (format.deserialize_value::<Cube>(v), v.len() as u64)) {
+        Ok(Some((Ok(cube), size))) => Ok(Some((cube, size))),
+        Ok(Some((Err(e), _))) => {
+            warn!("Value of {:?} is corrupted: {}", String::from_utf8_lossy(key), e);
+            Err(CommandError::Corrupted)
+        }
+        Ok(None) => Ok(None),
+        Err(_se) => Err(CommandError::StorageError),
+    }
+}
+
+/// Reads the keys of a MsgRemoteGet outside of the vnode, from a storage worker thread.
+/// Missing keys get `void`, the Cube::new of the vnode clocks when the read was queued.
+pub fn storage_get_keys(
+    storage: &Storage,
+    void: &Cube,
+    keys: &[Bytes],
+) -> Result<Vec<Cube>, FabricError> {
+    let now = now_millis();
+    let mut result = Vec::with_capacity(keys.len());
+    for key in keys {
+        let mut cube = storage_read(storage, key)
+            .map_err(|_| FabricError::StorageError)?
+            .unwrap_or_else(|| void.clone());
+        cube.expire(now);
+        result.push(cube);
+    }
+    Ok(result)
+}
+
+// marks each result as [STALE, result], the data may be partial (see do_get_stale)
+fn stale_response(mut response_fn: ResponseFn) -> ResponseFn {
+    Box::new(move |cube: Cube| {
+        RespValue::Array(vec![RespValue::Status("STALE".into()), response_fn(cube)])
+    })
+}
+
+fn write_frame<W: Write>(writer: &mut W, payload: &[u8]) -> Result<(), GenericError> {
+    if payload.len() > SNAPSHOT_FRAME_MAX {
+        return Err(format!("Snapshot frame of {} bytes over the limit", payload.len()).into());
+    }
+    writer.write_u32::<LittleEndian>(payload.len() as u32)?;
+    writer.write_all(payload)?;
+    Ok(())
+}
+
+fn read_frame<R: Read>(reader: &mut R) -> Result<Vec<u8>, GenericError> {
+    let len = reader.read_u32::<LittleEndian>()? as usize;
+    if len > SNAPSHOT_FRAME_MAX {
+        return Err(format!("Snapshot frame of {} bytes over the limit", len).into());
+    }
+    // grows as the payload is read, a truncated dump can't make it allocate len upfront
+    let mut payload = Vec::new();
+    reader.take(len as u64).read_to_end(&mut payload)?;
+    if payload.len() != len {
+        return Err("Truncated snapshot frame".into());
+    }
+    Ok(payload)
+}
+
+// exponential backoff in ms, capped at max
+fn bootstrap_backoff(failures: u32, base: u32, max: u32) -> Duration {
+    let ms = ((base as u64) << failures.saturating_sub(1).min(20)).min(max as u64);
+    Duration::from_millis(ms)
+}
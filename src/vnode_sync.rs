@@ -1,9 +1,12 @@
 use bincode;
 use bytes::Bytes;
+use command::{CommandError, KEYSPACE_PREFIX_LEN};
+use config::{self, OversizeValuePolicy};
 use cubes::Cube;
 use database::*;
 use fabric::*;
 use inflightmap::InFlightMap;
+use merkle::{MerkleTree, MERKLE_DEPTH};
 use metrics::{self, Meter};
 use std::collections::{hash_set, HashSet};
 use std::time::{Duration, Instant};
@@ -40,19 +43,53 @@ macro_rules! stry {
     }};
 }
 
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
 pub enum SyncDirection {
     Incomming,
     Outgoing,
 }
 
+/// Point in time progress of a running sync, see VNodeMetrics
+#[derive(Debug, Serialize)]
+pub struct SyncProgress {
+    pub cookie: Cookie,
+    pub peer: NodeId,
+    // sync, bootstrap or aae
+    pub kind: &'static str,
+    pub direction: SyncDirection,
+    // keys sent (includes inflight) or received (includes dups) and the size of their msgs
+    pub keys: u64,
+    pub bytes: u64,
+    pub elapsed_ms: u64,
+    pub keys_per_sec: f64,
+    // bootstraps only, the keys the sender expects to stream and the time left
+    // to reach them at the current rate
+    pub estimated_keys: Option<u64>,
+    pub eta_ms: Option<u64>,
+}
+
+// received keys between saves of the vnode state while bootstrapping
+const BOOTSTRAP_CHECKPOINT_KEYS: u64 = 10_000;
+// starts a receiver sends, each after twice the wait of the previous one, before giving up
+// on a peer that doesn't send anything, so the vnode can try another
+const SYNC_STARTS_MAX: u32 = 3;
+
+// the yielded key and value are moved into the sync message, which keeps them until acked
 type IteratorFn = Box<FnMut(&VNodeState) -> Result<Option<(Bytes, Cube)>, ()> + Send>;
 
-type InFlightSyncMsgMap = InFlightMap<u64, MsgSyncSend, Instant, IdHasherBuilder>;
+// SyncSend or SyncSendBatch msgs, by their (first) seq
+type InFlightSyncMsgMap = InFlightMap<u64, FabricMsg, Instant, IdHasherBuilder>;
+
+// whether the key belongs to the (index, count) slice of a bootstrap
+fn in_slice(key: &[u8], slice: (u16, u16)) -> bool {
+    slice.1 <= 1 || MerkleTree::leaf(key) % slice.1 as u32 == slice.0 as u32
+}
 
 struct SyncKeysIterator {
     dots_delta: BitmappedVersionVectorDelta,
     keys: hash_set::IntoIter<Bytes>,
+    // dots no longer in the log (past its retention)
+    missing: u64,
 }
 
 // TODO: Refactor into trait objects
@@ -71,6 +108,9 @@ pub enum Synchronization {
         peer: NodeId,
         // count of sent keys (includes inflight)
         count: u64,
+        // size of the sent msgs (includes inflight)
+        bytes: u64,
+        started: Instant,
         last_recv: Instant,
         last_send: Instant,
     },
@@ -81,25 +121,95 @@ pub enum Synchronization {
         peer: NodeId,
         // aprox count of received keys (includes dups)
         recv_count: u64,
+        // received seqs (+1), holes mean lost messages
+        recv_seqs: BitmappedVersion,
+        // acks held back while local writes are slow (seqs)
+        delayed_acks: Vec<u64>,
+        // starts sent without receiving anything back
+        starts_sent: u32,
+        // size of the received msgs (includes dups)
+        bytes: u64,
+        started: Instant,
         last_recv: Instant,
         last_send: Instant,
     },
     BootstrapSender {
         clocks_snapshot: BitmappedVersionVector,
+        // keys in the slice, None when resuming as it only sends the missing ones
+        estimated_keys: Option<u64>,
         iterator: IteratorFn,
         inflight: InFlightSyncMsgMap,
         cookie: Cookie,
         peer: NodeId,
         // count of sent keys (includes inflight)
         count: u64,
+        // size of the sent msgs (includes inflight)
+        bytes: u64,
+        started: Instant,
         last_recv: Instant,
         last_send: Instant,
     },
     BootstrapReceiver {
+        // what's already stored from a previous attempt, empty for a full bootstrap
+        clocks_in_peer: BitmappedVersionVector,
+        // slice of the keys streamed by this peer, see MsgSyncStart
+        slice: (u16, u16),
+        // keys the peer expects to stream, if it said so
+        estimated_keys: Option<u64>,
         cookie: Cookie,
         peer: NodeId,
         // aprox count of received keys (includes dups)
         recv_count: u64,
+        // received seqs (+1), holes mean lost messages
+        recv_seqs: BitmappedVersion,
+        // acks held back while local writes are slow (seqs)
+        delayed_acks: Vec<u64>,
+        // starts sent without receiving anything back
+        starts_sent: u32,
+        // size of the received msgs (includes dups)
+        bytes: u64,
+        started: Instant,
+        last_recv: Instant,
+        last_send: Instant,
+    },
+    // Like SyncSender but only streams the keys in the leaves of the
+    // hash tree that differ from the peer's
+    AAESender {
+        // bvv in peer at the time of sync start
+        clocks_in_peer: BitmappedVersionVector,
+        // partial copy of the local bvv at the time of sync start
+        clocks_snapshot: BitmappedVersionVector,
+        // tree level being compared and the nodes requested at that level,
+        // None once the differing leaves are known and the keys are being sent
+        exchange: Option<(u8, Vec<u32>)>,
+        iterator: IteratorFn,
+        inflight: InFlightSyncMsgMap,
+        cookie: Cookie,
+        peer: NodeId,
+        // count of sent keys (includes inflight)
+        count: u64,
+        // size of the sent msgs (includes inflight)
+        bytes: u64,
+        started: Instant,
+        last_recv: Instant,
+        last_send: Instant,
+    },
+    AAEReceiver {
+        // local bvv at the time of sync start
+        clocks_in_peer: BitmappedVersionVector,
+        cookie: Cookie,
+        peer: NodeId,
+        // aprox count of received keys (includes dups)
+        recv_count: u64,
+        // received seqs (+1), holes mean lost messages
+        recv_seqs: BitmappedVersion,
+        // acks held back while local writes are slow (seqs)
+        delayed_acks: Vec<u64>,
+        // starts sent without receiving anything back
+        starts_sent: u32,
+        // size of the received msgs (includes dups)
+        bytes: u64,
+        started: Instant,
         last_recv: Instant,
         last_send: Instant,
     },
@@ -110,6 +220,7 @@ impl SyncKeysIterator {
         SyncKeysIterator {
             dots_delta: dots_delta,
             keys: HashSet::new().into_iter(),
+            missing: 0,
         }
     }
 
@@ -134,7 +245,8 @@ impl SyncKeysIterator {
                         break;
                     }
                 } else {
-                    warn!("Can't find log key for ({}, {})", n, v);
+                    debug!("Can't find log key for ({}, {})", n, v);
+                    self.missing += 1;
                 }
             }
             if keys.is_empty() {
@@ -151,14 +263,30 @@ use self::Synchronization::*;
 impl Synchronization {
     pub fn new_bootstrap_receiver(
         _db: &Database,
-        _state: &mut VNodeState,
+        state: &mut VNodeState,
         peer: NodeId,
         cookie: Cookie,
+        slice: (u16, u16),
     ) -> Self {
+        // Resume from log_clocks, not clocks: every dot in it was written together with
+        // its key, while clocks only gets the rest of the sender clocks at the end.
+        let clocks_in_peer = if state.bootstrap_resume {
+            state.log_clocks.clone()
+        } else {
+            BitmappedVersionVector::new()
+        };
         BootstrapReceiver {
+            clocks_in_peer: clocks_in_peer,
+            slice: slice,
+            estimated_keys: None,
             cookie: cookie,
             peer: peer,
             recv_count: 0,
+            recv_seqs: Default::default(),
+            delayed_acks: Vec::new(),
+            starts_sent: 0,
+            bytes: 0,
+            started: Instant::now(),
             last_recv: Instant::now(),
             last_send: Instant::now(),
         }
@@ -170,30 +298,96 @@ impl Synchronization {
         peer: NodeId,
         msg: MsgSyncStart,
     ) -> Self {
-        let mut storage_iterator = state.storage.iterator();
-        let iterator_fn: IteratorFn = Box::new(move |_| {
-            let next = storage_iterator
-                .iter()
-                .map(|(k, v)| {
-                    let cube = bincode::deserialize::<Cube>(v).map_err(|_| ())?;
-                    Ok((Bytes::from(k), cube))
-                })
-                .next();
+        let clocks_in_peer = msg.clocks_in_peer;
+        let slice = msg.slice;
+        // A resumed bootstrap only needs the keys of the dots the peer is missing,
+        // as long as all of them can be found in the log. Otherwise start over.
+        let resume = clocks_in_peer.iter().next().is_some() && {
+            let mut logged = clocks_in_peer.clone();
+            logged.merge(&state.log_clocks);
+            state.clocks.delta(&logged).next().is_none()
+        };
 
-            match next {
-                Some(Ok(r)) => Ok(Some(r)),
-                None => Ok(None),
-                Some(Err(e)) => Err(e),
-            }
-        });
+        let iterator_fn: IteratorFn = if resume {
+            info!(
+                "Resuming bootstrap {:?} from {:?}",
+                msg.cookie, clocks_in_peer
+            );
+            let mut sync_keys = SyncKeysIterator::new(state.clocks.delta(&clocks_in_peer));
+            // like the syncs, go through the whole slice if the log lost some of them
+            let mut storage_iterator = None;
+            Box::new(move |state| {
+                if storage_iterator.is_none() {
+                    while let Some(key) = sync_keys.next(state)? {
+                        if in_slice(&key, slice) {
+                            match state.storage_get(&key) {
+                                Ok(cube) => return Ok(Some((key, cube))),
+                                // already logged, left for the read repairs and the other syncs
+                                Err(CommandError::Corrupted) => (),
+                                Err(_) => return Err(()),
+                            }
+                        }
+                    }
+                    if sync_keys.missing == 0 {
+                        return Ok(None);
+                    }
+                    warn!(
+                        "{} dots of vnode {} are no longer in the log, sending the whole slice",
+                        sync_keys.missing,
+                        state.num()
+                    );
+                    storage_iterator = Some(state.storage.iterator());
+                }
+                let format = state.storage.format();
+                let entries = storage_iterator
+                    .as_mut()
+                    .unwrap()
+                    .iter()
+                    .filter(|&(k, _)| in_slice(k, slice));
+                for (k, v) in entries {
+                    match format.deserialize_value::<Cube>(v) {
+                        Ok(cube) => return Ok(Some((Bytes::from(k), cube))),
+                        Err(e) => warn!(
+                            "Not sending the corrupted value of {:?}: {}",
+                            String::from_utf8_lossy(k),
+                            e
+                        ),
+                    }
+                }
+                Ok(None)
+            })
+        } else {
+            let format = state.storage.format();
+            let mut storage_iterator = state.storage.iterator();
+            Box::new(move |_| {
+                for (k, v) in storage_iterator.iter().filter(|&(k, _)| in_slice(k, slice)) {
+                    match format.deserialize_value::<Cube>(v) {
+                        Ok(cube) => return Ok(Some((Bytes::from(k), cube))),
+                        Err(e) => warn!(
+                            "Not sending the corrupted value of {:?}: {}",
+                            String::from_utf8_lossy(k),
+                            e
+                        ),
+                    }
+                }
+                Ok(None)
+            })
+        };
 
         BootstrapSender {
             cookie: msg.cookie,
             clocks_snapshot: state.clocks.clone(),
+            estimated_keys: if resume {
+                None
+            } else {
+                Some(state.keys() / slice.1.max(1) as u64)
+            },
             iterator: iterator_fn,
             inflight: InFlightMap::new(),
             peer: peer,
             count: 0,
+            bytes: 0,
+            started: Instant::now(),
             last_recv: Instant::now(),
             last_send: Instant::now(),
         }
@@ -210,6 +404,11 @@ impl Synchronization {
             peer: peer,
             cookie: cookie,
             recv_count: 0,
+            recv_seqs: Default::default(),
+            delayed_acks: Vec::new(),
+            starts_sent: 0,
+            bytes: 0,
+            started: Instant::now(),
             last_recv: Instant::now(),
             last_send: Instant::now(),
         }
@@ -238,13 +437,41 @@ impl Synchronization {
         );
 
         let mut sync_keys = SyncKeysIterator::new(dots_delta);
+        // walk the keys of the missing dots, if some of those are no longer in the
+        // log the peer can only catch up by going through the whole vnode afterwards
+        let mut storage_iterator = None;
         let iterator_fn: IteratorFn = Box::new(move |state| {
-            if let Some(key) = sync_keys.next(state)? {
-                let cube = state.storage_get(&key)?;
-                Ok(Some((key, cube)))
-            } else {
-                Ok(None)
+            if storage_iterator.is_none() {
+                while let Some(key) = sync_keys.next(state)? {
+                    match state.storage_get(&key) {
+                        Ok(cube) => return Ok(Some((key, cube))),
+                        // already logged, left for the read repairs and the other syncs
+                        Err(CommandError::Corrupted) => (),
+                        Err(_) => return Err(()),
+                    }
+                }
+                if sync_keys.missing == 0 {
+                    return Ok(None);
+                }
+                warn!(
+                    "{} dots of vnode {} are no longer in the log, sending the whole vnode",
+                    sync_keys.missing,
+                    state.num()
+                );
+                storage_iterator = Some(state.storage.iterator());
             }
+            let format = state.storage.format();
+            for (k, v) in storage_iterator.as_mut().unwrap().iter() {
+                match format.deserialize_value::<Cube>(v) {
+                    Ok(cube) => return Ok(Some((Bytes::from(k), cube))),
+                    Err(e) => warn!(
+                        "Not sending the corrupted value of {:?}: {}",
+                        String::from_utf8_lossy(k),
+                        e
+                    ),
+                }
+            }
+            Ok(None)
         });
 
         SyncSender {
@@ -255,6 +482,67 @@ impl Synchronization {
             cookie: cookie,
             peer: peer,
             count: 0,
+            bytes: 0,
+            started: Instant::now(),
+            last_recv: Instant::now(),
+            last_send: Instant::now(),
+        }
+    }
+
+    pub fn new_aae_receiver(
+        _db: &Database,
+        state: &mut VNodeState,
+        peer: NodeId,
+        cookie: Cookie,
+    ) -> Self {
+        AAEReceiver {
+            clocks_in_peer: state.clocks.clone(),
+            peer: peer,
+            cookie: cookie,
+            recv_count: 0,
+            recv_seqs: Default::default(),
+            delayed_acks: Vec::new(),
+            starts_sent: 0,
+            bytes: 0,
+            started: Instant::now(),
+            last_recv: Instant::now(),
+            last_send: Instant::now(),
+        }
+    }
+
+    pub fn new_aae_sender(
+        db: &Database,
+        state: &mut VNodeState,
+        peer: NodeId,
+        msg: MsgSyncStart,
+    ) -> Self {
+        let MsgSyncStart {
+            target,
+            cookie,
+            clocks_in_peer,
+            ..
+        } = msg;
+        assert_eq!(target, Some(db.dht.node()));
+
+        let clocks_snapshot = state.log_clocks.clone();
+        debug!(
+            "Creating AAESender {:?} from {:?} to {:?}",
+            cookie, clocks_snapshot, clocks_in_peer
+        );
+
+        AAESender {
+            clocks_in_peer: clocks_in_peer,
+            clocks_snapshot: clocks_snapshot,
+            // start from the root
+            exchange: Some((0, vec![0])),
+            // replaced once the exchange reaches the leaves
+            iterator: Box::new(|_| Ok(None)),
+            inflight: InFlightMap::new(),
+            cookie: cookie,
+            peer: peer,
+            count: 0,
+            bytes: 0,
+            started: Instant::now(),
             last_recv: Instant::now(),
             last_send: Instant::now(),
         }
@@ -262,25 +550,43 @@ impl Synchronization {
 
     // send SyncStart message, only valid for Receivers
     fn send_start(&mut self, db: &Database, state: &mut VNodeState) -> SyncResult {
-        let (peer, cookie, target, clocks_in_peer) = match *self {
+        let (peer, cookie, target, clocks_in_peer, aae, slice) = match *self {
             SyncReceiver {
                 cookie,
                 peer,
                 ref mut last_send,
+                ref mut starts_sent,
                 ref clocks_in_peer,
                 ..
             } => {
                 *last_send = Instant::now();
-                (peer, cookie, Some(peer), clocks_in_peer.clone())
+                *starts_sent += 1;
+                (peer, cookie, Some(peer), clocks_in_peer.clone(), false, (0, 1))
+            }
+            AAEReceiver {
+                cookie,
+                peer,
+                ref mut last_send,
+                ref mut starts_sent,
+                ref clocks_in_peer,
+                ..
+            } => {
+                *last_send = Instant::now();
+                *starts_sent += 1;
+                (peer, cookie, Some(peer), clocks_in_peer.clone(), true, (0, 1))
             }
             BootstrapReceiver {
                 peer,
                 cookie,
                 ref mut last_send,
+                ref mut starts_sent,
+                ref clocks_in_peer,
+                slice,
                 ..
             } => {
                 *last_send = Instant::now();
-                (peer, cookie, None, BitmappedVersionVector::new())
+                *starts_sent += 1;
+                (peer, cookie, None, clocks_in_peer.clone(), false, slice)
             }
             _ => unreachable!(),
         };
@@ -294,6 +600,8 @@ impl Synchronization {
                     vnode: state.num(),
                     clocks_in_peer: clocks_in_peer,
                     target: target,
+                    aae: aae,
+                    slice: slice,
                 },
             )
             .into()
@@ -330,6 +638,18 @@ impl Synchronization {
                 cookie,
                 ref mut last_send,
                 ..
+            }
+            | AAESender {
+                peer,
+                cookie,
+                ref mut last_send,
+                ..
+            }
+            | AAEReceiver {
+                peer,
+                cookie,
+                ref mut last_send,
+                ..
             } => {
                 *last_send = Instant::now();
                 let _ = db.fabric.send_msg(
@@ -362,6 +682,13 @@ impl Synchronization {
                 ref clocks_snapshot,
                 ref mut last_send,
                 ..
+            }
+            | AAESender {
+                peer,
+                cookie,
+                ref clocks_snapshot,
+                ref mut last_send,
+                ..
             } => {
                 *last_send = Instant::now();
                 db.fabric
@@ -382,14 +709,26 @@ impl Synchronization {
     // send (possibly multiple) SyncSend messages and eventual SyncFin
     // (also takes care of expired SyncSend)
     fn send_next(&mut self, db: &Database, state: &mut VNodeState) -> SyncResult {
+        if let AAESender {
+            exchange: Some(_), ..
+        } = *self
+        {
+            // still comparing the trees
+            return SyncResult::Continue;
+        }
         let now = Instant::now();
         let timeout = now + Duration::from_millis(db.config.sync_msg_timeout as _);
-        let (error, inflight_empty) = match *self {
+        let estimated_keys = match *self {
+            BootstrapSender { estimated_keys, .. } => estimated_keys,
+            _ => None,
+        };
+        let (error, throttled, inflight_empty) = match *self {
             SyncSender {
                 peer,
                 cookie,
                 ref mut iterator,
                 ref mut count,
+                ref mut bytes,
                 ref mut inflight,
                 ref mut last_send,
                 ..
@@ -399,50 +738,118 @@ impl Synchronization {
                 cookie,
                 ref mut iterator,
                 ref mut count,
+                ref mut bytes,
+                ref mut inflight,
+                ref mut last_send,
+                ..
+            }
+            | AAESender {
+                peer,
+                cookie,
+                ref mut iterator,
+                ref mut count,
+                ref mut bytes,
                 ref mut inflight,
                 ref mut last_send,
                 ..
             } => {
+                // a full send queue is backpressure, messages not sent because of it
+                // stay inflight and are resent once they time out
+                let mut queue_full = false;
                 while let Some((seq, msg)) = inflight.touch_expired(now, timeout) {
                     debug!("resending seq {} for sync/bootstrap {:?}", seq, cookie);
-                    let _ = stry!(db.fabric.send_msg(peer, msg,));
+                    match db.fabric.send_msg(peer, msg) {
+                        Err(FabricError::QueueFull) => {
+                            queue_full = true;
+                            break;
+                        }
+                        result => {
+                            let _ = stry!(result);
+                        }
+                    }
+                    db.sync_bandwidth_consume(bincode::serialized_size(msg).unwrap() as _);
                     metrics::SYNC_RESEND.mark(1);
                 }
                 let mut error = false;
-                while inflight.len() < db.config.sync_msg_inflight as usize {
-                    match iterator(state) {
-                        Ok(Some((k, v))) => {
-                            let msg = MsgSyncSend {
-                                cookie: cookie,
-                                vnode: state.num(),
-                                seq: *count,
-                                key: k.clone(),
-                                value: v.clone(),
-                            };
-                            let _ = stry!(db.fabric.send_msg(peer, &msg,));
-                            inflight.insert(*count, msg, timeout);
-                            *count += 1;
-                            *last_send = now;
-                            metrics::SYNC_SEND.mark(1);
-                            continue;
-                        }
-                        Ok(None) => {
-                            break;
+                let mut exhausted = false;
+                // out of bandwidth, the following keys wait for the next tick or ack
+                let mut throttled = false;
+                let batch_max = db.config.sync_msg_batch_max as u64;
+                while !queue_full
+                    && !exhausted
+                    && inflight.len() < db.config.sync_msg_inflight as usize
+                {
+                    if !db.sync_bandwidth_available() {
+                        throttled = true;
+                        break;
+                    }
+                    let mut entries = Vec::new();
+                    let mut entries_size = 0;
+                    while entries_size < batch_max || entries.is_empty() {
+                        match iterator(state) {
+                            Ok(Some((k, v))) => {
+                                if batch_max != 0 {
+                                    entries_size += bincode::serialized_size(&(&k, &v)).unwrap();
+                                }
+                                entries.push((k, v));
+                            }
+                            Ok(None) => {
+                                exhausted = true;
+                                break;
+                            }
+                            Err(_) => {
+                                error = true;
+                                break;
+                            }
                         }
-                        Err(_) => {
-                            error = true;
-                            break;
+                    }
+                    if error || entries.is_empty() {
+                        break;
+                    }
+
+                    let keys = entries.len() as u64;
+                    let first_estimate = if *count == 0 { estimated_keys } else { None };
+                    let msg: FabricMsg = if batch_max == 0 {
+                        let (key, value) = entries.pop().unwrap();
+                        MsgSyncSend {
+                            cookie: cookie,
+                            vnode: state.num(),
+                            seq: *count,
+                            key: key,
+                            value: value,
+                            estimated_keys: first_estimate,
+                        }.into()
+                    } else {
+                        MsgSyncSendBatch {
+                            cookie: cookie,
+                            vnode: state.num(),
+                            seq: *count,
+                            entries: entries,
+                            estimated_keys: first_estimate,
+                        }.into()
+                    };
+                    let msg_size = bincode::serialized_size(&msg).unwrap();
+                    match db.fabric.send_msg(peer, &msg) {
+                        Err(FabricError::QueueFull) => queue_full = true,
+                        result => {
+                            let _ = stry!(result);
+                            *last_send = now;
+                            db.sync_bandwidth_consume(msg_size as _);
+                            metrics::SYNC_SEND.mark(keys as _);
                         }
                     }
+                    *bytes += msg_size;
+                    inflight.insert(*count, msg, timeout);
+                    *count += keys;
                 }
-                (error, inflight.is_empty())
+                (error, throttled, inflight.is_empty())
             }
             _ => unreachable!(),
         };
 
         if error {
             self.send_error_fin(db, state, FabricError::SyncInterrupted)
-        } else if inflight_empty {
+        } else if inflight_empty && !throttled {
             // do not trottle success fin as we don't know if last_send
             // was set by MsgSend or MsgFin
             self.send_sender_success_fin(db, state)
@@ -451,21 +858,45 @@ impl Synchronization {
         }
     }
 
-    // called by vnode when node is transition to an incompatible state
-    // only valid for Receivers right now
-    pub fn on_cancel(&mut self, db: &Database, state: &mut VNodeState) {
+    // asks the receiver for the hashes of the nodes being compared, only valid for AAESender
+    fn send_tree_request(&mut self, db: &Database, state: &mut VNodeState) -> SyncResult {
         match *self {
-            BootstrapReceiver { .. } | SyncReceiver { .. } => {
-                let _ = self.send_error_fin(db, state, FabricError::BadVNodeStatus);
+            AAESender {
+                peer,
+                cookie,
+                exchange: Some((level, ref nodes)),
+                ref mut last_send,
+                ..
+            } => {
+                *last_send = Instant::now();
+                db.fabric
+                    .send_msg(
+                        peer,
+                        &MsgAAETreeRequest {
+                            cookie: cookie,
+                            vnode: state.num(),
+                            level: level,
+                            nodes: nodes.clone(),
+                        },
+                    )
+                    .into()
             }
+            AAESender { .. } => SyncResult::Continue,
             _ => unreachable!(),
         }
     }
 
+    // called by vnode when node is transition to an incompatible state,
+    // the sync is already unregistered and on_remove follows right after
+    pub fn on_cancel(&mut self, db: &Database, state: &mut VNodeState) {
+        // let the peer know, so it doesn't wait for the sync timeout
+        let _ = self.send_error_fin(db, state, FabricError::BadVNodeStatus);
+    }
+
     // called by vnode as soon as the sync is unregistered
     pub fn on_remove(self, db: &Database, state: &mut VNodeState) {
         match self {
-            SyncReceiver { peer, .. } => {
+            SyncReceiver { peer, .. } | AAEReceiver { peer, .. } => {
                 state.sync_nodes.remove(&peer);
             }
             _ => (),
@@ -475,22 +906,46 @@ impl Synchronization {
     }
 
     pub fn on_tick(&mut self, db: &Database, state: &mut VNodeState) -> SyncResult {
+        self.send_delayed_acks(db, state);
+        let sync_timeout = Duration::from_millis(db.config.effective_sync_timeout() as _);
+        let msg_timeout = Duration::from_millis(db.config.sync_msg_timeout as _);
         match *self {
             SyncSender {
                 last_recv, cookie, ..
             }
             | BootstrapSender {
                 last_recv, cookie, ..
-            } => if last_recv.elapsed() > Duration::from_millis(db.config.sync_timeout as _) {
+            }
+            | AAESender {
+                last_recv,
+                cookie,
+                exchange: None,
+                ..
+            } => if last_recv.elapsed() > sync_timeout {
                 warn!("sync/boostrap sender timed out {:?}", cookie);
                 SyncResult::Error
             } else {
                 self.send_next(db, state)
             },
+            AAESender {
+                last_recv,
+                last_send,
+                cookie,
+                ..
+            } => if last_recv.elapsed() > sync_timeout {
+                warn!("aae sender timed out {:?}", cookie);
+                SyncResult::Error
+            } else if last_send.elapsed() > msg_timeout {
+                self.send_tree_request(db, state)
+            } else {
+                SyncResult::Continue
+            },
             SyncReceiver {
                 last_recv,
                 recv_count,
                 last_send,
+                starts_sent,
+                peer,
                 cookie,
                 ..
             }
@@ -498,26 +953,93 @@ impl Synchronization {
                 last_recv,
                 recv_count,
                 last_send,
+                starts_sent,
+                peer,
+                cookie,
+                ..
+            }
+            | AAEReceiver {
+                last_recv,
+                recv_count,
+                last_send,
+                starts_sent,
+                peer,
                 cookie,
                 ..
-            } => if last_recv.elapsed() > Duration::from_millis(db.config.sync_timeout as _) {
+            } => if last_recv.elapsed() > sync_timeout {
                 warn!("sync/boostrap receiver timed out {:?}", cookie);
                 SyncResult::Error
             } else if recv_count == 0
-                && last_send.elapsed() > Duration::from_millis(db.config.sync_msg_timeout as _)
+                && last_send.elapsed() > msg_timeout * (1 << starts_sent.saturating_sub(1))
             {
-                self.send_start(db, state)
+                if starts_sent >= SYNC_STARTS_MAX {
+                    warn!(
+                        "sync/boostrap receiver {:?} got nothing from {} after {} starts",
+                        cookie, peer, starts_sent
+                    );
+                    state.stalled_peer = Some(peer);
+                    SyncResult::Error
+                } else {
+                    self.send_start(db, state)
+                }
             } else {
                 SyncResult::Continue
             },
         }
     }
 
+    // sends the acks held back by on_msg_send, only valid for Receivers
+    fn send_delayed_acks(&mut self, db: &Database, state: &mut VNodeState) {
+        match *self {
+            SyncReceiver {
+                peer,
+                cookie,
+                ref mut delayed_acks,
+                ref mut last_send,
+                ..
+            }
+            | BootstrapReceiver {
+                peer,
+                cookie,
+                ref mut delayed_acks,
+                ref mut last_send,
+                ..
+            }
+            | AAEReceiver {
+                peer,
+                cookie,
+                ref mut delayed_acks,
+                ref mut last_send,
+                ..
+            } => {
+                if delayed_acks.is_empty() {
+                    return;
+                }
+                debug!("Sending {} delayed acks for {:?}", delayed_acks.len(), cookie);
+                for seq in delayed_acks.drain(..) {
+                    let _ = db.fabric.send_msg(
+                        peer,
+                        &MsgSyncAck {
+                            cookie: cookie,
+                            vnode: state.num(),
+                            seq: seq,
+                        },
+                    );
+                }
+                *last_send = Instant::now();
+            }
+            _ => (),
+        }
+    }
+
     // called by vnode as soon as the sync is registered (after creation)
     pub fn on_start(&mut self, db: &Database, state: &mut VNodeState) {
         let _ = match *self {
-            SyncReceiver { .. } | BootstrapReceiver { .. } => self.send_start(db, state),
+            SyncReceiver { .. } | BootstrapReceiver { .. } | AAEReceiver { .. } => {
+                self.send_start(db, state)
+            }
             SyncSender { .. } | BootstrapSender { .. } => self.send_next(db, state),
+            AAESender { .. } => self.send_tree_request(db, state),
         };
     }
 
@@ -527,9 +1049,39 @@ impl Synchronization {
         state: &mut VNodeState,
         msg: MsgSyncFin,
     ) -> SyncResult {
+        // holes in the received seqs mean messages were lost
+        let missing_seqs = match *self {
+            SyncReceiver { ref recv_seqs, .. }
+            | BootstrapReceiver { ref recv_seqs, .. }
+            | AAEReceiver { ref recv_seqs, .. } => recv_seqs.max() != recv_seqs.base(),
+            _ => false,
+        };
+        // a slice only covers part of the vnode, its clocks are merged once all streams finish
+        let sliced = match *self {
+            BootstrapReceiver { slice, .. } => slice.1 > 1,
+            _ => false,
+        };
         match *self {
-            SyncReceiver { peer, .. } | BootstrapReceiver { peer, .. } => {
-                if msg.result.is_ok() {
+            SyncReceiver { peer, cookie, .. }
+            | BootstrapReceiver { peer, cookie, .. }
+            | AAEReceiver { peer, cookie, .. } => {
+                if msg.result.is_ok() && missing_seqs {
+                    // merging the sender clocks would cover the lost keys as well
+                    error!("Sync/bootstrap {:?} finished with missing seqs", cookie);
+                    self.send_error_fin(db, state, FabricError::SyncInterrupted)
+                } else if msg.result.is_ok() && sliced {
+                    let clocks = msg.result.as_ref().unwrap();
+                    // only versions present in all sources are surely stored by now
+                    state.bootstrap_clocks = Some(match state.bootstrap_clocks.take() {
+                        Some(mut bootstrap_clocks) => {
+                            bootstrap_clocks.intersect(clocks);
+                            bootstrap_clocks
+                        }
+                        None => clocks.clone(),
+                    });
+                    let _ = db.fabric.send_msg(peer, &msg);
+                    SyncResult::Done
+                } else if msg.result.is_ok() {
                     state.clocks.merge(msg.result.as_ref().unwrap());
                     state.save(db, false);
                     // send it back as a form of ack-ack
@@ -541,18 +1093,70 @@ impl Synchronization {
                     SyncResult::Error
                 }
             }
-            SyncSender { .. } | BootstrapSender { .. } => {
+            SyncSender { .. } | BootstrapSender { .. } | AAESender { .. } => {
                 // Senders are always Done on SyncFin messages
                 SyncResult::Done
             }
         }
     }
 
-    pub fn on_msg_send(&mut self, db: &Database, state: &mut VNodeState, msg: MsgSyncSend) {
+    // single key msgs arrive here as batches of one
+    pub fn on_msg_send(
+        &mut self,
+        db: &Database,
+        state: &mut VNodeState,
+        msg: MsgSyncSendBatch,
+    ) -> SyncResult {
+        let msg_size = bincode::serialized_size(&msg).unwrap();
+        if let BootstrapReceiver {
+            ref mut estimated_keys,
+            ..
+        } = *self
+        {
+            if msg.estimated_keys.is_some() {
+                *estimated_keys = msg.estimated_keys;
+            }
+        }
+        // Keys clients couldn't write are skipped instead of stored, the sync goes on so a
+        // single one can't fail every sync of the vnode. This replica won't have them
+        // until they're written again.
+        let mut skipped = Vec::new();
+        for (i, &(ref key, ref value)) in msg.entries.iter().enumerate() {
+            let value_len = value.max_value_len();
+            if key.len() > config::MAX_KEY_LEN + KEYSPACE_PREFIX_LEN {
+                error!(
+                    "Skipping key of {} bytes over the limit in sync {:?}",
+                    key.len(),
+                    msg.cookie
+                );
+                skipped.push(i);
+            } else if value_len > config::MAX_VALUE_LEN {
+                if db.config.oversize_value_policy == OversizeValuePolicy::Reject {
+                    error!(
+                        "Skipping key {:?} with a value of {} bytes over the limit in sync {:?}",
+                        key, value_len, msg.cookie
+                    );
+                    skipped.push(i);
+                    continue;
+                }
+                warn!(
+                    "Storing synced key {:?} with a value of {} bytes, over the limit",
+                    key, value_len
+                );
+            }
+        }
+        if !skipped.is_empty() {
+            metrics::SYNC_SKIPPED.mark(skipped.len() as _);
+        }
+        let keys = msg.entries.len() as u64;
+
         match *self {
             SyncReceiver {
                 peer,
                 ref mut recv_count,
+                ref mut recv_seqs,
+                ref mut delayed_acks,
+                ref mut bytes,
                 ref mut last_recv,
                 ref mut last_send,
                 ..
@@ -560,32 +1164,82 @@ impl Synchronization {
             | BootstrapReceiver {
                 peer,
                 ref mut recv_count,
+                ref mut recv_seqs,
+                ref mut delayed_acks,
+                ref mut bytes,
+                ref mut last_recv,
+                ref mut last_send,
+                ..
+            }
+            | AAEReceiver {
+                peer,
+                ref mut recv_count,
+                ref mut recv_seqs,
+                ref mut delayed_acks,
+                ref mut bytes,
                 ref mut last_recv,
                 ref mut last_send,
                 ..
             } => {
-                // TODO: what to do with errors here?
-                state
-                    .storage_set_remote(db, vec![(msg.key, msg.value, false)])
-                    .unwrap();
-
-                let _ = db.fabric.send_msg(
-                    peer,
-                    &MsgSyncAck {
-                        cookie: msg.cookie,
-                        vnode: state.num(),
-                        seq: msg.seq,
-                    },
-                );
+                let write_start = Instant::now();
+                // peers that didn't apply a ring split yet still send the keys moved away
+                let writes: Vec<_> = msg
+                    .entries
+                    .into_iter()
+                    .enumerate()
+                    .filter(|&(i, ref e)| {
+                        !skipped.contains(&i) && db.key_vnode(&e.0) == state.num()
+                    })
+                    .map(|(_, (key, value))| (key, value, false))
+                    .collect();
+                if !writes.is_empty() {
+                    // TODO: what to do with errors here?
+                    state.storage_set_remote(db, writes).unwrap();
+                }
 
-                *recv_count += 1;
+                // Slow writes hold back the acks until the next tick, throttling the sender
+                // as it's bounded by the inflight messages. Once acks are being held
+                // the following ones wait as well, so they're sent in order.
+                let latency_limit = db.config.sync_backpressure_latency;
                 let now = Instant::now();
+                if !delayed_acks.is_empty()
+                    || (latency_limit != 0
+                        && now - write_start > Duration::from_millis(latency_limit as _))
+                {
+                    delayed_acks.push(msg.seq);
+                    metrics::SYNC_ACK_DELAYED.mark(1);
+                } else {
+                    let _ = db.fabric.send_msg(
+                        peer,
+                        &MsgSyncAck {
+                            cookie: msg.cookie,
+                            vnode: state.num(),
+                            seq: msg.seq,
+                        },
+                    );
+                    *last_send = now;
+                }
+
+                for seq in msg.seq..msg.seq + keys {
+                    recv_seqs.add(seq + 1);
+                }
+                *recv_count += keys;
+                *bytes += msg_size;
                 *last_recv = now;
-                *last_send = now;
-                metrics::SYNC_RECV.mark(1);
+                metrics::SYNC_RECV.mark(keys as _);
             }
             _ => unreachable!(),
         }
+
+        // checkpoint so a restarted node can resume the bootstrap from here
+        if let BootstrapReceiver { recv_count, .. } = *self {
+            // batches may step over the multiples
+            let checkpoints = recv_count / BOOTSTRAP_CHECKPOINT_KEYS;
+            if (recv_count - keys) / BOOTSTRAP_CHECKPOINT_KEYS != checkpoints {
+                state.save(db, false);
+            }
+        }
+        SyncResult::Continue
     }
 
     pub fn on_msg_ack(&mut self, db: &Database, state: &mut VNodeState, msg: MsgSyncAck) {
@@ -599,6 +1253,11 @@ impl Synchronization {
                 ref mut inflight,
                 ref mut last_recv,
                 ..
+            }
+            | AAESender {
+                ref mut inflight,
+                ref mut last_recv,
+                ..
             } => {
                 inflight.remove(&msg.seq);
                 *last_recv = Instant::now();
@@ -608,10 +1267,242 @@ impl Synchronization {
         let _ = self.send_next(db, state);
     }
 
+    pub fn on_msg_aae_tree_request(
+        &mut self,
+        db: &Database,
+        state: &mut VNodeState,
+        msg: MsgAAETreeRequest,
+    ) {
+        let hashes = state.merkle_tree().map(|tree| {
+            msg.nodes
+                .iter()
+                .map(|&n| tree.hash(msg.level, n).unwrap_or(0))
+                .collect::<Vec<_>>()
+        });
+        let hashes = match hashes {
+            Ok(hashes) => hashes,
+            Err(_) => {
+                error!("Can't build merkle tree for vnode {}", state.num());
+                let _ = self.send_error_fin(db, state, FabricError::StorageError);
+                return;
+            }
+        };
+        match *self {
+            AAEReceiver {
+                peer,
+                cookie,
+                ref mut last_recv,
+                ref mut last_send,
+                ref mut starts_sent,
+                ..
+            } => {
+                let now = Instant::now();
+                // the sender is making progress, even if no key was received yet
+                *starts_sent = 0;
+                let _ = db.fabric.send_msg(
+                    peer,
+                    &MsgAAETree {
+                        cookie: cookie,
+                        vnode: state.num(),
+                        level: msg.level,
+                        hashes: hashes,
+                    },
+                );
+                *last_recv = now;
+                *last_send = now;
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn on_msg_aae_tree(&mut self, db: &Database, state: &mut VNodeState, msg: MsgAAETree) {
+        let (level, differing) = match *self {
+            AAESender {
+                exchange: Some((level, ref nodes)),
+                ref mut last_recv,
+                ..
+            } => {
+                if msg.level != level || msg.hashes.len() != nodes.len() {
+                    debug!("Ignoring unexpected aae tree for {:?}", msg.cookie);
+                    return;
+                }
+                *last_recv = Instant::now();
+                let differing = state.merkle_tree().map(|tree| {
+                    nodes
+                        .iter()
+                        .zip(&msg.hashes)
+                        .filter(|&(&n, &h)| tree.hash(level, n) != Some(h))
+                        .map(|(&n, _)| n)
+                        .collect::<Vec<_>>()
+                });
+                (level, differing)
+            }
+            // a retransmission, the exchange is already done
+            AAESender { .. } => return,
+            _ => unreachable!(),
+        };
+        let differing = match differing {
+            Ok(differing) => differing,
+            Err(_) => {
+                error!("Can't build merkle tree for vnode {}", state.num());
+                let _ = self.send_error_fin(db, state, FabricError::StorageError);
+                return;
+            }
+        };
+        debug!(
+            "aae {:?} found {} differing nodes at level {}",
+            msg.cookie,
+            differing.len(),
+            level
+        );
+
+        if level < MERKLE_DEPTH && !differing.is_empty() {
+            if let AAESender {
+                ref mut exchange, ..
+            } = *self
+            {
+                let children = differing
+                    .iter()
+                    .flat_map(|&n| MerkleTree::children(n))
+                    .collect();
+                *exchange = Some((level + 1, children));
+            }
+            let _ = self.send_tree_request(db, state);
+            return;
+        }
+
+        // stream the stored keys that fall in the differing leaves, regardless of the
+        // peer clocks, those may cover versions the peer lost (that's what aae is for).
+        // If the trees are equal there's nothing to send but the clocks
+        let leaves: HashSet<u32> = differing.into_iter().collect();
+        if let AAESender {
+            ref mut exchange,
+            ref mut iterator,
+            ..
+        } = *self
+        {
+            let format = state.storage.format();
+            let mut storage_iterator = state.storage.iterator();
+            *iterator = Box::new(move |_| {
+                if leaves.is_empty() {
+                    return Ok(None);
+                }
+                let entries = storage_iterator
+                    .iter()
+                    .filter(|&(k, _)| leaves.contains(&MerkleTree::leaf(k)));
+                for (k, v) in entries {
+                    match format.deserialize_value::<Cube>(v) {
+                        Ok(cube) => return Ok(Some((Bytes::from(k), cube))),
+                        Err(e) => warn!(
+                            "Not sending the corrupted value of {:?}: {}",
+                            String::from_utf8_lossy(k),
+                            e
+                        ),
+                    }
+                }
+                Ok(None)
+            });
+            *exchange = None;
+        }
+        let _ = self.send_next(db, state);
+    }
+
     pub fn direction(&self) -> SyncDirection {
         match *self {
-            BootstrapReceiver { .. } | SyncReceiver { .. } => SyncDirection::Incomming,
-            BootstrapSender { .. } | SyncSender { .. } => SyncDirection::Outgoing,
+            BootstrapReceiver { .. } | SyncReceiver { .. } | AAEReceiver { .. } => {
+                SyncDirection::Incomming
+            }
+            BootstrapSender { .. } | SyncSender { .. } | AAESender { .. } => {
+                SyncDirection::Outgoing
+            }
+        }
+    }
+
+    /// Number of keys received so far, always 0 for senders
+    pub fn recv_count(&self) -> u64 {
+        match *self {
+            BootstrapReceiver { recv_count, .. }
+            | SyncReceiver { recv_count, .. }
+            | AAEReceiver { recv_count, .. } => recv_count,
+            BootstrapSender { .. } | SyncSender { .. } | AAESender { .. } => 0,
+        }
+    }
+
+    pub fn progress(&self) -> SyncProgress {
+        let (kind, cookie, peer, keys, bytes, started, estimated_keys) = match *self {
+            SyncSender {
+                cookie,
+                peer,
+                count,
+                bytes,
+                started,
+                ..
+            } => ("sync", cookie, peer, count, bytes, started, None),
+            SyncReceiver {
+                cookie,
+                peer,
+                recv_count,
+                bytes,
+                started,
+                ..
+            } => ("sync", cookie, peer, recv_count, bytes, started, None),
+            BootstrapSender {
+                cookie,
+                peer,
+                count,
+                bytes,
+                started,
+                estimated_keys,
+                ..
+            } => ("bootstrap", cookie, peer, count, bytes, started, estimated_keys),
+            BootstrapReceiver {
+                cookie,
+                peer,
+                recv_count,
+                bytes,
+                started,
+                estimated_keys,
+                ..
+            } => ("bootstrap", cookie, peer, recv_count, bytes, started, estimated_keys),
+            AAESender {
+                cookie,
+                peer,
+                count,
+                bytes,
+                started,
+                ..
+            } => ("aae", cookie, peer, count, bytes, started, None),
+            AAEReceiver {
+                cookie,
+                peer,
+                recv_count,
+                bytes,
+                started,
+                ..
+            } => ("aae", cookie, peer, recv_count, bytes, started, None),
+        };
+        let elapsed = started.elapsed();
+        let elapsed_ms = elapsed.as_secs() * 1000 + elapsed.subsec_nanos() as u64 / 1_000_000;
+        let keys_per_sec = if elapsed_ms == 0 {
+            0.0
+        } else {
+            keys as f64 * 1000.0 / elapsed_ms as f64
+        };
+        // unknown until some keys went through
+        let eta_ms = estimated_keys
+            .filter(|_| keys_per_sec > 0.0)
+            .map(|total| (total.saturating_sub(keys) as f64 * 1000.0 / keys_per_sec) as u64);
+        SyncProgress {
+            cookie: cookie,
+            peer: peer,
+            kind: kind,
+            direction: self.direction(),
+            keys: keys,
+            bytes: bytes,
+            elapsed_ms: elapsed_ms,
+            keys_per_sec: keys_per_sec,
+            estimated_keys: estimated_keys,
+            eta_ms: eta_ms,
         }
     }
 }
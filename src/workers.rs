@@ -1,6 +1,31 @@
 use crossbeam_channel as chan;
+use metrics::{self, Meter};
+use std::panic::{self, AssertUnwindSafe};
+use std::process;
+use std::str::FromStr;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::{thread, time};
+use utils::GenericError;
+
+/// What a worker thread does if processing a message panics
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PanicPolicy {
+    /// Log and keep processing messages
+    Restart,
+    /// Abort the whole process
+    Abort,
+}
+
+impl FromStr for PanicPolicy {
+    type Err = GenericError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "restart" => Ok(PanicPolicy::Restart),
+            "abort" => Ok(PanicPolicy::Abort),
+            _ => Err(format!("Unknown panic policy `{}`", s).into()),
+        }
+    }
+}
 
 pub trait ExitMsg {
     fn exit_msg() -> Self;
@@ -8,7 +33,8 @@ pub trait ExitMsg {
 }
 
 /// A Sender attached to a WorkerManager
-/// messages are distributed to threads in a Round-Robin manner.
+/// messages are distributed to threads in a Round-Robin manner,
+/// unless the thread is picked with try_send_to.
 pub struct WorkerSender<T: ExitMsg + Send + 'static> {
     cursor: AtomicUsize,
     channels: Vec<chan::Sender<T>>,
@@ -29,16 +55,18 @@ pub struct WorkerManager<T: ExitMsg + Send + 'static> {
     threads: Vec<thread::JoinHandle<()>>,
     name: String,
     channels: Vec<chan::Sender<T>>,
+    panic_policy: PanicPolicy,
 }
 
 impl<T: ExitMsg + Send + 'static> WorkerManager<T> {
-    pub fn new(name: String, thread_count: usize) -> Self {
+    pub fn new(name: String, thread_count: usize, panic_policy: PanicPolicy) -> Self {
         assert!(thread_count > 0);
         WorkerManager {
             thread_count: thread_count,
             threads: Default::default(),
             name: name,
             channels: Default::default(),
+            panic_policy: panic_policy,
         }
     }
 
@@ -51,6 +79,7 @@ impl<T: ExitMsg + Send + 'static> WorkerManager<T> {
             // since neither closure cloning or Box<FnOnce> are stable use Box<FnMut>
             let mut worker_fn = worker_fn_gen();
             let (tx, rx) = chan::unbounded();
+            let panic_policy = self.panic_policy;
             self.channels.push(tx);
             self.threads.push(
                 thread::Builder::new()
@@ -60,7 +89,15 @@ impl<T: ExitMsg + Send + 'static> WorkerManager<T> {
                             if m.is_exit() {
                                 break;
                             }
-                            worker_fn(m);
+                            // the panic message is already printed by the panic hook
+                            if panic::catch_unwind(AssertUnwindSafe(|| worker_fn(m))).is_err() {
+                                metrics::WORKER_PANIC.mark(1);
+                                if panic_policy == PanicPolicy::Abort {
+                                    error!("Worker panicked, aborting");
+                                    process::abort();
+                                }
+                                error!("Worker panicked, restarting");
+                            }
                         }
                         info!("Exiting worker");
                     })
@@ -78,6 +115,17 @@ impl<T: ExitMsg + Send + 'static> WorkerManager<T> {
     }
 }
 
+impl<T: ExitMsg + Send + 'static> WorkerManager<T> {
+    /// Stops the threads once they process the messages already queued, the messages
+    /// sent afterwards are dropped. The returned threads can be joined to wait for it.
+    pub fn stop(&mut self) -> Vec<thread::JoinHandle<()>> {
+        for c in &*self.channels {
+            let _ = c.send(T::exit_msg());
+        }
+        self.threads.drain(..).collect()
+    }
+}
+
 impl<T: ExitMsg + Send + 'static> WorkerSender<T> {
     pub fn send(&self, msg: T) {
         // right now only possible error is disconected, so no need to do anything
@@ -89,17 +137,24 @@ impl<T: ExitMsg + Send + 'static> WorkerSender<T> {
         self.try_send_to(cursor, msg)
     }
 
+    // the same seed always goes to the same thread
     pub fn try_send_to(&self, seed: usize, msg: T) -> Result<(), chan::SendError<T>> {
         self.channels[seed % self.channels.len()].send(msg)
     }
+
+    pub fn workers(&self) -> usize {
+        self.channels.len()
+    }
+
+    /// Messages waiting in each thread queue
+    pub fn queue_lens(&self) -> Vec<usize> {
+        self.channels.iter().map(|c| c.len()).collect()
+    }
 }
 
 impl<T: ExitMsg + Send + 'static> Drop for WorkerManager<T> {
     fn drop(&mut self) {
-        for c in &*self.channels {
-            let _ = c.send(T::exit_msg());
-        }
-        for t in self.threads.drain(..) {
+        for t in self.stop() {
             let _ = t.join();
         }
     }